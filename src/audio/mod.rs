@@ -0,0 +1,73 @@
+extern crate sdl2;
+
+use sdl2::Sdl;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+use std::mem;
+
+const SAMPLE_RATE: i32 = 44100;
+const CHANNELS: u8 = 2;
+const BUFFER_SAMPLES: u16 = 1024;
+
+// How much audio we try to keep queued, in seconds
+const TARGET_LATENCY: f64 = 0.05;
+// Largest fraction the generated sample rate is nudged by
+const MAX_RATE_DELTA: f64 = 0.005;
+
+pub struct Speaker {
+    queue: AudioQueue<f32>,
+    sample_rate: u32,
+}
+
+impl Speaker {
+    pub fn new(sdl: &Sdl) -> Result<Self, String> {
+        let audio = sdl.audio()?;
+
+        let desired = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(CHANNELS),
+            samples: Some(BUFFER_SAMPLES),
+        };
+
+        let queue = audio.open_queue::<f32, _>(None, &desired)?;
+        queue.resume();
+
+        // The device may not give us the rate we asked for
+        let sample_rate = queue.spec().freq as u32;
+
+        Ok(Speaker { queue, sample_rate })
+    }
+
+    // What the device plays at, without any rate control
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn queue(&mut self, samples: &[f32]) {
+        // If we've somehow got far ahead, drop what's queued rather than
+        // letting the latency build up
+        if self.queued_seconds() > TARGET_LATENCY * 4.0 {
+            warn!("Audio queue overfilled, clearing");
+            self.queue.clear();
+        }
+
+        if !self.queue.queue(samples) {
+            warn!("Failed to queue {} audio samples!", samples.len());
+        }
+    }
+
+    fn queued_seconds(&self) -> f64 {
+        let bytes_per_frame = mem::size_of::<f32>() * CHANNELS as usize;
+        let frames = f64::from(self.queue.size()) / bytes_per_frame as f64;
+        frames / f64::from(self.sample_rate)
+    }
+
+    // Dynamic rate control: the emulator produces slightly more samples when
+    // the queue is running low and slightly fewer when it's filling up, so
+    // video can be paced by the clock without the audio crackling or drifting
+    pub fn adjusted_rate(&self) -> u32 {
+        let fill = (self.queued_seconds() / TARGET_LATENCY).min(2.0);
+        let delta = (1.0 - fill) * MAX_RATE_DELTA;
+        (f64::from(self.sample_rate) * (1.0 + delta)).round() as u32
+    }
+}
@@ -0,0 +1,131 @@
+// Pieces shared between the sound channels
+
+use crate::emulator::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
+pub struct LengthCounter {
+    pub enabled: bool,
+    counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> Self {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    // NRx1 holds the length as "max - remaining"
+    pub fn set(&mut self, value: u16) {
+        self.counter = self.max - value;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // Returns true when the counter runs out and the channel should turn off
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+}
+
+pub struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    pub volume: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            initial: 0,
+            increase: false,
+            period: 0,
+            timer: 0,
+            volume: 0,
+        }
+    }
+
+    // NRx2 - Bits 7-4: initial volume, bit 3: direction, bits 2-0: period
+    pub fn write(&mut self, data: u8) {
+        self.initial = data >> 4;
+        self.increase = tbit!(data, 3);
+        self.period = data & 0b111;
+    }
+
+    // The DAC is powered whenever the upper 5 bits of NRx2 are non-zero
+    pub fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    pub fn trigger(&mut self) {
+        self.timer = self.period;
+        self.volume = self.initial;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+impl Savestate for LengthCounter {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.counter);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.enabled = reader.read_bool()?;
+        self.counter = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Envelope {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.initial);
+        writer.write_bool(self.increase);
+        writer.write_u8(self.period);
+        writer.write_u8(self.timer);
+        writer.write_u8(self.volume);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.initial = reader.read_u8()?;
+        self.increase = reader.read_bool()?;
+        self.period = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+        self.volume = reader.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+// Debugging controls applied on top of what the game writes to NR51
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channel {
+    Square1 = 0,
+    Square2 = 1,
+    Wave    = 2,
+    Noise   = 3,
+}
+
+pub const ALL_CHANNELS: [Channel; 4] = [Channel::Square1, Channel::Square2, Channel::Wave, Channel::Noise];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Panning {
+    // Follow NR51
+    Game,
+    Left,
+    Right,
+    Both,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChannelControl {
+    pub muted: bool,
+    // When any channel is soloed, only soloed channels are heard
+    pub solo: bool,
+    pub panning: Panning,
+}
+
+impl ChannelControl {
+    pub fn new() -> Self {
+        ChannelControl {
+            muted: false,
+            solo: false,
+            panning: Panning::Game,
+        }
+    }
+
+    // Which sides this channel ends up on, given the game's NR51 value
+    pub fn outputs(&self, channel: Channel, nr51: u8) -> (bool, bool) {
+        match self.panning {
+            Panning::Game => (tbit!(nr51, channel as u8 + 4), tbit!(nr51, channel as u8)),
+            Panning::Left => (true, false),
+            Panning::Right => (false, true),
+            Panning::Both => (true, true),
+        }
+    }
+}
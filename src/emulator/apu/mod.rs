@@ -0,0 +1,541 @@
+mod channel;
+mod mixer;
+mod noise;
+mod square;
+mod wave;
+
+pub use mixer::{Channel, ChannelControl, Panning, ALL_CHANNELS};
+
+use noise::Noise;
+use square::Square;
+use wave::{Wave, WAVE_RAM_SIZE};
+
+use super::CLOCK_SPEED;
+use super::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+const NR10_ADDRESS: usize = 0xFF10;
+const NR14_ADDRESS: usize = 0xFF14;
+const NR21_ADDRESS: usize = 0xFF16;
+const NR24_ADDRESS: usize = 0xFF19;
+const NR30_ADDRESS: usize = 0xFF1A;
+const NR34_ADDRESS: usize = 0xFF1E;
+const NR41_ADDRESS: usize = 0xFF20;
+const NR44_ADDRESS: usize = 0xFF23;
+const NR50_ADDRESS: usize = 0xFF24;
+const NR51_ADDRESS: usize = 0xFF25;
+const NR52_ADDRESS: usize = 0xFF26;
+
+const WAVE_RAM_ADDRESS: usize = 0xFF30;
+const WAVE_RAM_END: usize = WAVE_RAM_ADDRESS + WAVE_RAM_SIZE - 1;
+
+// Bits that always read back as 1 for each register from 0xFF10 to 0xFF2F
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+pub struct Apu {
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+
+    // Last values written to 0xFF10-0xFF2F
+    registers: [u8; 0x20],
+    powered: bool,
+
+    // Frame sequencer step, 0-7, clocked at 512 Hz by DIV
+    frame_step: u8,
+
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    sample_timer: f64,
+    accumulated: (f32, f32),
+    accumulated_cycles: u32,
+    capacitors: (f32, f32),
+    charge_factor: f32,
+
+    // Interleaved left/right samples waiting for the frontend
+    samples: Vec<f32>,
+
+    controls: [ChannelControl; 4],
+    // Per-channel mono output, only collected while a debugger wants it
+    taps: Option<[Vec<f32>; 4]>,
+    tap_accumulated: [f32; 4],
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut apu = Apu {
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            registers: [0; 0x20],
+            powered: false,
+            frame_step: 0,
+            sample_rate: 0,
+            cycles_per_sample: 0.0,
+            sample_timer: 0.0,
+            accumulated: (0.0, 0.0),
+            accumulated_cycles: 0,
+            capacitors: (0.0, 0.0),
+            charge_factor: 0.0,
+            samples: Vec::new(),
+            controls: [ChannelControl::new(); 4],
+            taps: None,
+            tap_accumulated: [0.0; 4],
+        };
+        apu.set_sample_rate(sample_rate);
+        apu.init();
+        apu
+    }
+
+    fn init(&mut self) {
+        // Register state left behind by the boot ROM
+        self.write(0xFF26, 0xF1);
+        self.write(0xFF10, 0x80);
+        self.write(0xFF11, 0xBF);
+        self.write(0xFF12, 0xF3);
+        self.write(0xFF14, 0xBF);
+        self.write(0xFF16, 0x3F);
+        self.write(0xFF17, 0x00);
+        self.write(0xFF19, 0xBF);
+        self.write(0xFF1A, 0x7F);
+        self.write(0xFF1B, 0xFF);
+        self.write(0xFF1C, 0x9F);
+        self.write(0xFF1E, 0xBF);
+        self.write(0xFF20, 0xFF);
+        self.write(0xFF21, 0x00);
+        self.write(0xFF22, 0x00);
+        self.write(0xFF23, 0xBF);
+        self.write(0xFF24, 0x77);
+        self.write(0xFF25, 0xF3);
+    }
+
+    // Power cycle, keeping the frontend's sample rate and debugging controls
+    pub fn reset(&mut self) {
+        let mut apu = Apu::new(self.sample_rate);
+        apu.controls = self.controls;
+        apu.set_taps_enabled(self.taps.is_some());
+        *self = apu;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.cycles_per_sample = f64::from(CLOCK_SPEED) / f64::from(sample_rate);
+        // High-pass filter charge factor, scaled from the per-cycle value
+        self.charge_factor = 0.999_958f64.powf(self.cycles_per_sample) as f32;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        match address {
+            NR52_ADDRESS => {
+                let mut status = READ_MASKS[address - NR10_ADDRESS];
+                if self.powered { status |= 0b1000_0000; }
+                if self.square1.enabled { status |= 0b0001; }
+                if self.square2.enabled { status |= 0b0010; }
+                if self.wave.enabled { status |= 0b0100; }
+                if self.noise.enabled { status |= 0b1000; }
+                status
+            },
+            NR10_ADDRESS..=0xFF2F => {
+                let index = address - NR10_ADDRESS;
+                self.registers[index] | READ_MASKS[index]
+            },
+            WAVE_RAM_ADDRESS..=WAVE_RAM_END => {
+                self.wave.ram[address - WAVE_RAM_ADDRESS]
+            },
+            _ => panic!("Attempting to read address {} which is not a sound register!", address)
+        }
+    }
+
+    pub fn write(&mut self, address: usize, data: u8) {
+        match address {
+            NR52_ADDRESS => {
+                let power = tbit!(data, 7);
+                if self.powered && !power {
+                    self.power_off();
+                } else if !self.powered && power {
+                    self.frame_step = 0;
+                }
+                self.powered = power;
+            },
+            NR10_ADDRESS..=0xFF2F => {
+                // Registers are read-only while the APU is off
+                if !self.powered {
+                    return;
+                }
+
+                self.registers[address - NR10_ADDRESS] = data;
+
+                match address {
+                    NR10_ADDRESS..=NR14_ADDRESS => self.square1.write(address - NR10_ADDRESS, data),
+                    NR21_ADDRESS..=NR24_ADDRESS => self.square2.write(address - NR21_ADDRESS + 1, data),
+                    NR30_ADDRESS..=NR34_ADDRESS => self.wave.write(address - NR30_ADDRESS, data),
+                    NR41_ADDRESS..=NR44_ADDRESS => self.noise.write(address - NR41_ADDRESS + 1, data),
+                    _ => ()
+                }
+            },
+            WAVE_RAM_ADDRESS..=WAVE_RAM_END => {
+                self.wave.ram[address - WAVE_RAM_ADDRESS] = data;
+            },
+            _ => panic!("Attempting to write address {} which is not a sound register!", address)
+        }
+    }
+
+    fn power_off(&mut self) {
+        // Everything but wave RAM is cleared
+        let ram = self.wave.ram;
+
+        self.square1 = Square::new(true);
+        self.square2 = Square::new(false);
+        self.wave = Wave::new();
+        self.noise = Noise::new();
+        self.wave.ram = ram;
+
+        self.registers = [0; 0x20];
+    }
+
+    // Called on every falling edge of DIV bit 4
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.powered {
+            return;
+        }
+
+        // Step: 0 1 2 3 4 5 6 7
+        // Length: x   x   x   x
+        // Sweep:      x       x
+        // Envelope:               x
+        if self.frame_step & 1 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+
+        if self.frame_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            if self.powered {
+                self.square1.tick();
+                self.square2.tick();
+                self.wave.tick();
+                self.noise.tick();
+            }
+
+            let outputs = self.channel_outputs();
+            let (left, right) = self.mix(&outputs);
+            self.accumulated.0 += left;
+            self.accumulated.1 += right;
+            self.accumulated_cycles += 1;
+
+            if self.taps.is_some() {
+                for (total, output) in self.tap_accumulated.iter_mut().zip(outputs.iter()) {
+                    *total += output;
+                }
+            }
+
+            self.sample_timer += 1.0;
+            if self.sample_timer >= self.cycles_per_sample {
+                self.sample_timer -= self.cycles_per_sample;
+                self.push_sample();
+            }
+        }
+    }
+
+    fn channel_outputs(&self) -> [f32; 4] {
+        let outputs = [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ];
+
+        // The DAC maps 0-15 to an analog level between 1 and -1
+        let mut analog = [0.0; 4];
+        for (level, output) in analog.iter_mut().zip(outputs.iter()) {
+            if let Some(value) = output {
+                *level = 1.0 - f32::from(*value) / 7.5;
+            }
+        }
+        analog
+    }
+
+    fn mix(&self, outputs: &[f32; 4]) -> (f32, f32) {
+        // NR51 - Bits 7-4: channel 4-1 to left, bits 3-0: channel 4-1 to right
+        let panning = self.registers[NR51_ADDRESS - NR10_ADDRESS];
+        let any_solo = self.controls.iter().any(|control| control.solo);
+        let mut left = 0.0;
+        let mut right = 0.0;
+
+        for channel in ALL_CHANNELS.iter() {
+            let control = &self.controls[*channel as usize];
+            if control.muted || (any_solo && !control.solo) {
+                continue;
+            }
+
+            let (to_left, to_right) = control.outputs(*channel, panning);
+            if to_left {
+                left += outputs[*channel as usize];
+            }
+            if to_right {
+                right += outputs[*channel as usize];
+            }
+        }
+
+        // NR50 - Bits 6-4: left volume, bits 2-0: right volume
+        let volume = self.registers[NR50_ADDRESS - NR10_ADDRESS];
+        let left_volume = f32::from(((volume >> 4) & 0b111) + 1) / 8.0;
+        let right_volume = f32::from((volume & 0b111) + 1) / 8.0;
+
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+
+    fn push_sample(&mut self) {
+        let count = self.accumulated_cycles.max(1) as f32;
+        let left = self.accumulated.0 / count;
+        let right = self.accumulated.1 / count;
+        self.accumulated = (0.0, 0.0);
+        self.accumulated_cycles = 0;
+
+        if let Some(taps) = self.taps.as_mut() {
+            for (tap, total) in taps.iter_mut().zip(self.tap_accumulated.iter_mut()) {
+                tap.push(*total / count);
+                *total = 0.0;
+            }
+        }
+
+        let dacs_enabled = self.square1.output().is_some()
+            || self.square2.output().is_some()
+            || self.wave.output().is_some()
+            || self.noise.output().is_some();
+
+        let left = self.high_pass(left, dacs_enabled, true);
+        let right = self.high_pass(right, dacs_enabled, false);
+
+        self.samples.push(left);
+        self.samples.push(right);
+    }
+
+    // Removes the DC offset the way the capacitor on the real hardware does
+    fn high_pass(&mut self, input: f32, dacs_enabled: bool, left: bool) -> f32 {
+        if !dacs_enabled {
+            return 0.0;
+        }
+
+        let capacitor = if left { &mut self.capacitors.0 } else { &mut self.capacitors.1 };
+        let output = input - *capacitor;
+        *capacitor = input - output * self.charge_factor;
+        output
+    }
+
+    // Samples generated but not yet taken by the frontend
+    pub fn pending_samples(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn channel_control(&self, channel: Channel) -> ChannelControl {
+        self.controls[channel as usize]
+    }
+
+    pub fn set_channel_control(&mut self, channel: Channel, control: ChannelControl) {
+        self.controls[channel as usize] = control;
+    }
+
+    pub fn set_taps_enabled(&mut self, enabled: bool) {
+        self.taps = if enabled { Some(Default::default()) } else { None };
+        self.tap_accumulated = [0.0; 4];
+    }
+
+    // Mono samples of a single channel before mixing, at the output sample rate
+    pub fn take_channel_samples(&mut self, channel: Channel) -> Vec<f32> {
+        match self.taps.as_mut() {
+            Some(taps) => std::mem::take(&mut taps[channel as usize]),
+            None => Vec::new()
+        }
+    }
+}
+
+// Mixer controls, taps and the sample rate belong to the frontend, so they
+// aren't part of the state
+impl Savestate for Apu {
+    fn save(&self, writer: &mut StateWriter) {
+        self.square1.save(writer);
+        self.square2.save(writer);
+        self.wave.save(writer);
+        self.noise.save(writer);
+        writer.write_bytes(&self.registers);
+        writer.write_bool(self.powered);
+        writer.write_u8(self.frame_step);
+        writer.write_f64(self.sample_timer);
+        writer.write_f32(self.accumulated.0);
+        writer.write_f32(self.accumulated.1);
+        writer.write_u32(self.accumulated_cycles);
+        writer.write_f32(self.capacitors.0);
+        writer.write_f32(self.capacitors.1);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.square1.load(reader)?;
+        self.square2.load(reader)?;
+        self.wave.load(reader)?;
+        self.noise.load(reader)?;
+        reader.read_bytes(&mut self.registers)?;
+        self.powered = reader.read_bool()?;
+        self.frame_step = reader.read_u8()? % 8;
+        self.sample_timer = reader.read_f64()?;
+        self.accumulated.0 = reader.read_f32()?;
+        self.accumulated.1 = reader.read_f32()?;
+        self.accumulated_cycles = reader.read_u32()?;
+        self.capacitors.0 = reader.read_f32()?;
+        self.capacitors.1 = reader.read_f32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{Apu, Channel, DEFAULT_SAMPLE_RATE};
+
+    #[test]
+    fn power_on_status() {
+        let apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        assert_eq!(apu.read(0xFF26) & 0b1000_0000, 0b1000_0000);
+        assert_eq!(apu.read(0xFF24), 0x77);
+        assert_eq!(apu.read(0xFF25), 0xF3);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.write(0xFF30, 0x12);
+        apu.write(0xFF26, 0x00);
+
+        assert_eq!(apu.read(0xFF26), 0x70);
+        assert_eq!(apu.read(0xFF24), 0x00);
+        assert_eq!(apu.read(0xFF30), 0x12);
+
+        // Writes are ignored while powered off
+        apu.write(0xFF24, 0x77);
+        assert_eq!(apu.read(0xFF24), 0x00);
+    }
+
+    #[test]
+    fn trigger_sets_status() {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0x80);
+        assert_eq!(apu.read(0xFF26) & 0b10, 0b10);
+
+        // Turning the DAC off disables the channel
+        apu.write(0xFF17, 0x00);
+        assert_eq!(apu.read(0xFF26) & 0b10, 0);
+    }
+
+    #[test]
+    fn length_counter_expires() {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.write(0xFF21, 0xF0);
+        // Length of 1 with length enabled
+        apu.write(0xFF20, 63);
+        apu.write(0xFF23, 0b1100_0000);
+        assert_eq!(apu.read(0xFF26) & 0b1000, 0b1000);
+
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read(0xFF26) & 0b1000, 0);
+    }
+
+    #[test]
+    fn sample_rate() {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.step(4_194_304 / 10);
+        let samples = apu.take_samples();
+        // One tenth of a second, two channels
+        assert!((samples.len() as i32 - 8820).abs() <= 2);
+        assert!(apu.take_samples().is_empty());
+    }
+
+    #[test]
+    fn square_produces_sound() {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.write(0xFF16, 0b1000_0000);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF18, 0x00);
+        apu.write(0xFF19, 0x87);
+        apu.step(4_194_304 / 100);
+
+        let samples = apu.take_samples();
+        assert!(samples.iter().any(|sample| sample.abs() > 0.1));
+    }
+
+    #[test]
+    fn muted_channels_are_silent() {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.write(0xFF16, 0b1000_0000);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0x87);
+
+        for channel in [Channel::Square1, Channel::Square2].iter() {
+            let mut control = apu.channel_control(*channel);
+            control.muted = true;
+            apu.set_channel_control(*channel, control);
+        }
+
+        apu.step(4_194_304 / 100);
+        assert!(apu.take_samples().iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn solo_and_taps() {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.set_taps_enabled(true);
+        apu.write(0xFF16, 0b1000_0000);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0x87);
+
+        // Soloing the silent noise channel leaves nothing audible
+        let mut control = apu.channel_control(Channel::Noise);
+        control.solo = true;
+        apu.set_channel_control(Channel::Noise, control);
+
+        apu.step(4_194_304 / 100);
+        let samples = apu.take_samples();
+        assert!(samples.iter().all(|sample| *sample == 0.0));
+
+        // But the taps still see every channel
+        let square = apu.take_channel_samples(Channel::Square2);
+        assert_eq!(square.len(), samples.len() / 2);
+        assert!(square.iter().any(|sample| *sample < 0.0));
+        assert!(apu.take_channel_samples(Channel::Noise).iter().all(|sample| *sample == 0.0));
+    }
+}
@@ -0,0 +1,141 @@
+use super::channel::{Envelope, LengthCounter};
+use crate::emulator::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub struct Noise {
+    pub enabled: bool,
+    length: LengthCounter,
+    envelope: Envelope,
+
+    clock_shift: u8,
+    // In 7-bit mode the feedback is also written to bit 6
+    width_mode: bool,
+    divisor_code: u8,
+
+    timer: u32,
+    lfsr: u16,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    // Register is the offset from NR40 (unused), so 1..=4
+    pub fn write(&mut self, register: usize, data: u8) {
+        match register {
+            0 => (),
+            1 => {
+                self.length.set(u16::from(data & 0b0011_1111));
+            },
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => {
+                self.clock_shift = data >> 4;
+                self.width_mode = tbit!(data, 3);
+                self.divisor_code = data & 0b111;
+            },
+            4 => {
+                self.length.enabled = tbit!(data, 6);
+                if tbit!(data, 7) {
+                    self.trigger();
+                }
+            },
+            _ => unreachable!()
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    // Advance by a single T-cycle
+    pub fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period();
+
+            let feedback = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    // Digital output 0-15, or None when the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+
+        if self.enabled && (self.lfsr & 1) == 0 {
+            Some(self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+}
+
+impl Savestate for Noise {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.length.save(writer);
+        self.envelope.save(writer);
+        writer.write_u8(self.clock_shift);
+        writer.write_bool(self.width_mode);
+        writer.write_u8(self.divisor_code);
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.enabled = reader.read_bool()?;
+        self.length.load(reader)?;
+        self.envelope.load(reader)?;
+        self.clock_shift = reader.read_u8()? & 0xF;
+        self.width_mode = reader.read_bool()?;
+        self.divisor_code = reader.read_u8()? & 0b111;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,252 @@
+use super::channel::{Envelope, LengthCounter};
+use crate::emulator::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow: u16,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow: 0,
+        }
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 is treated as 8
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn calculate(&self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+pub struct Square {
+    pub enabled: bool,
+    sweep: Option<Sweep>,
+    length: LengthCounter,
+    envelope: Envelope,
+
+    duty: u8,
+    duty_position: u8,
+
+    frequency: u16,
+    timer: u16,
+}
+
+impl Square {
+    pub fn new(with_sweep: bool) -> Self {
+        Square {
+            enabled: false,
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    // Register is the offset from NRx0, so 0..=4
+    pub fn write(&mut self, register: usize, data: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.period = (data >> 4) & 0b111;
+                    sweep.negate = tbit!(data, 3);
+                    sweep.shift = data & 0b111;
+                }
+            },
+            1 => {
+                self.duty = data >> 6;
+                self.length.set(u16::from(data & 0b0011_1111));
+            },
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => {
+                self.frequency = (self.frequency & 0x700) | u16::from(data);
+            },
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (u16::from(data & 0b111) << 8);
+                self.length.enabled = tbit!(data, 6);
+                if tbit!(data, 7) {
+                    self.trigger();
+                }
+            },
+            _ => unreachable!()
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        let frequency = self.frequency;
+        let mut overflow = false;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            overflow = sweep.shift != 0 && sweep.calculate() > 0x7FF;
+        }
+
+        if overflow {
+            self.enabled = false;
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+
+    // Advance by a single T-cycle
+    pub fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return
+        };
+
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+
+        if sweep.timer != 0 {
+            return;
+        }
+
+        sweep.reload_timer();
+
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        let new_frequency = sweep.calculate();
+        if new_frequency > 0x7FF {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = new_frequency;
+            self.frequency = new_frequency;
+
+            // The new value is run through the overflow check once more
+            if sweep.calculate() > 0x7FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // Digital output 0-15, or None when the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+
+        if self.enabled {
+            Some(DUTY_PATTERNS[self.duty as usize][self.duty_position as usize] * self.envelope.volume)
+        } else {
+            Some(0)
+        }
+    }
+}
+
+impl Savestate for Sweep {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.period);
+        writer.write_bool(self.negate);
+        writer.write_u8(self.shift);
+        writer.write_u8(self.timer);
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.shadow);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.period = reader.read_u8()?;
+        self.negate = reader.read_bool()?;
+        self.shift = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+        self.enabled = reader.read_bool()?;
+        self.shadow = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl Savestate for Square {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        if let Some(sweep) = &self.sweep {
+            sweep.save(writer);
+        }
+        self.length.save(writer);
+        self.envelope.save(writer);
+        writer.write_u8(self.duty);
+        writer.write_u8(self.duty_position);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.enabled = reader.read_bool()?;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.load(reader)?;
+        }
+        self.length.load(reader)?;
+        self.envelope.load(reader)?;
+        self.duty = reader.read_u8()? & 0b11;
+        self.duty_position = reader.read_u8()? & 0b111;
+        self.frequency = reader.read_u16()? & 0x7FF;
+        self.timer = reader.read_u16()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use super::channel::LengthCounter;
+use crate::emulator::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
+pub const WAVE_RAM_SIZE: usize = 16;
+
+pub struct Wave {
+    pub enabled: bool,
+    dac_enabled: bool,
+    length: LengthCounter,
+
+    // 0: mute, 1: 100%, 2: 50%, 3: 25%
+    volume_code: u8,
+
+    frequency: u16,
+    timer: u16,
+
+    // 32 4-bit samples, high nibble first
+    pub ram: [u8; WAVE_RAM_SIZE],
+    position: u8,
+}
+
+impl Wave {
+    pub fn new() -> Self {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            ram: [0; WAVE_RAM_SIZE],
+            position: 0,
+        }
+    }
+
+    // Register is the offset from NR30, so 0..=4
+    pub fn write(&mut self, register: usize, data: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = tbit!(data, 7);
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            },
+            1 => {
+                self.length.set(u16::from(data));
+            },
+            2 => {
+                self.volume_code = (data >> 5) & 0b11;
+            },
+            3 => {
+                self.frequency = (self.frequency & 0x700) | u16::from(data);
+            },
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (u16::from(data & 0b111) << 8);
+                self.length.enabled = tbit!(data, 6);
+                if tbit!(data, 7) {
+                    self.trigger();
+                }
+            },
+            _ => unreachable!()
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    // Advance by a single T-cycle
+    pub fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // Digital output 0-15, or None when the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+
+        if !self.enabled {
+            return Some(0);
+        }
+
+        let byte = self.ram[(self.position / 2) as usize];
+        let sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0xF };
+
+        let shift = match self.volume_code {
+            0 => 4,
+            1 => 0,
+            2 => 1,
+            3 => 2,
+            _ => unreachable!()
+        };
+
+        Some(sample >> shift)
+    }
+}
+
+impl Savestate for Wave {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        self.length.save(writer);
+        writer.write_u8(self.volume_code);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+        writer.write_bytes(&self.ram);
+        writer.write_u8(self.position);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.length.load(reader)?;
+        self.volume_code = reader.read_u8()? & 0b11;
+        self.frequency = reader.read_u16()? & 0x7FF;
+        self.timer = reader.read_u16()?;
+        reader.read_bytes(&mut self.ram)?;
+        self.position = reader.read_u8()? % 32;
+        Ok(())
+    }
+}
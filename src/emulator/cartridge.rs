@@ -1,9 +1,18 @@
 use std::fs::File;
 use std::io::{self, Read};
 use std::boxed::Box;
+use std::convert::TryInto;
+
+use super::crc::crc32;
 
 const CARTRIDGE_SIZE: usize = 0x200_000;
 
+const CGB_FLAG_ADDRESS: usize = 0x143;
+const SGB_FLAG_ADDRESS: usize = 0x146;
+const ROM_SIZE_ADDRESS: usize = 0x148;
+const OLD_LICENSEE_ADDRESS: usize = 0x14B;
+const GLOBAL_CHECKSUM_ADDRESS: usize = 0x14E;
+
 pub struct Cartridge {
     data: Box<[u8; CARTRIDGE_SIZE]>,
     // data: Vec<u8>,
@@ -11,13 +20,45 @@ pub struct Cartridge {
 
 impl Cartridge {
     pub fn from_file(filename: &str) -> Result<Self, io::Error> {
-        let mut file = File::open(filename)?;
-        // let mut buffer = Vec::with_capacity(0x200_000);
-        // let mut buffer = Vec::new();
-        let mut buffer = [0; CARTRIDGE_SIZE];
+        let mut buffer = Vec::new();
+        File::open(filename)?.take(CARTRIDGE_SIZE as u64).read_to_end(&mut buffer)?;
+        Ok(Cartridge::from_bytes(&buffer))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        // Built on the heap, 2MB is too much for a test thread's stack
+        let mut buffer = vec![0; CARTRIDGE_SIZE];
+        let length = bytes.len().min(CARTRIDGE_SIZE);
+        buffer[..length].copy_from_slice(&bytes[..length]);
+        Cartridge { data: buffer.into_boxed_slice().try_into().unwrap() }
+    }
+
+    // Size of the ROM according to the header, 32KB << n
+    pub fn size(&self) -> usize {
+        match self.data[ROM_SIZE_ADDRESS] {
+            n @ 0..=6 => 0x8000 << n,
+            _ => CARTRIDGE_SIZE
+        }
+    }
+
+    // 0x80 (works on both) or 0xC0 (CGB only) in the header
+    pub fn supports_cgb(&self) -> bool {
+        tbit!(self.data[CGB_FLAG_ADDRESS], 7)
+    }
+
+    // 0x03 in the header, and the SGB won't listen unless the old licensee
+    // code is 0x33 as well
+    pub fn supports_sgb(&self) -> bool {
+        self.data[SGB_FLAG_ADDRESS] == 0x03 && self.data[OLD_LICENSEE_ADDRESS] == 0x33
+    }
+
+    pub fn crc32(&self) -> u32 {
+        crc32(&self.data[..self.size()])
+    }
 
-        file.read(&mut buffer)?;
-        Ok(Cartridge { data: Box::new(buffer) })
+    // Big-endian sum of every byte in the ROM, stored in the header
+    pub fn global_checksum(&self) -> u16 {
+        (u16::from(self.data[GLOBAL_CHECKSUM_ADDRESS]) << 8) | u16::from(self.data[GLOBAL_CHECKSUM_ADDRESS + 1])
     }
 
     pub fn read(&self, address: usize) -> u8 {
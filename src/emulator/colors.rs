@@ -0,0 +1,417 @@
+// The colours the four DMG shades come out as. Each of BGP, OBP0 and OBP1
+// gets its own set, since that's what a CGB gives DMG games.
+
+use super::cartridge::Cartridge;
+use super::palettes::rgb15;
+use super::vram::Palette;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x143;
+const OLD_LICENSEE: usize = 0x14B;
+const NEW_LICENSEE: usize = 0x144;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorScheme {
+    pub name: String,
+    // Lightest first
+    pub background: [(u8, u8, u8); 4],
+    pub object0: [(u8, u8, u8); 4],
+    pub object1: [(u8, u8, u8); 4],
+}
+
+impl ColorScheme {
+    // The same four shades everywhere
+    pub fn uniform(name: &str, shades: [(u8, u8, u8); 4]) -> Self {
+        ColorScheme {
+            name: name.to_string(),
+            background: shades,
+            object0: shades,
+            object1: shades,
+        }
+    }
+
+    pub fn grey() -> Self {
+        ColorScheme::uniform("grey", [(0xFF, 0xFF, 0xFF), (0xCC, 0xCC, 0xCC), (0x77, 0x77, 0x77), (0x00, 0x00, 0x00)])
+    }
+
+    // The original DMG's pea soup screen
+    pub fn green() -> Self {
+        ColorScheme::uniform("green", [(0x9B, 0xBC, 0x0F), (0x8B, 0xAC, 0x0F), (0x30, 0x62, 0x30), (0x0F, 0x38, 0x0F)])
+    }
+
+    pub fn pocket() -> Self {
+        ColorScheme::uniform("pocket", [(0xC4, 0xCF, 0xA1), (0x8B, 0x95, 0x6D), (0x4D, 0x53, 0x3C), (0x1F, 0x1F, 0x1F)])
+    }
+
+    pub fn builtin() -> Vec<ColorScheme> {
+        vec![ColorScheme::grey(), ColorScheme::green(), ColorScheme::pocket()]
+    }
+
+    pub fn shades(&self, palette: Palette) -> &[(u8, u8, u8); 4] {
+        match palette {
+            Palette::Background => &self.background,
+            Palette::Object0 => &self.object0,
+            Palette::Object1 => &self.object1,
+        }
+    }
+
+    // 4 hex colours for everything, or 12 for the background, OBP0 then
+    // OBP1. Commas, spaces and # are all fine.
+    pub fn from_hex(name: &str, text: &str) -> Option<Self> {
+        let colors = text.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| parse_color(word.trim_start_matches('#')))
+            .collect::<Option<Vec<_>>>()?;
+
+        let set = |start: usize| [colors[start], colors[start + 1], colors[start + 2], colors[start + 3]];
+        match colors.len() {
+            4 => Some(ColorScheme::uniform(name, set(0))),
+            12 => Some(ColorScheme {
+                name: name.to_string(),
+                background: set(0),
+                object0: set(4),
+                object1: set(8),
+            }),
+            _ => None
+        }
+    }
+
+    pub fn load(filename: &Path) -> Result<Vec<Self>, io::Error> {
+        Ok(ColorScheme::parse(&fs::read_to_string(filename)?))
+    }
+
+    // Lines of "name = colours" as from_hex takes them, with # comments at
+    // the start of a line. Lines that don't parse are skipped.
+    pub fn parse(text: &str) -> Vec<Self> {
+        let mut schemes = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let scheme = line.find('=').and_then(|equals| {
+                ColorScheme::from_hex(line[..equals].trim(), &line[equals + 1..])
+            });
+            match scheme {
+                Some(scheme) => schemes.push(scheme),
+                None => warn!("Skipping colour scheme on line {}: {}", number + 1, line)
+            }
+        }
+
+        schemes
+    }
+
+    // What the CGB boot ROM picks for a DMG game. Nintendo's own games are
+    // looked up by a checksum of their title, everything else gets the
+    // default.
+    pub fn for_cgb(cart: &Cartridge) -> Self {
+        let combination = if published_by_nintendo(cart) { title_combination(cart) } else { DEFAULT_COMBINATION };
+
+        let (object0, object1, background) = COMBINATIONS[combination];
+        ColorScheme {
+            name: "cgb".to_string(),
+            background: boot_palette(background),
+            object0: boot_palette(object0),
+            object1: boot_palette(object1),
+        }
+    }
+}
+
+fn parse_color(hex: &str) -> Option<(u8, u8, u8)> {
+    if hex.len() != 6 {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    Some(((value >> 16) as u8, (value >> 8) as u8, value as u8))
+}
+
+// Old licensee code 01, or 33 meaning look at the new one, which is "01"
+fn published_by_nintendo(cart: &Cartridge) -> bool {
+    match cart.read(OLD_LICENSEE) {
+        0x01 => true,
+        0x33 => cart.read(NEW_LICENSEE) == b'0' && cart.read(NEW_LICENSEE + 1) == b'1',
+        _ => false
+    }
+}
+
+// The boot ROM's palettes, as 15-bit CGB colours
+const BOOT_PALETTES: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// Where OBP0, OBP1 and BGP's four colours start in BOOT_PALETTES. Nearly
+// always on a palette boundary, but a few start partway into one.
+type Combination = (usize, usize, usize);
+
+const fn palettes(object0: usize, object1: usize, background: usize) -> Combination {
+    (object0 * 4, object1 * 4, background * 4)
+}
+
+const COMBINATIONS: [Combination; 51] = [
+    palettes(4, 4, 29),
+    palettes(18, 18, 18),
+    palettes(20, 20, 20),
+    palettes(24, 24, 24),
+    palettes(9, 9, 9),
+    palettes(0, 0, 0),
+    palettes(27, 27, 27),
+    palettes(5, 5, 5),
+    palettes(12, 12, 12),
+    palettes(26, 26, 26),
+    palettes(16, 8, 8),
+    palettes(4, 28, 28),
+    palettes(4, 2, 2),
+    palettes(3, 4, 4),
+    palettes(4, 29, 29),
+    palettes(28, 4, 28),
+    palettes(2, 17, 2),
+    palettes(16, 16, 8),
+    palettes(4, 4, 7),
+    palettes(4, 4, 18),
+    palettes(4, 4, 20),
+    palettes(19, 19, 9),
+    (4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+    palettes(17, 17, 2),
+    palettes(4, 4, 2),
+    palettes(4, 4, 3),
+    palettes(28, 28, 0),
+    palettes(3, 3, 0),
+    palettes(0, 0, 1),
+    palettes(18, 22, 18),
+    palettes(20, 22, 20),
+    palettes(24, 22, 24),
+    palettes(16, 22, 8),
+    palettes(17, 4, 13),
+    (28 * 4 - 1, 0, 14 * 4),
+    (28 * 4 - 1, 4 * 4, 15 * 4),
+    palettes(19, 22, 9),
+    palettes(16, 28, 10),
+    palettes(4, 23, 28),
+    palettes(17, 22, 2),
+    palettes(4, 0, 2),
+    palettes(4, 28, 3),
+    palettes(28, 3, 0),
+    palettes(3, 28, 4),
+    palettes(21, 28, 4),
+    palettes(3, 28, 0),
+    palettes(25, 3, 28),
+    palettes(0, 28, 8),
+    palettes(4, 3, 28),
+    palettes(28, 3, 6),
+    palettes(4, 28, 29),
+];
+
+// Red sprites on a green background, for everything not in the tables
+const DEFAULT_COMBINATION: usize = 0;
+
+// Title checksums the boot ROM knows and the combination each gets. Names
+// are only given for the games anyone has matched up.
+const TITLE_CHECKSUMS: [(u8, usize); 64] = [
+    (0x88, 4),  // ALLEY WAY
+    (0x16, 5),  // YAKUMAN
+    (0x36, 35), // BASEBALL, GAME&WATCH 2
+    (0xD1, 34), // TENNIS
+    (0xDB, 3),  // TETRIS
+    (0xF2, 31), // QIX
+    (0x3C, 15), // DR.MARIO
+    (0x8C, 10), // RADARMISSION
+    (0x92, 5),  // F1RACE
+    (0x3D, 19), // YOSSY NO TAMAGO
+    (0x5C, 36),
+    (0x58, 7),  // X
+    (0xC9, 37), // MARIOLAND2
+    (0x3E, 30), // YOSSY NO COOKIE
+    (0x70, 44), // ZELDA
+    (0x1D, 21),
+    (0x59, 32),
+    (0x69, 31), // TETRIS FLASH
+    (0x19, 20), // DONKEY KONG
+    (0x35, 5),  // MARIO'S PICROSS
+    (0xA8, 33),
+    (0x14, 13), // POKEMON RED, GAMEBOYCAMERA G
+    (0xAA, 14), // POKEMON GREEN
+    (0x75, 5),  // PICROSS 2
+    (0x95, 29), // YOSSY NO PANEPON
+    (0x99, 5),  // KIRAKIRA KIDS
+    (0x34, 18), // GAMEBOY GALLERY
+    (0x6F, 9),  // POCKETCAMERA
+    (0x15, 3),
+    (0xFF, 2),  // BALLOON KID
+    (0x97, 26), // KINGOFTHEZOO
+    (0x4B, 25), // DMG FOOTBALL
+    (0x90, 25), // WORLD CUP
+    (0x17, 41), // OTHELLO
+    (0x10, 42), // SUPER RC PRO-AM
+    (0x39, 26), // DYNABLASTER
+    (0xF7, 45), // BOY AND BLOB GB2
+    (0xF6, 42), // MEGAMAN
+    (0xA2, 45), // STAR WARS-NOA
+    (0x49, 36),
+    (0x4E, 38), // WAVERACE
+    (0x43, 26),
+    (0x68, 42), // LOLO2
+    (0xE0, 30), // YOSHI'S COOKIE
+    (0x8B, 41), // MYSTIC QUEST
+    (0xF0, 34),
+    (0xCE, 34), // TOPRANKINGTENNIS
+    (0x0C, 5),  // MANSELL
+    (0x29, 42), // MEGAMAN3
+    (0xE8, 6),  // SPACE INVADERS
+    (0xB7, 5),  // GAME&WATCH
+    (0x86, 33), // DONKEYKONGLAND95
+    (0x9A, 25), // ASTEROIDS/MISCMD
+    (0x52, 42), // STREET FIGHTER 2
+    (0x01, 42), // DEFENDER/JOUST
+    (0x9D, 40), // KILLERINSTINCT95
+    (0x71, 2),  // TETRIS BLAST
+    (0x9C, 16), // PINOCCHIO
+    (0xBD, 25),
+    (0x5D, 42), // BA.TOSHINDEN
+    (0x6D, 42), // NETTOU KOF 95
+    (0x67, 5),
+    (0x3F, 0),  // TETRIS PLUS
+    (0x6B, 39), // DONKEYKONGLAND 3
+];
+
+// Checksums more than one game shares, told apart by the fourth letter of
+// the title
+const SHARED_CHECKSUMS: [(u8, u8, usize); 29] = [
+    (0xB3, b'B', 36),
+    (0x46, b'E', 22), // SUPER MARIOLAND
+    (0x28, b'F', 25), // GOLF
+    (0xA5, b'A', 6),  // SOLARSTRIKER
+    (0xC6, b'A', 32), // GBWARS
+    (0xD3, b'R', 12), // KAERUNOTAMENI
+    (0x27, b'B', 36),
+    (0x61, b'E', 11), // POKEMON BLUE
+    (0x18, b'K', 39), // DONKEYKONGLAND
+    (0x66, b'E', 18), // GAMEBOY GALLERY2
+    (0x6A, b'K', 39), // DONKEYKONGLAND 2
+    (0xBF, b' ', 24), // KID ICARUS
+    (0x0D, b'R', 31), // TETRIS2
+    (0xF4, b'-', 50),
+    (0xB3, b'U', 17), // MOGURANYA
+    (0x46, b'R', 46),
+    (0x28, b'A', 6),  // GALAGA&GALAXIAN
+    (0xA5, b'R', 27), // BT2RAGNAROKWORLD
+    (0xC6, b' ', 0),  // KEN GRIFFEY JR
+    (0xD3, b'I', 47),
+    (0x27, b'N', 41), // MAGNETIC SOCCER
+    (0x61, b'A', 41), // VEGAS STAKES
+    (0x18, b'I', 0),
+    (0x66, b'L', 0),  // MILLI/CENTI/PEDE
+    (0x6A, b'I', 19), // MARIO & YOSHI
+    (0xBF, b'C', 34), // SOCCER
+    (0x0D, b'E', 23), // POKEBOM
+    (0xF4, b' ', 18), // G&W GALLERY
+    (0xB3, b'R', 29), // TETRIS ATTACK
+];
+
+fn title_combination(cart: &Cartridge) -> usize {
+    let checksum = (TITLE_START..=TITLE_END).fold(0u8, |sum, address| sum.wrapping_add(cart.read(address)));
+    let fourth_letter = cart.read(TITLE_START + 3);
+
+    TITLE_CHECKSUMS.iter()
+        .find(|(title, _)| *title == checksum)
+        .map(|(_, combination)| *combination)
+        .or_else(|| SHARED_CHECKSUMS.iter()
+            .find(|(title, letter, _)| *title == checksum && *letter == fourth_letter)
+            .map(|(_, _, combination)| *combination))
+        .unwrap_or(DEFAULT_COMBINATION)
+}
+
+fn boot_palette(start: usize) -> [(u8, u8, u8); 4] {
+    let colors = &BOOT_PALETTES[start..start + 4];
+    [rgb15(colors[0]), rgb15(colors[1]), rgb15(colors[2]), rgb15(colors[3])]
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::cartridge::Cartridge;
+    use super::{boot_palette, ColorScheme};
+
+    #[test]
+    fn hex_schemes() {
+        let scheme = ColorScheme::from_hex("ice", "#E0F8F8, 88C0D0 4C6A88,101828").unwrap();
+        assert_eq!(scheme.background[1], (0x88, 0xC0, 0xD0));
+        assert_eq!(scheme.object1, scheme.background);
+
+        let twelve = "FFFFFF AAAAAA 555555 000000 FF0000 AA0000 550000 000000 0000FF 0000AA 000055 000000";
+        let scheme = ColorScheme::from_hex("split", twelve).unwrap();
+        assert_eq!(scheme.object0[0], (0xFF, 0x00, 0x00));
+        assert_eq!(scheme.object1[2], (0x00, 0x00, 0x55));
+
+        assert!(ColorScheme::from_hex("short", "FFFFFF 000000").is_none());
+        assert!(ColorScheme::from_hex("bad", "FFFFFF AAAAAA 555555 00000G").is_none());
+
+        let schemes = ColorScheme::parse("# mine\nice = E0F8F8 88C0D0 4C6A88 101828\n\nbroken = 123\n");
+        assert_eq!(schemes.len(), 1);
+        assert_eq!(schemes[0].name, "ice");
+    }
+
+    fn cartridge(title: &[u8], licensee: u8) -> Cartridge {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x14B] = licensee;
+        Cartridge::from_bytes(&rom)
+    }
+
+    #[test]
+    fn cgb_picks_by_title() {
+        let tetris = ColorScheme::for_cgb(&cartridge(b"TETRIS", 0x01));
+        assert_eq!(tetris.background, [(0xFF, 0xFF, 0xFF), (0xFF, 0xFF, 0x00), (0xFF, 0x00, 0x00), (0x00, 0x00, 0x00)]);
+
+        // Only Nintendo's games are looked up
+        let default = ColorScheme::for_cgb(&cartridge(b"TETRIS", 0x08));
+        assert_eq!(default.background[1], (0x7B, 0xFF, 0x31));
+        assert_eq!(default.object0[1], (0xFF, 0x84, 0x84));
+        assert_ne!(ColorScheme::for_cgb(&cartridge(b"ZELDA", 0x01)), default);
+
+        // KID ICARUS and SOCCER share a checksum, so the fourth letter decides
+        let icarus = ColorScheme::for_cgb(&cartridge(b"KID ICARUS", 0x01));
+        assert_eq!(icarus.background, boot_palette(2 * 4));
+        let soccer = ColorScheme::for_cgb(&cartridge(b"SOCCER", 0x01));
+        assert_eq!(soccer.background, boot_palette(14 * 4));
+        // Its OBP0 starts on the last colour of one palette
+        assert_eq!(soccer.object0, boot_palette(28 * 4 - 1));
+        assert_eq!(soccer.object0[3], (0x00, 0x00, 0xFF));
+        // Same checksum, neither letter
+        assert_eq!(ColorScheme::for_cgb(&cartridge(b"KIDI CARUS", 0x01)), default);
+    }
+}
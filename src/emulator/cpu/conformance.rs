@@ -0,0 +1,493 @@
+// Checks the CPU against the SingleStepTests sm83 vectors: a JSON file per
+// opcode, each holding tests that give the registers and RAM before and
+// after one instruction, plus every M-cycle in between. The full set is too
+// big to keep in the repo, so it's read from tests/sm83/v1 or wherever
+// COOLBOY_SM83_TESTS points, and skipped if it isn't there.
+
+use super::{Bus, Cpu};
+use super::super::registers::Flags;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, PartialEq)]
+enum Cycle {
+    Read(u16, u8),
+    Write(u16, u8),
+    Idle,
+}
+
+// 64KB of plain RAM that remembers what happened on every cycle
+struct FlatBus {
+    memory: Vec<u8>,
+    cycles: Vec<Cycle>,
+}
+
+impl FlatBus {
+    fn new() -> Self {
+        FlatBus {
+            memory: vec![0; 0x10000],
+            cycles: Vec::new(),
+        }
+    }
+}
+
+impl Bus for FlatBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let data = self.memory[address as usize];
+        self.cycles.push(Cycle::Read(address, data));
+        data
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.memory[address as usize] = data;
+        self.cycles.push(Cycle::Write(address, data));
+    }
+
+    fn idle(&mut self) {
+        self.cycles.push(Cycle::Idle);
+    }
+
+    // The vectors each cover one instruction with nothing to interrupt it
+    fn interrupts(&mut self) -> u8 {
+        0
+    }
+
+    fn acknowledge(&mut self, _interrupt: u8) {}
+}
+
+// Just enough JSON for the vectors
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser { bytes: text.as_bytes(), position: 0 };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != parser.bytes.len() {
+            return Err(format!("trailing characters at {}", parser.position));
+        }
+        Ok(value)
+    }
+
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None
+        }
+    }
+
+    fn number(&self) -> Option<u16> {
+        match self {
+            Json::Number(number) => Some(*number as u16),
+            Json::Bool(value) => Some(*value as u16),
+            _ => None
+        }
+    }
+
+    fn array(&self) -> &[Json] {
+        match self {
+            Json::Array(items) => items,
+            _ => &[]
+        }
+    }
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.bytes.get(self.position).is_some_and(|byte| byte.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, wanted: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.bytes.get(self.position) != Some(&wanted) {
+            return Err(format!("expected '{}' at {}", wanted as char, self.position));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.bytes[self.position..].starts_with(word.as_bytes()) {
+            return Err(format!("bad literal at {}", self.position));
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.bytes.get(self.position) {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => {
+                self.position += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.position) == Some(&b']') {
+                    self.position += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.position) {
+                        Some(b',') => self.position += 1,
+                        Some(b']') => {
+                            self.position += 1;
+                            return Ok(Json::Array(items));
+                        },
+                        _ => return Err(format!("expected ',' or ']' at {}", self.position))
+                    }
+                }
+            },
+            Some(b'{') => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.position) == Some(&b'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let name = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((name, self.value()?));
+                    self.skip_whitespace();
+                    match self.bytes.get(self.position) {
+                        Some(b',') => self.position += 1,
+                        Some(b'}') => {
+                            self.position += 1;
+                            return Ok(Json::Object(fields));
+                        },
+                        _ => return Err(format!("expected ',' or '}}' at {}", self.position))
+                    }
+                }
+            },
+            Some(_) => {
+                let start = self.position;
+                while self.bytes.get(self.position).is_some_and(|byte| b"+-.eE0123456789".contains(byte)) {
+                    self.position += 1;
+                }
+                let text = std::str::from_utf8(&self.bytes[start..self.position]).unwrap();
+                text.parse().map(Json::Number).map_err(|_| format!("bad number at {}", start))
+            },
+            None => Err("unexpected end of input".to_string())
+        }
+    }
+
+    // Escapes other than \" and \\ never turn up in the vectors
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut string = Vec::new();
+        loop {
+            match self.bytes.get(self.position) {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    string.push(*self.bytes.get(self.position + 1).ok_or("unterminated string")?);
+                    self.position += 2;
+                },
+                Some(byte) => {
+                    string.push(*byte);
+                    self.position += 1;
+                },
+                None => return Err("unterminated string".to_string())
+            }
+        }
+        self.position += 1;
+        String::from_utf8(string).map_err(|e| e.to_string())
+    }
+}
+
+struct State {
+    pc: u16,
+    sp: u16,
+    // A F B C D E H L
+    registers: [u8; 8],
+    ime: Option<bool>,
+    ram: Vec<(u16, u8)>,
+}
+
+impl State {
+    fn from_json(json: &Json) -> Result<Self, String> {
+        let field = |name: &str| json.get(name).and_then(Json::number).ok_or(format!("missing {}", name));
+
+        let mut registers = [0; 8];
+        for (register, name) in registers.iter_mut().zip(&["a", "f", "b", "c", "d", "e", "h", "l"]) {
+            *register = field(name)? as u8;
+        }
+
+        let mut ram = Vec::new();
+        for entry in json.get("ram").map_or(&[][..], Json::array) {
+            match (entry.array().first().and_then(Json::number), entry.array().get(1).and_then(Json::number)) {
+                (Some(address), Some(data)) => ram.push((address, data as u8)),
+                _ => return Err("bad ram entry".to_string())
+            }
+        }
+
+        Ok(State {
+            pc: field("pc")?,
+            sp: field("sp")?,
+            registers,
+            ime: json.get("ime").and_then(Json::number).map(|ime| ime != 0),
+            ram,
+        })
+    }
+
+    fn registers_of(cpu: &Cpu) -> [u8; 8] {
+        let registers = cpu.registers();
+        [registers.a, u8::from(registers.f), registers.b, registers.c, registers.d, registers.e, registers.h, registers.l]
+    }
+}
+
+struct Vector {
+    name: String,
+    initial: State,
+    expected: State,
+    cycles: Vec<Cycle>,
+}
+
+impl Vector {
+    fn from_json(json: &Json) -> Result<Self, String> {
+        let name = match json.get("name") {
+            Some(Json::String(name)) => name.clone(),
+            _ => return Err("missing name".to_string())
+        };
+
+        let mut cycles = Vec::new();
+        for entry in json.get("cycles").map_or(&[][..], Json::array) {
+            let items = entry.array();
+            let address = items.first().and_then(Json::number).unwrap_or(0);
+            let data = items.get(1).and_then(Json::number).unwrap_or(0) as u8;
+            // "r-m", "-wm" or "---" for a cycle without a memory access
+            let kind = match items.get(2) {
+                Some(Json::String(kind)) => kind.as_str(),
+                _ => ""
+            };
+            cycles.push(if kind.contains('w') {
+                Cycle::Write(address, data)
+            } else if kind.contains('r') {
+                Cycle::Read(address, data)
+            } else {
+                Cycle::Idle
+            });
+        }
+
+        let state = |key: &str| json.get(key).ok_or(format!("{}: missing {}", name, key)).and_then(State::from_json);
+        Ok(Vector {
+            initial: state("initial")?,
+            expected: state("final")?,
+            name,
+            cycles,
+        })
+    }
+
+    fn run(&self) -> Result<(), String> {
+        let mut cpu = Cpu::new();
+        let mut bus = FlatBus::new();
+
+        let initial = &self.initial;
+        cpu.pc = initial.pc;
+        cpu.sp = initial.sp;
+        {
+            let registers = cpu.registers_mut();
+            registers.a = initial.registers[0];
+            registers.f = Flags::from(initial.registers[1]);
+            registers.b = initial.registers[2];
+            registers.c = initial.registers[3];
+            registers.d = initial.registers[4];
+            registers.e = initial.registers[5];
+            registers.h = initial.registers[6];
+            registers.l = initial.registers[7];
+        }
+        cpu.ime = initial.ime.unwrap_or(false);
+        for &(address, data) in initial.ram.iter() {
+            bus.memory[address as usize] = data;
+        }
+
+        let cycles = cpu.step(&mut bus);
+
+        let expected = &self.expected;
+        let mut problems = Vec::new();
+        let registers = State::registers_of(&cpu);
+        for (index, name) in ["A", "F", "B", "C", "D", "E", "H", "L"].iter().enumerate() {
+            if registers[index] != expected.registers[index] {
+                problems.push(format!("{} is {:02X}, expected {:02X}", name, registers[index], expected.registers[index]));
+            }
+        }
+        if cpu.pc != expected.pc {
+            problems.push(format!("PC is {:04X}, expected {:04X}", cpu.pc, expected.pc));
+        }
+        if cpu.sp != expected.sp {
+            problems.push(format!("SP is {:04X}, expected {:04X}", cpu.sp, expected.sp));
+        }
+        if expected.ime.is_some_and(|ime| ime != cpu.ime) {
+            problems.push(format!("IME is {}, expected {}", cpu.ime, !cpu.ime));
+        }
+        for &(address, data) in expected.ram.iter() {
+            if bus.memory[address as usize] != data {
+                problems.push(format!("({:04X}) is {:02X}, expected {:02X}", address, bus.memory[address as usize], data));
+            }
+        }
+        if bus.cycles != self.cycles {
+            problems.push(format!("cycles were {:?}, expected {:?}", bus.cycles, self.cycles));
+        }
+        if cycles != bus.cycles.len() as u32 * 4 {
+            problems.push(format!("took {} cycles for {} M-cycles", cycles, bus.cycles.len()));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("{}: {}", self.name, problems.join(", ")))
+        }
+    }
+}
+
+// Runs every vector in a file, returning the first failure and how many failed
+fn run_file(text: &str) -> Result<(), String> {
+    let json = Json::parse(text)?;
+    let mut first_failure = None;
+    let mut failed = 0;
+
+    for test in json.array() {
+        if let Err(e) = Vector::from_json(test)?.run() {
+            failed += 1;
+            first_failure.get_or_insert(e);
+        }
+    }
+
+    match first_failure {
+        Some(e) => Err(format!("{} of {} failed, first {}", failed, json.array().len(), e)),
+        None => Ok(())
+    }
+}
+
+// A few vectors in the same format, so the runner itself is always tested
+const INLINE_VECTORS: &str = r#"[
+    {"name": "09 ADD HL,BC", "initial": {"pc": 256, "sp": 65534, "a": 0, "f": 128, "b": 15, "c": 255, "d": 0, "e": 0,
+        "h": 240, "l": 1, "ime": 0, "ram": [[256, 9]]},
+     "final": {"pc": 257, "sp": 65534, "a": 0, "f": 176, "b": 15, "c": 255, "d": 0, "e": 0, "h": 0, "l": 0,
+        "ime": 0, "ram": [[256, 9]]},
+     "cycles": [[256, 9, "r-m"], null]},
+    {"name": "27 DAA after 0x45 + 0x38", "initial": {"pc": 0, "sp": 0, "a": 125, "f": 0, "b": 0, "c": 0, "d": 0, "e": 0,
+        "h": 0, "l": 0, "ram": [[0, 39]]},
+     "final": {"pc": 1, "sp": 0, "a": 131, "f": 0, "b": 0, "c": 0, "d": 0, "e": 0, "h": 0, "l": 0, "ram": [[0, 39]]},
+     "cycles": [[0, 39, "r-m"]]},
+    {"name": "c4 CALL NZ,nn", "initial": {"pc": 16384, "sp": 53248, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0, "e": 0,
+        "h": 0, "l": 0, "ram": [[16384, 196], [16385, 52], [16386, 18]]},
+     "final": {"pc": 4660, "sp": 53246, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0, "e": 0, "h": 0, "l": 0,
+        "ram": [[53247, 64], [53246, 3]]},
+     "cycles": [[16384, 196, "r-m"], [16385, 52, "r-m"], [16386, 18, "r-m"], [16386, null, "---"],
+        [53247, 64, "-wm"], [53246, 3, "-wm"]]},
+    {"name": "cb 46 BIT 0,(HL)", "initial": {"pc": 0, "sp": 0, "a": 0, "f": 16, "b": 0, "c": 0, "d": 0, "e": 0,
+        "h": 192, "l": 0, "ram": [[0, 203], [1, 70], [49152, 254]]},
+     "final": {"pc": 2, "sp": 0, "a": 0, "f": 176, "b": 0, "c": 0, "d": 0, "e": 0, "h": 192, "l": 0,
+        "ram": [[49152, 254]]},
+     "cycles": [[0, 203, "r-m"], [1, 70, "r-m"], [49152, 254, "r-m"]]},
+    {"name": "f1 POP AF", "initial": {"pc": 0, "sp": 49152, "a": 0, "f": 0, "b": 0, "c": 0, "d": 0, "e": 0,
+        "h": 0, "l": 0, "ram": [[0, 241], [49152, 255], [49153, 18]]},
+     "final": {"pc": 1, "sp": 49154, "a": 18, "f": 240, "b": 0, "c": 0, "d": 0, "e": 0, "h": 0, "l": 0, "ram": []},
+     "cycles": [[0, 241, "r-m"], [49152, 255, "r-m"], [49153, 18, "r-m"]]}
+]"#;
+
+#[test]
+fn inline_vectors() {
+    run_file(INLINE_VECTORS).unwrap();
+}
+
+#[test]
+fn mismatches_are_reported() {
+    // DAA given the wrong answer
+    let wrong = INLINE_VECTORS.replace(r#""a": 131"#, r#""a": 125"#);
+    let e = run_file(&wrong).unwrap_err();
+    assert!(e.starts_with("1 of 5 failed, first 27 DAA after 0x45 + 0x38: A is 83, expected 7D"), "{}", e);
+}
+
+#[test]
+fn interrupts_and_ei_delay() {
+    struct Pending(FlatBus, u8);
+
+    impl Bus for Pending {
+        fn read(&mut self, address: u16) -> u8 { self.0.read(address) }
+        fn write(&mut self, address: u16, data: u8) { self.0.write(address, data) }
+        fn idle(&mut self) { self.0.idle() }
+        fn interrupts(&mut self) -> u8 { self.1 }
+        fn acknowledge(&mut self, interrupt: u8) { self.1 &= !(1 << interrupt) }
+    }
+
+    // EI, NOP with the timer interrupt waiting
+    let mut bus = Pending(FlatBus::new(), 0b100);
+    bus.0.memory[0x0100] = 0xFB;
+    let mut cpu = Cpu::new();
+    cpu.pc = 0x0100;
+
+    assert_eq!(cpu.step(&mut bus), 4);
+    assert!(!cpu.ime());
+    assert_eq!(cpu.step(&mut bus), 4);
+    assert!(cpu.ime());
+
+    assert_eq!(cpu.step(&mut bus), 20);
+    assert_eq!(cpu.pc, 0x0050);
+    assert_eq!((bus.0.memory[0xFFFD], bus.0.memory[0xFFFC]), (0x01, 0x02));
+    assert_eq!(bus.1, 0);
+    assert!(!cpu.ime());
+
+    // HALT waits without IME until something's requested
+    bus.0.memory[0x0050] = 0x76;
+    cpu.step(&mut bus);
+    assert!(cpu.halted());
+    assert_eq!(cpu.step(&mut bus), 4);
+    assert_eq!(cpu.pc, 0x0051);
+    bus.1 = 0b1;
+    cpu.step(&mut bus);
+    assert!(!cpu.halted());
+    assert_eq!(cpu.pc, 0x0052);
+}
+
+#[test]
+fn sm83_vectors() {
+    let directory = env::var_os("COOLBOY_SM83_TESTS").map_or_else(|| PathBuf::from("tests/sm83/v1"), PathBuf::from);
+    let mut files: Vec<PathBuf> = match fs::read_dir(&directory) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+            .collect(),
+        Err(_) => {
+            println!("No sm83 vectors in {}, skipping", directory.display());
+            return;
+        }
+    };
+    files.sort();
+
+    let mut failures = Vec::new();
+    for file in files.iter() {
+        let text = fs::read_to_string(file).unwrap();
+        if let Err(e) = run_file(&text) {
+            failures.push(format!("{}: {}", file.display(), e));
+        }
+    }
+
+    for failure in failures.iter() {
+        println!("{}", failure);
+    }
+    assert!(failures.is_empty(), "{} of {} opcodes failed", failures.len(), files.len());
+}
@@ -0,0 +1,484 @@
+// Instruction decoding. Opcodes are grouped by the bit fields the hardware
+// decodes: r is B C D E H L (HL) A, rp is BC DE HL SP, rp2 is BC DE HL AF
+// and cc is NZ Z NC C.
+
+use super::super::registers::Flags;
+use super::{Bus, Cpu};
+
+impl Cpu {
+    pub(super) fn execute<B: Bus>(&mut self, bus: &mut B, opcode: u8) {
+        let y = (opcode >> 3) & 7;
+        let z = opcode & 7;
+        let p = y >> 1;
+
+        match opcode {
+            0x00 => (),
+            0x08 => {
+                let address = self.fetch16(bus);
+                self.write(bus, address, self.sp as u8);
+                self.write(bus, address.wrapping_add(1), (self.sp >> 8) as u8);
+            },
+            // Nothing stops the clock yet, so STOP just skips its padding
+            // byte, unless it switches speed. That takes 2050 M-cycles.
+            0x10 => {
+                self.fetch(bus);
+                if bus.switch_speed() {
+                    for _ in 0..2050 {
+                        self.idle(bus);
+                    }
+                }
+            },
+            0x18 => {
+                let offset = self.fetch(bus) as i8;
+                self.jump_relative(bus, offset);
+            },
+            0x20 | 0x28 | 0x30 | 0x38 => {
+                let offset = self.fetch(bus) as i8;
+                if self.condition(y - 4) {
+                    self.jump_relative(bus, offset);
+                }
+            },
+            0x01 | 0x11 | 0x21 | 0x31 => {
+                let value = self.fetch16(bus);
+                self.set_rp(p, value);
+            },
+            0x09 | 0x19 | 0x29 | 0x39 => {
+                let hl = self.registers.hl();
+                let value = self.rp(p);
+                let result = hl.wrapping_add(value);
+                let zero = self.registers.f.contains(Flags::ZERO);
+                self.set_flags(zero, false, (hl & 0x0FFF) + (value & 0x0FFF) > 0x0FFF, result < hl);
+                self.registers.set_hl(result);
+                self.idle(bus);
+            },
+            0x02 | 0x12 | 0x22 | 0x32 => {
+                let address = self.indirect_address(p);
+                self.write(bus, address, self.registers.a);
+            },
+            0x0A | 0x1A | 0x2A | 0x3A => {
+                let address = self.indirect_address(p);
+                self.registers.a = self.read(bus, address);
+            },
+            0x03 | 0x13 | 0x23 | 0x33 => {
+                let value = self.rp(p).wrapping_add(1);
+                self.set_rp(p, value);
+                self.idle(bus);
+            },
+            0x0B | 0x1B | 0x2B | 0x3B => {
+                let value = self.rp(p).wrapping_sub(1);
+                self.set_rp(p, value);
+                self.idle(bus);
+            },
+            _ if opcode & 0xC7 == 0x04 => {
+                let value = self.r(bus, y);
+                let result = value.wrapping_add(1);
+                let carry = self.registers.f.contains(Flags::CARRY);
+                self.set_flags(result == 0, false, value & 0x0F == 0x0F, carry);
+                self.set_r(bus, y, result);
+            },
+            _ if opcode & 0xC7 == 0x05 => {
+                let value = self.r(bus, y);
+                let result = value.wrapping_sub(1);
+                let carry = self.registers.f.contains(Flags::CARRY);
+                self.set_flags(result == 0, true, value & 0x0F == 0, carry);
+                self.set_r(bus, y, result);
+            },
+            _ if opcode & 0xC7 == 0x06 => {
+                let value = self.fetch(bus);
+                self.set_r(bus, y, value);
+            },
+            // RLCA RRCA RLA RRA are the CB rotates, except Z is always clear
+            0x07 | 0x0F | 0x17 | 0x1F => {
+                let result = self.shift(y, self.registers.a);
+                self.registers.f.remove(Flags::ZERO);
+                self.registers.a = result;
+            },
+            0x27 => self.daa(),
+            0x2F => {
+                self.registers.a = !self.registers.a;
+                self.registers.f.insert(Flags::SUBTRACT | Flags::HALF_CARRY);
+            },
+            0x37 => {
+                let zero = self.registers.f.contains(Flags::ZERO);
+                self.set_flags(zero, false, false, true);
+            },
+            0x3F => {
+                let zero = self.registers.f.contains(Flags::ZERO);
+                let carry = self.registers.f.contains(Flags::CARRY);
+                self.set_flags(zero, false, false, !carry);
+            },
+            0x76 => {
+                if !self.ime && bus.interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
+            },
+            0x40..=0x7F => {
+                let value = self.r(bus, z);
+                self.set_r(bus, y, value);
+            },
+            0x80..=0xBF => {
+                let value = self.r(bus, z);
+                self.alu(y, value);
+            },
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => {
+                self.idle(bus);
+                if self.condition(y) {
+                    self.pc = self.pop(bus);
+                    self.idle(bus);
+                }
+            },
+            0xC9 | 0xD9 => {
+                self.pc = self.pop(bus);
+                self.idle(bus);
+                if opcode == 0xD9 {
+                    self.ime = true;
+                }
+            },
+            0xC1 | 0xD1 | 0xE1 | 0xF1 => {
+                let value = self.pop(bus);
+                self.set_rp2(p, value);
+            },
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => {
+                self.idle(bus);
+                let value = self.rp2(p);
+                self.push(bus, value);
+            },
+            0xC3 => {
+                let address = self.fetch16(bus);
+                self.idle(bus);
+                self.pc = address;
+            },
+            0xC2 | 0xCA | 0xD2 | 0xDA => {
+                let address = self.fetch16(bus);
+                if self.condition(y) {
+                    self.idle(bus);
+                    self.pc = address;
+                }
+            },
+            0xE9 => self.pc = self.registers.hl(),
+            0xCD => {
+                let address = self.fetch16(bus);
+                self.call(bus, address);
+            },
+            0xC4 | 0xCC | 0xD4 | 0xDC => {
+                let address = self.fetch16(bus);
+                if self.condition(y) {
+                    self.call(bus, address);
+                }
+            },
+            _ if opcode & 0xC7 == 0xC7 => self.call(bus, (y as u16) * 8),
+            _ if opcode & 0xC7 == 0xC6 => {
+                let value = self.fetch(bus);
+                self.alu(y, value);
+            },
+            0xE0 => {
+                let address = 0xFF00 | self.fetch(bus) as u16;
+                self.write(bus, address, self.registers.a);
+            },
+            0xF0 => {
+                let address = 0xFF00 | self.fetch(bus) as u16;
+                self.registers.a = self.read(bus, address);
+            },
+            0xE2 => {
+                let address = 0xFF00 | self.registers.c as u16;
+                self.write(bus, address, self.registers.a);
+            },
+            0xF2 => {
+                let address = 0xFF00 | self.registers.c as u16;
+                self.registers.a = self.read(bus, address);
+            },
+            0xEA => {
+                let address = self.fetch16(bus);
+                self.write(bus, address, self.registers.a);
+            },
+            0xFA => {
+                let address = self.fetch16(bus);
+                self.registers.a = self.read(bus, address);
+            },
+            0xE8 => {
+                let offset = self.fetch(bus);
+                self.sp = self.add_sp(offset);
+                self.idle(bus);
+                self.idle(bus);
+            },
+            0xF8 => {
+                let offset = self.fetch(bus);
+                let result = self.add_sp(offset);
+                self.registers.set_hl(result);
+                self.idle(bus);
+            },
+            0xF9 => {
+                self.sp = self.registers.hl();
+                self.idle(bus);
+            },
+            0xF3 => {
+                self.ime = false;
+                self.ei_pending = false;
+            },
+            0xFB => self.ei_pending = true,
+            0xCB => {
+                let opcode = self.fetch(bus);
+                self.execute_cb(bus, opcode);
+            },
+            // D3 DB DD E3 E4 EB EC ED F4 FC FD
+            _ => {
+                warn!("Illegal opcode {:02X} at {:04X}, CPU locked up", opcode, self.pc.wrapping_sub(1));
+                self.locked = true;
+            }
+        }
+    }
+
+    fn execute_cb<B: Bus>(&mut self, bus: &mut B, opcode: u8) {
+        let y = (opcode >> 3) & 7;
+        let z = opcode & 7;
+        let value = self.r(bus, z);
+
+        match opcode >> 6 {
+            0 => {
+                let result = self.shift(y, value);
+                self.set_r(bus, z, result);
+            },
+            1 => {
+                let carry = self.registers.f.contains(Flags::CARRY);
+                self.set_flags(!tbit!(value, y), false, true, carry);
+            },
+            2 => self.set_r(bus, z, value & !(1 << y)),
+            _ => self.set_r(bus, z, value | (1 << y))
+        }
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let data = self.read(bus, self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        data
+    }
+
+    fn fetch16<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let lo = self.fetch(bus) as u16;
+        let hi = self.fetch(bus) as u16;
+        hi << 8 | lo
+    }
+
+    fn push<B: Bus>(&mut self, bus: &mut B, value: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(bus, self.sp, (value >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(bus, self.sp, value as u8);
+    }
+
+    fn pop<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let lo = self.read(bus, self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        let hi = self.read(bus, self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+        hi << 8 | lo
+    }
+
+    fn call<B: Bus>(&mut self, bus: &mut B, address: u16) {
+        self.idle(bus);
+        let pc = self.pc;
+        self.push(bus, pc);
+        self.pc = address;
+    }
+
+    fn jump_relative<B: Bus>(&mut self, bus: &mut B, offset: i8) {
+        self.pc = self.pc.wrapping_add(offset as u16);
+        self.idle(bus);
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        let flags = self.registers.f;
+        match cc {
+            0 => !flags.contains(Flags::ZERO),
+            1 => flags.contains(Flags::ZERO),
+            2 => !flags.contains(Flags::CARRY),
+            _ => flags.contains(Flags::CARRY)
+        }
+    }
+
+    fn r<B: Bus>(&mut self, bus: &mut B, r: u8) -> u8 {
+        match r {
+            0 => self.registers.b,
+            1 => self.registers.c,
+            2 => self.registers.d,
+            3 => self.registers.e,
+            4 => self.registers.h,
+            5 => self.registers.l,
+            6 => {
+                let address = self.registers.hl();
+                self.read(bus, address)
+            },
+            _ => self.registers.a
+        }
+    }
+
+    fn set_r<B: Bus>(&mut self, bus: &mut B, r: u8, value: u8) {
+        match r {
+            0 => self.registers.b = value,
+            1 => self.registers.c = value,
+            2 => self.registers.d = value,
+            3 => self.registers.e = value,
+            4 => self.registers.h = value,
+            5 => self.registers.l = value,
+            6 => {
+                let address = self.registers.hl();
+                self.write(bus, address, value);
+            },
+            _ => self.registers.a = value
+        }
+    }
+
+    fn rp(&self, rp: u8) -> u16 {
+        match rp {
+            0 => self.registers.bc(),
+            1 => self.registers.de(),
+            2 => self.registers.hl(),
+            _ => self.sp
+        }
+    }
+
+    fn set_rp(&mut self, rp: u8, value: u16) {
+        match rp {
+            0 => self.registers.set_bc(value),
+            1 => self.registers.set_de(value),
+            2 => self.registers.set_hl(value),
+            _ => self.sp = value
+        }
+    }
+
+    fn rp2(&self, rp: u8) -> u16 {
+        match rp {
+            3 => self.registers.af(),
+            _ => self.rp(rp)
+        }
+    }
+
+    // The low nibble of F doesn't exist, so it's dropped on the way in
+    fn set_rp2(&mut self, rp: u8, value: u16) {
+        match rp {
+            3 => self.registers.set_af(value),
+            _ => self.set_rp(rp, value)
+        }
+    }
+
+    // (BC), (DE), (HL+) and (HL-)
+    fn indirect_address(&mut self, p: u8) -> u16 {
+        let hl = self.registers.hl();
+        match p {
+            0 => self.registers.bc(),
+            1 => self.registers.de(),
+            2 => {
+                self.registers.set_hl(hl.wrapping_add(1));
+                hl
+            },
+            _ => {
+                self.registers.set_hl(hl.wrapping_sub(1));
+                hl
+            }
+        }
+    }
+
+    fn set_flags(&mut self, zero: bool, subtract: bool, half_carry: bool, carry: bool) {
+        let mut flags = Flags::empty();
+        flags.set(Flags::ZERO, zero);
+        flags.set(Flags::SUBTRACT, subtract);
+        flags.set(Flags::HALF_CARRY, half_carry);
+        flags.set(Flags::CARRY, carry);
+        self.registers.f = flags;
+    }
+
+    // ADD ADC SUB SBC AND XOR OR CP
+    fn alu(&mut self, operation: u8, value: u8) {
+        let a = self.registers.a;
+        let carry_in = match operation {
+            1 | 3 => self.registers.f.contains(Flags::CARRY) as u8,
+            _ => 0
+        };
+
+        let result = match operation {
+            0 | 1 => {
+                let result = a as u16 + value as u16 + carry_in as u16;
+                let half_carry = (a & 0x0F) + (value & 0x0F) + carry_in > 0x0F;
+                self.set_flags(result as u8 == 0, false, half_carry, result > 0xFF);
+                result as u8
+            },
+            2 | 3 | 7 => {
+                let result = a.wrapping_sub(value).wrapping_sub(carry_in);
+                let half_carry = (a & 0x0F) < (value & 0x0F) + carry_in;
+                let carry = (a as u16) < value as u16 + carry_in as u16;
+                self.set_flags(result == 0, true, half_carry, carry);
+                result
+            },
+            4 => {
+                let result = a & value;
+                self.set_flags(result == 0, false, true, false);
+                result
+            },
+            5 => {
+                let result = a ^ value;
+                self.set_flags(result == 0, false, false, false);
+                result
+            },
+            _ => {
+                let result = a | value;
+                self.set_flags(result == 0, false, false, false);
+                result
+            }
+        };
+
+        // CP only sets the flags
+        if operation != 7 {
+            self.registers.a = result;
+        }
+    }
+
+    // RLC RRC RL RR SLA SRA SWAP SRL
+    fn shift(&mut self, operation: u8, value: u8) -> u8 {
+        let carry_in = self.registers.f.contains(Flags::CARRY) as u8;
+        let (result, carry) = match operation {
+            0 => (value.rotate_left(1), tbit!(value, 7)),
+            1 => (value.rotate_right(1), tbit!(value, 0)),
+            2 => (value << 1 | carry_in, tbit!(value, 7)),
+            3 => (value >> 1 | carry_in << 7, tbit!(value, 0)),
+            4 => (value << 1, tbit!(value, 7)),
+            5 => (value >> 1 | (value & 0x80), tbit!(value, 0)),
+            6 => (value.rotate_left(4), false),
+            _ => (value >> 1, tbit!(value, 0))
+        };
+        self.set_flags(result == 0, false, false, carry);
+        result
+    }
+
+    // SP plus a signed byte. The flags come from adding the byte unsigned to
+    // the low byte of SP.
+    fn add_sp(&mut self, offset: u8) -> u16 {
+        let sp = self.sp;
+        let half_carry = (sp & 0x0F) + (offset as u16 & 0x0F) > 0x0F;
+        let carry = (sp & 0xFF) + offset as u16 > 0xFF;
+        self.set_flags(false, false, half_carry, carry);
+        sp.wrapping_add(offset as i8 as u16)
+    }
+
+    // Fixes A up into BCD after an add or subtract
+    fn daa(&mut self) {
+        let flags = self.registers.f;
+        let subtract = flags.contains(Flags::SUBTRACT);
+        let mut a = self.registers.a;
+        let mut carry = flags.contains(Flags::CARRY);
+
+        let mut correction = 0;
+        if flags.contains(Flags::HALF_CARRY) || (!subtract && a & 0x0F > 0x09) {
+            correction |= 0x06;
+        }
+        if carry || (!subtract && a > 0x99) {
+            correction |= 0x60;
+            carry = true;
+        }
+
+        a = if subtract { a.wrapping_sub(correction) } else { a.wrapping_add(correction) };
+        self.set_flags(a == 0, subtract, false, carry);
+        self.registers.a = a;
+    }
+}
@@ -1,23 +1,197 @@
-use super::memory::Memory;
+mod instructions;
+#[cfg(test)]
+mod conformance;
+
 use super::registers::Registers;
+use super::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
+// Everything the CPU can see. Each call is one M-cycle (4 clock cycles), so
+// the order of calls is the order things happen on the real bus.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, data: u8);
+
+    // An M-cycle spent inside the CPU with nothing on the bus
+    fn idle(&mut self) {}
 
+    // Interrupts both requested (IF) and enabled (IE). Looking doesn't take
+    // a cycle.
+    fn interrupts(&mut self) -> u8;
+    // Clears the request for the interrupt at this bit once it's serviced
+    fn acknowledge(&mut self, interrupt: u8);
+
+    // STOP with a speed switch armed (CGB KEY1) switches speed instead.
+    // Returns true if it did.
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+}
+
+#[derive(Clone)]
 pub struct Cpu {
     registers: Registers,
     pub pc: u16,
     sp: u16,
+
+    // Interrupt master enable
+    ime: bool,
+    // EI only takes effect after the instruction following it
+    ei_pending: bool,
+    halted: bool,
+    // HALT with IME off and an interrupt already pending doesn't halt, but
+    // the next opcode fetch fails to move PC on
+    halt_bug: bool,
+    // Illegal opcodes hang the CPU until power off
+    locked: bool,
+
+    // Cycles taken by the current step
+    cycles: u32,
 }
 
 impl Cpu {
     pub fn new() -> Cpu {
         Cpu {
             registers: Registers::new(),
-            pc: 0,
+            // Where the boot ROM hands over to the cartridge
+            pc: 0x0100,
             sp: 0xFFFE,
+            ime: false,
+            ei_pending: false,
+            halted: false,
+            halt_bug: false,
+            locked: false,
+            cycles: 0,
         }
     }
 
-    pub fn execute(&self, memory: &mut Memory) -> u32 {
-        // unimplemented!()
-        return 0;
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.sp = sp;
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // Stuck for good after an illegal opcode
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    // Runs one instruction, or services an interrupt, or waits a cycle while
+    // halted. Returns the clock cycles taken.
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        self.cycles = 0;
+
+        if self.halted && bus.interrupts() != 0 {
+            self.halted = false;
+        }
+        if self.halted || self.locked {
+            self.idle(bus);
+            return self.cycles;
+        }
+
+        if self.ime && bus.interrupts() != 0 {
+            self.service_interrupt(bus);
+            return self.cycles;
+        }
+
+        let enable_interrupts = self.ei_pending;
+        self.ei_pending = false;
+
+        let opcode = self.read(bus, self.pc);
+        if self.halt_bug {
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
+        self.execute(bus, opcode);
+
+        // DI straight after EI wins
+        if enable_interrupts && opcode != 0xF3 {
+            self.ime = true;
+        }
+        self.cycles
+    }
+
+    fn service_interrupt<B: Bus>(&mut self, bus: &mut B) {
+        self.ime = false;
+        self.idle(bus);
+        self.idle(bus);
+
+        let pc = self.pc;
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(bus, self.sp, (pc >> 8) as u8);
+        // Pushing onto IE can change which interrupt is serviced, or cancel
+        // it altogether and jump to 0
+        let pending = bus.interrupts();
+        self.sp = self.sp.wrapping_sub(1);
+        self.write(bus, self.sp, pc as u8);
+
+        self.pc = if pending == 0 {
+            0x0000
+        } else {
+            let interrupt = pending.trailing_zeros() as u8;
+            bus.acknowledge(interrupt);
+            0x0040 + interrupt as u16 * 8
+        };
+        self.idle(bus);
+    }
+
+    fn read<B: Bus>(&mut self, bus: &mut B, address: u16) -> u8 {
+        self.cycles += 4;
+        bus.read(address)
+    }
+
+    fn write<B: Bus>(&mut self, bus: &mut B, address: u16, data: u8) {
+        self.cycles += 4;
+        bus.write(address, data);
+    }
+
+    fn idle<B: Bus>(&mut self, bus: &mut B) {
+        self.cycles += 4;
+        bus.idle();
+    }
+}
+
+impl Savestate for Cpu {
+    fn save(&self, writer: &mut StateWriter) {
+        self.registers.save(writer);
+        writer.write_u16(self.pc);
+        writer.write_u16(self.sp);
+        writer.write_bool(self.ime);
+        writer.write_bool(self.ei_pending);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.halt_bug);
+        writer.write_bool(self.locked);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.registers.load(reader)?;
+        self.pc = reader.read_u16()?;
+        self.sp = reader.read_u16()?;
+        self.ime = reader.read_bool()?;
+        self.ei_pending = reader.read_bool()?;
+        self.halted = reader.read_bool()?;
+        self.halt_bug = reader.read_bool()?;
+        self.locked = reader.read_bool()?;
+        Ok(())
     }
 }
@@ -0,0 +1,534 @@
+use super::disasm::{self, Instruction};
+use super::inspect::{Freeze, PatternByte};
+use super::png::Image;
+use super::registers::Registers;
+use super::serial::SerialDevice;
+use super::symbols::Symbols;
+use super::watch::{WatchHit, Watchpoint};
+use super::{Channel, Emulator};
+
+use std::collections::BTreeSet;
+use std::io;
+
+const CALL: u8 = 0xCD;
+const RET: u8 = 0xC9;
+const RETI: u8 = 0xD9;
+
+pub enum StopReason {
+    // A single instruction was stepped
+    Step,
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    // Reached the end of a step over/out or run to cursor
+    Finished,
+    // Ran for the frame limit without stopping
+    FrameLimit,
+}
+
+// A CALL, RST or interrupt that hasn't returned yet
+pub struct CallFrame {
+    // For an interrupt, the instruction it came in before
+    pub call_site: u16,
+    pub target: u16,
+    pub return_address: u16,
+    // Where the return address was pushed
+    pub sp: u16,
+    pub interrupt: bool,
+}
+
+pub struct Banks {
+    pub rom: usize,
+    pub ram: usize,
+    pub ram_enabled: bool,
+}
+
+pub struct Debugger {
+    emulator: Emulator,
+    // Address and the ROM bank it's in, if it only applies to one
+    breakpoints: BTreeSet<(u16, Option<usize>)>,
+    call_stack: Vec<CallFrame>,
+    // Whether the last instruction was a taken RET or RETI
+    returned: bool,
+    symbols: Symbols,
+}
+
+impl Debugger {
+    pub fn new(emulator: Emulator) -> Self {
+        Debugger {
+            emulator,
+            breakpoints: BTreeSet::new(),
+            call_stack: Vec::new(),
+            returned: false,
+            symbols: Symbols::new(),
+        }
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Whether the game runs in CGB mode, for naming its registers
+    pub fn is_cgb(&self) -> bool {
+        self.emulator.is_cgb()
+    }
+
+    // The label for an address in whatever bank is mapped there now
+    pub fn label(&self, address: u16) -> Option<String> {
+        self.symbols.name(self.bank_at(address), address).map(str::to_string)
+    }
+
+    // The nearest label, with an offset if it's not exactly on one
+    pub fn describe(&self, address: u16) -> Option<String> {
+        self.symbols.describe(self.bank_at(address), address)
+    }
+
+    // Bank to look symbols up in; fixed regions are bank 0
+    fn bank_at(&self, address: u16) -> usize {
+        self.emulator.bank_at(address).unwrap_or(0)
+    }
+
+    // Returns false if there was already a breakpoint there. With a bank, it
+    // only stops while that bank is mapped.
+    pub fn add_breakpoint(&mut self, address: u16, bank: Option<usize>) -> bool {
+        self.breakpoints.insert((address, bank))
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16, bank: Option<usize>) -> bool {
+        self.breakpoints.remove(&(address, bank))
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &(u16, Option<usize>)> {
+        self.breakpoints.iter()
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        let pc = self.pc();
+        let bank = self.emulator.bank_at(pc);
+        self.breakpoints.range((pc, None)..=(pc, Some(usize::MAX))).any(|(_, wanted)| {
+            match (wanted, bank) {
+                (Some(wanted), Some(mapped)) => *wanted == mapped,
+                _ => true
+            }
+        })
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.emulator.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.emulator.watchpoints.len() {
+            Some(self.emulator.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.emulator.watchpoints
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.emulator.cpu.pc
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.emulator.cpu.sp()
+    }
+
+    pub fn registers(&self) -> &Registers {
+        self.emulator.cpu.registers()
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        self.emulator.cpu.registers_mut()
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.emulator.cpu.pc = pc;
+    }
+
+    pub fn set_sp(&mut self, sp: u16) {
+        self.emulator.cpu.set_sp(sp);
+    }
+
+    pub fn ime(&self) -> bool {
+        self.emulator.cpu.ime()
+    }
+
+    pub fn banks(&self) -> Banks {
+        Banks {
+            rom: self.emulator.memory.rom_bank(),
+            ram: self.emulator.memory.ram_bank(),
+            ram_enabled: self.emulator.memory.ram_enabled(),
+        }
+    }
+
+    // Innermost call last
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    // Doesn't trip watchpoints
+    pub fn read(&self, address: u16) -> u8 {
+        self.emulator.peek_memory(address as usize)
+    }
+
+    // Writes as the CPU would, so writes to ROM switch banks. Doesn't trip
+    // watchpoints either.
+    pub fn write(&mut self, address: u16, data: u8) {
+        self.emulator.poke_memory(address as usize, data);
+    }
+
+    // Like read, but can look at any ROM or external RAM bank
+    pub fn inspect(&self, address: u16, bank: Option<usize>) -> u8 {
+        self.emulator.inspect(address, bank)
+    }
+
+    pub fn edit(&mut self, address: u16, bank: Option<usize>, data: u8) -> Result<(), io::Error> {
+        self.emulator.edit(address, bank, data)
+    }
+
+    pub fn search(&self, pattern: &[PatternByte]) -> Vec<(u16, Option<usize>)> {
+        self.emulator.search(pattern)
+    }
+
+    pub fn add_freeze(&mut self, freeze: Freeze) -> Result<(), io::Error> {
+        self.emulator.add_freeze(freeze)
+    }
+
+    pub fn remove_freeze(&mut self, index: usize) -> Option<Freeze> {
+        self.emulator.remove_freeze(index)
+    }
+
+    pub fn freezes(&self) -> &[Freeze] {
+        self.emulator.freezes()
+    }
+
+    pub fn screenshot(&self) -> Image {
+        self.emulator.screenshot()
+    }
+
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        self.emulator.set_serial_device(device)
+    }
+
+    pub fn set_channel_taps(&mut self, enabled: bool) {
+        self.emulator.set_channel_taps(enabled);
+    }
+
+    pub fn take_channel_samples(&mut self, channel: Channel) -> Vec<f32> {
+        self.emulator.take_channel_samples(channel)
+    }
+
+    // Format the result with format_with(debugger.is_cgb(), |address| debugger.label(address))
+    // to get labels in it
+    pub fn disassemble(&self, address: u16) -> Instruction {
+        disasm::decode(|address| self.read(address), address)
+    }
+
+    pub fn step_in(&mut self) -> StopReason {
+        self.step_instruction();
+        match self.emulator.watch_hit.take() {
+            Some(hit) => StopReason::Watchpoint(hit),
+            None => StopReason::Step
+        }
+    }
+
+    // Like step_in, but runs a whole CALL or RST until it returns
+    pub fn step_over(&mut self, max_frames: u32) -> StopReason {
+        let pc = self.pc();
+        let sp = self.sp();
+
+        match call_length(self.read(pc)) {
+            Some(length) => {
+                let return_address = pc.wrapping_add(length);
+                self.run_until(max_frames, |debugger| debugger.pc() == return_address && debugger.sp() == sp)
+            },
+            None => self.step_in()
+        }
+    }
+
+    // Runs until the current function returns. With no known caller that's
+    // the next RET that's taken.
+    pub fn step_out(&mut self, max_frames: u32) -> StopReason {
+        let depth = self.call_stack.len();
+        self.run_until(max_frames, |debugger| {
+            debugger.returned && debugger.call_stack.len() < depth.max(1)
+        })
+    }
+
+    pub fn run_to(&mut self, address: u16, max_frames: u32) -> StopReason {
+        self.run_until(max_frames, |debugger| debugger.pc() == address)
+    }
+
+    // Runs until a breakpoint, or for at most max_frames frames
+    pub fn run(&mut self, max_frames: u32) -> StopReason {
+        self.run_until(max_frames, |_| false)
+    }
+
+    // Runs until done says so, or the same things that stop run
+    pub fn run_until<F: Fn(&Debugger) -> bool>(&mut self, max_frames: u32, done: F) -> StopReason {
+        let mut frames = 0;
+        loop {
+            // Always run at least one instruction, so continuing from a
+            // breakpoint doesn't stop on it straight away
+            if self.step_instruction() {
+                frames += 1;
+            }
+
+            if let Some(hit) = self.emulator.watch_hit.take() {
+                return StopReason::Watchpoint(hit);
+            }
+
+            if done(self) {
+                return StopReason::Finished;
+            }
+
+            if self.at_breakpoint() {
+                return StopReason::Breakpoint(self.pc());
+            }
+
+            if frames >= max_frames {
+                return StopReason::FrameLimit;
+            }
+        }
+    }
+
+    // Returns true if the instruction finished a frame
+    fn step_instruction(&mut self) -> bool {
+        let pc = self.pc();
+        let sp = self.sp();
+        let opcode = self.read(pc);
+
+        let finished_frame = self.emulator.step();
+
+        // Hits are reported against the instruction that caused them, not
+        // wherever PC had got to by the time of the access
+        if let Some(mut hit) = self.emulator.watch_hit.get() {
+            hit.pc = pc;
+            self.emulator.watch_hit.set(Some(hit));
+        }
+
+        let (new_pc, new_sp) = (self.pc(), self.sp());
+        self.track_calls(pc, sp, opcode, new_pc, new_sp, self.emulator.interrupted);
+        finished_frame
+    }
+
+    // An interrupt dispatched at a CALL looks just like the CALL from here,
+    // so the emulator says which it was
+    fn track_calls(&mut self, pc: u16, sp: u16, opcode: u8, new_pc: u16, new_sp: u16, interrupted: bool) {
+        // Anything whose return address is now above the stack pointer has
+        // returned, whether by RET or by the game resetting SP
+        while self.call_stack.last().is_some_and(|frame| frame.sp < new_sp) {
+            self.call_stack.pop();
+        }
+
+        if interrupted {
+            self.returned = false;
+            self.call_stack.push(CallFrame {
+                call_site: pc,
+                target: new_pc,
+                return_address: pc,
+                sp: new_sp,
+                interrupt: true,
+            });
+            return;
+        }
+
+        self.returned = is_return(opcode) && new_sp == sp.wrapping_add(2);
+
+        // Conditional calls that aren't taken leave SP alone
+        if let Some(length) = call_length(opcode) {
+            if new_sp == sp.wrapping_sub(2) {
+                self.call_stack.push(CallFrame {
+                    call_site: pc,
+                    target: new_pc,
+                    return_address: pc.wrapping_add(length),
+                    sp: new_sp,
+                    interrupt: false,
+                });
+            }
+        }
+    }
+}
+
+// Length of a CALL or RST instruction, None for anything else
+fn call_length(opcode: u8) -> Option<u16> {
+    match opcode {
+        CALL | 0xC4 | 0xCC | 0xD4 | 0xDC => Some(3),
+        // RST 00-38
+        _ if opcode & 0b1100_0111 == 0b1100_0111 => Some(1),
+        _ => None
+    }
+}
+
+fn is_return(opcode: u8) -> bool {
+    matches!(opcode, RET | RETI | 0xC0 | 0xC8 | 0xD0 | 0xD8)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{call_length, Debugger, StopReason};
+    use super::super::test_roms::test_rom;
+    use super::super::{Access, Condition, Emulator, Watchpoint};
+
+    fn debugger() -> Debugger {
+        let mut rom = test_rom();
+        // JR -2, forever
+        rom[0x0100] = 0x18;
+        rom[0x0101] = 0xFE;
+        Debugger::new(Emulator::from_bytes(&rom))
+    }
+
+    #[test]
+    fn call_lengths() {
+        assert_eq!(call_length(0xCD), Some(3));
+        assert_eq!(call_length(0xDC), Some(3));
+        assert_eq!(call_length(0xC7), Some(1));
+        assert_eq!(call_length(0xFF), Some(1));
+        assert_eq!(call_length(0xC9), None);
+        assert_eq!(call_length(0x00), None);
+    }
+
+    #[test]
+    fn call_stack_follows_calls_and_returns() {
+        let mut debugger = debugger();
+
+        // CALL $2000 from $0150, then RST $38 from inside it
+        debugger.track_calls(0x0150, 0xFFFE, 0xCD, 0x2000, 0xFFFC, false);
+        debugger.track_calls(0x2005, 0xFFFC, 0xFF, 0x0038, 0xFFFA, false);
+        assert_eq!(debugger.call_stack().len(), 2);
+        assert_eq!(debugger.call_stack()[0].return_address, 0x0153);
+        assert_eq!(debugger.call_stack()[1].return_address, 0x2006);
+
+        // A conditional call that isn't taken
+        debugger.track_calls(0x0038, 0xFFFA, 0xC4, 0x003B, 0xFFFA, false);
+        assert_eq!(debugger.call_stack().len(), 2);
+
+        debugger.track_calls(0x003B, 0xFFFA, 0xC9, 0x2006, 0xFFFC, false);
+        assert!(debugger.returned);
+        assert_eq!(debugger.call_stack().len(), 1);
+
+        // Resetting the stack drops everything
+        debugger.track_calls(0x2006, 0xFFFC, 0x31, 0x2009, 0xFFFE, false);
+        assert!(!debugger.returned);
+        assert!(debugger.call_stack().is_empty());
+    }
+
+    #[test]
+    fn breakpoints_and_frame_limit() {
+        let mut debugger = debugger();
+
+        match debugger.run(2) {
+            StopReason::FrameLimit => (),
+            _ => panic!("Expected to hit the frame limit")
+        }
+
+        let pc = debugger.pc();
+        assert!(debugger.add_breakpoint(pc, None));
+        assert!(!debugger.add_breakpoint(pc, None));
+        match debugger.run(2) {
+            StopReason::Breakpoint(address) => assert_eq!(address, pc),
+            _ => panic!("Expected to stop on the breakpoint")
+        }
+
+        assert!(debugger.remove_breakpoint(pc, None));
+        assert_eq!(debugger.breakpoints().count(), 0);
+    }
+
+    #[test]
+    fn interrupts_at_a_call_are_not_calls() {
+        let mut rom = test_rom();
+        let program = [
+            0x3E, 0x01,         // LD A,1
+            0xE0, 0xFF,         // LDH (FF),A (enable VBlank)
+            0xE0, 0x0F,         // LDH (0F),A (request it)
+            0xFB,               // EI
+            0x00,               // NOP
+            0xCD, 0x00, 0x20,   // CALL 2000, where the interrupt comes in
+            0x18, 0xFE,         // JR -2
+        ];
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        rom[0x0040] = 0xD9; // RETI
+        rom[0x2000] = 0xC9; // RET
+        let mut debugger = Debugger::new(Emulator::from_bytes(&rom));
+
+        while debugger.pc() != 0x0040 {
+            debugger.step_in();
+        }
+        let frame = &debugger.call_stack()[0];
+        assert!(frame.interrupt);
+        assert_eq!((frame.call_site, frame.return_address), (0x0108, 0x0108));
+
+        // Finishing the handler goes back to the CALL, which hasn't run yet
+        assert!(matches!(debugger.step_out(1), StopReason::Finished));
+        assert_eq!(debugger.pc(), 0x0108);
+        assert!(debugger.call_stack().is_empty());
+
+        debugger.step_in();
+        assert_eq!(debugger.pc(), 0x2000);
+        let frame = &debugger.call_stack()[0];
+        assert!(!frame.interrupt);
+        assert_eq!(frame.return_address, 0x010B);
+        assert!(matches!(debugger.step_out(1), StopReason::Finished));
+        assert_eq!(debugger.pc(), 0x010B);
+    }
+
+    #[test]
+    fn watchpoints_report_old_and_new_values() {
+        let mut rom = test_rom();
+        let program = [
+            0x3E, 0x3C,         // LD A,3C
+            0xEA, 0x00, 0xC0,   // LD (C000),A
+            0xEA, 0x00, 0xC0,   // LD (C000),A, which changes nothing
+            0xFA, 0x00, 0xC0,   // LD A,(C000)
+            0x18, 0xFE,         // JR -2
+        ];
+        rom[0x0100..0x0100 + program.len()].copy_from_slice(&program);
+        let mut debugger = Debugger::new(Emulator::from_bytes(&rom));
+        debugger.add_watchpoint(Watchpoint::new(Access::Change, 0xC000));
+
+        let hit = match debugger.run(1) {
+            StopReason::Watchpoint(hit) => hit,
+            _ => panic!("Expected a watchpoint hit")
+        };
+        assert_eq!(hit.access, Access::Change);
+        assert_eq!((hit.pc, hit.address, hit.bank), (0x0102, 0xC000, None));
+        assert_eq!((hit.old, hit.new), (0x00, 0x3C));
+
+        // Writing the same value again isn't a change, but is a write
+        debugger.add_watchpoint(Watchpoint::new(Access::Write, 0xC000));
+        let hit = match debugger.run(1) {
+            StopReason::Watchpoint(hit) => hit,
+            _ => panic!("Expected a watchpoint hit")
+        };
+        assert_eq!(hit.access, Access::Write);
+        assert_eq!((hit.pc, hit.address), (0x0105, 0xC000));
+        assert_eq!((hit.old, hit.new), (0x3C, 0x3C));
+
+        // The debugger looking doesn't count as a read
+        let mut read = Watchpoint::new(Access::Read, 0xC000);
+        read.condition = Condition::Equals(0x3C);
+        debugger.add_watchpoint(read);
+        assert_eq!(debugger.read(0xC000), 0x3C);
+        assert!(debugger.emulator.watch_hit.get().is_none());
+
+        let hit = match debugger.run(1) {
+            StopReason::Watchpoint(hit) => hit,
+            _ => panic!("Expected a watchpoint hit")
+        };
+        assert_eq!(hit.access, Access::Read);
+        assert_eq!((hit.pc, hit.address), (0x0108, 0xC000));
+        assert_eq!((hit.old, hit.new), (0x3C, 0x3C));
+        assert!(matches!(debugger.run(1), StopReason::FrameLimit));
+
+        assert!(debugger.remove_watchpoint(0).is_some());
+        assert!(debugger.remove_watchpoint(2).is_none());
+    }
+}
@@ -0,0 +1,388 @@
+// SM83 instruction decoding, for the debugger and listings. Output follows
+// RGBDS syntax so it can be pasted back into source.
+
+use super::{
+    DIVIDER_REGISTER, DMA_ADDRESS, INTERRUPT_ENABLED, INTERRUPT_REQUEST, KEY_ADDRESS,
+    LCD_CONTROL_ADDRESS, LCD_STATUS_ADDRESS, PALETTE_47_ADDRESS, PALETTE_48_ADDRESS,
+    PALETTE_49_ADDRESS, SCANLINE_ADDRESS, SCROLL_X_ADDRESS, SCROLL_Y_ADDRESS, TIMER_ADDRESS,
+    TIMER_CONTROLLER, TIMER_MODULATOR, WINDOW_X_ADDRESS, WINDOW_Y_ADDRESS,
+};
+use super::hdma::{HDMA_END, HDMA_START};
+use super::memory::{SPEED_SWITCH, VRAM_BANK, WRAM_BANK};
+use super::palettes::{BCPD, BCPS, OCPD, OCPS};
+
+use std::fmt;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "[HL]", "A"];
+const REGISTER_PAIRS: [&str; 4] = ["BC", "DE", "HL", "SP"];
+// PUSH and POP use AF in place of SP
+const STACK_PAIRS: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
+const ROTATES: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const ACCUMULATOR_OPS: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Operand {
+    // Registers and conditions, written as-is
+    Name(&'static str),
+    // [BC], [HL+], [C] and so on
+    Indirect(&'static str),
+    Byte(u8),
+    Word(u16),
+    // [a16]
+    Address(u16),
+    // [$FF00 + a8] for LDH
+    HighAddress(u8),
+    // Where a JR, JP or CALL goes
+    Target(u16),
+    // ADD SP, e8
+    Signed(i8),
+    // LD HL, SP+e8
+    StackOffset(i8),
+    Bit(u8),
+    Vector(u8),
+}
+
+impl Operand {
+    // Formats the operand, asking names for any address it refers to
+    fn write<F: Fn(u16) -> Option<String>>(&self, f: &mut String, cgb: bool, names: &F) {
+        match *self {
+            Operand::Name(name) => f.push_str(name),
+            Operand::Indirect(name) => f.push_str(&format!("[{}]", name)),
+            Operand::Byte(value) => f.push_str(&format!("${:02X}", value)),
+            Operand::Word(value) => f.push_str(&format!("${:04X}", value)),
+            Operand::Address(address) => f.push_str(&format!("[{}]", address_name(address, cgb, names))),
+            Operand::HighAddress(low) => f.push_str(&format!("[{}]", address_name(0xFF00 | u16::from(low), cgb, names))),
+            Operand::Target(address) => f.push_str(&names(address).unwrap_or_else(|| format!("${:04X}", address))),
+            Operand::Signed(offset) => f.push_str(&offset.to_string()),
+            Operand::StackOffset(offset) if offset < 0 => f.push_str(&format!("SP-{}", -i16::from(offset))),
+            Operand::StackOffset(offset) => f.push_str(&format!("SP+{}", offset)),
+            Operand::Bit(bit) => f.push_str(&bit.to_string()),
+            Operand::Vector(vector) => f.push_str(&format!("${:02X}", vector)),
+        }
+    }
+}
+
+fn address_name<F: Fn(u16) -> Option<String>>(address: u16, cgb: bool, names: &F) -> String {
+    names(address)
+        .or_else(|| io_register_name(address, cgb).map(str::to_string))
+        .unwrap_or_else(|| format!("${:04X}", address))
+}
+
+pub struct Instruction {
+    pub address: u16,
+    pub length: u16,
+    // T-cycles, for a conditional instruction when it isn't taken
+    pub cycles: u8,
+    // T-cycles for a conditional instruction when it is taken
+    pub taken_cycles: Option<u8>,
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+}
+
+impl Instruction {
+    fn new(address: u16, length: u16, cycles: u8, mnemonic: &'static str, operands: Vec<Operand>) -> Self {
+        Instruction {
+            address,
+            length,
+            cycles,
+            taken_cycles: None,
+            mnemonic,
+            operands,
+        }
+    }
+
+    fn taken(mut self, cycles: u8) -> Self {
+        self.taken_cycles = Some(cycles);
+        self
+    }
+
+    // Where a jump, call or RST goes, if known without running it
+    pub fn target(&self) -> Option<u16> {
+        self.operands.iter().find_map(|operand| match operand {
+            Operand::Target(address) => Some(*address),
+            Operand::Vector(vector) => Some(u16::from(*vector)),
+            _ => None
+        })
+    }
+
+    // Formats with labels from names wherever it has one for an address.
+    // The CGB registers are only named for CGB games.
+    pub fn format_with<F: Fn(u16) -> Option<String>>(&self, cgb: bool, names: F) -> String {
+        let mut text = self.mnemonic.to_string();
+        for (index, operand) in self.operands.iter().enumerate() {
+            text.push_str(if index == 0 { " " } else { ", " });
+            operand.write(&mut text, cgb, &names);
+        }
+        text
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format_with(false, |_| None))
+    }
+}
+
+// Decodes the instruction at an address. read is given each byte's address,
+// which lets the caller decide how banks are mapped.
+pub fn decode<F: Fn(u16) -> u8>(read: F, address: u16) -> Instruction {
+    use self::Operand::*;
+
+    let opcode = read(address);
+    let byte = || read(address.wrapping_add(1));
+    let word = || u16::from(read(address.wrapping_add(1))) | u16::from(read(address.wrapping_add(2))) << 8;
+    let relative = || address.wrapping_add(2).wrapping_add(byte() as i8 as u16);
+
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 0b111) as usize;
+    let z = opcode & 0b111;
+    let p = y >> 1;
+    let q = y & 1;
+
+    // Anything touching [HL] takes an extra memory access
+    let register_cycles = |register: usize, cycles: u8| if register == 6 { cycles + 4 } else { cycles };
+
+    let new = |length, cycles, mnemonic, operands| Instruction::new(address, length, cycles, mnemonic, operands);
+
+    match (x, z) {
+        (0, 0) => match y {
+            0 => new(1, 4, "NOP", vec![]),
+            1 => new(3, 20, "LD", vec![Address(word()), Name("SP")]),
+            2 => new(2, 4, "STOP", vec![]),
+            3 => new(2, 12, "JR", vec![Target(relative())]),
+            _ => new(2, 8, "JR", vec![Name(CONDITIONS[y - 4]), Target(relative())]).taken(12),
+        },
+        (0, 1) if q == 0 => new(3, 12, "LD", vec![Name(REGISTER_PAIRS[p]), Word(word())]),
+        (0, 1) => new(1, 8, "ADD", vec![Name("HL"), Name(REGISTER_PAIRS[p])]),
+        (0, 2) => {
+            let pointer = Indirect(["BC", "DE", "HL+", "HL-"][p]);
+            if q == 0 {
+                new(1, 8, "LD", vec![pointer, Name("A")])
+            } else {
+                new(1, 8, "LD", vec![Name("A"), pointer])
+            }
+        },
+        (0, 3) => new(1, 8, if q == 0 { "INC" } else { "DEC" }, vec![Name(REGISTER_PAIRS[p])]),
+        // [HL] is read and written back
+        (0, 4) => new(1, if y == 6 { 12 } else { 4 }, "INC", vec![register(y)]),
+        (0, 5) => new(1, if y == 6 { 12 } else { 4 }, "DEC", vec![register(y)]),
+        (0, 6) => new(2, register_cycles(y, 8), "LD", vec![register(y), Byte(byte())]),
+        (0, 7) => new(1, 4, ACCUMULATOR_OPS[y], vec![]),
+
+        (1, 6) if y == 6 => new(1, 4, "HALT", vec![]),
+        (1, _) => {
+            let source = z as usize;
+            let cycles = if y == 6 || source == 6 { 8 } else { 4 };
+            new(1, cycles, "LD", vec![register(y), register(source)])
+        },
+
+        (2, _) => alu(new, y, register(z as usize), register_cycles(z as usize, 4)),
+
+        (3, 0) => match y {
+            0..=3 => new(1, 8, "RET", vec![Name(CONDITIONS[y])]).taken(20),
+            4 => new(2, 12, "LDH", vec![HighAddress(byte()), Name("A")]),
+            5 => new(2, 16, "ADD", vec![Name("SP"), Signed(byte() as i8)]),
+            6 => new(2, 12, "LDH", vec![Name("A"), HighAddress(byte())]),
+            _ => new(2, 12, "LD", vec![Name("HL"), StackOffset(byte() as i8)]),
+        },
+        (3, 1) if q == 0 => new(1, 12, "POP", vec![Name(STACK_PAIRS[p])]),
+        (3, 1) => match p {
+            0 => new(1, 16, "RET", vec![]),
+            1 => new(1, 16, "RETI", vec![]),
+            2 => new(1, 4, "JP", vec![Name("HL")]),
+            _ => new(1, 8, "LD", vec![Name("SP"), Name("HL")]),
+        },
+        (3, 2) => match y {
+            0..=3 => new(3, 12, "JP", vec![Name(CONDITIONS[y]), Target(word())]).taken(16),
+            4 => new(1, 8, "LDH", vec![Indirect("C"), Name("A")]),
+            5 => new(3, 16, "LD", vec![Address(word()), Name("A")]),
+            6 => new(1, 8, "LDH", vec![Name("A"), Indirect("C")]),
+            _ => new(3, 16, "LD", vec![Name("A"), Address(word())]),
+        },
+        (3, 3) => match y {
+            0 => new(3, 16, "JP", vec![Target(word())]),
+            // CB prefix
+            1 => decode_cb(address, byte()),
+            6 => new(1, 4, "DI", vec![]),
+            7 => new(1, 4, "EI", vec![]),
+            _ => invalid(address, opcode),
+        },
+        (3, 4) if y < 4 => new(3, 12, "CALL", vec![Name(CONDITIONS[y]), Target(word())]).taken(24),
+        (3, 5) if q == 0 => new(1, 16, "PUSH", vec![Name(STACK_PAIRS[p])]),
+        (3, 5) if p == 0 => new(3, 24, "CALL", vec![Target(word())]),
+        (3, 6) => alu(new, y, Byte(byte()), 8),
+        (3, 7) => new(1, 16, "RST", vec![Vector(opcode & 0b0011_1000)]),
+
+        _ => invalid(address, opcode),
+    }
+}
+
+fn register(index: usize) -> Operand {
+    if index == 6 {
+        Operand::Indirect("HL")
+    } else {
+        Operand::Name(REGISTERS[index])
+    }
+}
+
+// ADD, ADC and SBC name A explicitly, the rest leave it implied
+fn alu<F: Fn(u16, u8, &'static str, Vec<Operand>) -> Instruction>(new: F, operation: usize, operand: Operand, cycles: u8) -> Instruction {
+    let length = if let Operand::Byte(_) = operand { 2 } else { 1 };
+    match operation {
+        0 | 1 | 3 => new(length, cycles, ALU[operation], vec![Operand::Name("A"), operand]),
+        _ => new(length, cycles, ALU[operation], vec![operand]),
+    }
+}
+
+fn decode_cb(address: u16, opcode: u8) -> Instruction {
+    let y = (opcode >> 3) & 0b111;
+    let z = (opcode & 0b111) as usize;
+    let target = register(z);
+
+    let (mnemonic, operands) = match opcode >> 6 {
+        0 => (ROTATES[y as usize], vec![target]),
+        1 => ("BIT", vec![Operand::Bit(y), target]),
+        2 => ("RES", vec![Operand::Bit(y), target]),
+        _ => ("SET", vec![Operand::Bit(y), target]),
+    };
+
+    // BIT only reads [HL], the others read and write it back
+    let cycles = match (z, opcode >> 6) {
+        (6, 1) => 12,
+        (6, _) => 16,
+        _ => 8
+    };
+
+    Instruction::new(address, 2, cycles, mnemonic, operands)
+}
+
+// The 11 unused opcodes lock up the CPU; show them as data
+fn invalid(address: u16, opcode: u8) -> Instruction {
+    Instruction::new(address, 1, 4, "db", vec![Operand::Byte(opcode)])
+}
+
+// hardware.inc names for the IO registers, with the CGB ones if asked
+pub fn io_register_name(address: u16, cgb: bool) -> Option<&'static str> {
+    let name = match address as usize {
+        KEY_ADDRESS => "rP1",
+        0xFF01 => "rSB",
+        0xFF02 => "rSC",
+        DIVIDER_REGISTER => "rDIV",
+        TIMER_ADDRESS => "rTIMA",
+        TIMER_MODULATOR => "rTMA",
+        TIMER_CONTROLLER => "rTAC",
+        INTERRUPT_REQUEST => "rIF",
+        0xFF10 => "rNR10",
+        0xFF11 => "rNR11",
+        0xFF12 => "rNR12",
+        0xFF13 => "rNR13",
+        0xFF14 => "rNR14",
+        0xFF16 => "rNR21",
+        0xFF17 => "rNR22",
+        0xFF18 => "rNR23",
+        0xFF19 => "rNR24",
+        0xFF1A => "rNR30",
+        0xFF1B => "rNR31",
+        0xFF1C => "rNR32",
+        0xFF1D => "rNR33",
+        0xFF1E => "rNR34",
+        0xFF20 => "rNR41",
+        0xFF21 => "rNR42",
+        0xFF22 => "rNR43",
+        0xFF23 => "rNR44",
+        0xFF24 => "rNR50",
+        0xFF25 => "rNR51",
+        0xFF26 => "rNR52",
+        LCD_CONTROL_ADDRESS => "rLCDC",
+        LCD_STATUS_ADDRESS => "rSTAT",
+        SCROLL_Y_ADDRESS => "rSCY",
+        SCROLL_X_ADDRESS => "rSCX",
+        SCANLINE_ADDRESS => "rLY",
+        0xFF45 => "rLYC",
+        DMA_ADDRESS => "rDMA",
+        PALETTE_47_ADDRESS => "rBGP",
+        PALETTE_48_ADDRESS => "rOBP0",
+        PALETTE_49_ADDRESS => "rOBP1",
+        WINDOW_Y_ADDRESS => "rWY",
+        WINDOW_X_ADDRESS => "rWX",
+        SPEED_SWITCH if cgb => "rKEY1",
+        VRAM_BANK if cgb => "rVBK",
+        HDMA_START if cgb => "rHDMA1",
+        0xFF52 if cgb => "rHDMA2",
+        0xFF53 if cgb => "rHDMA3",
+        0xFF54 if cgb => "rHDMA4",
+        HDMA_END if cgb => "rHDMA5",
+        BCPS if cgb => "rBCPS",
+        BCPD if cgb => "rBCPD",
+        OCPS if cgb => "rOCPS",
+        OCPD if cgb => "rOCPD",
+        WRAM_BANK if cgb => "rSVBK",
+        INTERRUPT_ENABLED => "rIE",
+        _ => return None
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{decode, io_register_name};
+
+    fn disassemble(bytes: &[u8]) -> (String, u16, u8) {
+        let instruction = decode(|address| bytes.get(address as usize).cloned().unwrap_or(0), 0);
+        (instruction.to_string(), instruction.length, instruction.cycles)
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(disassemble(&[0x00]), ("NOP".to_string(), 1, 4));
+        assert_eq!(disassemble(&[0xE0, 0x40]), ("LDH [rLCDC], A".to_string(), 2, 12));
+        assert_eq!(disassemble(&[0xF0, 0x80]), ("LDH A, [$FF80]".to_string(), 2, 12));
+        assert_eq!(disassemble(&[0xEA, 0x00, 0xC0]), ("LD [$C000], A".to_string(), 3, 16));
+        assert_eq!(disassemble(&[0x3E, 0x3C]), ("LD A, $3C".to_string(), 2, 8));
+        assert_eq!(disassemble(&[0x22]), ("LD [HL+], A".to_string(), 1, 8));
+        assert_eq!(disassemble(&[0x34]), ("INC [HL]".to_string(), 1, 12));
+        assert_eq!(disassemble(&[0x7E]), ("LD A, [HL]".to_string(), 1, 8));
+        assert_eq!(disassemble(&[0x76]), ("HALT".to_string(), 1, 4));
+        assert_eq!(disassemble(&[0x8E]), ("ADC A, [HL]".to_string(), 1, 8));
+        assert_eq!(disassemble(&[0xFE, 0x90]), ("CP $90".to_string(), 2, 8));
+        assert_eq!(disassemble(&[0xE8, 0xFE]), ("ADD SP, -2".to_string(), 2, 16));
+        assert_eq!(disassemble(&[0xF8, 0x02]), ("LD HL, SP+2".to_string(), 2, 12));
+        assert_eq!(disassemble(&[0xF5]), ("PUSH AF".to_string(), 1, 16));
+        assert_eq!(disassemble(&[0xFF]), ("RST $38".to_string(), 1, 16));
+        assert_eq!(disassemble(&[0xD3]), ("db $D3".to_string(), 1, 4));
+    }
+
+    #[test]
+    fn branches() {
+        let jr = decode(|address| [0x20, 0xFE][address as usize - 0x150], 0x150);
+        assert_eq!(jr.to_string(), "JR NZ, $0150");
+        assert_eq!((jr.cycles, jr.taken_cycles), (8, Some(12)));
+        assert_eq!(jr.target(), Some(0x150));
+
+        let call = decode(|address| [0xCD, 0x00, 0x20][address as usize], 0);
+        assert_eq!(call.to_string(), "CALL $2000");
+        assert_eq!(call.format_with(false, |address| if address == 0x2000 { Some("Main".to_string()) } else { None }), "CALL Main");
+    }
+
+    #[test]
+    fn cgb_registers() {
+        assert_eq!(io_register_name(0xFF4F, true), Some("rVBK"));
+        assert_eq!(io_register_name(0xFF4F, false), None);
+        assert_eq!(io_register_name(0xFF40, true), Some("rLCDC"));
+
+        let svbk = decode(|address| [0xE0, 0x70][address as usize], 0);
+        assert_eq!(svbk.format_with(true, |_| None), "LDH [rSVBK], A");
+        assert_eq!(svbk.to_string(), "LDH [$FF70], A");
+    }
+
+    #[test]
+    fn cb_prefixed() {
+        assert_eq!(disassemble(&[0xCB, 0x37]), ("SWAP A".to_string(), 2, 8));
+        assert_eq!(disassemble(&[0xCB, 0x7E]), ("BIT 7, [HL]".to_string(), 2, 12));
+        assert_eq!(disassemble(&[0xCB, 0xC6]), ("SET 0, [HL]".to_string(), 2, 16));
+        assert_eq!(disassemble(&[0xCB, 0x11]), ("RL C".to_string(), 2, 8));
+    }
+}
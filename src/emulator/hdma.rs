@@ -0,0 +1,166 @@
+// CGB VRAM DMA. HDMA1-2 (0xFF51-0xFF52) are the source, HDMA3-4 the
+// destination in VRAM and HDMA5 (0xFF55) the length in 16 byte blocks and
+// the mode: copy everything now, or a block every HBlank.
+
+use super::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
+pub const HDMA_START: usize = 0xFF51;
+pub const HDMA_END: usize = 0xFF55;
+
+pub const BLOCK_SIZE: u16 = 0x10;
+
+pub struct Hdma {
+    source: u16,
+    destination: u16,
+    // Blocks left minus one, as HDMA5 reads. 0x7F once finished.
+    length: u8,
+    // Copying a block every HBlank
+    hblank: bool,
+    // Blocks to copy before the CPU runs again
+    pending: u32,
+}
+
+impl Hdma {
+    pub fn new() -> Self {
+        Hdma {
+            source: 0,
+            destination: 0,
+            length: 0x7F,
+            hblank: false,
+            pending: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Hdma::new();
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        match address {
+            // Bit 7 clear while an HBlank transfer is still going
+            HDMA_END => if self.hblank { self.length } else { 0x80 | self.length },
+            _ => 0xFF
+        }
+    }
+
+    pub fn write(&mut self, address: usize, data: u8) {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | (u16::from(data) << 8),
+            // The low four bits of both addresses are ignored
+            0xFF52 => self.source = (self.source & 0xFF00) | u16::from(data & 0xF0),
+            0xFF53 => self.destination = (self.destination & 0x00FF) | (u16::from(data & 0x1F) << 8),
+            0xFF54 => self.destination = (self.destination & 0xFF00) | u16::from(data & 0xF0),
+            HDMA_END => {
+                // Writing with bit 7 clear during an HBlank transfer stops it
+                if self.hblank && !tbit!(data, 7) {
+                    self.hblank = false;
+                    return;
+                }
+
+                self.length = data & 0x7F;
+                if tbit!(data, 7) {
+                    self.hblank = true;
+                } else {
+                    self.pending = u32::from(self.length) + 1;
+                }
+            },
+            _ => ()
+        }
+    }
+
+    pub fn hblank_active(&self) -> bool {
+        self.hblank
+    }
+
+    // Called at the start of every HBlank
+    pub fn hblank(&mut self) {
+        if self.hblank {
+            self.pending += 1;
+        }
+    }
+
+    // Source and VRAM destination of the next block due, if any
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if self.pending == 0 {
+            return None;
+        }
+        self.pending -= 1;
+
+        let block = (self.source, 0x8000 | self.destination);
+        self.source = self.source.wrapping_add(BLOCK_SIZE);
+        self.destination = (self.destination + BLOCK_SIZE) & 0x1FF0;
+
+        if self.length == 0 {
+            self.length = 0x7F;
+            self.hblank = false;
+            self.pending = 0;
+        } else {
+            self.length -= 1;
+        }
+        Some(block)
+    }
+}
+
+impl Savestate for Hdma {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.source);
+        writer.write_u16(self.destination);
+        writer.write_u8(self.length);
+        writer.write_bool(self.hblank);
+        writer.write_u32(self.pending);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.source = reader.read_u16()?;
+        self.destination = reader.read_u16()? & 0x1FF0;
+        self.length = reader.read_u8()? & 0x7F;
+        self.hblank = reader.read_bool()?;
+        self.pending = reader.read_u32()?.min(0x80);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{Hdma, HDMA_END};
+
+    fn started(hdma5: u8) -> Hdma {
+        let mut hdma = Hdma::new();
+        hdma.write(0xFF51, 0xC1);
+        hdma.write(0xFF52, 0x2F);
+        hdma.write(0xFF53, 0xF8);
+        hdma.write(0xFF54, 0x1F);
+        hdma.write(HDMA_END, hdma5);
+        hdma
+    }
+
+    #[test]
+    fn general_purpose_copies_everything() {
+        let mut hdma = started(0x01);
+        assert_eq!(hdma.next_block(), Some((0xC120, 0x9810)));
+        assert_eq!(hdma.next_block(), Some((0xC130, 0x9820)));
+        assert_eq!(hdma.next_block(), None);
+        assert_eq!(hdma.read(HDMA_END), 0xFF);
+    }
+
+    #[test]
+    fn hblank_copies_a_block_at_a_time() {
+        let mut hdma = started(0x82);
+        assert_eq!(hdma.next_block(), None);
+        assert_eq!(hdma.read(HDMA_END), 0x02);
+
+        hdma.hblank();
+        assert_eq!(hdma.next_block(), Some((0xC120, 0x9810)));
+        assert_eq!(hdma.next_block(), None);
+        assert_eq!(hdma.read(HDMA_END), 0x01);
+
+        // Stopping keeps the length, with bit 7 set
+        hdma.write(HDMA_END, 0x00);
+        hdma.hblank();
+        assert_eq!(hdma.next_block(), None);
+        assert_eq!(hdma.read(HDMA_END), 0x81);
+    }
+}
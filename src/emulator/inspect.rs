@@ -0,0 +1,222 @@
+// Bank-aware access to the whole address space for the memory viewer, plus
+// cheat-style freezing. Nothing here trips watchpoints.
+
+use super::memory::MAX_RAMBANK;
+use super::state::invalid_state;
+use super::Emulator;
+
+use std::io;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+
+// The areas of the memory map, named as in hardware.inc
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Region {
+    Rom0,
+    RomX,
+    Vram,
+    Sram,
+    Wram,
+    Echo,
+    Oam,
+    Unusable,
+    Io,
+    Hram,
+    InterruptEnable,
+}
+
+impl Region {
+    pub fn of(address: u16) -> Self {
+        match address {
+            0x0000..=0x3FFF => Region::Rom0,
+            0x4000..=0x7FFF => Region::RomX,
+            0x8000..=0x9FFF => Region::Vram,
+            0xA000..=0xBFFF => Region::Sram,
+            0xC000..=0xDFFF => Region::Wram,
+            0xE000..=0xFDFF => Region::Echo,
+            0xFE00..=0xFE9F => Region::Oam,
+            0xFEA0..=0xFEFF => Region::Unusable,
+            0xFF00..=0xFF7F => Region::Io,
+            0xFF80..=0xFFFE => Region::Hram,
+            0xFFFF => Region::InterruptEnable,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Region::Rom0 => "ROM0",
+            Region::RomX => "ROMX",
+            Region::Vram => "VRAM",
+            Region::Sram => "SRAM",
+            Region::Wram => "WRAM",
+            Region::Echo => "ECHO",
+            Region::Oam => "OAM",
+            Region::Unusable => "----",
+            Region::Io => "IO",
+            Region::Hram => "HRAM",
+            Region::InterruptEnable => "IE",
+        }
+    }
+
+    // Only switchable ROM and external RAM have banks to pick from
+    pub fn is_banked(self) -> bool {
+        matches!(self, Region::RomX | Region::Sram)
+    }
+}
+
+// An address pinned to a value at the start of every frame
+#[derive(Copy, Clone, Debug)]
+pub struct Freeze {
+    pub address: u16,
+    // For external RAM, the bank to pin no matter which is mapped
+    pub bank: Option<usize>,
+    pub value: u8,
+}
+
+// A search pattern byte; None matches anything
+pub type PatternByte = Option<u8>;
+
+impl Emulator {
+    // Reads any bank of switchable ROM or external RAM, or whatever is
+    // mapped when bank is None
+    pub fn inspect(&self, address: u16, bank: Option<usize>) -> u8 {
+        match (Region::of(address), bank) {
+            (Region::RomX, Some(bank)) => {
+                self.memory.read_rom_bank(bank, address as usize - 0x4000)
+            },
+            (Region::Sram, Some(bank)) => {
+                self.memory.read_ram_bank(bank % MAX_RAMBANK, address as usize - 0xA000)
+            },
+            _ => self.peek_memory(address as usize)
+        }
+    }
+
+    // Writes as the game would, except external RAM can be written whether
+    // or not it's enabled. ROM is read only.
+    pub fn edit(&mut self, address: u16, bank: Option<usize>, data: u8) -> Result<(), io::Error> {
+        match Region::of(address) {
+            // A write here would switch banks instead
+            Region::Rom0 | Region::RomX => Err(invalid_state("ROM can't be edited")),
+            Region::Sram => {
+                let bank = bank.unwrap_or_else(|| self.memory.ram_bank()) % MAX_RAMBANK;
+                self.memory.write_ram_bank(bank, address as usize - 0xA000, data);
+                Ok(())
+            },
+            _ => {
+                self.poke_memory(address as usize, data);
+                Ok(())
+            }
+        }
+    }
+
+    // Every place the pattern appears: mapped memory, every ROM bank and
+    // every external RAM bank. Echo RAM is skipped since it's WRAM again.
+    pub fn search(&self, pattern: &[PatternByte]) -> Vec<(u16, Option<usize>)> {
+        let rom_banks = self.memory.cartridge().size() / ROM_BANK_SIZE;
+
+        let mut areas = vec![(0x0000, 0x3FFF, None)];
+        areas.extend((1..rom_banks).map(|bank| (0x4000, 0x7FFF, Some(bank))));
+        areas.push((0x8000, 0x9FFF, None));
+        areas.extend((0..MAX_RAMBANK).map(|bank| (0xA000, 0xBFFF, Some(bank))));
+        areas.extend_from_slice(&[(0xC000, 0xDFFF, None), (0xFE00, 0xFE9F, None), (0xFF00, 0xFFFF, None)]);
+
+        let mut found = Vec::new();
+        if pattern.is_empty() {
+            return found;
+        }
+
+        for (start, end, bank) in areas {
+            let bytes: Vec<u8> = (start..=end).map(|address| self.inspect(address, bank)).collect();
+            for (offset, window) in bytes.windows(pattern.len()).enumerate() {
+                let matches = window.iter().zip(pattern).all(|(byte, wanted)| wanted.is_none_or(|wanted| wanted == *byte));
+                if matches {
+                    found.push((start + offset as u16, bank));
+                }
+            }
+        }
+        found
+    }
+
+    pub fn add_freeze(&mut self, freeze: Freeze) -> Result<(), io::Error> {
+        self.edit(freeze.address, freeze.bank, freeze.value)?;
+        self.freezes.push(freeze);
+        Ok(())
+    }
+
+    pub fn remove_freeze(&mut self, index: usize) -> Option<Freeze> {
+        if index < self.freezes.len() {
+            Some(self.freezes.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn freezes(&self) -> &[Freeze] {
+        &self.freezes
+    }
+
+    pub(super) fn apply_freezes(&mut self) {
+        for index in 0..self.freezes.len() {
+            let freeze = self.freezes[index];
+            // Only addresses that can be edited are ever added
+            let _ = self.edit(freeze.address, freeze.bank, freeze.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::Emulator;
+    use super::{Freeze, Region};
+
+    fn emulator() -> Emulator {
+        let mut rom = vec![0; 0x10000];
+        // MBC1 with 64KB of ROM, and a marker in bank 3
+        rom[0x147] = 0x03;
+        rom[0x148] = 0x01;
+        rom[0xC000] = 0xAB;
+        rom[0xC001] = 0xCD;
+        Emulator::from_bytes(&rom)
+    }
+
+    #[test]
+    fn banked_reads_and_edits() {
+        let mut emulator = emulator();
+        assert_eq!(emulator.inspect(0x4000, Some(3)), 0xAB);
+
+        emulator.edit(0xA010, Some(2), 0x42).unwrap();
+        assert_eq!(emulator.inspect(0xA010, Some(2)), 0x42);
+        assert_eq!(emulator.inspect(0xA010, Some(0)), 0);
+        assert!(emulator.edit(0x0100, None, 0).is_err());
+
+        assert_eq!(Region::of(0xFF40), Region::Io);
+        assert!(Region::of(0xA000).is_banked());
+    }
+
+    #[test]
+    fn search_finds_patterns_in_any_bank() {
+        let mut emulator = emulator();
+        emulator.edit(0xC100, None, 0xAB).unwrap();
+        emulator.edit(0xC101, None, 0x99).unwrap();
+
+        assert_eq!(emulator.search(&[Some(0xAB), Some(0xCD)]), vec![(0x4000, Some(3))]);
+        assert_eq!(emulator.search(&[Some(0xAB), None]), vec![(0x4000, Some(3)), (0xC100, None)]);
+    }
+
+    #[test]
+    fn freezes_hold_every_frame() {
+        let mut emulator = emulator();
+        emulator.add_freeze(Freeze { address: 0xC000, bank: None, value: 0x63 }).unwrap();
+        emulator.edit(0xC000, None, 0).unwrap();
+
+        emulator.update();
+        emulator.update();
+        assert_eq!(emulator.inspect(0xC000, None), 0x63);
+
+        emulator.remove_freeze(0).unwrap();
+        emulator.edit(0xC000, None, 0).unwrap();
+        emulator.update();
+        assert_eq!(emulator.inspect(0xC000, None), 0);
+    }
+}
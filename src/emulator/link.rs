@@ -0,0 +1,138 @@
+// A link cable between two emulators in the same process
+
+use super::serial::SerialDevice;
+use super::Emulator;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Default)]
+struct Cable {
+    // For each end, the byte in SB while it waits on the other's clock
+    waiting: [Option<u8>; 2],
+    // For each end, a byte clocked across to it that it hasn't seen yet
+    incoming: [Option<u8>; 2],
+}
+
+// One end of the cable
+pub struct LinkPort {
+    cable: Rc<RefCell<Cable>>,
+    side: usize,
+}
+
+// Both ends of a new cable
+pub fn link_cable() -> (LinkPort, LinkPort) {
+    let cable = Rc::new(RefCell::new(Cable::default()));
+    (LinkPort { cable: cable.clone(), side: 0 }, LinkPort { cable, side: 1 })
+}
+
+impl SerialDevice for LinkPort {
+    fn exchange(&mut self, data: u8) -> u8 {
+        let mut cable = self.cable.borrow_mut();
+        let other = 1 - self.side;
+        // Driving the clock means this end isn't waiting on the other's
+        cable.waiting[self.side] = None;
+
+        match cable.waiting[other].take() {
+            Some(theirs) => {
+                cable.incoming[other] = Some(data);
+                theirs
+            },
+            // Nobody listening, so the line floats high
+            None => 0xFF
+        }
+    }
+
+    fn poll(&mut self, data: u8) -> Option<u8> {
+        let mut cable = self.cable.borrow_mut();
+        match cable.incoming[self.side].take() {
+            Some(theirs) => Some(theirs),
+            None => {
+                cable.waiting[self.side] = Some(data);
+                None
+            }
+        }
+    }
+}
+
+// Two Game Boys with a cable between them, run in lockstep so a transfer
+// lands at the same point every time. Inputs, movies and so on are still
+// per emulator.
+pub struct LinkedPair {
+    pub left: Emulator,
+    pub right: Emulator,
+}
+
+impl LinkedPair {
+    pub fn new(mut left: Emulator, mut right: Emulator) -> Self {
+        let (left_port, right_port) = link_cable();
+        left.set_serial_device(Box::new(left_port));
+        right.set_serial_device(Box::new(right_port));
+        LinkedPair { left, right }
+    }
+
+    // Runs both for a frame. Whichever is behind goes next, so they're never
+    // more than an instruction apart.
+    pub fn update(&mut self) {
+        let mut done = (false, false);
+        while !(done.0 && done.1) {
+            let left_next = !done.0 && (done.1 || self.left.frame_cycles <= self.right.frame_cycles);
+            if left_next {
+                done.0 = self.left.step();
+            } else {
+                done.1 = self.right.step();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::test_roms::serial_rom;
+    use super::super::{Emulator, Inputs, Movie};
+    use super::LinkedPair;
+
+    fn linked() -> LinkedPair {
+        LinkedPair::new(Emulator::from_bytes(&serial_rom(true)), Emulator::from_bytes(&serial_rom(false)))
+    }
+
+    #[test]
+    fn bytes_cross_the_cable() {
+        let mut pair = linked();
+        for _ in 0..10 {
+            pair.update();
+        }
+
+        // Each side gets the other's count, which was the same
+        for address in 0xC000..0xC010 {
+            let expected = (address - 0xC000 + 1) as u8;
+            assert_eq!(pair.left.inspect(address, None), expected);
+            assert_eq!(pair.right.inspect(address, None), expected);
+        }
+    }
+
+    #[test]
+    fn movies_replay_exactly() {
+        let mut pair = linked();
+        pair.left.start_movie(true);
+        pair.right.start_movie(true);
+        for frame in 0..20 {
+            if frame == 7 {
+                pair.left.input_down(Inputs::A);
+            }
+            pair.update();
+        }
+        let expected = (pair.left.save_state(), pair.right.save_state());
+        let left_movie = pair.left.stop_movie().unwrap().to_bytes();
+        let right_movie = pair.right.stop_movie().unwrap().to_bytes();
+
+        let mut replay = linked();
+        replay.left.play_movie(Movie::from_bytes(&left_movie).unwrap()).unwrap();
+        replay.right.play_movie(Movie::from_bytes(&right_movie).unwrap()).unwrap();
+        for _ in 0..20 {
+            replay.update();
+        }
+        assert_eq!((replay.left.save_state(), replay.right.save_state()), expected);
+    }
+}
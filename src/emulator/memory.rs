@@ -1,4 +1,5 @@
 use super::cartridge::Cartridge;
+use super::state::{invalid_state, Savestate, StateReader, StateWriter};
 use std::io;
 
 use super::TIMER_ADDRESS;
@@ -6,11 +7,23 @@ use super::TIMER_CONTROLLER;
 use super::TIMER_MODULATOR;
 
 const RBM_ADDRESS: usize = 0x147;
+
+// CGB only registers
+pub const SPEED_SWITCH: usize = 0xFF4D;
+pub const VRAM_BANK: usize = 0xFF4F;
+pub const WRAM_BANK: usize = 0xFF70;
 const MEMORY_SIZE: usize = 0x10000;
 
 const ROM_BANK_SIZE: usize = 0x4000;
 const RAM_BANK_SIZE: usize = 0x2000;
-const MAX_RAMBANK: usize = 4;
+pub const MAX_RAMBANK: usize = 4;
+// Enough to cover a 2MB cartridge
+const MAX_ROMBANK: usize = 128;
+
+const VRAM_BANK_SIZE: usize = 0x2000;
+const VRAM_BANKS: usize = 2;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
 
 pub enum RomBankMode {
     No,
@@ -29,12 +42,33 @@ pub struct Memory {
 
     enable_ram: bool,
     enable_rom: bool,
+
+    // Running in Game Boy Color mode, picked from the cartridge header
+    cgb: bool,
+    // VRAM and WRAM live apart from the rest since CGB banks them. On DMG
+    // only VRAM bank 0 and WRAM banks 0 and 1 are ever mapped.
+    vram: Box<[u8; VRAM_BANKS * VRAM_BANK_SIZE]>,
+    vram_bank: usize,
+    wram: Box<[u8; WRAM_BANKS * WRAM_BANK_SIZE]>,
+    // As written to SVBK, where 0 maps bank 1
+    wram_bank: usize,
+
+    double_speed: bool,
+    // Set through KEY1, the next STOP switches speed
+    speed_switch_armed: bool,
 }
 
 impl Memory {
     pub fn from_file(filename: &str) -> Result<Self, io::Error> {
-        let cart = Cartridge::from_file(filename)?;
+        Ok(Memory::new(Cartridge::from_file(filename)?))
+    }
+
+    #[cfg(test)]
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Memory::new(Cartridge::from_bytes(bytes))
+    }
 
+    fn new(cart: Cartridge) -> Self {
         let rbm_byte = cart.read(RBM_ADDRESS);
         let rbm = match rbm_byte {
             0 => RomBankMode::No,
@@ -43,43 +77,125 @@ impl Memory {
             _ => panic!("Unknown ROM Bank Mode Byte {}!", rbm_byte),
         };
 
+        let cgb = cart.supports_cgb();
         let mut mem = Memory { 
             rom: Box::new([0; MEMORY_SIZE]),
-            cart: cart,
+            cart,
             rom_bank_mode: rbm,
-            current_rom_bank: 0,
+            current_rom_bank: 1,
             ram_banks: Box::new([0; MAX_RAMBANK * RAM_BANK_SIZE]),
             current_ram_bank: 0,
             enable_ram: false,
             enable_rom: false,
+            cgb,
+            vram: Box::new([0; VRAM_BANKS * VRAM_BANK_SIZE]),
+            vram_bank: 0,
+            wram: Box::new([0; WRAM_BANKS * WRAM_BANK_SIZE]),
+            wram_bank: 0,
+            double_speed: false,
+            speed_switch_armed: false,
         };
 
         mem.init();
-        Ok(mem)
+        mem
+    }
+
+    // Back to the power on state, as if the cartridge was just inserted
+    pub fn reset(&mut self) {
+        *self.rom = [0; MEMORY_SIZE];
+        self.current_rom_bank = 1;
+        *self.ram_banks = [0; MAX_RAMBANK * RAM_BANK_SIZE];
+        self.current_ram_bank = 0;
+        self.enable_ram = false;
+        self.enable_rom = false;
+        *self.vram = [0; VRAM_BANKS * VRAM_BANK_SIZE];
+        self.vram_bank = 0;
+        *self.wram = [0; WRAM_BANKS * WRAM_BANK_SIZE];
+        self.wram_bank = 0;
+        self.double_speed = false;
+        self.speed_switch_armed = false;
+        self.init();
+    }
+
+    pub fn cgb(&self) -> bool {
+        self.cgb
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // What STOP does with a switch armed. Returns true if the speed changed.
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cart
+    }
+
+    pub fn rom_bank(&self) -> usize {
+        self.current_rom_bank
+    }
+
+    pub fn ram_bank(&self) -> usize {
+        self.current_ram_bank
+    }
+
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank
+    }
+
+    // The bank at 0xD000-0xDFFF, where 0 maps bank 1
+    pub fn wram_bank(&self) -> usize {
+        self.wram_bank.max(1)
+    }
+
+    pub fn ram_enabled(&self) -> bool {
+        self.enable_ram
+    }
+
+    // Any ROM bank, whatever is mapped, by offset into the bank
+    pub fn read_rom_bank(&self, bank: usize, offset: usize) -> u8 {
+        self.cart.read(bank * ROM_BANK_SIZE + offset)
+    }
+
+    // External RAM directly, even while it's disabled or another bank is mapped
+    pub fn read_ram_bank(&self, bank: usize, offset: usize) -> u8 {
+        self.ram_banks[bank * RAM_BANK_SIZE + offset]
+    }
+
+    pub fn write_ram_bank(&mut self, bank: usize, offset: usize, data: u8) {
+        self.ram_banks[bank * RAM_BANK_SIZE + offset] = data;
+    }
+
+    // Either VRAM bank, whichever is mapped, for the renderer
+    pub fn read_vram(&self, bank: usize, address: usize) -> u8 {
+        self.vram[bank * VRAM_BANK_SIZE + (address - 0x8000)]
+    }
+
+    // Where an address in 0x8000-0x9FFF lands in the current VRAM bank
+    fn vram_offset(&self, address: usize) -> usize {
+        self.vram_bank * VRAM_BANK_SIZE + (address - 0x8000)
+    }
+
+    // Where an address in 0xC000-0xDFFF lands, with 0xD000 on switchable
+    fn wram_offset(&self, address: usize) -> usize {
+        match address {
+            0xC000..=0xCFFF => address - 0xC000,
+            _ => self.wram_bank.max(1) * WRAM_BANK_SIZE + (address - 0xD000)
+        }
     }
 
     fn init(&mut self) {
         self.rom[0xFF05] = 0x00;
         self.rom[0xFF06] = 0x00;
         self.rom[0xFF07] = 0x00;
-        self.rom[0xFF10] = 0x80;
-        self.rom[0xFF11] = 0xBF;
-        self.rom[0xFF12] = 0xF3;
-        self.rom[0xFF14] = 0xBF;
-        self.rom[0xFF16] = 0x3F;
-        self.rom[0xFF17] = 0x00;
-        self.rom[0xFF19] = 0xBF;
-        self.rom[0xFF1A] = 0x7F;
-        self.rom[0xFF1B] = 0xFF;
-        self.rom[0xFF1C] = 0x9F;
-        self.rom[0xFF1E] = 0xBF;
-        self.rom[0xFF20] = 0xFF;
-        self.rom[0xFF21] = 0x00;
-        self.rom[0xFF22] = 0x00;
-        self.rom[0xFF23] = 0xBF;
-        self.rom[0xFF24] = 0x77;
-        self.rom[0xFF25] = 0xF3;
-        self.rom[0xFF26] = 0xF1;
         self.rom[0xFF40] = 0x91;
         self.rom[0xFF42] = 0x00;
         self.rom[0xFF43] = 0x00;
@@ -100,15 +216,22 @@ impl Memory {
             0x0000..=0x7FFF => { 
                 self.handle_banking(address, data);
             },
+            0x8000..=0x9FFF => {
+                let offset = self.vram_offset(address);
+                self.vram[offset] = data;
+            },
             0xA000..=0xBFFF => {
                 if self.enable_ram {
                     let translated = (address - 0xA000) + (self.current_ram_bank * RAM_BANK_SIZE);
                     self.ram_banks[translated] = data;
                 }
             }
+            0xC000..=0xDFFF => {
+                let offset = self.wram_offset(address);
+                self.wram[offset] = data;
+            },
             0xE000..=0xFDFF => {
-                // Echo memory; write to this address and 0x2000 addresses back
-                self.rom[address] = data;
+                // Echo memory, the same as 0x2000 addresses back
                 self.write(address - 0x2000, data);
             },
             0xFEA0..=0xFEFE => { 
@@ -118,10 +241,23 @@ impl Memory {
                 // Divider register - if written, set to 0
                 self.rom[address] = 0;
             },
+            0xFF41 => {
+                // The mode and coincidence bits are read only
+                self.rom[address] = (data & 0b11111000) | (self.rom[address] & 0b00000111);
+            },
             0xFF44 => {
                 // Scanline counter - if written, set to 0
                 self.rom[address] = 0;
             },
+            SPEED_SWITCH if self.cgb => {
+                self.speed_switch_armed = tbit!(data, 0);
+            },
+            VRAM_BANK if self.cgb => {
+                self.vram_bank = (data & 1) as usize;
+            },
+            WRAM_BANK if self.cgb => {
+                self.wram_bank = (data & 0b111) as usize;
+            },
             _ => { 
                 self.rom[address] = data; 
             }
@@ -252,17 +388,65 @@ impl Memory {
             MEMORY_SIZE..=std::usize::MAX => {
                 panic!("Attempting to read address {} which is out of range!", address);
             },
+            0x0000..=0x3FFF => self.cart.read(address),
             0x4000..=0x7FFF => {
                 // Reading from ROM bank
                 let translated = (address - 0x4000) + (self.current_rom_bank * ROM_BANK_SIZE);
                 self.cart.read(translated)
             },
+            0x8000..=0x9FFF => self.vram[self.vram_offset(address)],
             0xA000..=0xBFFF => {
                 // Reading from RAM bank
                 let translated = (address - 0xA000) + (self.current_ram_bank * RAM_BANK_SIZE);
                 self.ram_banks[translated]
             },
+            0xC000..=0xDFFF => self.wram[self.wram_offset(address)],
+            0xE000..=0xFDFF => self.read(address - 0x2000),
+            // Unused bits read as 1
+            SPEED_SWITCH if self.cgb => {
+                let speed = if self.double_speed { 0x80 } else { 0 };
+                0x7E | speed | self.speed_switch_armed as u8
+            },
+            VRAM_BANK if self.cgb => 0xFE | self.vram_bank as u8,
+            WRAM_BANK if self.cgb => 0xF8 | self.wram_bank as u8,
             _ => self.rom[address]
         }
     }
 }
+
+// The cartridge ROM itself isn't saved; states only load on top of the same game
+impl Savestate for Memory {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.rom[..]);
+        writer.write_bytes(&self.ram_banks[..]);
+        writer.write_u32(self.current_rom_bank as u32);
+        writer.write_u32(self.current_ram_bank as u32);
+        writer.write_bool(self.enable_ram);
+        writer.write_bool(self.enable_rom);
+        writer.write_bytes(&self.vram[..]);
+        writer.write_u8(self.vram_bank as u8);
+        writer.write_bytes(&self.wram[..]);
+        writer.write_u8(self.wram_bank as u8);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_armed);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        reader.read_bytes(&mut self.rom[..])?;
+        reader.read_bytes(&mut self.ram_banks[..])?;
+        self.current_rom_bank = reader.read_u32()? as usize;
+        if self.current_rom_bank >= MAX_ROMBANK {
+            return Err(invalid_state("ROM bank out of range"));
+        }
+        self.current_ram_bank = reader.read_u32()? as usize % MAX_RAMBANK;
+        self.enable_ram = reader.read_bool()?;
+        self.enable_rom = reader.read_bool()?;
+        reader.read_bytes(&mut self.vram[..])?;
+        self.vram_bank = reader.read_u8()? as usize % VRAM_BANKS;
+        reader.read_bytes(&mut self.wram[..])?;
+        self.wram_bank = reader.read_u8()? as usize % WRAM_BANKS;
+        self.double_speed = reader.read_bool()?;
+        self.speed_switch_armed = reader.read_bool()?;
+        Ok(())
+    }
+}
@@ -1,21 +1,75 @@
+mod apu;
 mod cartridge;
+mod colors;
 mod cpu;
+mod crc;
+mod debugger;
+mod hdma;
+mod inspect;
+mod link;
+pub mod disasm;
 mod memory;
+mod movie;
+mod palettes;
+mod png;
 mod registers;
-
+mod rewind;
+mod serial;
+mod sgb;
+mod state;
+mod symbols;
+#[cfg(test)]
+pub mod test_roms;
+mod trace;
+mod vram;
+mod watch;
+mod wav;
+
+use apu::{Apu, DEFAULT_SAMPLE_RATE};
+pub use apu::{Channel, ChannelControl, Panning, ALL_CHANNELS};
 use cartridge::Cartridge;
-use cpu::Cpu;
+pub use colors::ColorScheme;
+use cpu::{Bus, Cpu};
 use memory::{Memory, RomBankMode};
-
+pub use movie::{Movie, MovieStart};
+use palettes::{ColorPalettes, BCPS, OCPD};
+pub use png::Image;
+pub use crc::crc32;
+pub use debugger::{Debugger, StopReason};
+use hdma::{Hdma, BLOCK_SIZE, HDMA_END, HDMA_START};
+pub use inspect::{Freeze, PatternByte, Region};
+pub use link::LinkedPair;
+pub use watch::{Access, Condition, WatchHit, Watchpoint};
+pub use registers::Flags;
+use rewind::RewindBuffer;
+pub use rewind::RewindConfig;
+use serial::{Serial, SERIAL_CONTROL, SERIAL_DATA};
+pub use serial::{CaptureDevice, NullDevice, SerialDevice};
+use sgb::Sgb;
+pub use sgb::{SGB_HEIGHT, SGB_WIDTH};
+pub use symbols::Symbols;
+pub use trace::{TraceFilter, Tracer};
+pub use vram::{Palette, Sprite, ALL_PALETTES, TILE_MAP_0, TILE_MAP_1};
+use state::{invalid_state, Savestate, StateReader, StateWriter};
+use wav::WavWriter;
+
+use std::cell::Cell;
 use std::io;
 use std::time::{Duration, SystemTime};
 
+pub const CLOCK_SPEED: u32 = 4_194_304;
+// 154 scanlines of 456 cycles, giving a refresh rate of about 59.73 Hz
+pub const CYCLES_PER_FRAME: u32 = 70224;
+
 const TIMER_ADDRESS: usize = 0xFF05;
 const TIMER_MODULATOR: usize = 0xFF06;
 const TIMER_CONTROLLER: usize = 0xFF07;
 
 const DIVIDER_REGISTER: usize = 0xFF04;
 
+const SOUND_START_ADDRESS: usize = 0xFF10;
+const SOUND_END_ADDRESS: usize = 0xFF3F;
+
 const INTERRUPT_REQUEST: usize = 0xFF0F;
 const INTERRUPT_ENABLED: usize = 0xFFFF;
 
@@ -23,8 +77,16 @@ const SCANLINE_ADDRESS: usize = 0xFF44;
 const LCD_STATUS_ADDRESS: usize = 0xFF41;
 const LCD_CONTROL_ADDRESS: usize = 0xFF40;
 
-const LCD_MODE2_BOUND: u16 = 376; // 456 scanlines - 80 cycles
-const LCD_MODE3_BOUND: u16 = 204; // ^ 376 - 172 cycles
+const LCD_MODE2_BOUND: i32 = 376; // 456 scanlines - 80 cycles
+const LCD_MODE3_BOUND: i32 = 204; // ^ 376 - 172 cycles
+
+const CYCLES_PER_LINE: u32 = 456;
+// HBlank starts after the 80 cycles of mode 2 and 172 of mode 3
+const HBLANK_START: u32 = 80 + 172;
+const VISIBLE_LINES: u32 = 144;
+
+// Each 16 byte block of HDMA holds the CPU up this long, at either speed
+const CYCLES_PER_HDMA_BLOCK: u32 = 32;
 
 const DMA_ADDRESS: usize = 0xFF46;
 
@@ -43,11 +105,16 @@ const SPRITE_DATA_ADDRESS: usize = 0x8000;
 
 const KEY_ADDRESS: usize = 0xFF00;
 
+const STATE_MAGIC: &[u8; 4] = b"CBSS";
+// Bump whenever anything changes what goes into a save state
+const STATE_VERSION: u32 = 8;
+
 #[derive(Copy, Clone)]
 enum Interrupt {
     VBlank = 0b00000001,
     LCD    = 0b00000010,
     Timer  = 0b00000100,
+    Serial = 0b00001000,
     Joypad = 0b00010000,
 }
 
@@ -60,16 +127,45 @@ enum Color {
 
 impl Color {
 
-    fn rgb(&self) -> (u8, u8, u8) {
-        let value = match self {
-            Color::White => 0xFF,
-            Color::LightGrey => 0xCC,
-            Color::DarkGrey => 0x77,
-            Color::Black => 0x00
-        };
-        (value, value, value)
+    // Which of a colour scheme's four shades this is
+    fn shade(&self) -> usize {
+        match self {
+            Color::White => 0,
+            Color::LightGrey => 1,
+            Color::DarkGrey => 2,
+            Color::Black => 3
+        }
+    }
+
+}
+
+// Looks a colour number up in a BGP/OBP0/OBP1 style palette
+fn palette_color(palette: u8, color_num: u8) -> Color {
+    let (hi, lo) = match color_num {
+        0 => (1, 0),
+        1 => (3, 2),
+        2 => (5, 4),
+        3 => (7, 6),
+        _ => panic!("Unknown color number {}!", color_num)
+    };
+
+    let color = (gbit!(palette, hi) << 1) | gbit!(palette, lo);
+
+    match color {
+        0 => Color::White,
+        1 => Color::LightGrey,
+        2 => Color::DarkGrey,
+        3 => Color::Black,
+        _ => unreachable!()
     }
+}
 
+// What the background left at a pixel, for sprites to check against
+#[derive(Copy, Clone, Default)]
+struct BackgroundPixel {
+    color: u8,
+    // CGB attribute bit 7: the tile goes over sprites
+    priority: bool,
 }
 
 bitflags! {
@@ -115,56 +211,666 @@ impl Inputs {
 
 }
 
-const ALL_INTERRUPTS: [Interrupt; 4] = [Interrupt::VBlank, Interrupt::LCD, Interrupt::Timer, Interrupt::Joypad];
-
 pub struct Emulator {
     cpu: Cpu,
     memory: Memory,
+    apu: Apu,
+    serial: Serial,
+    hdma: Hdma,
+    palettes: ColorPalettes,
+    sgb: Sgb,
+
+    // What the DMG shades look like. A frontend setting, so it survives
+    // resets and isn't saved.
+    colors: ColorScheme,
+    // DMG games run on a CGB, which changes what the boot ROM leaves behind
+    cgb_hardware: bool,
+    // Or on a Super Game Boy, which colours and frames them
+    sgb_hardware: bool,
 
     timer_counter: i32,
     divider_counter: i32,
 
-    interrupt_master: bool,
+    // Cycles run so far in the current frame
+    frame_cycles: u32,
 
-    scanline_count: u16,
+    // Cycles left on the current line
+    scanline_count: i32,
 
     screen_buffer: [[[u8; 3]; 144]; 160],
 
     pressed_inputs: Inputs,
+
+    recording: Option<WavWriter>,
+
+    // Frames emulated since power on
+    frame: u64,
+    rewind: Option<RewindBuffer>,
+
+    movie_recording: Option<Movie>,
+    // Movie being played back and the next frame to play
+    movie_playback: Option<(Movie, usize)>,
+
+    watchpoints: Vec<Watchpoint>,
+    // First watchpoint hit since the debugger last looked. A Cell so reads
+    // can record hits too.
+    watch_hit: Cell<Option<WatchHit>>,
+    // Whether the last step dispatched an interrupt instead of running an
+    // instruction, so the debugger doesn't take it for a call
+    interrupted: bool,
+
+    tracer: Option<Tracer>,
+
+    // Addresses pinned to a value, rewritten at the start of every frame
+    freezes: Vec<Freeze>,
 }
 
 impl Emulator {
     pub fn from_file(filename: &str) -> Result<Self, io::Error> {
-        Ok(Emulator {
+        Ok(Emulator::new(Memory::from_file(filename)?))
+    }
+
+    #[cfg(test)]
+    pub fn from_bytes(rom: &[u8]) -> Self {
+        Emulator::new(Memory::from_bytes(rom))
+    }
+
+    fn new(memory: Memory) -> Self {
+        if memory.cgb() {
+            info!("Running in Game Boy Color mode");
+        }
+
+        let mut emulator = Emulator {
             cpu: Cpu::new(),
-            memory: Memory::from_file(filename)?,
+            memory,
+            apu: Apu::new(DEFAULT_SAMPLE_RATE),
+            serial: Serial::new(),
+            hdma: Hdma::new(),
+            palettes: ColorPalettes::new(),
+            sgb: Sgb::new(),
+            colors: ColorScheme::grey(),
+            cgb_hardware: false,
+            sgb_hardware: false,
             timer_counter: 0,
             divider_counter: 0,
-            interrupt_master: true,
-            scanline_count: 0,
+            frame_cycles: 0,
+            scanline_count: CYCLES_PER_LINE as i32,
             screen_buffer: [[[0; 3]; 144]; 160],
             pressed_inputs: Inputs::empty(),
-           })
+            recording: None,
+            frame: 0,
+            rewind: None,
+            movie_recording: None,
+            movie_playback: None,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            interrupted: false,
+            tracer: None,
+            freezes: Vec::new(),
+        };
+        emulator.init_cpu();
+        emulator
+    }
+
+    // The CGB boot ROM leaves 0x11 in A, which is how games tell it apart
+    fn init_cpu(&mut self) {
+        self.cpu = Cpu::new();
+        if self.memory.cgb() || self.cgb_hardware {
+            self.cpu.registers_mut().a = 0x11;
+        }
+    }
+
+    // Runs a DMG game the way a CGB would, in the colours its boot ROM picks
+    // for the title. Powers the machine off and on again.
+    pub fn use_cgb_hardware(&mut self) {
+        self.cgb_hardware = true;
+        self.colors = ColorScheme::for_cgb(self.memory.cartridge());
+        self.reset();
+    }
+
+    // Runs a DMG game on a Super Game Boy. Games that support it can send
+    // palettes, a border and ask for more joypads. Powers the machine off and
+    // on again.
+    pub fn use_sgb_hardware(&mut self) {
+        if !self.memory.cartridge().supports_sgb() {
+            warn!("The game doesn't support the Super Game Boy, it'll be in greys");
+        }
+        self.sgb_hardware = true;
+        self.reset();
+    }
+
+    // The last frame inside the SGB border, SGB_WIDTH by SGB_HEIGHT. None
+    // unless running on a Super Game Boy.
+    pub fn sgb_frame(&self) -> Option<Image> {
+        if self.sgb_hardware && !self.memory.cgb() {
+            Some(self.sgb.compose(&self.screenshot()))
+        } else {
+            None
+        }
+    }
+
+    pub fn color_scheme(&self) -> &ColorScheme {
+        &self.colors
+    }
+
+    // Only DMG games use it, CGB games bring their own colours
+    pub fn set_color_scheme(&mut self, scheme: ColorScheme) {
+        self.colors = scheme;
+    }
+
+    // Whether the cartridge put the machine in Game Boy Color mode
+    pub fn is_cgb(&self) -> bool {
+        self.memory.cgb()
+    }
+
+    // Whether frames come out in CGB colours, which CGB games and DMG games
+    // on CGB hardware both do
+    pub fn has_cgb_colors(&self) -> bool {
+        self.memory.cgb() || self.cgb_hardware
+    }
+
+    // Power cycles the machine. Frontend settings like the sample rate and
+    // mixer controls are kept.
+    pub fn reset(&mut self) {
+        self.memory.reset();
+        self.init_cpu();
+        self.apu.reset();
+        self.serial.reset();
+        self.hdma.reset();
+        self.palettes.reset();
+        self.sgb.reset();
+        self.timer_counter = 0;
+        self.divider_counter = 0;
+        self.frame_cycles = 0;
+        self.scanline_count = CYCLES_PER_LINE as i32;
+        self.screen_buffer = [[[0; 3]; 144]; 160];
+        self.pressed_inputs = Inputs::empty();
+        self.frame = 0;
+
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.clear();
+        }
     }
 
     pub fn update(&mut self) {
-        let mut elapsed_cycles = 0;
+        while !self.step() {}
+    }
+
+    // Runs a single instruction. Returns true if that finished the frame.
+    pub fn step(&mut self) -> bool {
+        if self.frame_cycles == 0 {
+            self.start_frame();
+        }
+
+        if self.tracer.is_some() && !self.cpu.halted() && !self.cpu.locked() {
+            self.trace_instruction();
+        }
+
+        let recorded_samples = self.apu.pending_samples().len();
+
+        // The CPU works on a copy so the rest of the machine can be its bus.
+        // Watchpoint hits still see the PC of the instruction running.
+        self.interrupted = false;
+        let mut cpu = self.cpu.clone();
+        let mut cycles = cpu.step(self);
+        if cpu.locked() && !self.cpu.locked() {
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.dump_ring("the CPU locked up");
+            }
+        }
+        self.cpu = cpu;
+        cycles += self.run_hdma();
+
+        // In double speed the CPU, timers and serial port run twice as fast
+        // as everything else
+        let elapsed = if self.memory.double_speed() { cycles / 2 } else { cycles };
+        let frame_cycles = self.frame_cycles;
+        self.frame_cycles += elapsed;
+        if self.hdma.hblank_active() && self.lcd_enabled() && entered_hblank(frame_cycles, self.frame_cycles) {
+            self.hdma.hblank();
+        }
+
+        self.update_timers(cycles as u16);
+        self.update_graphics(elapsed);
+        self.apu.step(elapsed);
+        if self.serial.step(cycles) {
+            self.request_interrupt(Interrupt::Serial);
+        }
+
+        if let Some(wav) = self.recording.as_mut() {
+            let samples = &self.apu.pending_samples()[recorded_samples..];
+            if let Err(e) = wav.write(samples) {
+                error!("Failed to record audio, stopping: {}", e);
+                self.recording = None;
+            }
+        }
+
+        if self.frame_cycles < CYCLES_PER_FRAME {
+            return false;
+        }
+
+        self.frame_cycles = 0;
+        self.frame += 1;
+        if self.sgb_hardware {
+            self.sgb.end_frame();
+        }
+        true
+    }
+
+    // Copies whatever HDMA blocks are due, returning how long the CPU was
+    // held up for
+    fn run_hdma(&mut self) -> u32 {
+        let mut blocks = 0;
+        while let Some((source, destination)) = self.hdma.next_block() {
+            for i in 0..BLOCK_SIZE {
+                let data = self.read_memory(source.wrapping_add(i) as usize);
+                self.write_memory((destination + i) as usize, data);
+            }
+            blocks += 1;
+        }
+
+        let speed = if self.memory.double_speed() { 2 } else { 1 };
+        blocks * CYCLES_PER_HDMA_BLOCK * speed
+    }
+
+    // Cycles run so far in the current frame
+    pub fn frame_cycles(&self) -> u32 {
+        self.frame_cycles
+    }
+
+    // Everything that happens between frames, before the first instruction
+    fn start_frame(&mut self) {
+        if let Some((movie, next_frame)) = self.movie_playback.take() {
+            if let Some(inputs) = movie.inputs.get(next_frame) {
+                self.set_inputs(*inputs);
+            }
+
+            if next_frame + 1 < movie.inputs.len() {
+                self.movie_playback = Some((movie, next_frame + 1));
+            } else {
+                info!("Movie finished after {} frames", movie.inputs.len());
+            }
+        }
+
+        if let Some(movie) = self.movie_recording.as_mut() {
+            movie.inputs.push(self.pressed_inputs);
+        }
+
+        if !self.freezes.is_empty() {
+            self.apply_freezes();
+        }
+
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.record(self.frame, self.pressed_inputs, || self.save_state());
+            self.rewind = Some(rewind);
+        }
+    }
+
+    // Logs the CPU state before every instruction
+    pub fn start_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    // Drops the tracer, flushing the log
+    #[cfg(test)]
+    pub fn stop_trace(&mut self) {
+        self.tracer = None;
+    }
 
-        // 69905 CPU cycles per frame
-        while elapsed_cycles < 69905 {
-            let cycles = self.cpu.execute(&mut self.memory);
-            elapsed_cycles += cycles;
+    fn trace_instruction(&mut self) {
+        let pc = self.cpu.pc;
+        let wanted = self.tracer.as_ref().is_some_and(|tracer| tracer.wants(pc, self.bank_at(pc)));
+        if !wanted {
+            return;
         }
+
+        let registers = self.cpu.registers();
+        let line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            registers.a, u8::from(registers.f), registers.b, registers.c, registers.d, registers.e,
+            registers.h, registers.l, self.cpu.sp(), pc,
+            self.peek_memory(pc as usize), self.peek_memory(pc.wrapping_add(1) as usize),
+            self.peek_memory(pc.wrapping_add(2) as usize), self.peek_memory(pc.wrapping_add(3) as usize));
+
+        // Off by default, since it stops the log matching other emulators'
+        let label = self.tracer.as_ref()
+            .and_then(|tracer| tracer.symbols.as_ref())
+            .and_then(|symbols| symbols.describe(self.bank_at(pc).unwrap_or(0), pc));
+        let line = match label {
+            Some(label) => format!("{} ; {}", line, label),
+            None => line
+        };
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Err(e) = tracer.write(line) {
+                error!("Failed to write instruction trace, stopping: {}", e);
+                self.tracer = None;
+            }
+        }
+    }
+
+    // Starts recording inputs, either from a fresh power on or from the
+    // current state
+    pub fn start_movie(&mut self, power_on: bool) {
+        self.movie_playback = None;
+
+        let start = if power_on {
+            self.reset();
+            MovieStart::PowerOn
+        } else {
+            MovieStart::SaveState(self.save_state())
+        };
+
+        self.movie_recording = Some(Movie::new(self.memory.cartridge().crc32(), start));
+    }
+
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie_recording.take()
+    }
+
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), io::Error> {
+        if let Some(crc) = movie.rom_crc {
+            if crc != self.memory.cartridge().crc32() {
+                return Err(invalid_state("movie was recorded with a different ROM"));
+            }
+        }
+
+        if movie.emulator_version != env!("CARGO_PKG_VERSION") {
+            warn!("Movie was recorded with {} {}, playback may desync",
+                if movie.rom_crc.is_some() { "coolboy" } else { "another emulator," },
+                movie.emulator_version);
+        }
+
+        match &movie.start {
+            MovieStart::PowerOn => self.reset(),
+            MovieStart::SaveState(state) => self.load_state(state)?,
+        }
+
+        self.movie_recording = None;
+        self.movie_playback = Some((movie, 0));
+        Ok(())
+    }
+
+    pub fn is_recording_movie(&self) -> bool {
+        self.movie_recording.is_some()
+    }
+
+    // While this is true the frontend shouldn't send any inputs of its own
+    pub fn is_playing_movie(&self) -> bool {
+        self.movie_playback.is_some()
+    }
+
+    // Frames left to play, if a movie is playing
+    pub fn movie_frames_left(&self) -> Option<usize> {
+        self.movie_playback.as_ref().map(|(movie, next_frame)| movie.inputs.len() - next_frame)
+    }
+
+    pub fn enable_rewind(&mut self, config: RewindConfig) {
+        self.rewind = Some(RewindBuffer::new(config));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    // Steps back the given number of frames, or as far as the rewind history
+    // goes. Returns how many frames were actually rewound.
+    pub fn rewind(&mut self, frames: u32) -> u32 {
+        let target = self.frame.saturating_sub(u64::from(frames));
+        let (frame, state, replay) = match self.rewind.as_mut().and_then(|rewind| rewind.seek(target)) {
+            Some(seek) => seek,
+            None => return 0
+        };
+
+        let start_frame = self.frame;
+        let held_inputs = self.pressed_inputs;
+        // Replayed frames shouldn't end up in the recording
+        let recording = self.recording.take();
+
+        self.restore_state(&state).expect("Rewind buffer produced an invalid save state!");
+        self.frame = frame;
+
+        // Replaying shouldn't record into or advance a movie
+        let movie_recording = self.movie_recording.take();
+        let movie_playback = self.movie_playback.take();
+
+        for inputs in replay {
+            self.set_inputs(inputs);
+            self.update();
+        }
+
+        self.movie_recording = movie_recording;
+        self.movie_playback = movie_playback;
+
+        // Nor should they be heard
+        self.apu.take_samples();
+
+        self.set_inputs(held_inputs);
+        self.recording = recording;
+
+        let rewound = (start_frame - self.frame) as u32;
+
+        // Recorded movie frames and played back frames go back too
+        if let Some(movie) = self.movie_recording.as_mut() {
+            let length = movie.inputs.len().saturating_sub(rewound as usize);
+            movie.inputs.truncate(length);
+        }
+        if let Some((_, next_frame)) = self.movie_playback.as_mut() {
+            *next_frame = next_frame.saturating_sub(rewound as usize);
+        }
+
+        rewound
+    }
+
+    // Mute, solo and pan overrides for debugging, independent of NR51
+    pub fn channel_control(&self, channel: Channel) -> ChannelControl {
+        self.apu.channel_control(channel)
+    }
+
+    pub fn set_channel_control(&mut self, channel: Channel, control: ChannelControl) {
+        self.apu.set_channel_control(channel, control);
+    }
+
+    // Starts or stops collecting per-channel samples for oscilloscope views
+    pub fn set_channel_taps(&mut self, enabled: bool) {
+        self.apu.set_taps_enabled(enabled);
+    }
+
+    pub fn take_channel_samples(&mut self, channel: Channel) -> Vec<f32> {
+        self.apu.take_channel_samples(channel)
+    }
+
+    // Records everything the APU outputs to a WAV file until stop_recording
+    pub fn start_recording(&mut self, filename: &str) -> Result<(), io::Error> {
+        self.stop_recording()?;
+        self.recording = Some(WavWriter::create(filename, self.apu.sample_rate())?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> Result<(), io::Error> {
+        match self.recording.take() {
+            Some(wav) => wav.finish(),
+            None => Ok(())
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    // Interleaved stereo samples generated since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.apu.take_samples()
+    }
+
+    // Plugs something into the link port, returning what was there before.
+    // Nothing's plugged in to start with.
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        self.serial.set_device(device)
+    }
+
+    // The last frame drawn
+    pub fn screenshot(&self) -> Image {
+        let mut image = Image::new(160, 144);
+        for (x, column) in self.screen_buffer.iter().enumerate() {
+            for (y, pixel) in column.iter().enumerate() {
+                image.set(x, y, (pixel[0], pixel[1], pixel[2]));
+            }
+        }
+        image
+    }
+
+    // Snapshot of the whole machine. Frontend settings (sample rate, mixer
+    // controls, audio recording) are left out.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(STATE_MAGIC);
+        writer.write_u32(STATE_VERSION);
+        writer.write_u16(self.memory.cartridge().global_checksum());
+
+        self.cpu.save(&mut writer);
+        self.memory.save(&mut writer);
+        self.apu.save(&mut writer);
+        self.serial.save(&mut writer);
+        self.hdma.save(&mut writer);
+        self.palettes.save(&mut writer);
+        if self.sgb_hardware {
+            self.sgb.save(&mut writer);
+        }
+
+        writer.write_i32(self.timer_counter);
+        writer.write_i32(self.divider_counter);
+        writer.write_u32(self.frame_cycles);
+        writer.write_i32(self.scanline_count);
+        for column in self.screen_buffer.iter() {
+            for pixel in column.iter() {
+                writer.write_bytes(pixel);
+            }
+        }
+        writer.write_u8(self.pressed_inputs.bits());
+
+        writer.into_bytes()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        self.restore_state(data)?;
+
+        // History from before the load doesn't lead here any more
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.clear();
+        }
+        Ok(())
+    }
+
+    // Either loads the whole state or leaves the machine as it was
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        let mut reader = StateReader::new(data);
+        self.read_state_header(&mut reader)?;
+
+        // Every field has a fixed size, so a state that's the wrong length
+        // can't be ours
+        let backup = self.save_state();
+        if data.len() != backup.len() {
+            return Err(invalid_state("save state is the wrong size"));
+        }
+
+        // Fields can still turn out to be out of range partway through, and
+        // then the backup goes back in
+        if let Err(e) = self.read_machine(&mut reader) {
+            let mut reader = StateReader::new(&backup);
+            self.read_state_header(&mut reader)
+                .and_then(|_| self.read_machine(&mut reader))
+                .expect("Failed to put back the machine's own state");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn read_state_header(&self, reader: &mut StateReader) -> Result<(), io::Error> {
+        let mut magic = [0; 4];
+        reader.read_bytes(&mut magic)?;
+        if &magic != STATE_MAGIC {
+            return Err(invalid_state("not a coolboy save state"));
+        }
+
+        let version = reader.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(invalid_state(&format!(
+                "save state version {} is not supported (expected {})", version, STATE_VERSION)));
+        }
+
+        if reader.read_u16()? != self.memory.cartridge().global_checksum() {
+            return Err(invalid_state("save state is for a different ROM"));
+        }
+        Ok(())
+    }
+
+    fn read_machine(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.cpu.load(reader)?;
+        self.memory.load(reader)?;
+        self.apu.load(reader)?;
+        self.serial.load(reader)?;
+        self.hdma.load(reader)?;
+        self.palettes.load(reader)?;
+        if self.sgb_hardware {
+            self.sgb.load(reader)?;
+        }
+
+        self.timer_counter = reader.read_i32()?;
+        self.divider_counter = reader.read_i32()?;
+        self.frame_cycles = reader.read_u32()?;
+        self.scanline_count = reader.read_i32()?.clamp(0, CYCLES_PER_LINE as i32);
+        for column in self.screen_buffer.iter_mut() {
+            for pixel in column.iter_mut() {
+                reader.read_bytes(pixel)?;
+            }
+        }
+        self.pressed_inputs = Inputs::from_bits_truncate(reader.read_u8()?);
+
+        Ok(())
     }
 
     fn read_memory(&self, address: usize) -> u8 {
+        let data = self.peek_memory(address);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(Access::Read, address, data, data);
+        }
+        data
+    }
+
+    fn write_memory(&mut self, address: usize, data: u8) {
+        if self.watchpoints.is_empty() {
+            self.poke_memory(address, data);
+            return;
+        }
+
+        let old = self.peek_memory(address);
+        self.check_watchpoints(Access::Write, address, old, data);
+        self.poke_memory(address, data);
+
+        let new = self.peek_memory(address);
+        if new != old {
+            self.check_watchpoints(Access::Change, address, old, new);
+        }
+    }
+
+    // Reads without tripping watchpoints, for anything that's only looking
+    fn peek_memory(&self, address: usize) -> u8 {
         match address {
             0xFF00 => self.joypad_state(),
+            SERIAL_DATA | SERIAL_CONTROL => self.serial.read(address),
+            SOUND_START_ADDRESS..=SOUND_END_ADDRESS => self.apu.read(address),
+            HDMA_START..=HDMA_END if self.memory.cgb() => self.hdma.read(address),
+            BCPS..=OCPD if self.memory.cgb() => self.palettes.read(address),
             _ => self.memory.read(address)
         }
     }
 
-    fn write_memory(&mut self, address: usize, data: u8) {
+    fn poke_memory(&mut self, address: usize, data: u8) {
         match address {
             TIMER_CONTROLLER => {
                 let current_freq = self.get_clock_freq();
@@ -177,19 +883,38 @@ impl Emulator {
             },
             DMA_ADDRESS => {
                 self.dma_transfer(data);
-            }
+            },
+            DIVIDER_REGISTER => {
+                // Resetting DIV can produce a falling edge on the frame
+                // sequencer's bit
+                if tbit!(self.read_memory(DIVIDER_REGISTER), self.frame_sequencer_bit()) {
+                    self.apu.clock_frame_sequencer();
+                }
+                self.divider_counter = 0;
+                self.memory.write(address, data);
+            },
+            SERIAL_DATA | SERIAL_CONTROL => {
+                self.serial.write(address, data);
+            },
+            SOUND_START_ADDRESS..=SOUND_END_ADDRESS => {
+                self.apu.write(address, data);
+            },
+            KEY_ADDRESS if self.sgb_hardware => {
+                if self.memory.cartridge().supports_sgb() {
+                    self.sgb.write_joypad(data);
+                }
+                self.memory.write(address, data);
+            },
+            HDMA_START..=HDMA_END if self.memory.cgb() => {
+                self.hdma.write(address, data);
+            },
+            BCPS..=OCPD if self.memory.cgb() => {
+                self.palettes.write(address, data);
+            },
             _ => self.memory.write(address, data)
         }
     }
 
-    fn push_stack8(&mut self, data: u8) {
-
-    }
-
-    fn push_stack16(&mut self, data: u16) {
-
-    }
-
     fn dma_transfer(&mut self, data: u8) {
         let address = (data as usize) << 8;
         for i in 0..0xA0 {
@@ -240,25 +965,24 @@ impl Emulator {
 
     fn handle_divider_register(&mut self, cycles: u16) {
         self.divider_counter += cycles as i32;
-        if self.divider_counter >= 255 {
-            self.divider_counter = 0;
-            let byte = self.read_memory(DIVIDER_REGISTER);
-            self.memory.write_force(DIVIDER_REGISTER, byte);
+        while self.divider_counter >= 256 {
+            self.divider_counter -= 256;
+            let old = self.read_memory(DIVIDER_REGISTER);
+            let new = old.wrapping_add(1);
+            self.memory.write_force(DIVIDER_REGISTER, new);
+
+            // The APU frame sequencer runs off the falling edge of DIV bit 4 (512 Hz)
+            let bit = self.frame_sequencer_bit();
+            if tbit!(old, bit) && !tbit!(new, bit) {
+                self.apu.clock_frame_sequencer();
+            }
         }
     }
 
-    fn handle_interrupts(&mut self) {
-        if self.interrupt_master {
-            let req = self.read_memory(INTERRUPT_REQUEST);
-            let enabled = self.read_memory(INTERRUPT_ENABLED);
-
-            for interrupt in ALL_INTERRUPTS.iter() {
-                // If interrupt request register is set and is enabled
-                if ((req | *interrupt as u8) != 0) && ((enabled | *interrupt as u8) != 0) {
-                    self.service_interrupt(*interrupt);
-                }
-            }
-        }
+    // DIV runs twice as fast in double speed, so the frame sequencer moves
+    // up a bit to stay at 512 Hz
+    fn frame_sequencer_bit(&self) -> u8 {
+        if self.memory.double_speed() { 5 } else { 4 }
     }
 
     fn request_interrupt(&mut self, interrupt: Interrupt) {
@@ -267,99 +991,68 @@ impl Emulator {
         self.write_memory(INTERRUPT_REQUEST, req_set);
     }
 
-    fn service_interrupt(&mut self, interrupt: Interrupt) {
-        self.interrupt_master = false;
-
-        let req = self.read_memory(INTERRUPT_REQUEST);
-
-        let req_unset = req & !(interrupt as u8);
-        self.write_memory(INTERRUPT_REQUEST, req_unset);
-
-        self.push_stack16(self.cpu.pc);
-
-        self.cpu.pc = match interrupt {
-            Interrupt::VBlank => 0x40,
-            Interrupt::LCD => 0x48,
-            Interrupt::Timer => 0x50,
-            Interrupt::Joypad => 0x60,
-        };
-    }
-
-    fn update_graphics(&mut self, cycles: u16) {
+    fn update_graphics(&mut self, cycles: u32) {
         self.set_lcd_status();
+        if !self.lcd_enabled() {
+            return;
+        }
 
-        if self.lcd_enabled() {
-            self.scanline_count -= cycles;
-
-            if self.scanline_count <= 0 {
-                let old_line = self.read_memory(SCANLINE_ADDRESS);
-
-                let new_line = old_line + 1;
-                self.memory.write_force(SCANLINE_ADDRESS, new_line);
+        self.scanline_count -= cycles as i32;
+        if self.scanline_count > 0 {
+            return;
+        }
+        // Whatever ran over counts towards the next line
+        self.scanline_count += CYCLES_PER_LINE as i32;
 
-                self.scanline_count = 456;
+        // Draw the line that's just finished, then move on
+        let line = self.read_memory(SCANLINE_ADDRESS);
+        if line < 144 {
+            self.draw_scanline();
+        }
 
-                if new_line == 144 {
-                    self.request_interrupt(Interrupt::VBlank);
-                } else if new_line > 153 {
-                    self.memory.write_force(SCANLINE_ADDRESS, 0);
-                } else if new_line < 144 {
-                    self.draw_scanline();
-                }
-            }
+        let next_line = if line >= 153 { 0 } else { line + 1 };
+        self.memory.write_force(SCANLINE_ADDRESS, next_line);
+        if next_line == 144 {
+            self.request_interrupt(Interrupt::VBlank);
         }
     }
 
     fn set_lcd_status(&mut self) {
         let status = self.read_memory(LCD_STATUS_ADDRESS);
 
+        // Switched off, the LCD waits at the start of line 0 in mode 0
         if !self.lcd_enabled() {
-            self.scanline_count = 456;
+            self.scanline_count = CYCLES_PER_LINE as i32;
             self.memory.write_force(SCANLINE_ADDRESS, 0);
-            let masked_status = (status & 0b11111100) | 0b00000001;
-            self.write_memory(LCD_STATUS_ADDRESS, masked_status);
-        } else {
-            let current_line = self.read_memory(SCANLINE_ADDRESS);
-            let current_mode = status & 0b00000011;
-
-            let mode = 
-                if current_line >= 144 {
-                    1
-                } else {
-                    match self.scanline_count {
-                        LCD_MODE2_BOUND..=std::u16::MAX => 2,
-                        LCD_MODE3_BOUND..=LCD_MODE2_BOUND => 3,
-                        _ => 0
-                    }
-                };
-
-            let masked_status = status & 0b11111100 | mode;
-            // Mode 0 sets bit 3, 1 sets bit 4, 2 sets bit 5
-            // So just set mode + 3 bits from the right
-            let req_int = match mode {
-                0 | 1 | 2 => (status & (1 << (3 + mode))) != 0,
-                _ => false
-            };
+            self.memory.write_force(LCD_STATUS_ADDRESS, status & 0b11111000);
+            return;
+        }
 
-            if req_int && (mode != current_mode) {
-                self.request_interrupt(Interrupt::LCD);
+        let line = self.read_memory(SCANLINE_ADDRESS);
+        let mode = if line >= 144 {
+            1
+        } else {
+            match self.scanline_count {
+                LCD_MODE2_BOUND.. => 2,
+                LCD_MODE3_BOUND.. => 3,
+                _ => 0
             }
+        };
 
-            let game_scanline = self.read_memory(0xFF45);
-
-            let cncd_status = 
-                if current_line == game_scanline {
-                    let new_status = status | 0b00000100;
-                    if (new_status & 0b01000000) != 0 {
-                        self.request_interrupt(Interrupt::LCD);
-                    }
-                    new_status
-                } else {
-                    status & 0b11111011
-                };
+        // Bits 3, 4 and 5 ask for an interrupt on entering mode 0, 1 or 2
+        if mode != status & 0b11 && mode < 3 && tbit!(status, 3 + mode) {
+            self.request_interrupt(Interrupt::LCD);
+        }
 
-            self.write_memory(LCD_STATUS_ADDRESS, cncd_status);
+        // Bit 2 is set while LY matches LYC, and bit 6 asks for an interrupt
+        // when it starts to
+        let coincidence = line == self.read_memory(0xFF45);
+        if coincidence && !tbit!(status, 2) && tbit!(status, 6) {
+            self.request_interrupt(Interrupt::LCD);
         }
+
+        let status = (status & 0b11111000) | ((coincidence as u8) << 2) | mode;
+        self.memory.write_force(LCD_STATUS_ADDRESS, status);
     }
 
     fn lcd_enabled(&mut self) -> bool {
@@ -369,234 +1062,184 @@ impl Emulator {
 
     fn draw_scanline(&mut self) {
         let control = self.read_memory(LCD_CONTROL_ADDRESS);
+        let scanline = self.read_memory(SCANLINE_ADDRESS);
+        if scanline >= 144 {
+            warn!("Attempting to write scanline {} which is out of bounds!", scanline);
+            return;
+        }
 
-        if tbit!(control, 0) {
-            self.render_tiles();
+        // On CGB bit 0 only takes priority away from the background, on DMG
+        // it blanks it
+        let mut background = [BackgroundPixel::default(); 160];
+        if tbit!(control, 0) || self.memory.cgb() {
+            self.render_tiles(scanline, &mut background);
+        } else {
+            let rgb = self.dmg_rgb(Palette::Background, 0);
+            for pixel in 0..160 {
+                self.set_pixel(pixel, scanline, rgb);
+            }
         }
         if tbit!(control, 1) {
-            self.render_sprites();
+            self.render_sprites(scanline, &background);
         }
     }
 
-    fn render_tiles(&mut self) {
+    fn render_tiles(&mut self, scanline: u8, background: &mut [BackgroundPixel; 160]) {
         // Each tile is 8x8 pixels
         // Resolution has 256x256 real pixels (32x32 tiles)
-        // 160x144 viewing space 
-
-        // 2*: Sprite layer
-        // 1*: Window layer
-        // 0: Background layer (256x256) (32x32 tiles)
-        // * Sprite and window..
+        // 160x144 viewing space
 
         // Position of background to start drawing viewing area
         let scroll_y = self.read_memory(SCROLL_Y_ADDRESS);
         let scroll_x = self.read_memory(SCROLL_X_ADDRESS);
-        // Position of viewing area to start drawing window
+        // Position of viewing area to start drawing window, which is 7 pixels
+        // left of WX
         let window_y = self.read_memory(WINDOW_Y_ADDRESS);
         let window_x = self.read_memory(WINDOW_X_ADDRESS);
 
-        let scanline = self.read_memory(SCANLINE_ADDRESS);
         let lcd_control = self.read_memory(LCD_CONTROL_ADDRESS);
+        let cgb = self.memory.cgb();
 
         // Bit 5 - whether or not game is drawing the window layer
-        let using_window = ((lcd_control & 0b00100000) != 0) 
-                       && (window_y <= scanline);
-        
-        // Bit 4 - which tile data bank to use
-        //   If using 0x8800, signed integers; else 0x8000
-        let (tile_data, signed) = match tbit!(lcd_control, 4) {
-            true => (0x8000, false),
-            false => (0x8800, true)
-        };
+        let using_window = tbit!(lcd_control, 5) && window_y <= scanline;
 
-        // Which background memory to use
-        let background_memory = 
-            if (using_window && tbit!(lcd_control, 3)) || (!using_window && tbit!(lcd_control, 6)) {
-                0x9C00
+        for pixel in 0..160u8 {
+            let in_window = using_window && pixel + 7 >= window_x;
+
+            // Bit 6 picks the window's tile map and bit 3 the background's
+            let (map, pos_x, pos_y) = if in_window {
+                (if tbit!(lcd_control, 6) { TILE_MAP_1 } else { TILE_MAP_0 }, pixel + 7 - window_x, scanline - window_y)
             } else {
-                0x9800
+                (if tbit!(lcd_control, 3) { TILE_MAP_1 } else { TILE_MAP_0 }, pixel.wrapping_add(scroll_x), scanline.wrapping_add(scroll_y))
             };
 
-        // Get the current y position of the scanline we're drawing
-        let pos_y = 
-            if using_window {
-                scanline - window_y
+            // 32 tiles in each row
+            let map_address = map as usize + (pos_y as usize / 8) * 32 + pos_x as usize / 8;
+            let tile = self.memory.read_vram(0, map_address);
+            // CGB keeps each tile's attributes at the same place in bank 1:
+            // palette, tile bank, flips and priority over sprites
+            let attributes = if cgb { self.memory.read_vram(1, map_address) } else { 0 };
+
+            // Bit 4 - which tile data to use; 0x8800 numbers are signed
+            let tile_address = if tbit!(lcd_control, 4) {
+                SPRITE_DATA_ADDRESS + tile as usize * 16
             } else {
-                scroll_y + scanline
+                (0x9000 + i32::from(tile as i8) * 16) as usize
             };
 
-        // Current row we're drawing - 32 tiles in each row
-        let tile_row = (pos_y as u16 / 8) * 32;
-
-        // Start drawing all the pixels on the screen
-        for pixel in 0..160 {
-            // The position of 
-            let pos_x = 
-                if using_window && pixel >= window_x {
-                    pixel - window_x
-                } else {
-                    pixel + scroll_x
-                };
-
-            // Current column we're drawing
-            let tile_column = pos_x / 8;
-            
-            // Find number identifier of the tile we want to draw
-            let tile_address: usize = (background_memory + tile_row + tile_column as u16) as usize;
-            let tile_num: i16 = 
-                if signed {
-                    // Signed: interpret as i8 and convert to 
-                    i16::from(self.read_memory(tile_address) as i8)
-                } else {
-                    i16::from(self.read_memory(tile_address) as u8)
-                };
+            let row = if tbit!(attributes, 6) { 7 - pos_y % 8 } else { pos_y % 8 };
+            let column = if tbit!(attributes, 5) { 7 - pos_x % 8 } else { pos_x % 8 };
+            let color_num = self.tile_color(gbit!(attributes, 3) as usize, tile_address, column, row);
 
-            // Find tile in memory
-            let tile_location = 
-                if signed {
-                    (tile_num + 128) * 16
-                } else {
-                    tile_num * 16
-                };
-
-            // Get which of 8 vertical lines we're drawing
-            // Remember each tile is 2 bytes
-            let line_offset = (pos_y % 8) * 2;
-            let data1 = self.read_memory((tile_location + i16::from(line_offset)) as usize);
-            let data2 = self.read_memory((tile_location + i16::from(line_offset)) as usize + 1);
-
-            // Data1 : 7 6 5 4 3 2 1 0
-            // Data2 : 7 6 5 4 3 2 1 0
-            // X position indexes the bit position
-            // Data 2 is bit 1 of the color ID, data 1 is bit 0
-            // BUT pixel 1 is in bit 7, pixel 2 in bit 6, etc. so we need to invert
-            let color_bit = -((pos_x % 8) as i16 - 7);
-            let color_num = (gbit!(data2, color_bit) << 1) | gbit!(data1, color_bit);
-
-            let color = self.get_color(color_num, PALETTE_47_ADDRESS);
-            let (red, green, blue) = color.rgb();
-
-            if scanline < 0 || scanline > 143 {
-                warn!("Attempting to write scanline {} which is out of bounds!", scanline);
+            background[pixel as usize] = BackgroundPixel { color: color_num, priority: tbit!(attributes, 7) };
+            if cgb {
+                let rgb = self.palettes.background_rgb(attributes & 0b111, color_num);
+                self.set_pixel(pixel, scanline, rgb);
             } else {
-                self.screen_buffer[pixel as usize][scanline as usize][0] = red;
-                self.screen_buffer[pixel as usize][scanline as usize][1] = green;
-                self.screen_buffer[pixel as usize][scanline as usize][2] = blue;
+                self.set_dmg_pixel(pixel, scanline, Palette::Background, color_num);
             }
         }
     }
 
-    fn render_sprites(&mut self) {
-        // Sprite data from 0x8000 to 0x8FFF
-
-        // Sprite attribute table from 0xFE00 to 0xFE9F
-        // This holds 40 sprites of 4 bytes each
-        // Byte 0: y position (minus 16)
-        // Byte 1: x position (minus 8)
-        // Byte 2: pattern number - used to look up in  0x8000
-        // Byte 3: attributes - 
-        //   Bit 7: sprite / background priority; 0 is sprite above, 1 is sprite behind unless BG is white
-        //   Bit 6: y flip
-        //   Bit 5: x flip
-        //   Bit 4: palette number; 0 is 0xFF48, 1 is 0xFF49
-        //   Bits 3-0: unused
-
+    fn render_sprites(&mut self, scanline: u8, background: &[BackgroundPixel; 160]) {
+        // Sprite data from 0x8000 to 0x8FFF, attributes from 0xFE00 to 0xFE9F.
+        // See Sprite for what's in each entry.
         let lcd_control = self.read_memory(LCD_CONTROL_ADDRESS);
-        let double_height = tbit!(lcd_control, 2);
+        let cgb = self.memory.cgb();
+        let tall = tbit!(lcd_control, 2);
+        let height = if tall { 16 } else { 8 };
+
+        // Only the first ten on the line, in OAM order, are drawn
+        let mut sprites: Vec<Sprite> = self.sprites().into_iter()
+            .filter(|sprite| {
+                let top = i16::from(sprite.y) - 16;
+                top <= i16::from(scanline) && i16::from(scanline) < top + height
+            })
+            .take(10)
+            .collect();
+
+        // Where they overlap the first in OAM wins on CGB, the leftmost on
+        // DMG. Drawing the winner last puts it on top.
+        if !cgb {
+            sprites.sort_by_key(|sprite| (sprite.x, sprite.index));
+        }
 
-        let size_x = 8;
-        let size_y = if double_height { 16 } else { 8 };
+        for sprite in sprites.iter().rev() {
+            let sprite_line = (i16::from(scanline) - (i16::from(sprite.y) - 16)) as u8;
+            let line = if sprite.flip_y() { height as u8 - 1 - sprite_line } else { sprite_line };
+            // Tall sprites ignore the bottom bit of the tile number
+            let tile = if tall { sprite.tile & 0xFE } else { sprite.tile };
+            let address = SPRITE_DATA_ADDRESS + tile as usize * 16 + (line as usize / 8) * 16;
+            let bank = if cgb { sprite.bank() } else { 0 };
+
+            for tile_pixel in 0..8u8 {
+                let pixel = i16::from(sprite.x) - 8 + i16::from(tile_pixel);
+                if !(0..160).contains(&pixel) {
+                    continue;
+                }
 
-        let scanline = self.read_memory(SCANLINE_ADDRESS);
+                let column = if sprite.flip_x() { 7 - tile_pixel } else { tile_pixel };
+                let color_num = self.tile_color(bank, address, column, line % 8);
+                // Colour 0 is see-through
+                if color_num == 0 {
+                    continue;
+                }
 
-        // Check all sprites in memory 0xFE00-0xFE9F
-        for sprite in 0..40 {
-            let base_address = SPRITE_ATTRIBUTE_TABLE + (sprite * 4);
-
-            let pos_y = self.read_memory(base_address);
-            let pos_x = self.read_memory(base_address + 1);
-            let location = self.read_memory(base_address + 2);
-            let attributes = self.read_memory(base_address + 3);
-
-            let flip_y = tbit!(attributes, 6);
-            let flip_x = tbit!(attributes, 5);
-
-            // If current scanline intercepts sprite
-            if scanline >= pos_y && scanline < (pos_y + size_y) {
-                let sprite_line = scanline - pos_y;
-                let line = 
-                    if flip_y {
-                        2 * -(sprite_line as i16 - size_y as i16)
-                    } else {
-                        2 * sprite_line as i16
-                    };
-
-                let address = (SPRITE_DATA_ADDRESS + (location * 16) as usize) + line as usize;
-                let data1 = self.read_memory(address);
-                let data2 = self.read_memory(address + 1);
-
-                for tile_pixel in 7..0 {
-                    let color_bit = 
-                        if flip_x {
-                            -(tile_pixel as i16 - 7)
-                        } else {
-                            tile_pixel
-                        };
-                    
-                    let color_num = (gbit!(data2, color_bit) << 1) | gbit!(data1, color_bit);
-
-                    let color_address =
-                        if tbit!(attributes, 4) {
-                            PALETTE_49_ADDRESS
-                        } else {
-                            PALETTE_48_ADDRESS
-                        };
-                    
-                    let color = self.get_color(color_num, color_address);
-
-                    let transparent = match color {
-                        Color::White => true,
-                        _ => false
-                    };
-
-                    if !transparent {
-                        let (red, green, blue) = color.rgb();
-                        let pixel = 7 + pos_x as i16 - tile_pixel as i16;
-
-                        if scanline < 0 || scanline > 143 {
-                            warn!("Attempting to write scanline {} which is out of bounds!", scanline);
-                        } else {
-                            self.screen_buffer[pixel as usize][scanline as usize][0] = red;
-                            self.screen_buffer[pixel as usize][scanline as usize][1] = green;
-                            self.screen_buffer[pixel as usize][scanline as usize][2] = blue;
-                        }
-                    }
+                // Background colour 0 never covers a sprite. Otherwise either
+                // the sprite or the tile can ask for the background on top,
+                // unless LCDC bit 0 is clear on CGB.
+                let below = background[pixel as usize];
+                let master_priority = !cgb || tbit!(lcd_control, 0);
+                if master_priority && below.color != 0 && (sprite.behind_background() || below.priority) {
+                    continue;
+                }
+
+                if cgb {
+                    let rgb = self.palettes.object_rgb(sprite.color_palette(), color_num);
+                    self.set_pixel(pixel as u8, scanline, rgb);
+                } else {
+                    self.set_dmg_pixel(pixel as u8, scanline, sprite.palette(), color_num);
                 }
             }
         }
     }
 
-    fn get_color(&self, color_num: u8, address: usize) -> Color {
-        let palette = self.read_memory(address);
-        let (hi, lo) = match color_num {
-            0 => (1, 0),
-            1 => (3, 2),
-            2 => (5, 4),
-            3 => (7, 6),
-            _ => panic!("Unknown color number {}!", color_num)
-        };
+    // Colour number 0-3 of a pixel in the tile at address, from either VRAM
+    // bank
+    fn tile_color(&self, bank: usize, address: usize, x: u8, y: u8) -> u8 {
+        let data1 = self.memory.read_vram(bank, address + y as usize * 2);
+        let data2 = self.memory.read_vram(bank, address + y as usize * 2 + 1);
+        // Data 2 is bit 1 of the color ID, data 1 is bit 0. Pixel 0 is bit 7.
+        let color_bit = 7 - x;
+        (gbit!(data2, color_bit) << 1) | gbit!(data1, color_bit)
+    }
 
-        let color = (gbit!(palette, hi) << 1) | gbit!(palette, lo);
+    fn set_pixel(&mut self, x: u8, y: u8, (red, green, blue): (u8, u8, u8)) {
+        self.screen_buffer[x as usize][y as usize] = [red, green, blue];
+    }
 
-        match color {
-            0 => Color::White,
-            1 => Color::LightGrey,
-            2 => Color::DarkGrey,
-            3 => Color::Black,
-            _ => unreachable!()
+    // On a Super Game Boy the shade goes through the palette of the cell
+    // it's in instead of the colour scheme
+    fn set_dmg_pixel(&mut self, x: u8, y: u8, palette: Palette, color_num: u8) {
+        if !self.sgb_hardware {
+            let rgb = self.dmg_rgb(palette, color_num);
+            self.set_pixel(x, y, rgb);
+            return;
+        }
+
+        let shade = palette_color(self.peek_memory(palette.address()), color_num).shade();
+        self.sgb.set_shade(x, y, shade);
+        if let Some(rgb) = self.sgb.rgb(x, y, shade) {
+            self.set_pixel(x, y, rgb);
         }
     }
 
+    // A colour number through BGP, OBP0 or OBP1 and the colour scheme
+    fn dmg_rgb(&self, palette: Palette, color_num: u8) -> (u8, u8, u8) {
+        let color = palette_color(self.peek_memory(palette.address()), color_num);
+        self.colors.shades(palette)[color.shade()]
+    }
+
     pub fn input_down(&mut self, input: Inputs) {
         let was_unset = (self.pressed_inputs & input) != Inputs::empty();
         self.pressed_inputs |= input;
@@ -614,9 +1257,62 @@ impl Emulator {
         self.pressed_inputs &= !input;
     }
 
+    // Records a hit if any watchpoint covers this access. Only the first
+    // hit is kept, the debugger stops on that one
+    fn check_watchpoints(&self, access: Access, address: usize, old: u8, new: u8) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+
+        let address = address as u16;
+        let bank = self.bank_at(address);
+        let hit = self.watchpoints.iter().any(|watchpoint| watchpoint.matches(access, address, bank, new));
+
+        if hit {
+            self.watch_hit.set(Some(WatchHit { access, pc: self.cpu.pc, address, bank, old, new }));
+        }
+    }
+
+    // The bank mapped at an address, for the switchable regions. VRAM and
+    // the upper half of WRAM only switch in CGB mode.
+    fn bank_at(&self, address: u16) -> Option<usize> {
+        match address {
+            0x4000..=0x7FFF => Some(self.memory.rom_bank()),
+            0x8000..=0x9FFF if self.memory.cgb() => Some(self.memory.vram_bank()),
+            0xA000..=0xBFFF => Some(self.memory.ram_bank()),
+            0xD000..=0xDFFF if self.memory.cgb() => Some(self.memory.wram_bank()),
+            _ => None
+        }
+    }
+
+    // Presses and releases inputs so that exactly the given set is held
+    fn set_inputs(&mut self, inputs: Inputs) {
+        for input in ALL_INPUTS.iter() {
+            let held = self.pressed_inputs.contains(*input);
+            let wanted = inputs.contains(*input);
+
+            if wanted && !held {
+                self.input_down(*input);
+            } else if held && !wanted {
+                self.input_up(*input);
+            }
+        }
+    }
+
     fn joypad_state(&self) -> u8 {
         // Current status
         let old_state = self.memory.read(KEY_ADDRESS);
+
+        // With nothing selected the SGB gives the joypad number, counting
+        // down from 0xF. Only the first one has anything pressed.
+        if self.sgb_hardware {
+            if old_state & 0x30 == 0x30 {
+                return 0xFF - self.sgb.player();
+            }
+            if self.sgb.player() != 0 {
+                return old_state | 0xCF;
+            }
+        }
         let mut new_state = 0xFF;
 
         let active_select = if tbit!(old_state, 4) {5} else {4};
@@ -638,3 +1334,442 @@ impl Emulator {
         return new_state;
     }
 }
+
+impl Bus for Emulator {
+    fn read(&mut self, address: u16) -> u8 {
+        self.read_memory(address as usize)
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.write_memory(address as usize, data);
+    }
+
+    fn interrupts(&mut self) -> u8 {
+        self.memory.read(INTERRUPT_ENABLED) & self.memory.read(INTERRUPT_REQUEST) & 0x1F
+    }
+
+    fn acknowledge(&mut self, interrupt: u8) {
+        self.interrupted = true;
+        let requested = self.memory.read(INTERRUPT_REQUEST);
+        self.memory.write(INTERRUPT_REQUEST, ubit!(requested, interrupt));
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        self.memory.switch_speed()
+    }
+}
+
+// Whether a visible line's HBlank started after from and by to, going by
+// where the frame has got to
+fn entered_hblank(from: u32, to: u32) -> bool {
+    let line = from / CYCLES_PER_LINE;
+    let start = if from % CYCLES_PER_LINE < HBLANK_START { line } else { line + 1 };
+    let hblank = start * CYCLES_PER_LINE + HBLANK_START;
+    hblank <= to && start < VISIBLE_LINES
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::test_roms::test_rom;
+    use super::{Emulator, Inputs, Movie, RewindConfig, Tracer, SGB_HEIGHT, SGB_WIDTH};
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut emulator = Emulator::from_bytes(&test_rom());
+        emulator.update();
+        let state = emulator.save_state();
+
+        emulator.update();
+        emulator.update();
+        assert_ne!(emulator.save_state(), state);
+
+        emulator.load_state(&state).unwrap();
+        assert_eq!(emulator.save_state(), state);
+    }
+
+    #[test]
+    fn rewind_matches_earlier_states() {
+        let mut emulator = Emulator::from_bytes(&test_rom());
+        let mut config = RewindConfig::new();
+        config.interval = 4;
+        config.keyframe_interval = 3;
+        emulator.enable_rewind(config);
+
+        let mut states = vec![emulator.save_state()];
+        for frame in 0..40 {
+            match frame {
+                5 => emulator.input_down(Inputs::A),
+                9 => emulator.input_down(Inputs::START),
+                17 => emulator.input_up(Inputs::A),
+                _ => ()
+            }
+            emulator.update();
+            states.push(emulator.save_state());
+        }
+
+        // Whatever is held when rewinding stays held, so leave the pressed
+        // inputs (the last byte of the state) out of the comparison
+        let without_inputs = |state: &[u8]| state[..state.len() - 1].to_vec();
+        emulator.input_up(Inputs::START);
+
+        assert_eq!(emulator.rewind(7), 7);
+        assert_eq!(without_inputs(&emulator.save_state()), without_inputs(&states[33]));
+
+        assert_eq!(emulator.rewind(20), 20);
+        assert_eq!(without_inputs(&emulator.save_state()), without_inputs(&states[13]));
+
+        // Can't go back past power on
+        assert_eq!(emulator.rewind(100), 13);
+        assert_eq!(emulator.save_state(), states[0]);
+    }
+
+    #[test]
+    fn movie_playback_is_exact() {
+        let mut emulator = Emulator::from_bytes(&test_rom());
+        emulator.update();
+        emulator.start_movie(false);
+
+        for frame in 0..30 {
+            match frame {
+                3 => emulator.input_down(Inputs::A),
+                8 => emulator.input_down(Inputs::UP),
+                12 => emulator.input_up(Inputs::A),
+                20 => emulator.input_up(Inputs::UP),
+                _ => ()
+            }
+            emulator.update();
+        }
+
+        let movie = emulator.stop_movie().unwrap();
+        assert_eq!(movie.inputs.len(), 30);
+        let expected = emulator.save_state();
+
+        let mut other = Emulator::from_bytes(&test_rom());
+        other.play_movie(Movie::from_bytes(&movie.to_bytes()).unwrap()).unwrap();
+        while other.is_playing_movie() {
+            other.update();
+        }
+
+        assert_eq!(other.save_state(), expected);
+    }
+
+    #[test]
+    fn trace_uses_gameboy_doctor_format() {
+        let filename = env::temp_dir().join("coolboy-emulator-trace-test.log");
+        let filename = filename.to_str().unwrap();
+
+        // The usual entry point: NOP; JP 0150
+        let mut rom = test_rom();
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        let mut emulator = Emulator::from_bytes(&rom);
+        emulator.start_trace(Tracer::create(filename, None).unwrap());
+        emulator.step();
+        emulator.step();
+        emulator.step();
+        emulator.stop_trace();
+
+        let trace = fs::read_to_string(filename).unwrap();
+        fs::remove_file(filename).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines, [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,00",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:00,00,00,00",
+        ]);
+    }
+
+    #[test]
+    fn trace_ring_is_dumped_on_lock_up() {
+        let filename = env::temp_dir().join("coolboy-emulator-lockup-test.log");
+        let filename = filename.to_str().unwrap();
+
+        // NOP, then an illegal opcode
+        let mut rom = test_rom();
+        rom[0x100..0x102].copy_from_slice(&[0x00, 0xD3]);
+        let mut emulator = Emulator::from_bytes(&rom);
+        emulator.start_trace(Tracer::create(filename, Some(8)).unwrap());
+        for _ in 0..4 {
+            emulator.step();
+        }
+
+        // Written before the tracer's dropped, and nothing after the lock up
+        let trace = fs::read_to_string(filename).unwrap();
+        emulator.stop_trace();
+        fs::remove_file(filename).unwrap();
+        let pcs: Vec<&str> = trace.lines().map(|line| line.split_whitespace().nth(9).unwrap()).collect();
+        assert_eq!(pcs, ["PC:0100", "PC:0101"]);
+    }
+
+    #[test]
+    fn ppu_runs_from_step() {
+        let mut rom = test_rom();
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]); // JR -2
+        let mut emulator = Emulator::from_bytes(&rom);
+
+        // Tile 0 has its top row in colour 3 and the rest in colour 0, over
+        // the whole background
+        emulator.edit(0x8000, None, 0xFF).unwrap();
+        emulator.edit(0x8001, None, 0xFF).unwrap();
+        emulator.edit(0xFF47, None, 0xE4).unwrap();
+        emulator.edit(0xFF40, None, 0x91).unwrap();
+
+        while emulator.frame_cycles() < 10 * 456 + 100 {
+            emulator.step();
+        }
+        assert_eq!(emulator.inspect(0xFF44, None), 10);
+        // Partway through the line's pixel transfer
+        assert_eq!(emulator.inspect(0xFF41, None) & 0b11, 3);
+
+        while emulator.frame_cycles() < 144 * 456 + 100 {
+            emulator.step();
+        }
+        assert_eq!(emulator.inspect(0xFF44, None), 144);
+        assert_eq!(emulator.inspect(0xFF41, None) & 0b11, 1);
+        assert_eq!(emulator.inspect(0xFF0F, None) & 0x01, 0x01);
+
+        emulator.update();
+        assert_eq!(emulator.inspect(0xFF44, None), 0);
+        let screen = emulator.screenshot();
+        assert_eq!(screen.get(0, 0), (0, 0, 0));
+        assert_eq!(screen.get(159, 136), (0, 0, 0));
+        // The screen starts out black, so these had to be drawn
+        assert_eq!(screen.get(0, 1), (0xFF, 0xFF, 0xFF));
+        assert_eq!(screen.get(159, 143), (0xFF, 0xFF, 0xFF));
+    }
+
+    #[test]
+    fn cgb_speed_switch_banks_and_hdma() {
+        let mut rom = test_rom();
+        rom[0x143] = 0x80;
+        let program = [
+            0x3E, 0x01, 0xE0, 0x4D,         // LD A,1; LDH (4D),A (arm the switch)
+            0x10, 0x00,                     // STOP
+            0x3E, 0x01, 0xE0, 0x4F,         // VRAM bank 1
+            0x3E, 0xAB, 0xEA, 0x00, 0x80,   // LD (8000),AB
+            0x3E, 0x03, 0xE0, 0x70,         // WRAM bank 3
+            0x3E, 0xCD, 0xEA, 0x00, 0xD0,   // LD (D000),CD
+            0x3E, 0xD0, 0xE0, 0x51,         // HDMA from D000...
+            0x3E, 0x00, 0xE0, 0x52,
+            0x3E, 0x00, 0xE0, 0x53,         // ...to 8010...
+            0x3E, 0x10, 0xE0, 0x54,
+            0x3E, 0x00, 0xE0, 0x55,         // ...one block, right now
+            0x18, 0xFE,                     // JR -2
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+
+        let mut emulator = Emulator::from_bytes(&rom);
+        assert!(emulator.is_cgb());
+        assert_eq!(emulator.cpu.registers().a, 0x11);
+        emulator.update();
+
+        assert_eq!(emulator.inspect(0xFF4D, None), 0xFE);
+        assert_eq!(emulator.inspect(0x8000, None), 0xAB);
+        assert_eq!(emulator.inspect(0x8010, None), 0xCD);
+        assert_eq!(emulator.inspect(0xFF55, None), 0xFF);
+
+        emulator.edit(0xFF4F, None, 0).unwrap();
+        emulator.edit(0xFF70, None, 0).unwrap();
+        assert_eq!(emulator.inspect(0x8000, None), 0x00);
+        assert_eq!(emulator.inspect(0xD000, None), 0x00);
+        emulator.edit(0xFF70, None, 3).unwrap();
+        assert_eq!(emulator.inspect(0xF000, None), 0xCD);
+
+        // None of it exists on DMG
+        let mut emulator = Emulator::from_bytes(&rom[..0x143]);
+        emulator.update();
+        assert!(!emulator.is_cgb());
+        assert_eq!(emulator.inspect(0xFF4D, None) & 0x80, 0);
+    }
+
+    #[test]
+    fn cgb_banks_for_the_debugger() {
+        let mut rom = test_rom();
+        rom[0x143] = 0xC0;
+        let mut emulator = Emulator::from_bytes(&rom);
+        emulator.edit(0xFF4F, None, 1).unwrap();
+        emulator.edit(0xFF70, None, 3).unwrap();
+        assert_eq!(emulator.bank_at(0x8000), Some(1));
+        assert_eq!(emulator.bank_at(0xC000), None);
+        assert_eq!(emulator.bank_at(0xD000), Some(3));
+
+        // SVBK 0 still maps bank 1
+        emulator.edit(0xFF70, None, 0).unwrap();
+        assert_eq!(emulator.bank_at(0xDFFF), Some(1));
+
+        let emulator = Emulator::from_bytes(&test_rom());
+        assert_eq!(emulator.bank_at(0x8000), None);
+        assert_eq!(emulator.bank_at(0xD000), None);
+    }
+
+    #[test]
+    fn cgb_attributes_and_priority() {
+        let mut rom = test_rom();
+        rom[0x143] = 0xC0;
+        let mut emulator = Emulator::from_bytes(&rom);
+
+        let mut edit = |address: u16, data: &[u8]| {
+            for (i, byte) in data.iter().enumerate() {
+                emulator.edit(address + i as u16, None, *byte).unwrap();
+            }
+        };
+        // Background palette 0 colour 1 red, palette 2 colour 2 blue, object
+        // palette 1 colour 1 green and palette 2 colour 1 black
+        edit(0xFF68, &[0x80 | 2]);
+        edit(0xFF69, &[0x1F]);
+        edit(0xFF69, &[0x00]);
+        edit(0xFF68, &[0x80 | 20]);
+        edit(0xFF69, &[0x00]);
+        edit(0xFF69, &[0x7C]);
+        edit(0xFF6A, &[0x80 | 10]);
+        edit(0xFF6B, &[0xE0]);
+        edit(0xFF6B, &[0x03]);
+        edit(0xFF6A, &[0x80 | 18]);
+        edit(0xFF6B, &[0x00]);
+        edit(0xFF6B, &[0x00]);
+
+        // Tile 1 is all colour 1 in bank 0 and all colour 2 in bank 1
+        edit(0x8010, &[0xFF, 0x00]);
+        edit(0xFF4F, &[1]);
+        edit(0x8010, &[0x00, 0xFF]);
+        // Tiles 1, 1 from bank 1 in palette 2, 0, then 1 over sprites
+        edit(0x9800, &[0x00, 0x0A, 0x00, 0x80]);
+        edit(0xFF4F, &[0]);
+        edit(0x9800, &[0x01, 0x01, 0x00, 0x01]);
+
+        // Green sprites over tiles 0 and 2 to 3, then a black one overlapping
+        // the second from the left
+        edit(0xFE00, &[16, 8, 1, 0x01, 16, 28, 1, 0x01, 16, 24, 1, 0x02]);
+
+        emulator.edit(0xFF40, None, 0x93).unwrap();
+        emulator.draw_scanline();
+        let screen = emulator.screenshot();
+        let (red, green, blue, black) = ((0xFF, 0, 0), (0, 0xFF, 0), (0, 0, 0xFF), (0, 0, 0));
+        assert_eq!(screen.get(0, 0), green);
+        assert_eq!(screen.get(8, 0), blue);
+        assert_eq!(screen.get(16, 0), black);
+        // Lower OAM index wins, even though the other is further left
+        assert_eq!(screen.get(20, 0), green);
+        // The tile's priority bit puts it over the sprite
+        assert_eq!(screen.get(25, 0), red);
+
+        // Unless LCDC bit 0 takes priority away from the background
+        emulator.edit(0xFF40, None, 0x92).unwrap();
+        emulator.draw_scanline();
+        assert_eq!(emulator.screenshot().get(25, 0), green);
+        assert_eq!(emulator.screenshot().get(8, 0), blue);
+    }
+
+    #[test]
+    fn sgb_palettes_and_joypads() {
+        let mut rom = test_rom();
+        rom[0x146] = 0x03;
+        rom[0x14B] = 0x33;
+        let mut emulator = Emulator::from_bytes(&rom);
+        emulator.use_sgb_hardware();
+
+        let mut send = |packet: &[u8]| {
+            let mut bytes = [0; 16];
+            bytes[..packet.len()].copy_from_slice(packet);
+            emulator.edit(0xFF00, None, 0x00).unwrap();
+            emulator.edit(0xFF00, None, 0x30).unwrap();
+            for bit in 0..128 {
+                let one = (bytes[bit / 8] >> (bit % 8)) & 1 == 1;
+                emulator.edit(0xFF00, None, if one { 0x10 } else { 0x20 }).unwrap();
+                emulator.edit(0xFF00, None, 0x30).unwrap();
+            }
+            emulator.edit(0xFF00, None, 0x20).unwrap();
+            emulator.edit(0xFF00, None, 0x30).unwrap();
+        };
+        // PAL01 with red for colour 3 of palette 0, then two joypads
+        send(&[0x01, 0xFF, 0x7F, 0, 0, 0, 0, 0x1F, 0x00]);
+        send(&[0x89, 1]);
+
+        assert_eq!(emulator.inspect(0xFF00, None), 0xFF);
+        emulator.edit(0xFF00, None, 0x10).unwrap();
+        emulator.edit(0xFF00, None, 0x30).unwrap();
+        assert_eq!(emulator.inspect(0xFF00, None), 0xFE);
+        // The second player has nothing held
+        emulator.input_down(Inputs::A);
+        emulator.edit(0xFF00, None, 0x10).unwrap();
+        assert_eq!(emulator.inspect(0xFF00, None) & 0x0F, 0x0F);
+
+        // Tile 0 is all colour 3
+        emulator.edit(0x8000, None, 0xFF).unwrap();
+        emulator.edit(0x8001, None, 0xFF).unwrap();
+        emulator.edit(0xFF47, None, 0xE4).unwrap();
+        emulator.edit(0xFF40, None, 0x91).unwrap();
+        emulator.draw_scanline();
+        assert_eq!(emulator.screenshot().get(0, 0), (0xFF, 0, 0));
+
+        let frame = emulator.sgb_frame().unwrap();
+        assert_eq!((frame.width, frame.height), (SGB_WIDTH, SGB_HEIGHT));
+        assert_eq!(frame.get(48, 40), (0xFF, 0, 0));
+        assert_eq!(frame.get(0, 0), (0xFF, 0xFF, 0xFF));
+
+        // The SGB's side goes in save states too
+        let state = emulator.save_state();
+        emulator.reset();
+        emulator.load_state(&state).unwrap();
+        emulator.draw_scanline();
+        assert_eq!(emulator.screenshot().get(0, 0), (0xFF, 0, 0));
+
+        // Without the header flags the SGB doesn't listen
+        let mut emulator = Emulator::from_bytes(&test_rom());
+        emulator.use_sgb_hardware();
+        emulator.edit(0xFF40, None, 0x91).unwrap();
+        emulator.edit(0xFF47, None, 0xE4).unwrap();
+        emulator.edit(0x8000, None, 0xFF).unwrap();
+        emulator.edit(0x8001, None, 0xFF).unwrap();
+        emulator.draw_scanline();
+        assert_eq!(emulator.screenshot().get(0, 0), (0, 0, 0));
+        assert!(Emulator::from_bytes(&test_rom()).sgb_frame().is_none());
+    }
+
+    #[test]
+    fn load_state_rejects_bad_headers() {
+        let mut emulator = Emulator::from_bytes(&test_rom());
+        let state = emulator.save_state();
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] = b'X';
+        assert!(emulator.load_state(&bad_magic).is_err());
+
+        let mut bad_version = state.clone();
+        bad_version[4] = 0xFF;
+        assert!(emulator.load_state(&bad_version).is_err());
+
+        assert!(emulator.load_state(&state[..state.len() - 1]).is_err());
+
+        let mut other_rom = test_rom();
+        other_rom[0x14F] = 0x35;
+        assert!(Emulator::from_bytes(&other_rom).load_state(&state).is_err());
+    }
+
+    #[test]
+    fn failed_load_leaves_the_machine_alone() {
+        // MBC1, 128KB
+        let mut rom = test_rom();
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x02;
+        let mut emulator = Emulator::from_bytes(&rom);
+        emulator.update();
+        let bank_1 = emulator.save_state();
+        emulator.write_memory(0x2000, 5);
+        let mut state = emulator.save_state();
+
+        // The ROM bank is the only thing that changed
+        let offset = (0..state.len()).find(|&i| state[i] != bank_1[i]).unwrap();
+        assert_eq!(state[offset], 5);
+        state[offset..offset + 4].copy_from_slice(&[0xFF; 4]);
+
+        emulator.update();
+        emulator.update();
+        let expected = emulator.save_state();
+        assert!(emulator.load_state(&state).is_err());
+        assert_eq!(emulator.save_state(), expected);
+    }
+}
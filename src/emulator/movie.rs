@@ -0,0 +1,260 @@
+use super::Inputs;
+use super::state::{invalid_state, StateReader, StateWriter};
+
+use std::fs;
+use std::io;
+
+const MOVIE_MAGIC: &[u8; 4] = b"CBMV";
+const MOVIE_VERSION: u32 = 1;
+
+const VBM_MAGIC: &[u8; 4] = b"VBM\x1A";
+const VBM_FRAME_COUNT_ADDRESS: usize = 0x0C;
+const VBM_START_FLAGS_ADDRESS: usize = 0x14;
+const VBM_CONTROLLER_FLAGS_ADDRESS: usize = 0x15;
+const VBM_INPUT_OFFSET_ADDRESS: usize = 0x3C;
+
+pub enum MovieStart {
+    PowerOn,
+    SaveState(Vec<u8>),
+}
+
+// Inputs held on every frame from a known starting point, which is enough to
+// reproduce a run exactly
+pub struct Movie {
+    pub emulator_version: String,
+    // CRC-32 of the ROM it was recorded with, if known
+    pub rom_crc: Option<u32>,
+    pub start: MovieStart,
+    pub inputs: Vec<Inputs>,
+}
+
+impl Movie {
+    pub fn new(rom_crc: u32, start: MovieStart) -> Self {
+        Movie {
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            rom_crc: Some(rom_crc),
+            start,
+            inputs: Vec::new(),
+        }
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), io::Error> {
+        fs::write(filename, self.to_bytes())
+    }
+
+    // Loads our own movies, VBA .vbm files, and the "Input Log.txt" found
+    // inside BizHawk .bk2 archives
+    pub fn load(filename: &str) -> Result<Self, io::Error> {
+        let data = fs::read(filename)?;
+
+        if data.starts_with(MOVIE_MAGIC) {
+            Movie::from_bytes(&data)
+        } else if data.starts_with(VBM_MAGIC) {
+            Movie::from_vbm(&data)
+        } else if data.starts_with(b"[Input]") {
+            Movie::from_bk2_log(&String::from_utf8_lossy(&data))
+        } else {
+            Err(invalid_state("unrecognised movie format"))
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(MOVIE_MAGIC);
+        writer.write_u32(MOVIE_VERSION);
+
+        writer.write_u8(self.emulator_version.len() as u8);
+        writer.write_bytes(self.emulator_version.as_bytes());
+
+        writer.write_bool(self.rom_crc.is_some());
+        writer.write_u32(self.rom_crc.unwrap_or(0));
+
+        match &self.start {
+            MovieStart::PowerOn => writer.write_u8(0),
+            MovieStart::SaveState(state) => {
+                writer.write_u8(1);
+                writer.write_u32(state.len() as u32);
+                writer.write_bytes(state);
+            }
+        }
+
+        writer.write_u32(self.inputs.len() as u32);
+        for inputs in self.inputs.iter() {
+            writer.write_u8(inputs.bits());
+        }
+
+        writer.into_bytes()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, io::Error> {
+        let mut reader = StateReader::new(data);
+
+        let mut magic = [0; 4];
+        reader.read_bytes(&mut magic)?;
+        if &magic != MOVIE_MAGIC {
+            return Err(invalid_state("not a coolboy movie"));
+        }
+
+        let version = reader.read_u32()?;
+        if version != MOVIE_VERSION {
+            return Err(invalid_state(&format!(
+                "movie version {} is not supported (expected {})", version, MOVIE_VERSION)));
+        }
+
+        let mut emulator_version = vec![0; reader.read_u8()? as usize];
+        reader.read_bytes(&mut emulator_version)?;
+
+        let has_crc = reader.read_bool()?;
+        let crc = reader.read_u32()?;
+
+        let start = match reader.read_u8()? {
+            0 => MovieStart::PowerOn,
+            1 => {
+                let mut state = vec![0; reader.read_u32()? as usize];
+                reader.read_bytes(&mut state)?;
+                MovieStart::SaveState(state)
+            },
+            _ => return Err(invalid_state("unknown movie start"))
+        };
+
+        let mut inputs = vec![0; reader.read_u32()? as usize];
+        reader.read_bytes(&mut inputs)?;
+
+        Ok(Movie {
+            emulator_version: String::from_utf8_lossy(&emulator_version).into_owned(),
+            rom_crc: if has_crc { Some(crc) } else { None },
+            start,
+            inputs: inputs.into_iter().map(Inputs::from_bits_truncate).collect(),
+        })
+    }
+
+    fn from_vbm(data: &[u8]) -> Result<Self, io::Error> {
+        if data.len() < 0x40 {
+            return Err(invalid_state("VBM header is truncated"));
+        }
+
+        let read_u32 = |address: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&data[address..address + 4]);
+            u32::from_le_bytes(bytes) as usize
+        };
+
+        // Bit 0: starts from a VBA save state, bit 1: starts from SRAM
+        if data[VBM_START_FLAGS_ADDRESS] & 0b11 != 0 {
+            return Err(invalid_state("only VBM movies recorded from power on are supported"));
+        }
+
+        let frames = read_u32(VBM_FRAME_COUNT_ADDRESS);
+        let offset = read_u32(VBM_INPUT_OFFSET_ADDRESS);
+        // Each controller in use takes 2 bytes per frame, we only read the first
+        let controllers = (data[VBM_CONTROLLER_FLAGS_ADDRESS] & 0xF).count_ones().max(1) as usize;
+        let stride = controllers * 2;
+
+        if offset + frames * stride > data.len() {
+            return Err(invalid_state("VBM input data is truncated"));
+        }
+
+        let inputs = (0..frames).map(|frame| {
+            let bits = data[offset + frame * stride];
+            let mut inputs = Inputs::empty();
+            inputs.set(Inputs::A, tbit!(bits, 0));
+            inputs.set(Inputs::B, tbit!(bits, 1));
+            inputs.set(Inputs::SELECT, tbit!(bits, 2));
+            inputs.set(Inputs::START, tbit!(bits, 3));
+            inputs.set(Inputs::RIGHT, tbit!(bits, 4));
+            inputs.set(Inputs::LEFT, tbit!(bits, 5));
+            inputs.set(Inputs::UP, tbit!(bits, 6));
+            inputs.set(Inputs::DOWN, tbit!(bits, 7));
+            inputs
+        }).collect();
+
+        Ok(Movie {
+            emulator_version: "VisualBoyAdvance".to_string(),
+            rom_crc: None,
+            start: MovieStart::PowerOn,
+            inputs,
+        })
+    }
+
+    fn from_bk2_log(text: &str) -> Result<Self, io::Error> {
+        // The LogKey line names the columns, e.g. "LogKey:#Up|Down|...|Power|"
+        let key = text.lines()
+            .find(|line| line.starts_with("LogKey:"))
+            .ok_or_else(|| invalid_state("BizHawk input log has no LogKey"))?;
+
+        let columns: Vec<Option<Inputs>> = key["LogKey:".len()..]
+            .split(&['#', '|'][..])
+            .filter(|name| !name.is_empty())
+            .map(|name| match name {
+                "Up" => Some(Inputs::UP),
+                "Down" => Some(Inputs::DOWN),
+                "Left" => Some(Inputs::LEFT),
+                "Right" => Some(Inputs::RIGHT),
+                "Start" => Some(Inputs::START),
+                "Select" => Some(Inputs::SELECT),
+                "B" => Some(Inputs::B),
+                "A" => Some(Inputs::A),
+                _ => None
+            })
+            .collect();
+
+        // Each frame looks like "|U.......|", where '.' means not pressed
+        let inputs = text.lines()
+            .filter(|line| line.starts_with('|'))
+            .map(|line| {
+                let mut inputs = Inputs::empty();
+                let buttons = line.trim_matches('|').chars().filter(|c| *c != '|');
+                for (column, button) in columns.iter().zip(buttons) {
+                    if let Some(input) = column {
+                        inputs.set(*input, button != '.' && button != ' ');
+                    }
+                }
+                inputs
+            })
+            .collect();
+
+        Ok(Movie {
+            emulator_version: "BizHawk".to_string(),
+            rom_crc: None,
+            start: MovieStart::PowerOn,
+            inputs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{Movie, MovieStart};
+    use super::super::Inputs;
+
+    #[test]
+    fn round_trip() {
+        let mut movie = Movie::new(0xDEAD_BEEF, MovieStart::SaveState(vec![1, 2, 3]));
+        movie.inputs = vec![Inputs::empty(), Inputs::A | Inputs::UP, Inputs::START];
+
+        let loaded = Movie::from_bytes(&movie.to_bytes()).unwrap();
+        assert_eq!(loaded.emulator_version, movie.emulator_version);
+        assert_eq!(loaded.rom_crc, Some(0xDEAD_BEEF));
+        assert_eq!(loaded.inputs, movie.inputs);
+        match loaded.start {
+            MovieStart::SaveState(state) => assert_eq!(state, vec![1, 2, 3]),
+            MovieStart::PowerOn => panic!("Expected a save state start")
+        }
+
+        assert!(Movie::from_bytes(&movie.to_bytes()[..10]).is_err());
+    }
+
+    #[test]
+    fn bk2_log() {
+        let log = "[Input]\nLogKey:#Up|Down|Left|Right|Start|Select|B|A|Power|\n\
+                   |.........|\n|U......A.|\n|...RS....|\n[/Input]\n";
+
+        let movie = Movie::from_bk2_log(log).unwrap();
+        assert_eq!(movie.inputs, vec![
+            Inputs::empty(),
+            Inputs::UP | Inputs::A,
+            Inputs::RIGHT | Inputs::START,
+        ]);
+    }
+}
@@ -0,0 +1,161 @@
+// CGB colour palette RAM. Eight background and eight object palettes of four
+// 15-bit colours each, reached a byte at a time through an index register
+// (BCPS/OCPS) and a data register (BCPD/OCPD).
+
+use super::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
+pub const BCPS: usize = 0xFF68;
+pub const BCPD: usize = 0xFF69;
+pub const OCPS: usize = 0xFF6A;
+pub const OCPD: usize = 0xFF6B;
+
+// 8 palettes of 4 little endian colours
+const PALETTE_RAM_SIZE: usize = 64;
+
+struct PaletteRam {
+    data: [u8; PALETTE_RAM_SIZE],
+    index: u8,
+    // Move the index on after every write to the data register
+    auto_increment: bool,
+}
+
+impl PaletteRam {
+    fn new() -> Self {
+        // The boot ROM leaves everything white
+        PaletteRam {
+            data: [0xFF; PALETTE_RAM_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    fn read_index(&self) -> u8 {
+        // Bit 6 is unused and reads as 1
+        0x40 | ((self.auto_increment as u8) << 7) | self.index
+    }
+
+    fn write_index(&mut self, data: u8) {
+        self.index = data & 0x3F;
+        self.auto_increment = tbit!(data, 7);
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.data[self.index as usize] = data;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    fn color(&self, palette: u8, color_num: u8) -> u16 {
+        let offset = ((palette as usize & 0b111) * 4 + color_num as usize) * 2;
+        u16::from(self.data[offset]) | (u16::from(self.data[offset + 1]) << 8)
+    }
+}
+
+pub struct ColorPalettes {
+    background: PaletteRam,
+    objects: PaletteRam,
+}
+
+impl ColorPalettes {
+    pub fn new() -> Self {
+        ColorPalettes {
+            background: PaletteRam::new(),
+            objects: PaletteRam::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = ColorPalettes::new();
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        match address {
+            BCPS => self.background.read_index(),
+            BCPD => self.background.read_data(),
+            OCPS => self.objects.read_index(),
+            OCPD => self.objects.read_data(),
+            _ => 0xFF
+        }
+    }
+
+    pub fn write(&mut self, address: usize, data: u8) {
+        match address {
+            BCPS => self.background.write_index(data),
+            BCPD => self.background.write_data(data),
+            OCPS => self.objects.write_index(data),
+            OCPD => self.objects.write_data(data),
+            _ => ()
+        }
+    }
+
+    pub fn background_rgb(&self, palette: u8, color_num: u8) -> (u8, u8, u8) {
+        rgb15(self.background.color(palette, color_num))
+    }
+
+    pub fn object_rgb(&self, palette: u8, color_num: u8) -> (u8, u8, u8) {
+        rgb15(self.objects.color(palette, color_num))
+    }
+}
+
+// A CGB colour, 5 bits each of red, green and blue from the bottom, spread
+// over 8 bits so 0x1F comes out as 0xFF
+pub fn rgb15(color: u16) -> (u8, u8, u8) {
+    let scale = |value: u16| {
+        let value = (value & 0x1F) as u8;
+        (value << 3) | (value >> 2)
+    };
+    (scale(color), scale(color >> 5), scale(color >> 10))
+}
+
+impl Savestate for ColorPalettes {
+    fn save(&self, writer: &mut StateWriter) {
+        for ram in [&self.background, &self.objects].iter() {
+            writer.write_bytes(&ram.data);
+            writer.write_u8(ram.read_index());
+        }
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        for ram in [&mut self.background, &mut self.objects].iter_mut() {
+            reader.read_bytes(&mut ram.data)?;
+            ram.write_index(reader.read_u8()?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{ColorPalettes, BCPD, BCPS, OCPD, OCPS};
+
+    #[test]
+    fn auto_increment_and_colors() {
+        let mut palettes = ColorPalettes::new();
+        assert_eq!(palettes.background_rgb(3, 2), (0xFF, 0xFF, 0xFF));
+
+        // Background palette 1, colour 1 onwards
+        palettes.write(BCPS, 0x80 | 10);
+        for data in [0x1F, 0x00, 0xE0, 0x03].iter() {
+            palettes.write(BCPD, *data);
+        }
+        assert_eq!(palettes.read(BCPS), 0xC0 | 14);
+        assert_eq!(palettes.background_rgb(1, 1), (0xFF, 0x00, 0x00));
+        assert_eq!(palettes.background_rgb(1, 2), (0x00, 0xFF, 0x00));
+
+        // Without auto increment every write lands in the same place
+        palettes.write(OCPS, 0x3F);
+        palettes.write(OCPD, 0x7C);
+        palettes.write(OCPD, 0x3C);
+        assert_eq!(palettes.read(OCPS), 0x7F);
+        assert_eq!(palettes.read(OCPD), 0x3C);
+        assert_eq!(palettes.object_rgb(7, 3), (0xFF, 0x39, 0x7B));
+    }
+}
@@ -0,0 +1,529 @@
+use super::crc::crc32;
+use super::state::invalid_state;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+// Largest stored deflate block
+const MAX_BLOCK_SIZE: usize = 0xFFFF;
+// Longest Huffman code deflate allows
+const MAX_CODE_LENGTH: usize = 15;
+
+// Lengths and distances for deflate's back references: the smallest value
+// for each symbol, and how many extra bits are added to it
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u32; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u32; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// The order a dynamic block lists its code length code's lengths in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+// An RGB image, 3 bytes per pixel with rows top to bottom
+#[derive(Clone)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![0; width * height * 3],
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+        let index = (y * self.width + x) * 3;
+        self.pixels[index] = rgb.0;
+        self.pixels[index + 1] = rgb.1;
+        self.pixels[index + 2] = rgb.2;
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let index = (y * self.width + x) * 3;
+        (self.pixels[index], self.pixels[index + 1], self.pixels[index + 2])
+    }
+
+    // Copies another image in with its top left corner at (x, y)
+    pub fn blit(&mut self, image: &Image, x: usize, y: usize) {
+        for row in 0..image.height.min(self.height.saturating_sub(y)) {
+            for column in 0..image.width.min(self.width.saturating_sub(x)) {
+                self.set(x + column, y + row, image.get(column, row));
+            }
+        }
+    }
+
+    pub fn save_png(&self, filename: &Path) -> Result<(), io::Error> {
+        fs::write(filename, self.to_png())
+    }
+
+    pub fn load_png(filename: &Path) -> Result<Self, io::Error> {
+        Image::from_png(&fs::read(filename)?)
+    }
+
+    // Reads any non-interlaced PNG of up to 8 bits a channel, which covers
+    // the test suites' reference images. Alpha is dropped.
+    pub fn from_png(png: &[u8]) -> Result<Self, io::Error> {
+        if !png.starts_with(SIGNATURE) {
+            return Err(invalid_state("not a PNG"));
+        }
+
+        let mut header = Vec::new();
+        let mut palette = Vec::new();
+        let mut compressed = Vec::new();
+        let mut rest = &png[SIGNATURE.len()..];
+        while rest.len() >= 12 {
+            let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let data = rest.get(8..8 + length).ok_or_else(|| invalid_state("PNG is truncated"))?;
+            match &rest[4..8] {
+                b"IHDR" => header = data.to_vec(),
+                b"PLTE" => palette = data.to_vec(),
+                b"IDAT" => compressed.extend_from_slice(data),
+                b"IEND" => break,
+                _ => {}
+            }
+            rest = rest.get(length + 12..).unwrap_or(&[]);
+        }
+
+        if header.len() != 13 || compressed.len() < 2 {
+            return Err(invalid_state("PNG has no image"));
+        }
+        let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let (depth, color_type) = (header[8] as usize, header[9]);
+        let channels = match color_type {
+            0 | 3 => 1,
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => return Err(invalid_state("unknown PNG colour type"))
+        };
+        if depth > 8 || (depth < 8 && channels > 1) || header[12] != 0 {
+            return Err(invalid_state("PNG is 16 bit or interlaced"));
+        }
+
+        // Skip the zlib header, and don't bother with the checksum
+        let raw = inflate(&compressed[2..])?;
+        let stride = (width * channels * depth).div_ceil(8);
+        // Filters look back one pixel, or one byte when pixels are smaller
+        let pixel_size = (channels * depth).div_ceil(8);
+        if raw.len() < (stride + 1) * height {
+            return Err(invalid_state("PNG image data is truncated"));
+        }
+
+        let mut image = Image::new(width, height);
+        let mut previous = vec![0; stride];
+        for (y, line) in raw.chunks(stride + 1).take(height).enumerate() {
+            let mut row = line[1..].to_vec();
+            unfilter(line[0], &mut row, &previous, pixel_size)?;
+
+            for x in 0..width {
+                let sample = |channel: usize| if depth == 8 {
+                    row[x * channels + channel]
+                } else {
+                    let bit = x * depth;
+                    (row[bit / 8] >> (8 - depth - bit % 8)) & ((1 << depth) - 1) as u8
+                };
+
+                let rgb = match color_type {
+                    0 | 4 => {
+                        // Scale smaller samples up to 0-255
+                        let grey = sample(0) * (255 / ((1 << depth) - 1)) as u8;
+                        (grey, grey, grey)
+                    },
+                    3 => {
+                        let index = sample(0) as usize * 3;
+                        let color = palette.get(index..index + 3).ok_or_else(|| invalid_state("PNG colour isn't in the palette"))?;
+                        (color[0], color[1], color[2])
+                    },
+                    _ => (sample(0), sample(1), sample(2))
+                };
+                image.set(x, y, rgb);
+            }
+            previous = row;
+        }
+        Ok(image)
+    }
+
+    // Uncompressed, which is plenty for debug views and saves needing deflate
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut png = SIGNATURE.to_vec();
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, truecolour, default compression, filter and no interlacing
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(&mut png, b"IHDR", &header);
+
+        // Every row starts with its filter type, 0 for none
+        let mut raw = Vec::with_capacity((self.width * 3 + 1) * self.height);
+        for row in self.pixels.chunks(self.width * 3) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// A zlib stream made of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, no preset dictionary
+    let mut output = vec![0x78, 0x01];
+
+    let blocks: Vec<&[u8]> = data.chunks(MAX_BLOCK_SIZE).collect();
+    if blocks.is_empty() {
+        output.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    for (index, block) in blocks.iter().enumerate() {
+        let last = index + 1 == blocks.len();
+        let length = block.len() as u16;
+        output.push(last as u8);
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&(!length).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+// Undoes a row's filter, given the row above already unfiltered
+fn unfilter(filter: u8, row: &mut [u8], previous: &[u8], pixel_size: usize) -> Result<(), io::Error> {
+    for i in 0..row.len() {
+        let left = if i >= pixel_size { row[i - pixel_size] } else { 0 };
+        let up = previous[i];
+        let up_left = if i >= pixel_size { previous[i - pixel_size] } else { 0 };
+        let predicted = match filter {
+            0 => 0,
+            1 => left,
+            2 => up,
+            3 => ((u16::from(left) + u16::from(up)) / 2) as u8,
+            4 => paeth(left, up, up_left),
+            _ => return Err(invalid_state("unknown PNG filter"))
+        };
+        row[i] = row[i].wrapping_add(predicted);
+    }
+    Ok(())
+}
+
+// Whichever neighbour is closest to left + up - up_left
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = i16::from(left) + i16::from(up) - i16::from(up_left);
+    let distance = |value: u8| (estimate - i16::from(value)).abs();
+    if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+        left
+    } else if distance(up) <= distance(up_left) {
+        up
+    } else {
+        up_left
+    }
+}
+
+// Reads a deflate stream a bit at a time, lowest bit of each byte first
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bit(&mut self) -> Result<u16, io::Error> {
+        let byte = self.data.get(self.position / 8).ok_or_else(|| invalid_state("deflate stream is truncated"))?;
+        let bit = (byte >> (self.position % 8)) & 1;
+        self.position += 1;
+        Ok(u16::from(bit))
+    }
+
+    fn bits(&mut self, count: u32) -> Result<usize, io::Error> {
+        let mut value = 0;
+        for i in 0..count {
+            value |= usize::from(self.bit()?) << i;
+        }
+        Ok(value)
+    }
+
+    // Stored blocks start on a byte
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+}
+
+// A canonical Huffman code, as the number of codes of each length and the
+// symbols in code order
+struct Huffman {
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; MAX_CODE_LENGTH + 1];
+        for length in lengths {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Where each length's symbols start
+        let mut offsets = [0; MAX_CODE_LENGTH + 1];
+        for length in 1..MAX_CODE_LENGTH {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate().filter(|(_, length)| **length > 0) {
+            symbols[offsets[*length as usize] as usize] = symbol as u16;
+            offsets[*length as usize] += 1;
+        }
+        Huffman { counts, symbols }
+    }
+
+    // Codes of each length follow on from the last of the length before
+    fn decode(&self, reader: &mut BitReader) -> Result<usize, io::Error> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for length in 1..=MAX_CODE_LENGTH {
+            code |= usize::from(reader.bit()?);
+            let count = usize::from(self.counts[length]);
+            if code < first + count {
+                return Ok(usize::from(self.symbols[index + code - first]));
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid_state("bad Huffman code in deflate stream"))
+    }
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut reader = BitReader { data, position: 0 };
+    let mut output = Vec::new();
+
+    loop {
+        let last = reader.bit()? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let length = reader.bits(16)?;
+                // Then the length again, inverted
+                reader.bits(16)?;
+                for _ in 0..length {
+                    output.push(reader.bits(8)? as u8);
+                }
+            },
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                inflate_block(&mut reader, &mut output, &Huffman::new(&lengths), &Huffman::new(&[5; 30]))?;
+            },
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            },
+            _ => return Err(invalid_state("unknown deflate block type"))
+        }
+
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+// The literal/length and distance codes a dynamic block starts with, which
+// are themselves Huffman coded
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), io::Error> {
+    let literal_count = reader.bits(5)? + 257;
+    let distance_count = reader.bits(5)? + 1;
+    let code_length_count = reader.bits(4)? + 4;
+
+    let mut code_lengths = [0; 19];
+    for index in CODE_LENGTH_ORDER.iter().take(code_length_count) {
+        code_lengths[*index] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::new();
+    while lengths.len() < literal_count + distance_count {
+        let (length, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last().ok_or_else(|| invalid_state("nothing to repeat in deflate code lengths"))?, 3 + reader.bits(2)?),
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(length, repeat));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(invalid_state("too many deflate code lengths"));
+    }
+
+    Ok((Huffman::new(&lengths[..literal_count]), Huffman::new(&lengths[literal_count..])))
+}
+
+// Literals and back references up to the end of block symbol
+fn inflate_block(reader: &mut BitReader, output: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), io::Error> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        if symbol < 256 {
+            output.push(symbol as u8);
+            continue;
+        } else if symbol == 256 {
+            return Ok(());
+        }
+
+        let index = symbol - 257;
+        if index >= LENGTH_BASE.len() {
+            return Err(invalid_state("bad length in deflate stream"));
+        }
+        let length = LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index])?;
+
+        let index = distances.decode(reader)?;
+        if index >= DISTANCE_BASE.len() {
+            return Err(invalid_state("bad distance in deflate stream"));
+        }
+        let distance = DISTANCE_BASE[index] as usize + reader.bits(DISTANCE_EXTRA[index])?;
+        if distance > output.len() {
+            return Err(invalid_state("deflate stream refers back too far"));
+        }
+
+        // Copied a byte at a time, since it can overlap what it's writing
+        let start = output.len() - distance;
+        for i in 0..length {
+            output.push(output[start + i]);
+        }
+    }
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + u32::from(*byte)) % MODULUS;
+        b = (b + a) % MODULUS;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{adler32, inflate, write_chunk, zlib_stored, Image, SIGNATURE};
+
+    #[test]
+    fn adler() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn stored_blocks() {
+        let data = vec![7; 70000];
+        let stream = zlib_stored(&data);
+        // Header, two block headers, the data and the checksum
+        assert_eq!(stream.len(), 2 + 5 * 2 + 70000 + 4);
+        assert_eq!(stream[2], 0);
+        assert_eq!(stream[2 + 5 + 0xFFFF], 1);
+    }
+
+    #[test]
+    fn png_layout() {
+        let mut image = Image::new(2, 1);
+        image.set(1, 0, (0xFF, 0x80, 0x00));
+        let png = image.to_png();
+
+        assert_eq!(&png[..8], SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+        // IEND always has the same CRC
+        assert_eq!(&png[png.len() - 4..], &[0xAE, 0x42, 0x60, 0x82]);
+    }
+
+    // A PNG as written by to_png, but with any header and rows
+    fn png(header: &[u8], palette: &[u8], raw: &[u8]) -> Vec<u8> {
+        let mut png = SIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", header);
+        if !palette.is_empty() {
+            write_chunk(&mut png, b"PLTE", palette);
+        }
+        write_chunk(&mut png, b"IDAT", &zlib_stored(raw));
+        write_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    #[test]
+    fn inflate_huffman_blocks() {
+        // Fixed codes with back references, straight out of zlib
+        let fixed = [
+            0x78, 0xDA, 0x4B, 0xCE, 0xCF, 0xCF, 0x49, 0xCA, 0xAF, 0x54, 0x48, 0xC6, 0x41, 0x03, 0x00, 0xCF,
+            0x68, 0x0C, 0x5D,
+        ];
+        assert_eq!(inflate(&fixed[2..]).unwrap(), b"coolboy ".repeat(4));
+
+        // Dynamic codes, from zlib's Huffman only strategy
+        let dynamic = [
+            0x78, 0x01, 0x05, 0xC1, 0xB1, 0x0D, 0x00, 0x30, 0x08, 0xC0, 0xB0, 0x57, 0x38, 0xA0, 0x4F, 0x75,
+            0x60, 0xC8, 0x80, 0x84, 0x08, 0xEA, 0xFD, 0xB5, 0x0B, 0x45, 0xE9, 0x26, 0x86, 0x97, 0x73, 0xA2,
+            0x50, 0x94, 0x6E, 0xC2, 0xBD, 0x9B, 0x1F, 0x0B, 0x5F, 0x0E, 0x50,
+        ];
+        assert_eq!(inflate(&dynamic[2..]).unwrap(), b"mississippi river, mississippi state");
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let mut image = Image::new(3, 2);
+        image.set(0, 0, (0xFF, 0x80, 0x00));
+        image.set(2, 1, (0x12, 0x34, 0x56));
+        let loaded = Image::from_png(&image.to_png()).unwrap();
+
+        assert_eq!((loaded.width, loaded.height), (3, 2));
+        assert_eq!(loaded.pixels, image.pixels);
+        assert!(Image::from_png(&image.to_png()[..40]).is_err());
+    }
+
+    #[test]
+    fn png_filters_and_palettes() {
+        // 2x4 RGB with the sub, up, average and Paeth filters in turn
+        let header = [0, 0, 0, 2, 0, 0, 0, 4, 8, 2, 0, 0, 0];
+        let raw = [
+            1, 10, 20, 30, 30, 30, 30,
+            2, 5, 5, 5, 5, 5, 5,
+            3, 193, 88, 239, 134, 23, 168,
+            4, 57, 158, 3, 250, 249, 52,
+        ];
+        let image = Image::from_png(&png(&header, &[], &raw)).unwrap();
+        assert_eq!(image.get(1, 0), (40, 50, 60));
+        assert_eq!(image.get(1, 1), (45, 55, 65));
+        assert_eq!(image.get(0, 2), (200, 100, 0));
+        assert_eq!(image.get(1, 3), (250, 251, 252));
+
+        // Four 2 bit pixels in one byte, through a palette and as grey
+        let palette = [0, 0, 0, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0xFF, 0xFF, 0xFF];
+        let image = Image::from_png(&png(&[0, 0, 0, 4, 0, 0, 0, 1, 2, 3, 0, 0, 0], &palette, &[0, 0b00_01_10_11])).unwrap();
+        assert_eq!((image.get(0, 0), image.get(2, 0)), ((0, 0, 0), (0x40, 0x50, 0x60)));
+        let image = Image::from_png(&png(&[0, 0, 0, 4, 0, 0, 0, 1, 2, 0, 0, 0, 0], &[], &[0, 0b00_01_10_11])).unwrap();
+        assert_eq!((image.get(1, 0), image.get(3, 0)), ((0x55, 0x55, 0x55), (0xFF, 0xFF, 0xFF)));
+    }
+}
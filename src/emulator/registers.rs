@@ -1,3 +1,7 @@
+use super::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
 bitflags! {
     pub struct Flags: u8 {
         const ZERO = 0b1000_0000;
@@ -7,6 +11,7 @@ bitflags! {
     }
 }
 
+#[derive(Clone)]
 pub struct Registers {
     pub a: u8,
     pub b: u8,
@@ -94,6 +99,23 @@ impl Registers {
     }
 }
 
+impl Savestate for Registers {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.af());
+        writer.write_u16(self.bc());
+        writer.write_u16(self.de());
+        writer.write_u16(self.hl());
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.set_af(reader.read_u16()?);
+        self.set_bc(reader.read_u16()?);
+        self.set_de(reader.read_u16()?);
+        self.set_hl(reader.read_u16()?);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
 
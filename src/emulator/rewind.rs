@@ -0,0 +1,313 @@
+use super::Inputs;
+
+use std::collections::VecDeque;
+
+// Zero runs shorter than this are cheaper to leave in the literal data
+const MIN_ZERO_RUN: usize = 4;
+
+pub struct RewindConfig {
+    // Frames between snapshots; frames in between are rebuilt from recorded inputs
+    pub interval: u32,
+    // A full keyframe is stored every this many snapshots, the rest are XOR
+    // deltas against the keyframe before them
+    pub keyframe_interval: u32,
+    // Upper bound on the compressed snapshot data, in bytes
+    pub budget: usize,
+}
+
+impl RewindConfig {
+    pub fn new() -> Self {
+        RewindConfig {
+            interval: 10,
+            keyframe_interval: 30,
+            budget: 32 * 1024 * 1024,
+        }
+    }
+}
+
+struct Snapshot {
+    frame: u64,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+pub struct RewindBuffer {
+    config: RewindConfig,
+    snapshots: VecDeque<Snapshot>,
+    used: usize,
+
+    // Uncompressed copy of the newest keyframe, which new deltas are taken against
+    keyframe: Option<Vec<u8>>,
+    since_keyframe: u32,
+
+    // Inputs held at the start of each frame, from inputs_start onwards
+    inputs: VecDeque<Inputs>,
+    inputs_start: u64,
+}
+
+impl RewindBuffer {
+    pub fn new(config: RewindConfig) -> Self {
+        RewindBuffer {
+            config,
+            snapshots: VecDeque::new(),
+            used: 0,
+            keyframe: None,
+            since_keyframe: 0,
+            inputs: VecDeque::new(),
+            inputs_start: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.used = 0;
+        self.keyframe = None;
+        self.since_keyframe = 0;
+        self.inputs.clear();
+    }
+
+    // Called at the start of every frame, before it's emulated. The snapshot
+    // closure is only run when this frame needs one.
+    pub fn record<F: FnOnce() -> Vec<u8>>(&mut self, frame: u64, inputs: Inputs, snapshot: F) {
+        // Anything that breaks the chain of frames makes the history useless
+        if !self.inputs.is_empty() && frame != self.inputs_start + self.inputs.len() as u64 {
+            self.clear();
+        }
+
+        if self.inputs.is_empty() {
+            self.inputs_start = frame;
+        }
+
+        let due = match self.snapshots.back() {
+            Some(last) => frame >= last.frame + u64::from(self.config.interval),
+            None => true
+        };
+
+        if due {
+            self.push_snapshot(frame, snapshot());
+        }
+
+        self.inputs.push_back(inputs);
+    }
+
+    fn push_snapshot(&mut self, frame: u64, state: Vec<u8>) {
+        let delta = match &self.keyframe {
+            Some(keyframe) => self.since_keyframe < self.config.keyframe_interval && keyframe.len() == state.len(),
+            None => false
+        };
+
+        let snapshot = if delta {
+            let keyframe = self.keyframe.as_ref().unwrap();
+            let xor: Vec<u8> = state.iter().zip(keyframe.iter()).map(|(a, b)| a ^ b).collect();
+            self.since_keyframe += 1;
+            Snapshot { frame, keyframe: false, data: compress(&xor) }
+        } else {
+            let data = compress(&state);
+            self.keyframe = Some(state);
+            self.since_keyframe = 0;
+            Snapshot { frame, keyframe: true, data }
+        };
+
+        self.used += snapshot.data.len();
+        self.snapshots.push_back(snapshot);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        // Deltas are useless without their keyframe, so whole groups are
+        // dropped at once. The newest group always stays.
+        while self.used > self.config.budget {
+            let next_keyframe = self.snapshots.iter().skip(1).position(|snapshot| snapshot.keyframe);
+            let group = match next_keyframe {
+                Some(position) => position + 1,
+                None => break
+            };
+
+            for snapshot in self.snapshots.drain(..group) {
+                self.used -= snapshot.data.len();
+            }
+        }
+
+        if let Some(oldest) = self.snapshots.front() {
+            while self.inputs_start < oldest.frame && !self.inputs.is_empty() {
+                self.inputs.pop_front();
+                self.inputs_start += 1;
+            }
+        }
+    }
+
+    fn decode(&self, index: usize) -> Vec<u8> {
+        let keyframe_index = (0..=index).rev()
+            .find(|i| self.snapshots[*i].keyframe)
+            .expect("Rewind buffer has a delta with no keyframe!");
+
+        let keyframe = &self.snapshots[keyframe_index];
+        let mut state = decompress(&keyframe.data);
+
+        if keyframe_index != index {
+            let delta = decompress(&self.snapshots[index].data);
+            for (byte, xor) in state.iter_mut().zip(delta.iter()) {
+                *byte ^= xor;
+            }
+        }
+
+        state
+    }
+
+    // Finds the newest snapshot at or before the target frame (or the oldest
+    // one if the target is too far back) and drops all history after it.
+    // Returns the snapshot's frame, its state, and the inputs to replay to get
+    // from it to the target.
+    pub fn seek(&mut self, target: u64) -> Option<(u64, Vec<u8>, Vec<Inputs>)> {
+        let index = match self.snapshots.iter().rposition(|snapshot| snapshot.frame <= target) {
+            Some(index) => index,
+            None if !self.snapshots.is_empty() => 0,
+            None => return None
+        };
+
+        let frame = self.snapshots[index].frame;
+        let target = target.max(frame);
+        let state = self.decode(index);
+
+        let first = (frame - self.inputs_start) as usize;
+        let last = ((target - self.inputs_start) as usize).min(self.inputs.len());
+        let replay = self.inputs.range(first..last).cloned().collect();
+
+        // The replayed frames record themselves again, so forget them now
+        for snapshot in self.snapshots.drain(index + 1..) {
+            self.used -= snapshot.data.len();
+        }
+        self.inputs.truncate(first);
+
+        match self.snapshots.iter().rposition(|snapshot| snapshot.keyframe) {
+            Some(keyframe_index) => {
+                self.keyframe = Some(self.decode(keyframe_index));
+                self.since_keyframe = (self.snapshots.len() - 1 - keyframe_index) as u32;
+            },
+            None => {
+                self.keyframe = None;
+                self.since_keyframe = 0;
+            }
+        }
+
+        Some((frame, state, replay))
+    }
+}
+
+// Run-length encodes runs of zeros, which is most of an XOR delta. The output
+// is a series of (zero run, literal length, literal bytes) with varint lengths.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let zeros_start = i;
+        while i < data.len() && data[i] == 0 {
+            i += 1;
+        }
+        let zeros = i - zeros_start;
+
+        let literal_start = i;
+        while i < data.len() {
+            let run = data[i..].iter().take(MIN_ZERO_RUN).take_while(|byte| **byte == 0).count();
+            if run == MIN_ZERO_RUN || i + run == data.len() {
+                break;
+            }
+            i += 1;
+        }
+
+        write_varint(&mut output, zeros);
+        write_varint(&mut output, i - literal_start);
+        output.extend_from_slice(&data[literal_start..i]);
+    }
+
+    output
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let zeros = read_varint(data, &mut i);
+        let literals = read_varint(data, &mut i);
+
+        output.resize(output.len() + zeros, 0);
+        output.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+
+    output
+}
+
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{compress, decompress, RewindBuffer, RewindConfig};
+    use super::super::Inputs;
+
+    #[test]
+    fn compress_round_trip() {
+        let mut data = vec![0; 5000];
+        data[3] = 1;
+        data[4] = 2;
+        data[6] = 3;
+        data[4000] = 0xFF;
+        data[4999] = 7;
+
+        let compressed = compress(&data);
+        assert!(compressed.len() < 32);
+        assert_eq!(decompress(&compressed), data);
+
+        let noise: Vec<u8> = (0..1000).map(|i| (i * 7 + 3) as u8).collect();
+        assert_eq!(decompress(&compress(&noise)), noise);
+        assert!(decompress(&compress(&[])).is_empty());
+    }
+
+    #[test]
+    fn budget_drops_oldest_groups() {
+        let mut config = RewindConfig::new();
+        config.interval = 1;
+        config.keyframe_interval = 2;
+        config.budget = 2000;
+        let mut buffer = RewindBuffer::new(config);
+
+        for frame in 0..30 {
+            let state: Vec<u8> = (0..1000).map(|i| (i as u64 * frame) as u8 | 1).collect();
+            buffer.record(frame, Inputs::empty(), || state);
+        }
+
+        assert!(buffer.snapshots.len() <= 3);
+        assert!(buffer.snapshots.front().unwrap().keyframe);
+        assert_eq!(buffer.inputs_start, buffer.snapshots.front().unwrap().frame);
+
+        let (frame, state, replay) = buffer.seek(28).unwrap();
+        assert_eq!(frame, 28);
+        assert!(replay.is_empty());
+        assert_eq!(state[1], 28 | 1);
+    }
+}
@@ -0,0 +1,261 @@
+// The link port. SB (0xFF01) is the shift register and SC (0xFF02) starts a
+// transfer and picks whose clock drives it.
+
+use super::CLOCK_SPEED;
+use super::state::{Savestate, StateReader, StateWriter};
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+pub const SERIAL_DATA: usize = 0xFF01;
+pub const SERIAL_CONTROL: usize = 0xFF02;
+
+// SC bits: transfer running, and whether we're the clock
+const TRANSFER: u8 = 0x80;
+const INTERNAL_CLOCK: u8 = 0x01;
+// Unused SC bits read back as 1
+const CONTROL_MASK: u8 = 0x7E;
+
+// The internal clock shifts a bit out at 8192 Hz
+const CYCLES_PER_BIT: u32 = CLOCK_SPEED / 8192;
+
+// Whatever's on the other end of the cable. Bytes go across whole, when the
+// last bit is shifted.
+pub trait SerialDevice {
+    // We're driving the clock: sends a byte and returns the one shifted in
+    fn exchange(&mut self, data: u8) -> u8;
+
+    // Asked while we're waiting on the other end's clock with a byte in SB.
+    // Returns the byte it sent once it has clocked a whole one across.
+    fn poll(&mut self, _data: u8) -> Option<u8> {
+        None
+    }
+}
+
+// No cable plugged in: the line floats high
+pub struct NullDevice;
+
+impl SerialDevice for NullDevice {
+    fn exchange(&mut self, _data: u8) -> u8 {
+        0xFF
+    }
+}
+
+// No cable either, but keeps every byte sent and logs them a line at a time.
+// Test ROMs like Blargg's print their results this way.
+pub struct CaptureDevice {
+    sent: Rc<RefCell<Vec<u8>>>,
+    line: String,
+}
+
+impl CaptureDevice {
+    pub fn new() -> Self {
+        CaptureDevice {
+            sent: Rc::new(RefCell::new(Vec::new())),
+            line: String::new(),
+        }
+    }
+
+    // Everything sent so far. Shared, so it can still be read once the
+    // device is plugged in.
+    pub fn sent(&self) -> Rc<RefCell<Vec<u8>>> {
+        self.sent.clone()
+    }
+}
+
+impl SerialDevice for CaptureDevice {
+    fn exchange(&mut self, data: u8) -> u8 {
+        self.sent.borrow_mut().push(data);
+
+        match data {
+            b'\n' => {
+                info!("Serial: {}", self.line);
+                self.line.clear();
+            },
+            0x20..=0x7E => self.line.push(data as char),
+            _ => ()
+        }
+        0xFF
+    }
+}
+
+pub struct Serial {
+    data: u8,
+    control: u8,
+    // The byte being sent, since SB shifts it out as it goes
+    outgoing: u8,
+    // Bits still to shift in the current transfer, on the internal clock
+    bits_left: u8,
+    // Cycles until the next one
+    counter: u32,
+
+    device: Box<dyn SerialDevice>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            outgoing: 0,
+            bits_left: 0,
+            counter: 0,
+            device: Box::new(NullDevice),
+        }
+    }
+
+    // Back to power on. The device stays plugged in.
+    pub fn reset(&mut self) {
+        self.data = 0;
+        self.control = 0;
+        self.outgoing = 0;
+        self.bits_left = 0;
+        self.counter = 0;
+    }
+
+    // Returns whatever was plugged in before
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, device)
+    }
+
+    pub fn read(&self, address: usize) -> u8 {
+        match address {
+            SERIAL_DATA => self.data,
+            _ => self.control | CONTROL_MASK
+        }
+    }
+
+    pub fn write(&mut self, address: usize, data: u8) {
+        match address {
+            SERIAL_DATA => self.data = data,
+            _ => {
+                self.control = data & !CONTROL_MASK;
+                if self.control == TRANSFER | INTERNAL_CLOCK {
+                    self.outgoing = self.data;
+                    self.bits_left = 8;
+                    self.counter = CYCLES_PER_BIT;
+                } else {
+                    self.bits_left = 0;
+                }
+            }
+        }
+    }
+
+    // Returns true when a transfer finishes, which requests the serial interrupt
+    pub fn step(&mut self, cycles: u32) -> bool {
+        if self.control & TRANSFER == 0 {
+            return false;
+        }
+
+        if self.control & INTERNAL_CLOCK == 0 {
+            return match self.device.poll(self.data) {
+                Some(data) => {
+                    self.finish(data);
+                    true
+                },
+                None => false
+            };
+        }
+
+        let mut finished = false;
+        let mut cycles = cycles;
+        while self.bits_left > 0 && cycles >= self.counter {
+            cycles -= self.counter;
+            self.counter = CYCLES_PER_BIT;
+            self.bits_left -= 1;
+
+            if self.bits_left == 0 {
+                let received = self.device.exchange(self.outgoing);
+                self.finish(received);
+                finished = true;
+            } else {
+                // Shift in 1s until the other end's byte arrives whole
+                self.data = self.data << 1 | 1;
+            }
+        }
+        if self.bits_left > 0 {
+            self.counter -= cycles;
+        }
+        finished
+    }
+
+    fn finish(&mut self, received: u8) {
+        self.data = received;
+        self.control &= !TRANSFER;
+        self.bits_left = 0;
+    }
+}
+
+impl Savestate for Serial {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.control);
+        writer.write_u8(self.outgoing);
+        writer.write_u8(self.bits_left);
+        writer.write_u32(self.counter);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        self.data = reader.read_u8()?;
+        self.control = reader.read_u8()?;
+        self.outgoing = reader.read_u8()?;
+        self.bits_left = reader.read_u8()?;
+        self.counter = reader.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{CaptureDevice, Serial, SerialDevice, CYCLES_PER_BIT, SERIAL_CONTROL, SERIAL_DATA};
+
+    #[test]
+    fn internal_clock_transfer() {
+        let mut serial = Serial::new();
+        let capture = CaptureDevice::new();
+        let sent = capture.sent();
+        serial.set_device(Box::new(capture));
+
+        serial.write(SERIAL_DATA, b'P');
+        serial.write(SERIAL_CONTROL, 0x81);
+        assert_eq!(serial.read(SERIAL_CONTROL), 0xFF);
+
+        // Bits go out one at a time, with 1s coming back in
+        assert!(!serial.step(CYCLES_PER_BIT * 3));
+        assert_eq!(serial.read(SERIAL_DATA), b'P' << 3 | 0b111);
+        assert!(!serial.step(CYCLES_PER_BIT * 5 - 4));
+        assert!(serial.step(4));
+
+        assert_eq!(*sent.borrow(), b"P");
+        assert_eq!(serial.read(SERIAL_DATA), 0xFF);
+        assert_eq!(serial.read(SERIAL_CONTROL), 0x7F);
+    }
+
+    #[test]
+    fn external_clock_waits_for_the_other_end() {
+        struct Sender(Option<u8>);
+
+        impl SerialDevice for Sender {
+            fn exchange(&mut self, _data: u8) -> u8 {
+                0xFF
+            }
+
+            fn poll(&mut self, _data: u8) -> Option<u8> {
+                self.0.take()
+            }
+        }
+
+        let mut serial = Serial::new();
+        serial.write(SERIAL_DATA, 0x12);
+        serial.write(SERIAL_CONTROL, 0x80);
+        assert!(!serial.step(CYCLES_PER_BIT * 100));
+        assert_eq!(serial.read(SERIAL_DATA), 0x12);
+
+        serial.set_device(Box::new(Sender(Some(0x34))));
+        assert!(serial.step(4));
+        assert_eq!(serial.read(SERIAL_DATA), 0x34);
+        assert!(!serial.step(CYCLES_PER_BIT * 8));
+    }
+}
@@ -0,0 +1,647 @@
+// Super Game Boy. Games talk to the SNES side by sending 16 byte packets a
+// bit at a time through P14/P15 of the joypad register, and send anything
+// bigger (palettes, border tiles) by putting it on screen for a frame. What
+// comes back is four palettes picked per 8x8 cell, a 256x224 border around
+// the screen and up to four joypads.
+
+use super::palettes::rgb15;
+use super::png::Image;
+use super::state::{Savestate, StateReader, StateWriter};
+
+use std::io;
+
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+// Where the Game Boy screen sits inside the border
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+// The screen in 8x8 cells, each with its own palette
+const CELLS_WIDE: usize = 20;
+const CELLS_HIGH: usize = 18;
+const CELLS: usize = CELLS_WIDE * CELLS_HIGH;
+
+// What one frame of the screen carries in a transfer: 256 tiles laid out 20
+// to a row
+const TRANSFER_SIZE: usize = 0x1000;
+// 512 palettes of 4 colours, filled by PAL_TRN
+const SYSTEM_PALETTES_SIZE: usize = 0x1000;
+// 45 files of 2 bits per cell, filled by ATTR_TRN
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = CELLS / 4;
+// 256 4bpp tiles of 32 bytes, half from each CHR_TRN
+const BORDER_TILES_SIZE: usize = 0x2000;
+// 32x28 entries of 2 bytes, followed by palettes 4-7 at 0x800
+const BORDER_MAP_SIZE: usize = 32 * 28 * 2;
+const BORDER_PALETTES: usize = 0x800;
+
+// What MASK_EN shows in place of the game while it sets things up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mask {
+    Off,
+    // Keep the last frame
+    Freeze,
+    Black,
+    // Colour 0 of the palettes
+    Color0,
+}
+
+// Data a command asked for from the screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Transfer {
+    Palettes,
+    // The upper half of the tiles if true
+    Tiles(bool),
+    Border,
+    Attributes,
+}
+
+pub struct Sgb {
+    // The packet coming in, a bit at a time
+    packet: [u8; PACKET_SIZE],
+    bit: usize,
+    receiving: bool,
+    // P14/P15 as last written
+    lines: u8,
+    // Every packet of the command so far, and how many more it needs
+    command: Vec<u8>,
+    packets_left: u8,
+
+    palettes: [[u16; 4]; 4],
+    // Palette of each cell
+    attributes: [u8; CELLS],
+    system_palettes: Box<[u8; SYSTEM_PALETTES_SIZE]>,
+    attribute_files: Box<[u8; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE]>,
+    border_tiles: Box<[u8; BORDER_TILES_SIZE]>,
+    border_map: Box<[u8; BORDER_MAP_SIZE]>,
+    border_palettes: [[u16; 16]; 4],
+    mask: Mask,
+
+    players: u8,
+    player: u8,
+
+    // Waiting on the screen, and whether a whole frame's been drawn since
+    transfer: Option<(Transfer, bool)>,
+    // Shade 0-3 of every pixel on screen, which is what transfers read
+    shades: Box<[u8; 160 * 144]>,
+}
+
+impl Sgb {
+    pub fn new() -> Self {
+        Sgb {
+            packet: [0; PACKET_SIZE],
+            bit: 0,
+            receiving: false,
+            lines: 0x30,
+            command: Vec::new(),
+            packets_left: 0,
+            // Greys until the game sends its own
+            palettes: [[0x7FFF, 0x5294, 0x294A, 0x0000]; 4],
+            attributes: [0; CELLS],
+            system_palettes: Box::new([0; SYSTEM_PALETTES_SIZE]),
+            attribute_files: Box::new([0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE]),
+            border_tiles: Box::new([0; BORDER_TILES_SIZE]),
+            border_map: Box::new([0; BORDER_MAP_SIZE]),
+            border_palettes: [[0; 16]; 4],
+            mask: Mask::Off,
+            players: 1,
+            player: 0,
+            transfer: None,
+            shades: Box::new([0; 160 * 144]),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Sgb::new();
+    }
+
+    // A write to the joypad register. Pulling both lines low starts a
+    // packet, then each bit is P14 (0) or P15 (1) pulled low followed by
+    // both high, and a 0 bit ends it.
+    pub fn write_joypad(&mut self, data: u8) {
+        let lines = data & 0x30;
+        let previous = self.lines;
+        self.lines = lines;
+
+        match lines {
+            0x00 => {
+                self.packet = [0; PACKET_SIZE];
+                self.bit = 0;
+                self.receiving = true;
+            },
+            0x10 | 0x20 if self.receiving && previous == 0x30 => {
+                if self.bit == PACKET_BITS {
+                    self.receiving = false;
+                    if lines == 0x20 {
+                        self.packet_received();
+                    }
+                } else {
+                    if lines == 0x10 {
+                        self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+                    }
+                    self.bit += 1;
+                }
+            },
+            // Letting go of P15 moves on to the next joypad
+            0x30 if !self.receiving && previous & 0x20 == 0 => {
+                self.player = (self.player + 1) % self.players;
+            },
+            _ => ()
+        }
+    }
+
+    pub fn player(&self) -> u8 {
+        self.player
+    }
+
+    fn packet_received(&mut self) {
+        if self.packets_left == 0 {
+            // The bottom 3 bits of the first byte say how many packets long
+            // the command is
+            self.packets_left = self.packet[0] & 0b111;
+            self.command.clear();
+            if self.packets_left == 0 {
+                return;
+            }
+        }
+
+        self.command.extend_from_slice(&self.packet);
+        self.packets_left -= 1;
+        if self.packets_left == 0 {
+            self.run_command();
+        }
+    }
+
+    fn run_command(&mut self) {
+        let command = std::mem::take(&mut self.command);
+        // Bytes past the end of what was sent read as 0
+        let byte = |index: usize| command.get(index).copied().unwrap_or(0);
+        let word = |index: usize| u16::from(byte(index)) | (u16::from(byte(index + 1)) << 8);
+
+        match command[0] >> 3 {
+            // PAL01, PAL23, PAL03, PAL12: colour 0 for everything, then 1-3
+            // of each of a pair
+            0x00..=0x03 => {
+                let (first, second) = [(0, 1), (2, 3), (0, 3), (1, 2)][command[0] as usize >> 3];
+                for palette in self.palettes.iter_mut() {
+                    palette[0] = word(1);
+                }
+                for color in 1..4 {
+                    self.palettes[first][color] = word(1 + color * 2);
+                    self.palettes[second][color] = word(7 + color * 2);
+                }
+            },
+            // ATTR_BLK: rectangles, each with a palette for inside, the
+            // border and outside
+            0x04 => {
+                for set in 0..(byte(1) & 0x1F) as usize {
+                    let data = |index: usize| byte(2 + set * 6 + index);
+                    let palettes = data(1);
+                    // Inside or outside alone colours the border too
+                    let (control, border) = match data(0) & 0b111 {
+                        0b001 => (0b011, palettes),
+                        0b100 => (0b110, palettes >> 4),
+                        control => (control, palettes >> 2)
+                    };
+                    let (left, top, right, bottom) = (data(2) & 0x1F, data(3) & 0x1F, data(4) & 0x1F, data(5) & 0x1F);
+
+                    for y in 0..CELLS_HIGH as u8 {
+                        for x in 0..CELLS_WIDE as u8 {
+                            let within = (left..=right).contains(&x) && (top..=bottom).contains(&y);
+                            let on_edge = within && (x == left || x == right || y == top || y == bottom);
+                            let (apply, palette) = if on_edge {
+                                (tbit!(control, 1), border)
+                            } else if within {
+                                (tbit!(control, 0), palettes)
+                            } else {
+                                (tbit!(control, 2), palettes >> 4)
+                            };
+                            if apply {
+                                self.attributes[y as usize * CELLS_WIDE + x as usize] = palette & 0b11;
+                            }
+                        }
+                    }
+                }
+            },
+            // ATTR_LIN: whole rows and columns
+            0x05 => {
+                for line in 0..byte(1) as usize {
+                    let data = byte(2 + line);
+                    let (number, palette) = ((data & 0x1F) as usize, (data >> 5) & 0b11);
+                    if tbit!(data, 7) {
+                        if number < CELLS_HIGH {
+                            self.attributes[number * CELLS_WIDE..(number + 1) * CELLS_WIDE].iter_mut().for_each(|cell| *cell = palette);
+                        }
+                    } else if number < CELLS_WIDE {
+                        for y in 0..CELLS_HIGH {
+                            self.attributes[y * CELLS_WIDE + number] = palette;
+                        }
+                    }
+                }
+            },
+            // ATTR_DIV: split the screen in two at a row or column, with a
+            // third palette for the line itself
+            0x06 => {
+                let data = byte(1);
+                let split = byte(2) & 0x1F;
+                for y in 0..CELLS_HIGH {
+                    for x in 0..CELLS_WIDE {
+                        let position = if tbit!(data, 6) { y } else { x } as u8;
+                        let palette = if position < split {
+                            data >> 2
+                        } else if position == split {
+                            data >> 4
+                        } else {
+                            data
+                        };
+                        self.attributes[y * CELLS_WIDE + x] = palette & 0b11;
+                    }
+                }
+            },
+            // ATTR_CHR: cell by cell from a starting point, 4 to a byte
+            0x07 => {
+                let (mut x, mut y) = ((byte(1) as usize).min(CELLS_WIDE - 1), (byte(2) as usize).min(CELLS_HIGH - 1));
+                let count = (word(3) as usize).min(CELLS).min((command.len() - 6) * 4);
+                let vertical = byte(5) == 1;
+                for cell in 0..count {
+                    self.attributes[y * CELLS_WIDE + x] = (byte(6 + cell / 4) >> (6 - (cell % 4) * 2)) & 0b11;
+                    if vertical {
+                        y += 1;
+                        if y == CELLS_HIGH {
+                            y = 0;
+                            x = (x + 1) % CELLS_WIDE;
+                        }
+                    } else {
+                        x += 1;
+                        if x == CELLS_WIDE {
+                            x = 0;
+                            y = (y + 1) % CELLS_HIGH;
+                        }
+                    }
+                }
+            },
+            // PAL_SET: four palettes out of those PAL_TRN sent, and maybe
+            // an attribute file
+            0x0A => {
+                for palette in 0..4 {
+                    let offset = (word(1 + palette * 2) as usize & 0x1FF) * 8;
+                    for color in 0..4 {
+                        let data = &self.system_palettes[offset + color * 2..];
+                        self.palettes[palette][color] = u16::from(data[0]) | (u16::from(data[1]) << 8);
+                    }
+                }
+                self.set_attributes(byte(9));
+            },
+            0x0B => self.transfer = Some((Transfer::Palettes, false)),
+            // MLT_REQ: 1, 2 or 4 joypads
+            0x11 => {
+                self.players = match byte(1) & 0b11 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1
+                };
+                self.player = 0;
+            },
+            0x13 => self.transfer = Some((Transfer::Tiles(tbit!(byte(1), 0)), false)),
+            0x14 => self.transfer = Some((Transfer::Border, false)),
+            0x15 => self.transfer = Some((Transfer::Attributes, false)),
+            // ATTR_SET
+            0x16 => self.set_attributes(byte(1) | 0x80),
+            0x17 => {
+                self.mask = match byte(1) & 0b11 {
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    3 => Mask::Color0,
+                    _ => Mask::Off
+                };
+            },
+            // Sound, SNES code uploads and the like
+            code => info!("Ignoring SGB command {:02X}", code)
+        }
+    }
+
+    // Bit 7 loads the attribute file in the bottom 6 bits, bit 6 lifts the
+    // mask
+    fn set_attributes(&mut self, data: u8) {
+        let file = (data & 0x3F) as usize;
+        if tbit!(data, 7) && file < ATTRIBUTE_FILES {
+            let start = file * ATTRIBUTE_FILE_SIZE;
+            for cell in 0..CELLS {
+                let byte = self.attribute_files[start + cell / 4];
+                self.attributes[cell] = (byte >> (6 - (cell % 4) * 2)) & 0b11;
+            }
+        }
+        if tbit!(data, 6) {
+            self.mask = Mask::Off;
+        }
+    }
+
+    pub fn set_shade(&mut self, x: u8, y: u8, shade: usize) {
+        self.shades[y as usize * 160 + x as usize] = shade as u8;
+    }
+
+    // The colour of a shade at a pixel, or None while the mask is holding
+    // the last frame
+    pub fn rgb(&self, x: u8, y: u8, shade: usize) -> Option<(u8, u8, u8)> {
+        let palette = self.attributes[(y as usize / 8) * CELLS_WIDE + x as usize / 8] as usize;
+        let color = match self.mask {
+            Mask::Freeze => return None,
+            Mask::Black => 0,
+            // Colour 0 is shared by all four palettes
+            Mask::Color0 => self.palettes[0][0],
+            Mask::Off if shade == 0 => self.palettes[0][0],
+            Mask::Off => self.palettes[palette][shade],
+        };
+        Some(rgb15(color))
+    }
+
+    // Called at the end of every frame. Transfers take the frame after the
+    // one the command came in on, so it's the whole screen.
+    pub fn end_frame(&mut self) {
+        match self.transfer {
+            Some((transfer, true)) => {
+                self.transfer = None;
+                self.receive(transfer);
+            },
+            Some((transfer, false)) => self.transfer = Some((transfer, true)),
+            None => ()
+        }
+    }
+
+    fn receive(&mut self, transfer: Transfer) {
+        // Turn the screen back into tile data, as if BGP was 0xE4
+        let mut data = vec![0; TRANSFER_SIZE];
+        for tile in 0..TRANSFER_SIZE / 16 {
+            let (column, row) = (tile % CELLS_WIDE, tile / CELLS_WIDE);
+            for line in 0..8 {
+                let start = (row * 8 + line) * 160 + column * 8;
+                for (x, shade) in self.shades[start..start + 8].iter().enumerate() {
+                    data[tile * 16 + line * 2] |= (shade & 1) << (7 - x);
+                    data[tile * 16 + line * 2 + 1] |= (shade >> 1) << (7 - x);
+                }
+            }
+        }
+
+        match transfer {
+            Transfer::Palettes => self.system_palettes.copy_from_slice(&data),
+            Transfer::Tiles(upper) => {
+                let start = if upper { TRANSFER_SIZE } else { 0 };
+                self.border_tiles[start..start + TRANSFER_SIZE].copy_from_slice(&data);
+            },
+            Transfer::Border => {
+                self.border_map.copy_from_slice(&data[..BORDER_MAP_SIZE]);
+                for (palette, colors) in self.border_palettes.iter_mut().enumerate() {
+                    for (color, value) in colors.iter_mut().enumerate() {
+                        let offset = BORDER_PALETTES + (palette * 16 + color) * 2;
+                        *value = u16::from(data[offset]) | (u16::from(data[offset + 1]) << 8);
+                    }
+                }
+            },
+            Transfer::Attributes => {
+                let size = self.attribute_files.len();
+                self.attribute_files.copy_from_slice(&data[..size]);
+            },
+        }
+    }
+
+    // The Game Boy screen in the middle of the border. Border colour 0 is
+    // see-through, showing the screen or colour 0 of the palettes around it.
+    pub fn compose(&self, screen: &Image) -> Image {
+        let mut image = Image::new(SGB_WIDTH, SGB_HEIGHT);
+        let backdrop = rgb15(self.palettes[0][0]);
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                image.set(x, y, backdrop);
+            }
+        }
+        image.blit(screen, SCREEN_X, SCREEN_Y);
+
+        for y in 0..SGB_HEIGHT {
+            for x in 0..SGB_WIDTH {
+                // Tile number, palette 4-7 and flips
+                let offset = ((y / 8) * 32 + x / 8) * 2;
+                let entry = u16::from(self.border_map[offset]) | (u16::from(self.border_map[offset + 1]) << 8);
+                let tile = &self.border_tiles[(entry as usize & 0xFF) * 32..];
+                let row = if tbit!(entry, 15) { 7 - y % 8 } else { y % 8 };
+                let bit = if tbit!(entry, 14) { x % 8 } else { 7 - x % 8 };
+
+                // Four bitplanes, the first two interleaved in the first 16
+                // bytes and the other two in the next
+                let color = [tile[row * 2], tile[row * 2 + 1], tile[16 + row * 2], tile[16 + row * 2 + 1]].iter()
+                    .enumerate()
+                    .fold(0, |color, (plane, data)| color | (((data >> bit) & 1) << plane));
+                if color != 0 {
+                    let palette = ((entry >> 10) & 0b11) as usize;
+                    image.set(x, y, rgb15(self.border_palettes[palette][color as usize]));
+                }
+            }
+        }
+        image
+    }
+}
+
+impl Savestate for Sgb {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.packet);
+        writer.write_u8(self.bit as u8);
+        writer.write_bool(self.receiving);
+        writer.write_u8(self.lines);
+        // A command can be at most 7 packets long
+        let mut command = [0; PACKET_SIZE * 7];
+        command[..self.command.len()].copy_from_slice(&self.command);
+        writer.write_bytes(&command);
+        writer.write_u8(self.command.len() as u8);
+        writer.write_u8(self.packets_left);
+
+        for color in self.palettes.iter().flatten() {
+            writer.write_u16(*color);
+        }
+        writer.write_bytes(&self.attributes);
+        writer.write_bytes(&self.system_palettes[..]);
+        writer.write_bytes(&self.attribute_files[..]);
+        writer.write_bytes(&self.border_tiles[..]);
+        writer.write_bytes(&self.border_map[..]);
+        for color in self.border_palettes.iter().flatten() {
+            writer.write_u16(*color);
+        }
+        writer.write_u8(self.mask as u8);
+
+        writer.write_u8(self.players);
+        writer.write_u8(self.player);
+        let transfer = match self.transfer {
+            None => 0,
+            Some((Transfer::Palettes, _)) => 1,
+            Some((Transfer::Tiles(false), _)) => 2,
+            Some((Transfer::Tiles(true), _)) => 3,
+            Some((Transfer::Border, _)) => 4,
+            Some((Transfer::Attributes, _)) => 5,
+        };
+        writer.write_u8(transfer);
+        writer.write_bool(self.transfer.is_some_and(|(_, ready)| ready));
+        writer.write_bytes(&self.shades[..]);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), io::Error> {
+        reader.read_bytes(&mut self.packet)?;
+        self.bit = (reader.read_u8()? as usize).min(PACKET_BITS);
+        self.receiving = reader.read_bool()?;
+        self.lines = reader.read_u8()? & 0x30;
+        let mut command = [0; PACKET_SIZE * 7];
+        reader.read_bytes(&mut command)?;
+        let length = (reader.read_u8()? as usize).min(command.len());
+        self.command = command[..length].to_vec();
+        self.packets_left = reader.read_u8()? & 0b111;
+
+        for color in self.palettes.iter_mut().flatten() {
+            *color = reader.read_u16()?;
+        }
+        reader.read_bytes(&mut self.attributes)?;
+        self.attributes.iter_mut().for_each(|palette| *palette &= 0b11);
+        reader.read_bytes(&mut self.system_palettes[..])?;
+        reader.read_bytes(&mut self.attribute_files[..])?;
+        reader.read_bytes(&mut self.border_tiles[..])?;
+        reader.read_bytes(&mut self.border_map[..])?;
+        for color in self.border_palettes.iter_mut().flatten() {
+            *color = reader.read_u16()?;
+        }
+        self.mask = match reader.read_u8()? {
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            _ => Mask::Off
+        };
+
+        self.players = match reader.read_u8()? {
+            players @ (2 | 4) => players,
+            _ => 1
+        };
+        self.player = reader.read_u8()? % self.players;
+        let transfer = match reader.read_u8()? {
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::Tiles(false)),
+            3 => Some(Transfer::Tiles(true)),
+            4 => Some(Transfer::Border),
+            5 => Some(Transfer::Attributes),
+            _ => None
+        };
+        let ready = reader.read_bool()?;
+        self.transfer = transfer.map(|transfer| (transfer, ready));
+        reader.read_bytes(&mut self.shades[..])?;
+        self.shades.iter_mut().for_each(|shade| *shade &= 0b11);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::png::Image;
+    use super::{Mask, Sgb, SGB_HEIGHT, SGB_WIDTH};
+
+    fn send(sgb: &mut Sgb, packet: &[u8]) {
+        let mut bytes = [0; 16];
+        bytes[..packet.len()].copy_from_slice(packet);
+
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        for bit in 0..128 {
+            let one = (bytes[bit / 8] >> (bit % 8)) & 1 == 1;
+            sgb.write_joypad(if one { 0x10 } else { 0x20 });
+            sgb.write_joypad(0x30);
+        }
+        sgb.write_joypad(0x20);
+        sgb.write_joypad(0x30);
+    }
+
+    #[test]
+    fn palettes_and_attributes() {
+        let mut sgb = Sgb::new();
+        // PAL12: white for colour 0, red and blue for colour 3 of 1 and 2
+        send(&mut sgb, &[0x19, 0xFF, 0x7F, 0, 0, 0, 0, 0x1F, 0x00, 0, 0, 0, 0, 0x00, 0x7C]);
+        // ATTR_BLK: palette 1 inside and on the border of cells 1,1 to 3,3,
+        // palette 2 outside
+        send(&mut sgb, &[0x21, 1, 0b111, 0b100101, 1, 1, 3, 3]);
+
+        assert_eq!(sgb.rgb(8, 8, 3), Some((0xFF, 0, 0)));
+        assert_eq!(sgb.rgb(31, 31, 3), Some((0xFF, 0, 0)));
+        assert_eq!(sgb.rgb(32, 8, 3), Some((0, 0, 0xFF)));
+        assert_eq!(sgb.rgb(0, 0, 0), Some((0xFF, 0xFF, 0xFF)));
+
+        // ATTR_LIN: row 0 in palette 1
+        send(&mut sgb, &[0x29, 1, 0x80 | (1 << 5)]);
+        assert_eq!(sgb.rgb(100, 7, 3), Some((0xFF, 0, 0)));
+
+        // MASK_EN and PAL_SET lifting it
+        send(&mut sgb, &[0xB9, 1]);
+        assert_eq!(sgb.mask, Mask::Freeze);
+        assert_eq!(sgb.rgb(0, 0, 3), None);
+        send(&mut sgb, &[0x51, 0, 0, 0, 0, 0, 0, 0, 0, 0x40]);
+        assert_eq!(sgb.mask, Mask::Off);
+
+        // A packet cut short by a reset is dropped
+        sgb.write_joypad(0x00);
+        sgb.write_joypad(0x30);
+        sgb.write_joypad(0x10);
+        send(&mut sgb, &[0xB9, 2]);
+        assert_eq!(sgb.mask, Mask::Black);
+    }
+
+    #[test]
+    fn multiplayer() {
+        let mut sgb = Sgb::new();
+        send(&mut sgb, &[0x89, 1]);
+        assert_eq!(sgb.player(), 0);
+        for player in [1, 0, 1].iter() {
+            sgb.write_joypad(0x10);
+            sgb.write_joypad(0x30);
+            assert_eq!(sgb.player(), *player);
+        }
+    }
+
+    // Puts data on screen the way a game does for a transfer
+    fn show(sgb: &mut Sgb, data: &[u8]) {
+        for tile in 0..256 {
+            for line in 0..8 {
+                let (low, high) = (data[tile * 16 + line * 2], data[tile * 16 + line * 2 + 1]);
+                for x in 0..8 {
+                    let shade = ((low >> (7 - x)) & 1) | (((high >> (7 - x)) & 1) << 1);
+                    sgb.set_shade((tile % 20 * 8 + x) as u8, (tile / 20 * 8 + line) as u8, shade as usize);
+                }
+            }
+        }
+        sgb.end_frame();
+        sgb.end_frame();
+    }
+
+    #[test]
+    fn border_transfer() {
+        let mut sgb = Sgb::new();
+        // CHR_TRN with colour 1 along the top of tile 0
+        let mut data = vec![0; 0x1000];
+        data[0] = 0xFF;
+        send(&mut sgb, &[0x99, 0]);
+        show(&mut sgb, &data);
+
+        // PCT_TRN with every map entry tile 0 in palette 4, whose colour 1
+        // is green
+        let mut data = vec![0; 0x1000];
+        for entry in 0..32 * 28 {
+            data[entry * 2 + 1] = 4 << 2;
+        }
+        data[0x802] = 0xE0;
+        data[0x803] = 0x03;
+        send(&mut sgb, &[0xA1]);
+        show(&mut sgb, &data);
+
+        let image = sgb.compose(&Image::new(160, 144));
+        assert_eq!((image.width, image.height), (SGB_WIDTH, SGB_HEIGHT));
+        assert_eq!(image.get(0, 0), (0, 0xFF, 0));
+        assert_eq!(image.get(0, 1), (0xFF, 0xFF, 0xFF));
+        // The game screen shows through the see-through part of the border
+        assert_eq!(image.get(48, 40), (0, 0xFF, 0));
+        assert_eq!(image.get(48, 41), (0, 0, 0));
+    }
+}
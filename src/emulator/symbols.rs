@@ -0,0 +1,130 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Labels from an RGBDS or no$gmb .sym file, which are lines of
+// "BANK:ADDRESS Label" in hex with ; comments
+pub struct Symbols {
+    by_address: BTreeMap<(usize, u16), String>,
+    by_name: HashMap<String, (usize, u16)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols {
+            by_address: BTreeMap::new(),
+            by_name: HashMap::new(),
+        }
+    }
+
+    pub fn load(filename: &Path) -> Result<Self, io::Error> {
+        Ok(Symbols::parse(&fs::read_to_string(filename)?))
+    }
+
+    // Looks for a .sym file with the same name as the ROM
+    pub fn for_rom(rom: &str) -> Option<Self> {
+        let path = Path::new(rom).with_extension("sym");
+        if !path.exists() {
+            return None;
+        }
+
+        match Symbols::load(&path) {
+            Ok(symbols) => {
+                info!("Loaded {} symbols from {}", symbols.len(), path.display());
+                Some(symbols)
+            },
+            Err(e) => {
+                warn!("Failed to load symbols from {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    // Lines that don't parse are skipped
+    pub fn parse(text: &str) -> Self {
+        let mut symbols = Symbols::new();
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            let mut words = line.split_whitespace();
+            let (location, name) = match (words.next(), words.next()) {
+                (Some(location), Some(name)) => (location, name),
+                _ => continue
+            };
+
+            let colon = match location.find(':') {
+                Some(colon) => colon,
+                None => continue
+            };
+            let bank = usize::from_str_radix(&location[..colon], 16);
+            let address = u16::from_str_radix(&location[colon + 1..], 16);
+
+            if let (Ok(bank), Ok(address)) = (bank, address) {
+                symbols.insert(bank, address, name);
+            }
+        }
+
+        symbols
+    }
+
+    pub fn insert(&mut self, bank: usize, address: u16, name: &str) {
+        // Keep the first label when several share an address, it's usually
+        // the section's main one
+        self.by_address.entry((bank, address)).or_insert_with(|| name.to_string());
+        self.by_name.insert(name.to_string(), (bank, address));
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn name(&self, bank: usize, address: u16) -> Option<&str> {
+        self.by_address.get(&(bank, address)).map(String::as_str)
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<(usize, u16)> {
+        self.by_name.get(name).cloned()
+    }
+
+    // The closest label at or before an address, e.g. "Main+$0C". Only looks
+    // within the same 16KB region so RAM never gets described by a ROM label.
+    pub fn describe(&self, bank: usize, address: u16) -> Option<String> {
+        let ((_, label_address), name) = self.by_address.range((bank, 0)..=(bank, address)).next_back()?;
+
+        if label_address & 0xC000 != address & 0xC000 {
+            None
+        } else if *label_address == address {
+            Some(name.clone())
+        } else {
+            Some(format!("{}+${:X}", name, address - label_address))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::Symbols;
+
+    #[test]
+    fn parse_and_describe() {
+        let symbols = Symbols::parse("; File generated by rgblink\n\
+                                      00:0150 Main\n\
+                                      00:0158 Main.loop\n\
+                                      02:4000 Bank2Routine\n\
+                                      0000:C000 wPlayerX ; no$gmb style\n\
+                                      not a symbol\n");
+
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.name(0, 0x150), Some("Main"));
+        assert_eq!(symbols.lookup("Bank2Routine"), Some((2, 0x4000)));
+        assert_eq!(symbols.lookup("wPlayerX"), Some((0, 0xC000)));
+
+        assert_eq!(symbols.describe(0, 0x158).unwrap(), "Main.loop");
+        assert_eq!(symbols.describe(0, 0x15C).unwrap(), "Main.loop+$4");
+        assert_eq!(symbols.describe(2, 0x4010).unwrap(), "Bank2Routine+$10");
+        assert!(symbols.describe(1, 0x4010).is_none());
+        assert!(symbols.describe(0, 0x8000).is_none());
+    }
+}
@@ -0,0 +1,40 @@
+// ROMs for tests to build on
+
+// 32KB of zeroes with a header checksum, so nothing complains about it
+pub fn test_rom() -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x14E] = 0x12;
+    rom[0x14F] = 0x34;
+    rom
+}
+
+// Sends 0x01, 0x02, ... on the internal clock and keeps what comes back at
+// C000 on, while the other side does the same on the external clock.
+// Pressing A stops it for good, so the inputs matter. Counting from 1 leaves
+// a 0 after the last byte received.
+pub fn serial_rom(internal_clock: bool) -> Vec<u8> {
+    let mut rom = test_rom();
+    let control = if internal_clock { 0x81 } else { 0x80 };
+    let program = [
+        0x21, 0x00, 0xC0,       // LD HL,C000
+        0x06, 0x01,             // LD B,1
+        0x3E, 0x10,             // loop: LD A,10 (select buttons)
+        0xE0, 0x00,             // LDH (00),A
+        0xF0, 0x00,             // LDH A,(00)
+        0xE6, 0x01,             // AND 1 (A held reads 0)
+        0x28, 0xFE,             // JR Z,$ (stop for good)
+        0x78,                   // LD A,B
+        0xE0, 0x01,             // LDH (01),A
+        0x3E, control,          // LD A,control
+        0xE0, 0x02,             // LDH (02),A
+        0xF0, 0x02,             // wait: LDH A,(02)
+        0xE6, 0x80,             // AND 80
+        0x20, 0xFA,             // JR NZ,wait
+        0xF0, 0x01,             // LDH A,(01)
+        0x22,                   // LD (HL+),A
+        0x04,                   // INC B
+        0x18, 0xE3,             // JR loop
+    ];
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    rom
+}
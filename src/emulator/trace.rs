@@ -0,0 +1,152 @@
+use super::symbols::Symbols;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::thread;
+
+// Only trace instructions in this range of PC
+#[derive(Copy, Clone)]
+pub struct TraceFilter {
+    pub start: u16,
+    pub end: u16,
+    // Only while this ROM bank is mapped, for PCs in 4000-7FFF
+    pub bank: Option<usize>,
+}
+
+impl TraceFilter {
+    fn matches(&self, pc: u16, bank: Option<usize>) -> bool {
+        let bank_matches = match (self.bank, bank) {
+            (Some(wanted), Some(mapped)) => wanted == mapped,
+            _ => true
+        };
+        (self.start..=self.end).contains(&pc) && bank_matches
+    }
+}
+
+// Writes one line per instruction in the Gameboy Doctor format, either as
+// it goes or, in ring mode, only the last few lines when the CPU locks up,
+// the emulator panics or it's done
+pub struct Tracer {
+    filename: String,
+    output: Option<BufWriter<File>>,
+    ring: VecDeque<String>,
+    ring_size: Option<usize>,
+    pub filter: Option<TraceFilter>,
+    // Adds the nearest label to the end of each line
+    pub symbols: Option<Symbols>,
+}
+
+impl Tracer {
+    pub fn create(filename: &str, ring_size: Option<usize>) -> Result<Self, io::Error> {
+        // Ring mode leaves the file alone until there's something to dump
+        let output = match ring_size {
+            Some(_) => None,
+            None => Some(BufWriter::new(File::create(filename)?)),
+        };
+
+        Ok(Tracer {
+            filename: filename.to_string(),
+            output,
+            ring: VecDeque::new(),
+            ring_size,
+            filter: None,
+            symbols: None,
+        })
+    }
+
+    pub fn wants(&self, pc: u16, bank: Option<usize>) -> bool {
+        self.filter.as_ref().is_none_or(|filter| filter.matches(pc, bank))
+    }
+
+    pub fn write(&mut self, line: String) -> Result<(), io::Error> {
+        match (self.output.as_mut(), self.ring_size) {
+            (Some(output), None) => writeln!(output, "{}", line),
+            (_, Some(size)) => {
+                if self.ring.len() == size {
+                    self.ring.pop_front();
+                }
+                self.ring.push_back(line);
+                Ok(())
+            },
+            (None, None) => Ok(())
+        }
+    }
+
+    // Writes out whatever the ring holds, oldest first
+    pub fn dump(&mut self) -> Result<(), io::Error> {
+        if self.output.is_none() {
+            self.output = Some(BufWriter::new(File::create(&self.filename)?));
+        }
+
+        let output = self.output.as_mut().unwrap();
+        for line in self.ring.drain(..) {
+            writeln!(output, "{}", line)?;
+        }
+        output.flush()
+    }
+
+    // Dumps the ring, if there is one with anything in it, saying why
+    pub fn dump_ring(&mut self, reason: &str) {
+        if self.ring_size.is_none() || self.ring.is_empty() {
+            return;
+        }
+
+        match self.dump() {
+            Ok(()) => eprintln!("Dumped the last instructions before {} to {}", reason, self.filename),
+            Err(e) => eprintln!("Failed to dump the instruction trace: {}", e)
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.dump_ring(if thread::panicking() { "the crash" } else { "exiting" });
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{TraceFilter, Tracer};
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn ring_keeps_the_last_lines() {
+        let filename = env::temp_dir().join("coolboy-trace-test.log");
+        let filename = filename.to_str().unwrap();
+
+        let mut tracer = Tracer::create(filename, Some(2)).unwrap();
+        for line in ["one", "two", "three"].iter() {
+            tracer.write(line.to_string()).unwrap();
+        }
+        tracer.dump().unwrap();
+        drop(tracer);
+
+        assert_eq!(fs::read_to_string(filename).unwrap(), "two\nthree\n");
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn ring_is_dumped_when_dropped() {
+        let filename = env::temp_dir().join("coolboy-trace-drop-test.log");
+        let filename = filename.to_str().unwrap();
+
+        let mut tracer = Tracer::create(filename, Some(4)).unwrap();
+        tracer.write("one".to_string()).unwrap();
+        drop(tracer);
+
+        assert_eq!(fs::read_to_string(filename).unwrap(), "one\n");
+        fs::remove_file(filename).unwrap();
+    }
+
+    #[test]
+    fn filter() {
+        let filter = TraceFilter { start: 0x4000, end: 0x4FFF, bank: Some(2) };
+        assert!(filter.matches(0x4100, Some(2)));
+        assert!(!filter.matches(0x4100, Some(3)));
+        assert!(!filter.matches(0x5000, Some(2)));
+    }
+}
@@ -0,0 +1,285 @@
+// Debug views of VRAM and OAM. Everything reads through peek_memory so
+// looking doesn't trip watchpoints.
+
+use super::png::Image;
+use super::{
+    Emulator, LCD_CONTROL_ADDRESS, PALETTE_47_ADDRESS, PALETTE_48_ADDRESS,
+    PALETTE_49_ADDRESS, SCROLL_X_ADDRESS, SCROLL_Y_ADDRESS, SPRITE_ATTRIBUTE_TABLE,
+    SPRITE_DATA_ADDRESS, WINDOW_X_ADDRESS, WINDOW_Y_ADDRESS,
+};
+
+use std::fmt;
+
+pub const TILE_COUNT: usize = 384;
+// Tile sheets are laid out 16 tiles across
+const SHEET_COLUMNS: usize = 16;
+const SPRITE_COUNT: usize = 40;
+// Pixels between previews in the sprite strip
+const STRIP_GAP: usize = 1;
+
+pub const TILE_MAP_0: u16 = 0x9800;
+pub const TILE_MAP_1: u16 = 0x9C00;
+
+const VIEWPORT_OUTLINE: (u8, u8, u8) = (0xFF, 0x00, 0x00);
+const WINDOW_OUTLINE: (u8, u8, u8) = (0x00, 0x80, 0xFF);
+// Colour 0 in a sprite is see-through
+const TRANSPARENT: (u8, u8, u8) = (0xFF, 0x00, 0xFF);
+
+#[derive(Copy, Clone, Debug)]
+pub enum Palette {
+    Background,
+    Object0,
+    Object1,
+}
+
+pub const ALL_PALETTES: [Palette; 3] = [Palette::Background, Palette::Object0, Palette::Object1];
+
+impl Palette {
+    pub(super) fn address(self) -> usize {
+        match self {
+            Palette::Background => PALETTE_47_ADDRESS,
+            Palette::Object0 => PALETTE_48_ADDRESS,
+            Palette::Object1 => PALETTE_49_ADDRESS,
+        }
+    }
+
+    // The register's name
+    pub fn name(self) -> &'static str {
+        match self {
+            Palette::Background => "BGP",
+            Palette::Object0 => "OBP0",
+            Palette::Object1 => "OBP1",
+        }
+    }
+}
+
+// One OAM entry, as render_sprites sees it
+pub struct Sprite {
+    pub index: usize,
+    // Raw OAM positions, so (8, 16) is the top left of the screen
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+}
+
+impl Sprite {
+    pub fn behind_background(&self) -> bool {
+        tbit!(self.attributes, 7)
+    }
+
+    pub fn flip_y(&self) -> bool {
+        tbit!(self.attributes, 6)
+    }
+
+    pub fn flip_x(&self) -> bool {
+        tbit!(self.attributes, 5)
+    }
+
+    pub fn palette(&self) -> Palette {
+        if tbit!(self.attributes, 4) { Palette::Object1 } else { Palette::Object0 }
+    }
+
+    // CGB only: which of the eight object palettes, and VRAM bank
+    pub fn color_palette(&self) -> u8 {
+        self.attributes & 0b111
+    }
+
+    pub fn bank(&self) -> usize {
+        gbit!(self.attributes, 3) as usize
+    }
+}
+
+impl fmt::Display for Sprite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02} X:{:3} Y:{:3} tile ${:02X} {}{}{}{}",
+            self.index, self.x, self.y, self.tile,
+            if let Palette::Object1 = self.palette() { "OBP1" } else { "OBP0" },
+            if self.flip_x() { " xflip" } else { "" },
+            if self.flip_y() { " yflip" } else { "" },
+            if self.behind_background() { " behind" } else { "" })
+    }
+}
+
+impl Emulator {
+    // All 384 tiles at 8000-97FF, 16 to a row
+    pub fn tile_sheet(&self, palette: Palette) -> Image {
+        let rows = TILE_COUNT / SHEET_COLUMNS;
+        let mut image = Image::new(SHEET_COLUMNS * 8, rows * 8);
+        for tile in 0..TILE_COUNT {
+            let address = SPRITE_DATA_ADDRESS + tile * 16;
+            let (left, top) = ((tile % SHEET_COLUMNS) * 8, (tile / SHEET_COLUMNS) * 8);
+            for y in 0..8 {
+                for x in 0..8 {
+                    let color = self.tile_pixel(address, x, y);
+                    image.set(left + x, top + y, self.dmg_rgb(palette, color));
+                }
+            }
+        }
+
+        image
+    }
+
+    // The whole 256x256 background a map describes, with the part on screen
+    // outlined
+    pub fn tile_map(&self, map: u16) -> Image {
+        let mut image = Image::new(256, 256);
+        let lcd_control = self.peek_memory(LCD_CONTROL_ADDRESS);
+        let unsigned = tbit!(lcd_control, 4);
+
+        for row in 0..32 {
+            for column in 0..32 {
+                let tile = self.peek_memory(map as usize + row * 32 + column);
+                let address = if unsigned {
+                    SPRITE_DATA_ADDRESS + tile as usize * 16
+                } else {
+                    (0x9000 + i32::from(tile as i8) * 16) as usize
+                };
+
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let color = self.tile_pixel(address, x, y);
+                        image.set(column * 8 + x, row * 8 + y, self.dmg_rgb(Palette::Background, color));
+                    }
+                }
+            }
+        }
+
+        let background_map = if tbit!(lcd_control, 3) { TILE_MAP_1 } else { TILE_MAP_0 };
+        if map == background_map {
+            let scroll_x = self.peek_memory(SCROLL_X_ADDRESS) as usize;
+            let scroll_y = self.peek_memory(SCROLL_Y_ADDRESS) as usize;
+            outline(&mut image, scroll_x, scroll_y, 160, 144, VIEWPORT_OUTLINE);
+        }
+
+        // The window always draws from the top left of its map
+        let window_map = if tbit!(lcd_control, 6) { TILE_MAP_1 } else { TILE_MAP_0 };
+        if map == window_map && tbit!(lcd_control, 5) {
+            let window_x = (self.peek_memory(WINDOW_X_ADDRESS) as usize).saturating_sub(7);
+            let window_y = self.peek_memory(WINDOW_Y_ADDRESS) as usize;
+            if window_x < 160 && window_y < 144 {
+                outline(&mut image, 0, 0, 160 - window_x, 144 - window_y, WINDOW_OUTLINE);
+            }
+        }
+
+        image
+    }
+
+    pub fn sprites(&self) -> Vec<Sprite> {
+        (0..SPRITE_COUNT).map(|index| {
+            let address = SPRITE_ATTRIBUTE_TABLE + index * 4;
+            Sprite {
+                index,
+                y: self.peek_memory(address),
+                x: self.peek_memory(address + 1),
+                tile: self.peek_memory(address + 2),
+                attributes: self.peek_memory(address + 3),
+            }
+        }).collect()
+    }
+
+    // Every sprite's preview side by side, in OAM order
+    pub fn sprite_strip(&self) -> Image {
+        let sprites = self.sprites();
+        let previews: Vec<Image> = sprites.iter().map(|sprite| self.sprite_preview(sprite)).collect();
+
+        let mut strip = Image::new(SPRITE_COUNT * (8 + STRIP_GAP) - STRIP_GAP, previews[0].height);
+        for (index, preview) in previews.iter().enumerate() {
+            strip.blit(preview, index * (8 + STRIP_GAP), 0);
+        }
+        strip
+    }
+
+    // The sprite as it would be drawn, 8x8 or 8x16 depending on LCDC
+    pub fn sprite_preview(&self, sprite: &Sprite) -> Image {
+        let tall = tbit!(self.peek_memory(LCD_CONTROL_ADDRESS), 2);
+        let height = if tall { 16 } else { 8 };
+        // Tall sprites ignore the bottom bit of the tile number
+        let tile = if tall { sprite.tile & 0xFE } else { sprite.tile };
+
+        let mut image = Image::new(8, height);
+        for y in 0..height {
+            for x in 0..8 {
+                let source_x = if sprite.flip_x() { 7 - x } else { x };
+                let source_y = if sprite.flip_y() { height - 1 - y } else { y };
+                let address = SPRITE_DATA_ADDRESS + tile as usize * 16 + (source_y / 8) * 16;
+
+                let color = self.tile_pixel(address, source_x, source_y % 8);
+                let rgb = if color == 0 { TRANSPARENT } else { self.dmg_rgb(sprite.palette(), color) };
+                image.set(x, y, rgb);
+            }
+        }
+
+        image
+    }
+
+    // Colour number 0-3 of a pixel in the tile at address
+    fn tile_pixel(&self, address: usize, x: usize, y: usize) -> u8 {
+        let low = self.peek_memory(address + y * 2);
+        let high = self.peek_memory(address + y * 2 + 1);
+        // Leftmost pixel is bit 7
+        let bit = 7 - x;
+        (gbit!(high, bit) << 1) | gbit!(low, bit)
+    }
+}
+
+// Draws a rectangle that wraps around the edges, like the viewport does
+fn outline(image: &mut Image, left: usize, top: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+    let (image_width, image_height) = (image.width, image.height);
+    for x in 0..width {
+        image.set((left + x) % image_width, top % image_height, rgb);
+        image.set((left + x) % image_width, (top + height - 1) % image_height, rgb);
+    }
+    for y in 0..height {
+        image.set(left % image_width, (top + y) % image_height, rgb);
+        image.set((left + width - 1) % image_width, (top + y) % image_height, rgb);
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::test_roms::test_rom;
+    use super::super::Emulator;
+    use super::{Palette, TILE_MAP_0};
+
+    #[test]
+    fn views() {
+        let mut emulator = Emulator::from_bytes(&test_rom());
+
+        // Top row of tile 1 is colour 3 on the left half, then a sprite using it
+        emulator.poke_memory(0x8010, 0xF0);
+        emulator.poke_memory(0x8011, 0xF0);
+        emulator.poke_memory(0xFE00, 16);
+        emulator.poke_memory(0xFE01, 8);
+        emulator.poke_memory(0xFE02, 1);
+        emulator.poke_memory(0xFE03, 0b0010_0000);
+
+        let sheet = emulator.tile_sheet(Palette::Background);
+        assert_eq!((sheet.width, sheet.height), (128, 192));
+        assert_eq!(sheet.get(8, 0), (0, 0, 0));
+        assert_eq!(sheet.get(12, 0), (0xFF, 0xFF, 0xFF));
+        // Colour 3 is white in an all white OBP0
+        emulator.poke_memory(0xFF48, 0x00);
+        assert_eq!(emulator.tile_sheet(Palette::Object0).get(8, 0), (0xFF, 0xFF, 0xFF));
+
+        let map = emulator.tile_map(TILE_MAP_0);
+        assert_eq!((map.width, map.height), (256, 256));
+        // Viewport outline at SCX/SCY = 0
+        assert_eq!(map.get(0, 0), (0xFF, 0x00, 0x00));
+        assert_eq!(map.get(159, 143), (0xFF, 0x00, 0x00));
+
+        let sprites = emulator.sprites();
+        assert_eq!(sprites.len(), 40);
+        assert!(sprites[0].flip_x());
+        assert_eq!(sprites[0].to_string(), "#00 X:  8 Y: 16 tile $01 OBP0 xflip");
+
+        // Flipped, so the coloured half is on the right
+        let preview = emulator.sprite_preview(&sprites[0]);
+        assert_ne!(preview.get(7, 0), (0xFF, 0x00, 0xFF));
+        assert_eq!(preview.get(0, 0), (0xFF, 0x00, 0xFF));
+
+        let strip = emulator.sprite_strip();
+        assert_eq!((strip.width, strip.height), (359, 8));
+    }
+}
@@ -0,0 +1,97 @@
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    // A write that changed the value
+    Change,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Condition {
+    Any,
+    Equals(u8),
+    // (value & mask) == expected
+    Masked { mask: u8, expected: u8 },
+}
+
+impl Condition {
+    fn matches(self, value: u8) -> bool {
+        match self {
+            Condition::Any => true,
+            Condition::Equals(expected) => value == expected,
+            Condition::Masked { mask, expected } => value & mask == expected,
+        }
+    }
+}
+
+pub struct Watchpoint {
+    pub access: Access,
+    // Inclusive range of addresses
+    pub start: u16,
+    pub end: u16,
+    // Only trigger while this bank is mapped. Ignored outside the switchable
+    // ROM and RAM regions.
+    pub bank: Option<usize>,
+    // Checked against the value read or written
+    pub condition: Condition,
+}
+
+impl Watchpoint {
+    pub fn new(access: Access, address: u16) -> Self {
+        Watchpoint {
+            access,
+            start: address,
+            end: address,
+            bank: None,
+            condition: Condition::Any,
+        }
+    }
+
+    pub fn matches(&self, access: Access, address: u16, bank: Option<usize>, value: u8) -> bool {
+        let bank_matches = match (self.bank, bank) {
+            (Some(wanted), Some(mapped)) => wanted == mapped,
+            _ => true
+        };
+
+        access == self.access
+            && (self.start..=self.end).contains(&address)
+            && bank_matches
+            && self.condition.matches(value)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct WatchHit {
+    pub access: Access,
+    pub pc: u16,
+    pub address: u16,
+    pub bank: Option<usize>,
+    pub old: u8,
+    pub new: u8,
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::{Access, Condition, Watchpoint};
+
+    #[test]
+    fn matching() {
+        let mut watchpoint = Watchpoint::new(Access::Write, 0xFE00);
+        watchpoint.end = 0xFE9F;
+        assert!(watchpoint.matches(Access::Write, 0xFE40, None, 0x12));
+        assert!(!watchpoint.matches(Access::Read, 0xFE40, None, 0x12));
+        assert!(!watchpoint.matches(Access::Write, 0xFEA0, None, 0x12));
+
+        watchpoint.condition = Condition::Masked { mask: 0xF0, expected: 0x30 };
+        assert!(watchpoint.matches(Access::Write, 0xFE00, None, 0x3C));
+        assert!(!watchpoint.matches(Access::Write, 0xFE00, None, 0x4C));
+
+        let mut banked = Watchpoint::new(Access::Write, 0x4000);
+        banked.bank = Some(2);
+        banked.condition = Condition::Equals(0x3C);
+        assert!(banked.matches(Access::Write, 0x4000, Some(2), 0x3C));
+        assert!(!banked.matches(Access::Write, 0x4000, Some(3), 0x3C));
+        assert!(!banked.matches(Access::Write, 0x4000, Some(2), 0x3D));
+    }
+}
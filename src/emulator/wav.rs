@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: u32 = 44;
+
+// Writes interleaved stereo samples out as 16-bit PCM
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create(filename: &str, sample_rate: u32) -> Result<Self, io::Error> {
+        let file = File::create(filename)?;
+        let mut wav = WavWriter {
+            writer: BufWriter::new(file),
+            data_size: 0,
+        };
+
+        wav.write_header(sample_rate)?;
+        Ok(wav)
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> Result<(), io::Error> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let byte_rate = sample_rate * u32::from(block_align);
+
+        // Sizes are filled in once we know how much data there is
+        self.writer.write_all(b"RIFF")?;
+        self.writer.write_all(&0u32.to_le_bytes())?;
+        self.writer.write_all(b"WAVE")?;
+
+        self.writer.write_all(b"fmt ")?;
+        self.writer.write_all(&16u32.to_le_bytes())?;
+        // Format 1 is uncompressed PCM
+        self.writer.write_all(&1u16.to_le_bytes())?;
+        self.writer.write_all(&CHANNELS.to_le_bytes())?;
+        self.writer.write_all(&sample_rate.to_le_bytes())?;
+        self.writer.write_all(&byte_rate.to_le_bytes())?;
+        self.writer.write_all(&block_align.to_le_bytes())?;
+        self.writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        self.writer.write_all(b"data")?;
+        self.writer.write_all(&0u32.to_le_bytes())?;
+        Ok(())
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> Result<(), io::Error> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+
+        self.data_size += (samples.len() * 2) as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::WavWriter;
+    use std::fs;
+
+    #[test]
+    fn header_sizes() {
+        let path = std::env::temp_dir().join("coolboy_wav_test.wav");
+        let filename = path.to_str().unwrap();
+
+        let mut wav = WavWriter::create(filename, 44100).unwrap();
+        wav.write(&[0.0, 0.5, -0.5, 1.0]).unwrap();
+        wav.finish().unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[4..8], &(36u32 + 8).to_le_bytes());
+        assert_eq!(&bytes[24..28], &44100u32.to_le_bytes());
+        assert_eq!(&bytes[40..44], &8u32.to_le_bytes());
+        assert_eq!(&bytes[50..52], &i16::MAX.to_le_bytes());
+    }
+}
@@ -0,0 +1,577 @@
+use super::emulator::{Access, Debugger, Emulator, StopReason, Symbols, Watchpoint};
+use super::options::Options;
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str;
+
+// Largest packet GDB may send us, in bytes. Advertised in hex by qSupported.
+const PACKET_SIZE: usize = 0x1000;
+// AF BC DE HL SP PC, 16 bits each and little endian. That's the start of
+// GDB's Z80 layout, so "set architecture z80" lines up.
+const REGISTER_COUNT: usize = 6;
+
+// Sent by GDB on its own, outside a packet, when Ctrl-C is pressed
+const INTERRUPT: u8 = 0x03;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// Waits for GDB on a local port and serves it until it detaches or kills us
+pub fn run(options: &Options, port: u16) -> Result<(), String> {
+    let mut emulator = Emulator::from_file(&options.rom).map_err(|e| e.to_string())?;
+    if let Some(tracer) = options.tracer()? {
+        emulator.start_trace(tracer);
+    }
+    for freeze in &options.freezes {
+        emulator.add_freeze(*freeze).map_err(|e| e.to_string())?;
+    }
+    let mut debugger = Debugger::new(emulator);
+    if let Some(symbols) = Symbols::for_rom(&options.rom) {
+        debugger.set_symbols(symbols);
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| e.to_string())?;
+    info!("Waiting for GDB on 127.0.0.1:{}, try: target remote :{}", port, port);
+    let (stream, address) = listener.accept().map_err(|e| e.to_string())?;
+    info!("GDB connected from {}", address);
+
+    serve(&mut debugger, stream).map_err(|e| e.to_string())?;
+    info!("GDB disconnected");
+    Ok(())
+}
+
+// Speaks the remote serial protocol over a connection until it's closed
+pub fn serve(debugger: &mut Debugger, stream: TcpStream) -> Result<(), io::Error> {
+    // Packets are small and GDB waits for each reply
+    stream.set_nodelay(true)?;
+
+    let mut session = Session {
+        debugger,
+        writer: stream.try_clone()?,
+        reader: BufReader::new(stream),
+        acks: true,
+        break_reasons: false,
+        hardware_breakpoints: BTreeSet::new(),
+        last_stop: format!("S{:02X}", SIGTRAP),
+    };
+
+    while let Some(packet) = session.read_packet()? {
+        match session.handle(&packet)? {
+            Some(reply) => session.send(&reply)?,
+            None => return Ok(())
+        }
+
+        // Only after the OK, which still gets acknowledged
+        if packet == b"QStartNoAckMode" {
+            session.acks = false;
+        }
+    }
+    Ok(())
+}
+
+struct Session<'a> {
+    debugger: &'a mut Debugger,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    // Every packet gets a +, until QStartNoAckMode turns that off
+    acks: bool,
+    // GDB said it understands swbreak and hwbreak stop reasons
+    break_reasons: bool,
+    // Breakpoints set with Z1, so stops can say which kind was hit
+    hardware_breakpoints: BTreeSet<u16>,
+    // Sent again in answer to ?
+    last_stop: String,
+}
+
+impl<'a> Session<'a> {
+    // The reply to a packet, or None to end the session
+    fn handle(&mut self, packet: &[u8]) -> Result<Option<String>, io::Error> {
+        // The command is always one byte, whatever comes after it
+        let (command, arguments) = match packet.split_first() {
+            Some((command, arguments)) => (char::from(*command), String::from_utf8_lossy(arguments)),
+            None => return Ok(Some(String::new()))
+        };
+        let arguments: &str = &arguments;
+
+        let reply = match command {
+            '?' => self.last_stop.clone(),
+            'g' => self.read_registers(),
+            'G' => ok_or_error(self.write_registers(arguments)),
+            'p' => usize::from_str_radix(arguments, 16).ok()
+                .and_then(|index| self.read_register(index))
+                .unwrap_or_else(|| "E01".to_string()),
+            'P' => ok_or_error(self.write_register(arguments)),
+            'm' => self.read_memory(arguments).unwrap_or_else(|| "E01".to_string()),
+            'M' => ok_or_error(self.write_memory(arguments)),
+            // Binary data comes after the colon, so use the raw packet
+            'X' => ok_or_error(self.write_binary(packet)),
+            'c' | 's' => {
+                if let Some(address) = parse_hex(arguments) {
+                    self.debugger.set_pc(address as u16);
+                }
+                self.resume(command == 's')?
+            },
+            'Z' | 'z' => self.breakpoint(command == 'Z', arguments),
+            'v' => return self.handle_v(arguments),
+            'q' => self.query(arguments),
+            'Q' if arguments == "StartNoAckMode" => "OK".to_string(),
+            // Only one thread, so any is fine
+            'H' | 'T' => "OK".to_string(),
+            'D' => {
+                self.send("OK")?;
+                return Ok(None);
+            },
+            'k' => return Ok(None),
+            // Anything else is unsupported, which an empty reply says
+            _ => String::new()
+        };
+        Ok(Some(reply))
+    }
+
+    fn handle_v(&mut self, arguments: &str) -> Result<Option<String>, io::Error> {
+        if arguments == "Cont?" {
+            return Ok(Some("vCont;c;C;s;S".to_string()));
+        }
+
+        // Only the first action matters with a single thread
+        let reply = match arguments.strip_prefix("Cont;").and_then(|actions| actions.chars().next()) {
+            Some('c') | Some('C') => self.resume(false)?,
+            Some('s') | Some('S') => self.resume(true)?,
+            _ => String::new()
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, arguments: &str) -> String {
+        let name = arguments.split(':').next().unwrap_or("");
+        match name {
+            "Supported" => {
+                self.break_reasons = arguments.contains("swbreak+");
+                format!("PacketSize={:X};QStartNoAckMode+;swbreak+;hwbreak+", PACKET_SIZE)
+            },
+            "Attached" => "1".to_string(),
+            "C" => "QC1".to_string(),
+            "fThreadInfo" => "m1".to_string(),
+            "sThreadInfo" => "l".to_string(),
+            "Symbol" => "OK".to_string(),
+            _ => String::new()
+        }
+    }
+
+    fn resume(&mut self, step: bool) -> Result<String, io::Error> {
+        let reason = if step {
+            self.debugger.step_in()
+        } else {
+            // A frame at a time, so Ctrl-C gets noticed
+            loop {
+                match self.debugger.run(1) {
+                    StopReason::FrameLimit => {
+                        if self.interrupted()? {
+                            break StopReason::FrameLimit;
+                        }
+                    },
+                    reason => break reason
+                }
+            }
+        };
+
+        self.last_stop = self.stop_reply(reason);
+        Ok(self.last_stop.clone())
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Breakpoint(address) if self.break_reasons => {
+                let kind = if self.hardware_breakpoints.contains(&address) { "hwbreak" } else { "swbreak" };
+                format!("T{:02X}{}:;", SIGTRAP, kind)
+            },
+            StopReason::Watchpoint(hit) => {
+                let kind = if hit.access == Access::Read { "rwatch" } else { "watch" };
+                format!("T{:02X}{}:{:04X};", SIGTRAP, kind, hit.address)
+            },
+            StopReason::FrameLimit => format!("S{:02X}", SIGINT),
+            _ => format!("S{:02X}", SIGTRAP)
+        }
+    }
+
+    // Checks for a Ctrl-C without waiting for one
+    fn interrupted(&mut self) -> Result<bool, io::Error> {
+        self.reader.get_ref().set_nonblocking(true)?;
+        let next = match self.reader.fill_buf() {
+            // Treat a closed connection as an interrupt too, so we stop and
+            // notice when replying
+            Ok(buffer) => Some(buffer.first().copied().unwrap_or(INTERRUPT)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => {
+                self.reader.get_ref().set_nonblocking(false)?;
+                return Err(e);
+            }
+        };
+        self.reader.get_ref().set_nonblocking(false)?;
+
+        if next != Some(INTERRUPT) {
+            return Ok(false);
+        }
+        if !self.reader.buffer().is_empty() {
+            self.reader.consume(1);
+        }
+        Ok(true)
+    }
+
+    // Z0/Z1 are breakpoints, Z2-Z4 write, read and access watchpoints.
+    // Breakpoints never patch memory, so software and hardware ones are the same.
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let mut fields = arguments.split(',');
+        let (kind, address, length) = match (fields.next(), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) {
+            (Some(kind), Some(address), Some(length)) => (kind, address as u16, length as u16),
+            _ => return "E01".to_string()
+        };
+
+        match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.add_breakpoint(address, None);
+                } else {
+                    self.debugger.remove_breakpoint(address, None);
+                }
+
+                if kind == "1" && insert {
+                    self.hardware_breakpoints.insert(address);
+                } else {
+                    self.hardware_breakpoints.remove(&address);
+                }
+            },
+            "2" | "3" | "4" => {
+                let accesses: &[Access] = match kind {
+                    "2" => &[Access::Write],
+                    "3" => &[Access::Read],
+                    _ => &[Access::Read, Access::Write]
+                };
+                let end = address.wrapping_add(length.max(1) - 1);
+
+                for access in accesses {
+                    if insert {
+                        let mut watchpoint = Watchpoint::new(*access, address);
+                        watchpoint.end = end;
+                        self.debugger.add_watchpoint(watchpoint);
+                    } else {
+                        let index = self.debugger.watchpoints().iter().position(|watchpoint| {
+                            watchpoint.access == *access && watchpoint.start == address && watchpoint.end == end
+                        });
+                        if let Some(index) = index {
+                            self.debugger.remove_watchpoint(index);
+                        }
+                    }
+                }
+            },
+            _ => return String::new()
+        }
+        "OK".to_string()
+    }
+
+    fn registers(&self) -> [u16; REGISTER_COUNT] {
+        let registers = self.debugger.registers();
+        [registers.af(), registers.bc(), registers.de(), registers.hl(), self.debugger.sp(), self.debugger.pc()]
+    }
+
+    fn set_register(&mut self, index: usize, value: u16) -> bool {
+        match index {
+            0 => self.debugger.registers_mut().set_af(value),
+            1 => self.debugger.registers_mut().set_bc(value),
+            2 => self.debugger.registers_mut().set_de(value),
+            3 => self.debugger.registers_mut().set_hl(value),
+            4 => self.debugger.set_sp(value),
+            5 => self.debugger.set_pc(value),
+            _ => return false
+        }
+        true
+    }
+
+    fn read_registers(&self) -> String {
+        let bytes: Vec<u8> = self.registers().iter().flat_map(|register| register.to_le_bytes().to_vec()).collect();
+        to_hex(&bytes)
+    }
+
+    fn write_registers(&mut self, arguments: &str) -> bool {
+        let bytes = match from_hex(arguments) {
+            Some(bytes) => bytes,
+            None => return false
+        };
+
+        // Anything past our registers is for a Z80 we aren't
+        for (index, pair) in bytes.chunks_exact(2).take(REGISTER_COUNT).enumerate() {
+            self.set_register(index, u16::from_le_bytes([pair[0], pair[1]]));
+        }
+        true
+    }
+
+    fn read_register(&self, index: usize) -> Option<String> {
+        let register = self.registers().get(index).copied()?;
+        Some(to_hex(&register.to_le_bytes()))
+    }
+
+    fn write_register(&mut self, arguments: &str) -> bool {
+        let equals = match arguments.find('=') {
+            Some(equals) => equals,
+            None => return false
+        };
+        let index = usize::from_str_radix(&arguments[..equals], 16).ok();
+        let value = from_hex(&arguments[equals + 1..]).filter(|bytes| bytes.len() == 2);
+
+        match (index, value) {
+            (Some(index), Some(value)) => self.set_register(index, u16::from_le_bytes([value[0], value[1]])),
+            _ => false
+        }
+    }
+
+    // m ADDR,LENGTH
+    fn read_memory(&self, arguments: &str) -> Option<String> {
+        let comma = arguments.find(',')?;
+        let address = parse_hex(&arguments[..comma])?;
+        let length = parse_hex(&arguments[comma + 1..])?.min(PACKET_SIZE / 2);
+
+        let bytes: Vec<u8> = (0..length)
+            .map(|offset| self.debugger.read((address + offset) as u16))
+            .collect();
+        Some(to_hex(&bytes))
+    }
+
+    // M ADDR,LENGTH:BYTES
+    fn write_memory(&mut self, arguments: &str) -> bool {
+        let (address, _, data) = match split_write(arguments.as_bytes()) {
+            Some(parts) => parts,
+            None => return false
+        };
+        let bytes = match str::from_utf8(data).ok().and_then(from_hex) {
+            Some(bytes) => bytes,
+            None => return false
+        };
+
+        self.write_bytes(address, &bytes);
+        true
+    }
+
+    // X ADDR,LENGTH:BINARY with }-escapes
+    fn write_binary(&mut self, packet: &[u8]) -> bool {
+        let (address, length, data) = match split_write(&packet[1..]) {
+            Some(parts) => parts,
+            None => return false
+        };
+
+        let bytes = unescape(data);
+        if bytes.len() != length {
+            return false;
+        }
+        self.write_bytes(address, &bytes);
+        true
+    }
+
+    fn write_bytes(&mut self, address: usize, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.debugger.write((address + offset) as u16, *byte);
+        }
+    }
+
+    // Skips anything between packets: acks, and Ctrl-Cs that arrive while
+    // we're already stopped. None when GDB has gone.
+    fn read_packet(&mut self) -> Result<Option<Vec<u8>>, io::Error> {
+        loop {
+            match self.read_byte()? {
+                Some(b'$') => (),
+                Some(_) => continue,
+                None => return Ok(None)
+            }
+
+            let mut data = Vec::new();
+            let mut checksum = 0u8;
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => {
+                        checksum = checksum.wrapping_add(byte);
+                        data.push(byte);
+                    },
+                    None => return Ok(None)
+                }
+            }
+
+            let mut sent = [0; 2];
+            self.reader.read_exact(&mut sent)?;
+            let sent = str::from_utf8(&sent).ok().and_then(|sent| u8::from_str_radix(sent, 16).ok());
+
+            if self.acks {
+                if sent != Some(checksum) {
+                    self.writer.write_all(b"-")?;
+                    continue;
+                }
+                self.writer.write_all(b"+")?;
+            }
+            return Ok(Some(data));
+        }
+    }
+
+    // Resends until GDB acknowledges it
+    fn send(&mut self, data: &str) -> Result<(), io::Error> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        loop {
+            write!(self.writer, "${}#{:02x}", data, checksum)?;
+            self.writer.flush()?;
+
+            if !self.acks {
+                return Ok(());
+            }
+
+            // Anything but an ack is left for read_packet
+            let ack = self.reader.fill_buf()?.first().copied();
+            match ack {
+                Some(b'+') => {
+                    self.reader.consume(1);
+                    return Ok(());
+                },
+                Some(b'-') => self.reader.consume(1),
+                _ => return Ok(())
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, io::Error> {
+        let mut byte = [0];
+        match self.reader.read_exact(&mut byte) {
+            Ok(()) => Ok(Some(byte[0])),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+fn ok_or_error(ok: bool) -> String {
+    let reply = if ok { "OK" } else { "E01" };
+    reply.to_string()
+}
+
+// ADDR,LENGTH:DATA as sent with M and X
+fn split_write(arguments: &[u8]) -> Option<(usize, usize, &[u8])> {
+    let colon = arguments.iter().position(|&byte| byte == b':')?;
+    let header = str::from_utf8(&arguments[..colon]).ok()?;
+    let comma = header.find(',')?;
+    Some((parse_hex(&header[..comma])?, parse_hex(&header[comma + 1..])?, &arguments[colon + 1..]))
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok()).collect()
+}
+
+// } escapes the next byte, which is XORed with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for &byte in data {
+        if escaped {
+            bytes.push(byte ^ 0x20);
+            escaped = false;
+        } else if byte == b'}' {
+            escaped = true;
+        } else {
+            bytes.push(byte);
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::emulator::test_roms::test_rom;
+    use super::super::emulator::{Debugger, Emulator};
+    use super::{serve, unescape};
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // Sends a packet and returns the reply's data, checking the acks
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(stream, "${}#{:02x}", data, checksum).unwrap();
+
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+');
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut sent = [0; 2];
+        stream.read_exact(&mut sent).unwrap();
+        stream.write_all(b"+").unwrap();
+
+        assert_eq!(reply[0], b'$');
+        String::from_utf8(reply[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn scripted_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let mut rom = test_rom();
+            // INC A; JR -3 at 0150
+            rom[0x150..0x153].copy_from_slice(&[0x3C, 0x18, 0xFD]);
+            let mut debugger = Debugger::new(Emulator::from_bytes(&rom));
+            let (stream, _) = listener.accept().unwrap();
+            serve(&mut debugger, stream).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(request(&mut stream, "qSupported:multiprocess+;swbreak+;hwbreak+").contains("swbreak+"));
+        assert_eq!(request(&mut stream, "?"), "S05");
+
+        // AF BC DE HL SP PC after power on
+        assert_eq!(request(&mut stream, "g"), "b0011300d8004d01feff0001");
+        assert_eq!(request(&mut stream, "P5=5001"), "OK");
+        assert_eq!(request(&mut stream, "p5"), "5001");
+
+        assert_eq!(request(&mut stream, "MC000,3:3c00ff"), "OK");
+        assert_eq!(request(&mut stream, "mc000,3"), "3c00ff");
+        assert_eq!(request(&mut stream, "XC001,1:}]"), "OK");
+        assert_eq!(request(&mut stream, "mc001,1"), "7d");
+
+        // Runs the INC A at 0150 and stops on the JR after it
+        assert_eq!(request(&mut stream, "Z1,151,1"), "OK");
+        assert_eq!(request(&mut stream, "c"), "T05hwbreak:;");
+        assert_eq!(request(&mut stream, "p5"), "5101");
+        assert_eq!(request(&mut stream, "p0"), "1002");
+        assert_eq!(request(&mut stream, "z1,151,1"), "OK");
+        // Then steps back round the loop
+        assert_eq!(request(&mut stream, "s"), "S05");
+        assert_eq!(request(&mut stream, "p5"), "5001");
+        assert_eq!(request(&mut stream, "vUnknown"), "");
+        // Not even ASCII
+        assert_eq!(request(&mut stream, "\u{FF}"), "");
+
+        write!(stream, "$k#6b").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(unescape(b"a}]b}\x03"), b"a}b#");
+    }
+}
@@ -0,0 +1,54 @@
+use super::super::emulator::{Emulator, Image};
+use super::{Filters, HEIGHT, WIDTH};
+
+use sdl2::Sdl;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+const SCALE: u32 = 3;
+// Pixels between the screens
+const GAP: usize = 4;
+const DUAL_WIDTH: usize = WIDTH as usize * 2 + GAP;
+
+// Two linked Game Boys side by side in one window
+pub struct DualScreen {
+    canvas: Canvas<Window>,
+    // One each, since blending remembers the last frame
+    filters: [Filters; 2],
+}
+
+impl DualScreen {
+    pub fn new(sdl: &Sdl, left: Filters, right: Filters) -> Result<Self, String> {
+        let video = sdl.video()?;
+        let window = video.window("coolboy link", DUAL_WIDTH as u32 * SCALE, HEIGHT * SCALE)
+            .position_centered()
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        Ok(DualScreen {
+            canvas,
+            filters: [left, right],
+        })
+    }
+
+    pub fn draw(&mut self, left: &Emulator, right: &Emulator) -> Result<(), String> {
+        let scale = SCALE as usize;
+        let mut image = Image::new(DUAL_WIDTH * scale, HEIGHT as usize * scale);
+        image.blit(&self.filters[0].apply(&left.screenshot(), left.has_cgb_colors(), scale), 0, 0);
+        image.blit(&self.filters[1].apply(&right.screenshot(), right.has_cgb_colors(), scale), (WIDTH as usize + GAP) * scale, 0);
+
+        // The texture only lives for this frame, so it can't outlive its creator
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, image.width as u32, image.height as u32)
+            .map_err(|e| e.to_string())?;
+        texture.update(None, &image.pixels, image.width * 3).map_err(|e| e.to_string())?;
+
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None)?;
+        self.canvas.present();
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+// Post-processing on frames between the emulator and the window, all done
+// on the CPU: CGB colour correction, blending frames together like a slow
+// LCD, and scaling up with the gaps between the pixels showing.
+
+use super::super::emulator::Image;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Blend {
+    Off,
+    // Each frame averaged with the last, for games that flicker sprites
+    // every other frame to fake transparency
+    Mix,
+    // Old frames fade out over a few more, like the DMG screen's ghosting
+    Ghost,
+}
+
+impl Blend {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Blend::Off),
+            "mix" => Some(Blend::Mix),
+            "ghost" => Some(Blend::Ghost),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Grid {
+    Off,
+    // Faint lines between rows and columns
+    Lines,
+    // Darker gaps, so every pixel stands on its own
+    Dots,
+}
+
+impl Grid {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Grid::Off),
+            "lines" => Some(Grid::Lines),
+            "dots" => Some(Grid::Dots),
+            _ => None
+        }
+    }
+}
+
+pub struct Filters {
+    pub color_correction: bool,
+    pub blend: Blend,
+    pub grid: Grid,
+    // The last frame as drawn for Mix, or as shown for Ghost
+    previous: Option<Image>,
+}
+
+impl Filters {
+    pub fn new() -> Self {
+        Filters {
+            color_correction: false,
+            blend: Blend::Off,
+            grid: Grid::Off,
+            previous: None,
+        }
+    }
+
+    // Runs a frame through everything that's turned on and scales it up.
+    // Colour correction only applies to frames in CGB colours.
+    pub fn apply(&mut self, frame: &Image, cgb: bool, scale: usize) -> Image {
+        let mut image = Image::new(frame.width, frame.height);
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let rgb = frame.get(x, y);
+                image.set(x, y, if self.color_correction && cgb { correct(rgb) } else { rgb });
+            }
+        }
+
+        let image = self.blend(image);
+        self.scale(&image, scale.max(1))
+    }
+
+    fn blend(&mut self, image: Image) -> Image {
+        let previous = match self.previous.take() {
+            Some(previous) if self.blend != Blend::Off
+                && previous.width == image.width && previous.height == image.height => previous,
+            _ => {
+                if self.blend != Blend::Off {
+                    self.previous = Some(image.clone());
+                }
+                return image;
+            }
+        };
+
+        let mut blended = Image::new(image.width, image.height);
+        for (out, (new, old)) in blended.pixels.iter_mut().zip(image.pixels.iter().zip(previous.pixels.iter())) {
+            let (new, old) = (u16::from(*new), u16::from(*old));
+            *out = match self.blend {
+                Blend::Ghost => (new * 5 + old * 3) / 8,
+                _ => (new + old) / 2,
+            } as u8;
+        }
+
+        self.previous = Some(if self.blend == Blend::Ghost { blended.clone() } else { image });
+        blended
+    }
+
+    fn scale(&self, image: &Image, scale: usize) -> Image {
+        let mut scaled = Image::new(image.width * scale, image.height * scale);
+        for y in 0..scaled.height {
+            for x in 0..scaled.width {
+                let (r, g, b) = image.get(x / scale, y / scale);
+                // Edges are the last row and column of each pixel's block
+                let edges = (x % scale == scale - 1) as u16 + (y % scale == scale - 1) as u16;
+                // Out of 8
+                let brightness = match self.grid {
+                    _ if scale == 1 || edges == 0 => 8,
+                    Grid::Off => 8,
+                    Grid::Lines => 6,
+                    Grid::Dots => 4 >> (edges - 1),
+                };
+                let dim = |value: u8| (u16::from(value) * brightness / 8) as u8;
+                scaled.set(x, y, (dim(r), dim(g), dim(b)));
+            }
+        }
+        scaled
+    }
+}
+
+// The CGB screen is darker and less saturated than a modern display, and
+// bleeds the channels into each other. This is the matrix higan uses, on
+// 5 bit channels, coming out at no more than 240.
+fn correct((r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+    let (r, g, b) = (u16::from(r >> 3), u16::from(g >> 3), u16::from(b >> 3));
+    let mix = |value: u16| (value.min(960) >> 2) as u8;
+    (mix(r * 26 + g * 4 + b * 2), mix(g * 24 + b * 8), mix(r * 6 + g * 4 + b * 22))
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::super::emulator::Image;
+    use super::{Blend, Filters, Grid};
+
+    fn filled(rgb: (u8, u8, u8)) -> Image {
+        let mut image = Image::new(2, 2);
+        for y in 0..2 {
+            for x in 0..2 {
+                image.set(x, y, rgb);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn correction_and_blending() {
+        let mut filters = Filters::new();
+        filters.color_correction = true;
+        assert_eq!(filters.apply(&filled((0xFF, 0x00, 0x00)), true, 1).get(0, 0), (0xC9, 0x00, 0x2E));
+        assert_eq!(filters.apply(&filled((0xFF, 0xFF, 0xFF)), true, 1).get(0, 0), (0xF0, 0xF0, 0xF0));
+        // DMG colours are left alone
+        assert_eq!(filters.apply(&filled((0xFF, 0x00, 0x00)), false, 1).get(1, 1), (0xFF, 0x00, 0x00));
+
+        filters.blend = Blend::Mix;
+        assert_eq!(filters.apply(&filled((0xFF, 0xFF, 0xFF)), false, 1).get(0, 0), (0xFF, 0xFF, 0xFF));
+        assert_eq!(filters.apply(&filled((0x00, 0x00, 0x00)), false, 1).get(0, 0), (0x7F, 0x7F, 0x7F));
+        assert_eq!(filters.apply(&filled((0x00, 0x00, 0x00)), false, 1).get(0, 0), (0x00, 0x00, 0x00));
+
+        // Ghosting keeps fading out
+        filters.blend = Blend::Ghost;
+        filters.apply(&filled((0xFF, 0xFF, 0xFF)), false, 1);
+        assert_eq!(filters.apply(&filled((0x00, 0x00, 0x00)), false, 1).get(0, 0), (0x3B, 0x3B, 0x3B));
+        assert_eq!(filters.apply(&filled((0x00, 0x00, 0x00)), false, 1).get(0, 0), (0x16, 0x16, 0x16));
+    }
+
+    #[test]
+    fn grid() {
+        let mut filters = Filters::new();
+        filters.grid = Grid::Dots;
+        let image = filters.apply(&filled((0xFF, 0xFF, 0xFF)), false, 3);
+        assert_eq!((image.width, image.height), (6, 6));
+        assert_eq!(image.get(3, 3), (0xFF, 0xFF, 0xFF));
+        assert_eq!(image.get(2, 0), (0x7F, 0x7F, 0x7F));
+        assert_eq!(image.get(5, 5), (0x3F, 0x3F, 0x3F));
+
+        filters.grid = Grid::Lines;
+        let image = filters.apply(&filled((0xFF, 0xFF, 0xFF)), false, 3);
+        assert_eq!(image.get(0, 2), (0xBF, 0xBF, 0xBF));
+        assert_eq!(image.get(2, 2), (0xBF, 0xBF, 0xBF));
+
+        // Too small for gaps
+        assert_eq!(filters.apply(&filled((0xFF, 0xFF, 0xFF)), false, 1).get(1, 1), (0xFF, 0xFF, 0xFF));
+    }
+}
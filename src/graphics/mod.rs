@@ -1,7 +1,15 @@
 extern crate sdl2;
 extern crate rand;
 
-use super::emulator::Emulator;
+mod dual;
+mod filters;
+mod viewer;
+
+pub use self::dual::DualScreen;
+pub use self::filters::{Blend, Filters, Grid};
+pub use self::viewer::VramViewer;
+
+use super::emulator::{Emulator, Image};
 
 use rand::prelude::*;
 use sdl2::Sdl;
@@ -10,6 +18,8 @@ use sdl2::video::{Window, WindowContext};
 use sdl2::pixels::{Color, PixelFormatEnum};
 
 pub const PIXEL_SIZE: u32 = 5;
+// Smaller with the SGB border, so it still fits on screen
+pub const SGB_PIXEL_SIZE: u32 = 4;
 pub const WIDTH: u32 = 160;
 pub const HEIGHT: u32 = 144;
 
@@ -20,9 +30,10 @@ pub struct Screen {
 }
 
 impl Screen {
-    pub fn new(sdl: &Sdl) -> Result<(Self, TextureCreator<WindowContext>), String>{
+    // The window's size in pixels
+    pub fn new(sdl: &Sdl, width: u32, height: u32) -> Result<(Self, TextureCreator<WindowContext>), String>{
         let video = sdl.video()?;
-        let window = video.window("coolboy v1.0", WIDTH * PIXEL_SIZE, HEIGHT * PIXEL_SIZE)
+        let window = video.window("coolboy v1.0", width, height)
             .position_centered()
             .opengl()
             .build()
@@ -48,9 +59,13 @@ impl Screen {
         
     }
 
-    pub fn draw(&mut self, texture: &Texture) {
-        info!("Drawing to screen");
+    // Shows a frame that's already been through the filters, at the size of
+    // the texture
+    pub fn draw(&mut self, texture: &mut Texture, image: &Image) -> Result<(), String> {
+        texture.update(None, &image.pixels, image.width * 3).map_err(|e| e.to_string())?;
         self.canvas.clear();
+        self.canvas.copy(texture, None, None)?;
         self.canvas.present();
+        Ok(())
     }
 }
\ No newline at end of file
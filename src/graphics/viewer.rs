@@ -0,0 +1,91 @@
+use super::super::emulator::{Emulator, Image, Palette, TILE_MAP_0, TILE_MAP_1};
+
+use sdl2::Sdl;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+const SCALE: u32 = 2;
+// Pixels between the views
+const GAP: usize = 4;
+const WIDTH: usize = 128 + 256 * 2 + GAP * 2;
+const HEIGHT: usize = 256 + GAP + 16;
+
+// A second window showing the tile sheet, both tile maps and the sprites
+pub struct VramViewer {
+    canvas: Canvas<Window>,
+    visible: bool,
+    // What the tile sheet is coloured with
+    palette: Palette,
+}
+
+impl VramViewer {
+    pub fn new(sdl: &Sdl) -> Result<Self, String> {
+        let video = sdl.video()?;
+        let window = video.window("coolboy VRAM", WIDTH as u32 * SCALE, HEIGHT as u32 * SCALE)
+            .hidden()
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+
+        Ok(VramViewer {
+            canvas,
+            visible: false,
+            palette: Palette::Background,
+        })
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+        if self.visible {
+            self.canvas.window_mut().show();
+        } else {
+            self.canvas.window_mut().hide();
+        }
+    }
+
+    pub fn hide(&mut self) {
+        if self.visible {
+            self.toggle();
+        }
+    }
+
+    // Sprite tiles are easier to make out in the palettes sprites use
+    pub fn next_palette(&mut self) {
+        self.palette = match self.palette {
+            Palette::Background => Palette::Object0,
+            Palette::Object0 => Palette::Object1,
+            Palette::Object1 => Palette::Background,
+        };
+        info!("Tile sheet in {}", self.palette.name());
+    }
+
+    pub fn draw(&mut self, emulator: &Emulator) -> Result<(), String> {
+        if !self.visible {
+            return Ok(());
+        }
+
+        let mut image = Image::new(WIDTH, HEIGHT);
+        image.blit(&emulator.tile_sheet(self.palette), 0, 0);
+        image.blit(&emulator.tile_map(TILE_MAP_0), 128 + GAP, 0);
+        image.blit(&emulator.tile_map(TILE_MAP_1), 128 + 256 + GAP * 2, 0);
+        image.blit(&emulator.sprite_strip(), 0, 256 + GAP);
+
+        // The texture only lives for this frame, so it can't outlive its creator
+        let texture_creator = self.canvas.texture_creator();
+        let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, WIDTH as u32, HEIGHT as u32)
+            .map_err(|e| e.to_string())?;
+        texture.update(None, &image.pixels, WIDTH * 3).map_err(|e| e.to_string())?;
+
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None)?;
+        self.canvas.present();
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+use super::emulator::{CaptureDevice, Debugger, Emulator, Image, StopReason};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+// Mooneye and acid2 ROMs run this when they're done
+const LD_B_B: u8 = 0x40;
+// B C D E H L after a Mooneye test passes; failures set them all to 42
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// Blargg's ROMs also report through cartridge RAM: a status byte, a
+// signature, then the same text they send over serial
+const BLARGG_STATUS: u16 = 0xA000;
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+const BLARGG_TEXT: u16 = 0xA004;
+
+// Each acid2 ROM's reference image, named as it ships in the suite's img
+// folder
+const ACID2_REFERENCES: [(&str, &str); 2] = [("dmg-acid2", "reference-dmg.png"), ("cgb-acid2", "reference.png")];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Suite {
+    Blargg,
+    Mooneye,
+    Acid2,
+}
+
+impl Suite {
+    // Emulated time to give a ROM before calling it hung. The combined
+    // cpu_instrs takes almost a minute on hardware.
+    fn frame_limit(self) -> u32 {
+        match self {
+            Suite::Blargg => 60 * 120,
+            Suite::Mooneye => 60 * 20,
+            Suite::Acid2 => 60 * 10,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    TimedOut,
+    // Not in the test ROM directory
+    Skipped,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "PASS"),
+            Outcome::Failed(detail) => write!(f, "FAIL  {}", detail),
+            Outcome::TimedOut => write!(f, "TIMEOUT"),
+            Outcome::Skipped => write!(f, "SKIP"),
+        }
+    }
+}
+
+// Paths are relative to the test ROM directory, laid out as the suites ship
+const TEST_ROMS: &[(&str, Suite)] = &[
+    ("blargg/cpu_instrs/individual/01-special.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/02-interrupts.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/03-op sp,hl.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/04-op r,imm.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/05-op rp.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/06-ld r,r.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/07-jr,jp,call,ret,rst.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/08-misc instrs.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/09-op r,r.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/10-bit ops.gb", Suite::Blargg),
+    ("blargg/cpu_instrs/individual/11-op a,(hl).gb", Suite::Blargg),
+    ("blargg/cpu_instrs/cpu_instrs.gb", Suite::Blargg),
+    ("blargg/instr_timing/instr_timing.gb", Suite::Blargg),
+    ("blargg/mem_timing/individual/01-read_timing.gb", Suite::Blargg),
+    ("blargg/mem_timing/individual/02-write_timing.gb", Suite::Blargg),
+    ("blargg/mem_timing/individual/03-modify_timing.gb", Suite::Blargg),
+    ("blargg/mem_timing/mem_timing.gb", Suite::Blargg),
+    ("blargg/halt_bug.gb", Suite::Blargg),
+    ("mooneye/acceptance/add_sp_e_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/boot_div-dmgABCmgb.gb", Suite::Mooneye),
+    ("mooneye/acceptance/boot_hwio-dmgABCmgb.gb", Suite::Mooneye),
+    ("mooneye/acceptance/boot_regs-dmgABC.gb", Suite::Mooneye),
+    ("mooneye/acceptance/call_cc_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/call_cc_timing2.gb", Suite::Mooneye),
+    ("mooneye/acceptance/call_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/call_timing2.gb", Suite::Mooneye),
+    ("mooneye/acceptance/di_timing-GS.gb", Suite::Mooneye),
+    ("mooneye/acceptance/div_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/ei_sequence.gb", Suite::Mooneye),
+    ("mooneye/acceptance/ei_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/halt_ime0_ei.gb", Suite::Mooneye),
+    ("mooneye/acceptance/halt_ime0_nointr_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/halt_ime1_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/halt_ime1_timing2-GS.gb", Suite::Mooneye),
+    ("mooneye/acceptance/if_ie_registers.gb", Suite::Mooneye),
+    ("mooneye/acceptance/intr_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/jp_cc_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/jp_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/ld_hl_sp_e_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/oam_dma_restart.gb", Suite::Mooneye),
+    ("mooneye/acceptance/oam_dma_start.gb", Suite::Mooneye),
+    ("mooneye/acceptance/oam_dma_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/pop_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/push_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/rapid_di_ei.gb", Suite::Mooneye),
+    ("mooneye/acceptance/ret_cc_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/ret_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/reti_intr_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/reti_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/rst_timing.gb", Suite::Mooneye),
+    ("mooneye/acceptance/bits/mem_oam.gb", Suite::Mooneye),
+    ("mooneye/acceptance/bits/reg_f.gb", Suite::Mooneye),
+    ("mooneye/acceptance/bits/unused_hwio-GS.gb", Suite::Mooneye),
+    ("mooneye/acceptance/instr/daa.gb", Suite::Mooneye),
+    ("mooneye/acceptance/interrupts/ie_push.gb", Suite::Mooneye),
+    ("mooneye/acceptance/oam_dma/basic.gb", Suite::Mooneye),
+    ("mooneye/acceptance/oam_dma/reg_read.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/div_write.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/rapid_toggle.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tim00.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tim00_div_trigger.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tim01.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tim01_div_trigger.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tim10.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tim10_div_trigger.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tim11.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tim11_div_trigger.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tima_reload.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tima_write_reloading.gb", Suite::Mooneye),
+    ("mooneye/acceptance/timer/tma_write_reloading.gb", Suite::Mooneye),
+    ("mooneye/emulator-only/mbc1/bits_bank1.gb", Suite::Mooneye),
+    ("mooneye/emulator-only/mbc1/bits_bank2.gb", Suite::Mooneye),
+    ("mooneye/emulator-only/mbc1/bits_mode.gb", Suite::Mooneye),
+    ("mooneye/emulator-only/mbc1/bits_ramg.gb", Suite::Mooneye),
+    ("mooneye/emulator-only/mbc1/ram_64kb.gb", Suite::Mooneye),
+    ("mooneye/emulator-only/mbc1/ram_256kb.gb", Suite::Mooneye),
+    ("mooneye/emulator-only/mbc1/rom_512kb.gb", Suite::Mooneye),
+    ("mooneye/emulator-only/mbc1/rom_1Mb.gb", Suite::Mooneye),
+    ("mooneye/emulator-only/mbc1/rom_2Mb.gb", Suite::Mooneye),
+    ("dmg-acid2.gb", Suite::Acid2),
+    ("cgb-acid2.gbc", Suite::Acid2),
+];
+
+// Runs every test ROM found in a directory and prints a table of results
+pub fn run(directory: &str) -> Result<(), String> {
+    let results = run_all(Path::new(directory), |rom, outcome| println!("{:<56} {}", rom, outcome));
+
+    let count = |wanted: fn(&Outcome) -> bool| results.iter().filter(|outcome| wanted(outcome)).count();
+    let passed = count(|outcome| *outcome == Outcome::Passed);
+    let failed = count(|outcome| matches!(outcome, Outcome::Failed(_) | Outcome::TimedOut));
+    let skipped = count(|outcome| *outcome == Outcome::Skipped);
+
+    println!();
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+
+    if failed > 0 {
+        Err(format!("{} test ROMs failed", failed))
+    } else {
+        Ok(())
+    }
+}
+
+// Results in TEST_ROMS order, handed to report as each one finishes
+pub fn run_all<F: FnMut(&str, &Outcome)>(directory: &Path, mut report: F) -> Vec<Outcome> {
+    TEST_ROMS.iter().map(|(rom, suite)| {
+        let path = directory.join(rom);
+        let outcome = if path.exists() {
+            // One ROM taking the emulator down shouldn't stop the rest
+            panic::catch_unwind(AssertUnwindSafe(|| run_rom(&path, *suite)))
+                .unwrap_or_else(|_| Ok(Outcome::Failed("emulator panicked".to_string())))
+                .unwrap_or_else(|e| Outcome::Failed(e.to_string()))
+        } else {
+            Outcome::Skipped
+        };
+
+        report(rom, &outcome);
+        outcome
+    }).collect()
+}
+
+pub fn run_rom(path: &Path, suite: Suite) -> Result<Outcome, io::Error> {
+    let filename = path.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path isn't UTF-8"))?;
+    let mut debugger = Debugger::new(Emulator::from_file(filename)?);
+
+    match suite {
+        Suite::Blargg => Ok(run_blargg(&mut debugger)),
+        Suite::Mooneye => Ok(run_mooneye(&mut debugger)),
+        Suite::Acid2 => run_acid2(&mut debugger, path),
+    }
+}
+
+// Checks what's been sent over serial, and cartridge RAM, once a frame
+fn run_blargg(debugger: &mut Debugger) -> Outcome {
+    let capture = CaptureDevice::new();
+    let sent = capture.sent();
+    debugger.set_serial_device(Box::new(capture));
+
+    let mut failing = false;
+    for _ in 0..Suite::Blargg.frame_limit() {
+        debugger.run(1);
+
+        let output = String::from_utf8_lossy(&sent.borrow()).into_owned();
+        if output.contains("Passed") {
+            return Outcome::Passed;
+        }
+
+        // Give a failure a frame to finish printing its details
+        if failing {
+            return Outcome::Failed(summarise(&output));
+        }
+        failing = output.contains("Failed");
+        if let Some(outcome) = blargg_memory(debugger) {
+            return outcome;
+        }
+    }
+    Outcome::TimedOut
+}
+
+fn blargg_memory(debugger: &Debugger) -> Option<Outcome> {
+    let signature = [debugger.read(BLARGG_STATUS + 1), debugger.read(BLARGG_STATUS + 2), debugger.read(BLARGG_STATUS + 3)];
+    let status = debugger.read(BLARGG_STATUS);
+    if signature != BLARGG_SIGNATURE || status == BLARGG_RUNNING {
+        return None;
+    }
+
+    let text: String = (BLARGG_TEXT..0xC000)
+        .map(|address| debugger.read(address))
+        .take_while(|byte| *byte != 0)
+        .map(char::from)
+        .collect();
+
+    Some(match status {
+        0 => Outcome::Passed,
+        code => Outcome::Failed(format!("result code {}: {}", code, summarise(&text))),
+    })
+}
+
+// The output on one line, which is all the table has room for
+fn summarise(output: &str) -> String {
+    output.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn run_mooneye(debugger: &mut Debugger) -> Outcome {
+    if !run_to_ld_b_b(debugger, Suite::Mooneye.frame_limit()) {
+        return Outcome::TimedOut;
+    }
+
+    let registers = debugger.registers();
+    mooneye_verdict([registers.b, registers.c, registers.d, registers.e, registers.h, registers.l])
+}
+
+fn mooneye_verdict(registers: [u8; 6]) -> Outcome {
+    if registers == MOONEYE_PASSED {
+        Outcome::Passed
+    } else {
+        Outcome::Failed(format!("B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X}",
+            registers[0], registers[1], registers[2], registers[3], registers[4], registers[5]))
+    }
+}
+
+// The screen is checked against the suite's reference image, and a missing
+// reference fails. Screens that don't match are saved as .actual.png.
+fn run_acid2(debugger: &mut Debugger, path: &Path) -> Result<Outcome, io::Error> {
+    if !run_to_ld_b_b(debugger, Suite::Acid2.frame_limit()) {
+        return Ok(Outcome::TimedOut);
+    }
+
+    let screen = debugger.screenshot();
+    let outcome = match acid2_reference(path) {
+        Some(reference) => match picture_differences(&screen, &Image::load_png(&reference)?) {
+            0 => return Ok(Outcome::Passed),
+            differences => format!("{} pixels differ from {}", differences, reference.display()),
+        },
+        None => "no reference image".to_string(),
+    };
+
+    screen.save_png(&path.with_extension("actual.png"))?;
+    Ok(Outcome::Failed(outcome))
+}
+
+// A .png next to the ROM with the same name, or else the image the suite
+// ships in its img folder
+fn acid2_reference(path: &Path) -> Option<PathBuf> {
+    let shipped = ACID2_REFERENCES.iter()
+        .find(|(stem, _)| path.file_stem().is_some_and(|name| name == *stem))
+        .map(|(_, reference)| path.with_file_name("img").join(reference));
+    iter::once(path.with_extension("png")).chain(shipped).find(|reference| reference.exists())
+}
+
+// Pixels that don't match, allowing for every colour being swapped for
+// another throughout, since the DMG reference isn't in our shades. A
+// different size means nothing matches.
+fn picture_differences(screen: &Image, reference: &Image) -> usize {
+    if (screen.width, screen.height) != (reference.width, reference.height) {
+        return screen.width * screen.height;
+    }
+
+    let mut ours = HashMap::new();
+    let mut theirs = HashMap::new();
+    (0..screen.height).flat_map(|y| (0..screen.width).map(move |x| (x, y))).filter(|&(x, y)| {
+        let (mine, expected) = (screen.get(x, y), reference.get(x, y));
+        *ours.entry(mine).or_insert(expected) != expected || *theirs.entry(expected).or_insert(mine) != mine
+    }).count()
+}
+
+// Stops on the LD B,B without running it
+fn run_to_ld_b_b(debugger: &mut Debugger, max_frames: u32) -> bool {
+    let reason = debugger.run_until(max_frames, |debugger| debugger.read(debugger.pc()) == LD_B_B);
+    matches!(reason, StopReason::Finished)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::emulator::test_roms::test_rom;
+    use super::super::emulator::{Debugger, Emulator, Image};
+    use super::{blargg_memory, mooneye_verdict, run_all, run_rom, Outcome, Suite, TEST_ROMS};
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn mooneye_signature() {
+        assert_eq!(mooneye_verdict([3, 5, 8, 13, 21, 34]), Outcome::Passed);
+        assert_eq!(mooneye_verdict([0x42; 6]), Outcome::Failed("B:42 C:42 D:42 E:42 H:42 L:42".to_string()));
+    }
+
+    #[test]
+    fn blargg_memory_report() {
+        let mut rom = test_rom();
+        rom[0x147] = 0x01;
+        let mut debugger = Debugger::new(Emulator::from_bytes(&rom));
+        assert_eq!(blargg_memory(&debugger), None);
+
+        for (offset, byte) in [0x80, 0xDE, 0xB0, 0x61].iter().chain(b"Failed #3\n\0").enumerate() {
+            debugger.edit(0xA000 + offset as u16, None, *byte).unwrap();
+        }
+        assert_eq!(blargg_memory(&debugger), None);
+
+        debugger.edit(0xA000, None, 3).unwrap();
+        assert_eq!(blargg_memory(&debugger), Some(Outcome::Failed("result code 3: Failed #3".to_string())));
+    }
+
+    #[test]
+    fn missing_roms_are_skipped() {
+        let directory = env::temp_dir().join("coolboy-no-test-roms");
+        let mut reported = 0;
+        let results = run_all(&directory, |_, _| reported += 1);
+
+        assert_eq!(reported, TEST_ROMS.len());
+        assert!(results.iter().all(|outcome| *outcome == Outcome::Skipped));
+    }
+
+    #[test]
+    fn acid2_needs_a_reference() {
+        let directory = env::temp_dir().join("coolboy-acid2-test");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("acid2.gb");
+        // Finishes straight away on an LD B,B
+        let mut rom = test_rom();
+        rom[0x100] = 0x40;
+        fs::write(&path, &rom).unwrap();
+
+        assert_eq!(run_rom(&path, Suite::Acid2).unwrap(), Outcome::Failed("no reference image".to_string()));
+        let mut screen = Image::load_png(&path.with_extension("actual.png")).unwrap();
+
+        // Other shades are fine as long as the picture is the same
+        for pixel in screen.pixels.iter_mut() {
+            *pixel = !*pixel;
+        }
+        screen.save_png(&path.with_extension("png")).unwrap();
+        assert_eq!(run_rom(&path, Suite::Acid2).unwrap(), Outcome::Passed);
+
+        let shade = screen.get(0, 0);
+        screen.set(5, 5, (shade.0, shade.1, !shade.2));
+        screen.save_png(&path.with_extension("png")).unwrap();
+        match run_rom(&path, Suite::Acid2).unwrap() {
+            Outcome::Failed(detail) => assert!(detail.starts_with("1 pixels differ")),
+            outcome => panic!("{:?}", outcome),
+        }
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // Needs the suites in test-roms, or wherever COOLBOY_TEST_ROMS points.
+    // Run with cargo test --release -- --ignored
+    #[test]
+    #[ignore]
+    fn test_roms() {
+        let directory = env::var_os("COOLBOY_TEST_ROMS").map_or_else(|| PathBuf::from("test-roms"), PathBuf::from);
+        let results = run_all(&directory, |rom, outcome| println!("{:<56} {}", rom, outcome));
+        let failed = results.iter().filter(|outcome| matches!(outcome, Outcome::Failed(_) | Outcome::TimedOut)).count();
+        assert_eq!(failed, 0);
+    }
+}
@@ -0,0 +1,83 @@
+use super::emulator::{crc32, CaptureDevice, Emulator, Image, Movie, ALL_PALETTES, TILE_MAP_0, TILE_MAP_1};
+use super::netlink;
+use super::options::Options;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Runs the emulator for a fixed number of frames with no window or audio device
+pub fn run(options: &Options) -> Result<(), String> {
+    let mut emulator = Emulator::from_file(&options.rom).map_err(|e| e.to_string())?;
+    options.apply_colors(&mut emulator)?;
+
+    if let Some(tracer) = options.tracer()? {
+        emulator.start_trace(tracer);
+    }
+
+    for freeze in &options.freezes {
+        emulator.add_freeze(*freeze).map_err(|e| e.to_string())?;
+    }
+
+    if options.print_serial {
+        emulator.set_serial_device(Box::new(CaptureDevice::new()));
+    }
+
+    if let Some(filename) = &options.wav {
+        emulator.start_recording(filename).map_err(|e| e.to_string())?;
+    }
+
+    let mut network = netlink::from_options(options, &mut emulator)?;
+
+    if options.record_movie.is_some() {
+        emulator.start_movie(true);
+    }
+
+    // A movie runs to its end unless told otherwise
+    let mut frames = options.frames_or_default();
+    if let Some(filename) = &options.play_movie {
+        let movie = Movie::load(filename).map_err(|e| e.to_string())?;
+        frames = options.frames.unwrap_or(movie.inputs.len() as u32);
+        emulator.play_movie(movie).map_err(|e| e.to_string())?;
+    }
+
+    info!("Running {} for {} frames", options.rom, frames);
+
+    for _ in 0..frames {
+        netlink::update(&mut network, &mut emulator);
+        // Nothing is playing these, so don't let them pile up
+        emulator.take_samples();
+    }
+
+    emulator.stop_recording().map_err(|e| e.to_string())?;
+
+    if let (Some(filename), Some(movie)) = (&options.record_movie, emulator.stop_movie()) {
+        movie.save(filename).map_err(|e| e.to_string())?;
+    }
+
+    if let Some(directory) = &options.dump_vram {
+        dump_vram(&emulator, Path::new(directory)).map_err(|e| e.to_string())?;
+        info!("Saved VRAM views to {}", directory);
+    }
+
+    // Two runs that ended up in the same place print the same checksum
+    info!("Final state CRC-32: {:08X}", crc32(&emulator.save_state()));
+    Ok(())
+}
+
+// The tile sheet in each palette, both maps and the sprites as PNGs, plus
+// the OAM table as text
+fn dump_vram(emulator: &Emulator, directory: &Path) -> Result<(), io::Error> {
+    fs::create_dir_all(directory)?;
+
+    let save = |image: Image, name: &str| image.save_png(&directory.join(name));
+    for palette in ALL_PALETTES.iter() {
+        save(emulator.tile_sheet(*palette), &format!("tiles-{}.png", palette.name().to_lowercase()))?;
+    }
+    save(emulator.tile_map(TILE_MAP_0), "map-9800.png")?;
+    save(emulator.tile_map(TILE_MAP_1), "map-9c00.png")?;
+    save(emulator.sprite_strip(), "oam.png")?;
+
+    let table: Vec<String> = emulator.sprites().iter().map(|sprite| sprite.to_string()).collect();
+    fs::write(directory.join("oam.txt"), table.join("\n") + "\n")
+}
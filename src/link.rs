@@ -0,0 +1,142 @@
+use super::audio;
+use super::emulator::{Emulator, Inputs, LinkedPair, Movie, CLOCK_SPEED, CYCLES_PER_FRAME};
+use super::graphics;
+use super::options::Options;
+
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Two Game Boys side by side with a link cable between them. The first runs
+// the usual ROM and the second other_rom, which can be the same one.
+pub fn run(options: &Options, other_rom: &str) -> Result<(), String> {
+    let sdl = sdl2::init()?;
+    let mut screen = graphics::DualScreen::new(&sdl, options.filters(), options.filters())?;
+    let mut speaker = audio::Speaker::new(&sdl)?;
+
+    let mut left = Emulator::from_file(&options.rom).map_err(|e| e.to_string())?;
+    let mut right = Emulator::from_file(other_rom).map_err(|e| e.to_string())?;
+    options.apply_colors(&mut left)?;
+    options.apply_colors(&mut right)?;
+    let mut pair = LinkedPair::new(left, right);
+    pair.left.set_sample_rate(speaker.adjusted_rate());
+
+    // Each player gets a movie, the second's named with a -2
+    if options.record_movie.is_some() {
+        pair.left.start_movie(true);
+        pair.right.start_movie(true);
+    }
+    if let Some(filename) = &options.play_movie {
+        let movie = Movie::load(filename).map_err(|e| e.to_string())?;
+        pair.left.play_movie(movie).map_err(|e| e.to_string())?;
+        let movie = Movie::load(&second_player(filename)).map_err(|e| e.to_string())?;
+        pair.right.play_movie(movie).map_err(|e| e.to_string())?;
+    }
+
+    let timestep = Duration::from_secs(1) * CYCLES_PER_FRAME / CLOCK_SPEED;
+    let mut next_frame = Instant::now();
+    let mut event_pump = sdl.event_pump().map_err(|e| e.to_string())?;
+
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit {..} => break 'running,
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    if let Some((emulator, input)) = joypad_key(&mut pair, keycode) {
+                        emulator.input_down(input);
+                    }
+                },
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some((emulator, input)) = joypad_key(&mut pair, keycode) {
+                        emulator.input_up(input);
+                    }
+                },
+                _ => ()
+            }
+        }
+
+        pair.update();
+
+        // Only the first player is heard
+        speaker.queue(&pair.left.take_samples());
+        pair.right.take_samples();
+        pair.left.set_sample_rate(speaker.adjusted_rate());
+
+        if let Err(e) = screen.draw(&pair.left, &pair.right) {
+            error!("Failed to draw: {}", e);
+        }
+
+        next_frame += timestep;
+        let now = Instant::now();
+        if next_frame > now {
+            ::std::thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+
+    if let Some(filename) = &options.record_movie {
+        if let (Some(left), Some(right)) = (pair.left.stop_movie(), pair.right.stop_movie()) {
+            left.save(filename).map_err(|e| e.to_string())?;
+            right.save(&second_player(filename)).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+// The first player keeps the usual WASD, M, N, Enter and Escape, and the
+// second gets the arrows and L, K, O and P. Whoever's playing a movie back
+// ignores the keyboard.
+fn joypad_key(pair: &mut LinkedPair, keycode: Keycode) -> Option<(&mut Emulator, Inputs)> {
+    let (second, input) = match keycode {
+        Keycode::W => (false, Inputs::UP),
+        Keycode::A => (false, Inputs::LEFT),
+        Keycode::S => (false, Inputs::DOWN),
+        Keycode::D => (false, Inputs::RIGHT),
+        Keycode::M => (false, Inputs::A),
+        Keycode::N => (false, Inputs::B),
+        Keycode::Return => (false, Inputs::SELECT),
+        Keycode::Escape => (false, Inputs::START),
+        Keycode::Up => (true, Inputs::UP),
+        Keycode::Left => (true, Inputs::LEFT),
+        Keycode::Down => (true, Inputs::DOWN),
+        Keycode::Right => (true, Inputs::RIGHT),
+        Keycode::L => (true, Inputs::A),
+        Keycode::K => (true, Inputs::B),
+        Keycode::O => (true, Inputs::SELECT),
+        Keycode::P => (true, Inputs::START),
+        _ => return None
+    };
+
+    let emulator = if second { &mut pair.right } else { &mut pair.left };
+    if emulator.is_playing_movie() {
+        None
+    } else {
+        Some((emulator, input))
+    }
+}
+
+// game.cbm becomes game-2.cbm
+fn second_player(filename: &str) -> String {
+    let path = Path::new(filename);
+    let stem = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let name = match path.extension() {
+        Some(extension) => format!("{}-2.{}", stem, extension.to_string_lossy()),
+        None => format!("{}-2", stem)
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::second_player;
+
+    #[test]
+    fn second_player_movie_names() {
+        assert_eq!(second_player("movies/tetris.cbm"), "movies/tetris-2.cbm");
+        assert_eq!(second_player("versus"), "versus-2");
+    }
+}
@@ -0,0 +1,72 @@
+use super::emulator::disasm::{self, Instruction};
+use super::emulator::Symbols;
+use super::options::Options;
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const CGB_FLAG_ADDRESS: usize = 0x143;
+
+// Prints every ROM bank as a linear disassembly, data included. Banks 1 and
+// up are shown at 4000-7FFF where the game sees them.
+pub fn run(options: &Options) -> Result<(), String> {
+    let rom = fs::read(&options.rom).map_err(|e| e.to_string())?;
+    let symbols = Symbols::for_rom(&options.rom).unwrap_or_else(Symbols::new);
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    // Name the CGB registers for games that run in CGB mode
+    let cgb = rom.get(CGB_FLAG_ADDRESS).is_some_and(|flag| tbit!(*flag, 7));
+
+    for bank in 0..rom.len().div_ceil(ROM_BANK_SIZE) {
+        let base = if bank == 0 { 0 } else { ROM_BANK_SIZE };
+        let read = |address: u16| {
+            let address = address as usize;
+            let offset = if address < ROM_BANK_SIZE { address } else { bank * ROM_BANK_SIZE + address - ROM_BANK_SIZE };
+            rom.get(offset).cloned().unwrap_or(0)
+        };
+
+        let mut instructions = Vec::new();
+        let mut address = base;
+        while address < base + ROM_BANK_SIZE {
+            let instruction = disasm::decode(read, address as u16);
+            address += instruction.length as usize;
+            instructions.push(instruction);
+        }
+
+        // Symbols first, then anything jumped to from this bank gets a label
+        let targets: BTreeSet<u16> = instructions.iter().filter_map(Instruction::target).collect();
+        let label = |address: u16| {
+            let bank = if (0x4000..0x8000).contains(&address) { bank } else { 0 };
+            match symbols.name(bank, address) {
+                Some(name) => Some(name.to_string()),
+                None if targets.contains(&address) => Some(format!("jump_{:02X}_{:04X}", bank, address)),
+                None => None
+            }
+        };
+
+        writeln!(out, "SECTION \"ROM Bank ${:02X}\", ROM{}[${:04X}]", bank, if bank == 0 { "0" } else { "X" }, base)
+            .map_err(|e| e.to_string())?;
+
+        for instruction in instructions.iter() {
+            if let Some(label) = label(instruction.address) {
+                writeln!(out, "\n{}:", label).map_err(|e| e.to_string())?;
+            }
+
+            let text = instruction.format_with(cgb, label);
+            let bytes: Vec<String> = (0..instruction.length)
+                .map(|i| format!("{:02X}", read(instruction.address.wrapping_add(i))))
+                .collect();
+            let cycles = match instruction.taken_cycles {
+                Some(taken) => format!("{}/{}", taken, instruction.cycles),
+                None => instruction.cycles.to_string(),
+            };
+
+            writeln!(out, "    {:<28} ; {:02X}:{:04X}  {:<8} {}", text, bank, instruction.address, bytes.join(" "), cycles)
+                .map_err(|e| e.to_string())?;
+        }
+    }
+
+    out.flush().map_err(|e| e.to_string())
+}
@@ -24,43 +24,140 @@ pub mod macros {
     }
 }
 
+mod audio;
 mod emulator;
 mod graphics;
+mod gdb;
+mod harness;
+mod headless;
+mod link;
+mod listing;
 mod logging;
+mod netlink;
+mod options;
+mod repl;
+mod slots;
 
-use emulator::{Emulator, Inputs};
+use emulator::{CaptureDevice, Channel, ColorScheme, Panning, Emulator, Inputs, Movie, RewindConfig, CLOCK_SPEED, CYCLES_PER_FRAME, SGB_HEIGHT, SGB_WIDTH};
+use options::Options;
+use slots::SaveSlots;
 
 use sdl2::pixels::PixelFormatEnum;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
 
-use std::time::Duration;
+use chrono::Local;
+
+use std::time::{Duration, Instant};
 
 fn main() -> Result<(), String> {
     println!("here");
     logging::init().map_err(|e| e.to_string())?;
+
+    let options = Options::from_args()?;
+    if options.disasm {
+        return listing::run(&options);
+    }
+    if options.headless {
+        return headless::run(&options);
+    }
+    if options.debug {
+        return repl::run(&options);
+    }
+    if let Some(directory) = &options.test_roms {
+        return harness::run(directory);
+    }
+    if let Some(port) = options.gdb {
+        return gdb::run(&options, port);
+    }
+    if let Some(other_rom) = &options.link {
+        return link::run(&options, other_rom);
+    }
     
     let sdl = sdl2::init()?;
 
-    let (mut screen, texture_creator) = graphics::Screen::new(&sdl).map_err(|e| e.to_string())?;
+    let mut viewer = graphics::VramViewer::new(&sdl)?;
+
+    let mut speaker = audio::Speaker::new(&sdl)?;
+
+    let mut emulator = Emulator::from_file(&options.rom).map_err(|e| e.to_string())?;
+    emulator.set_sample_rate(speaker.adjusted_rate());
+    let color_schemes = options.apply_colors(&mut emulator)?;
+    let mut filters = options.filters();
+
+    // The SGB border makes for a bigger picture
+    let (width, height, scale) = if emulator.sgb_frame().is_some() {
+        (SGB_WIDTH as u32, SGB_HEIGHT as u32, graphics::SGB_PIXEL_SIZE)
+    } else {
+        (graphics::WIDTH, graphics::HEIGHT, graphics::PIXEL_SIZE)
+    };
+    let (mut screen, texture_creator) = graphics::Screen::new(&sdl, width * scale, height * scale).map_err(|e| e.to_string())?;
     
     let mut texture = texture_creator.create_texture_streaming(
         PixelFormatEnum::RGB24, 
-        graphics::WIDTH * graphics::PIXEL_SIZE, 
-        graphics::HEIGHT * graphics::PIXEL_SIZE)
+        width * scale, 
+        height * scale)
         .map_err(|e| e.to_string())?;
 
-    
-    let mut emulator = Emulator::from_file("roms/tetris.gb").map_err(|e| e.to_string())?;
+    if let Some(tracer) = options.tracer()? {
+        emulator.start_trace(tracer);
+    }
+
+    if let Some(filename) = &options.wav {
+        emulator.set_sample_rate(speaker.sample_rate());
+        emulator.start_recording(filename).map_err(|e| e.to_string())?;
+    }
+
+    for freeze in &options.freezes {
+        emulator.add_freeze(*freeze).map_err(|e| e.to_string())?;
+    }
+
+    if options.print_serial {
+        emulator.set_serial_device(Box::new(CaptureDevice::new()));
+    }
+
+    if options.rewind_budget > 0 {
+        let mut config = RewindConfig::new();
+        config.budget = options.rewind_budget * 1024 * 1024;
+        emulator.enable_rewind(config);
+    }
+
+    let mut network = netlink::from_options(&options, &mut emulator)?;
+
+    let mut slots = SaveSlots::new(&options.rom);
+    let mut rewinding = false;
+
+    if options.record_movie.is_some() {
+        emulator.start_movie(true);
+    }
+    if let Some(filename) = &options.play_movie {
+        let movie = Movie::load(filename).map_err(|e| e.to_string())?;
+        emulator.play_movie(movie).map_err(|e| e.to_string())?;
+    }
+
     // info!("fuck");
-    let timestep = Duration::from_secs(1) / 60;
+    let timestep = Duration::from_secs(1) * CYCLES_PER_FRAME / CLOCK_SPEED;
+    let mut next_frame = Instant::now();
     let mut event_pump = sdl.event_pump().map_err(|e| e.to_string())?;
 
     'running: loop {
         for event in event_pump.poll_iter() {
+            // Movie playback owns the joypad
+            let event = match event {
+                Event::KeyDown { keycode: Some(keycode), .. } | Event::KeyUp { keycode: Some(keycode), .. }
+                    if emulator.is_playing_movie() && is_joypad_key(keycode) => continue,
+                event => event
+            };
+
             match event {
                 Event::Quit {..} => break 'running,
-                Event::KeyDown { keycode: Some(keycode), .. } => {
+                // Closing the viewer shouldn't close the emulator
+                Event::Window { window_id, win_event: WindowEvent::Close, .. } if window_id == viewer.window_id() => {
+                    viewer.hide();
+                },
+                Event::KeyDown { keycode: Some(keycode), keymod, .. } => {
+                    let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    let ctrl = keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD);
                     match keycode {
                         Keycode::W => emulator.input_down(Inputs::UP),
                         Keycode::A => emulator.input_down(Inputs::LEFT),
@@ -70,6 +167,41 @@ fn main() -> Result<(), String> {
                         Keycode::N => emulator.input_down(Inputs::B),
                         Keycode::Return => emulator.input_down(Inputs::SELECT),
                         Keycode::Escape => emulator.input_down(Inputs::START),
+                        Keycode::F1 if ctrl => next_panning(&mut emulator, Channel::Square1),
+                        Keycode::F2 if ctrl => next_panning(&mut emulator, Channel::Square2),
+                        Keycode::F3 if ctrl => next_panning(&mut emulator, Channel::Wave),
+                        Keycode::F4 if ctrl => next_panning(&mut emulator, Channel::Noise),
+                        Keycode::F1 => toggle_channel(&mut emulator, Channel::Square1, shift),
+                        Keycode::F2 => toggle_channel(&mut emulator, Channel::Square2, shift),
+                        Keycode::F3 => toggle_channel(&mut emulator, Channel::Wave, shift),
+                        Keycode::F4 => toggle_channel(&mut emulator, Channel::Noise, shift),
+                        Keycode::F5 => {
+                            if let Err(e) = slots.save(&emulator) {
+                                error!("Failed to save state: {}", e);
+                            }
+                        },
+                        Keycode::F6 => next_color_scheme(&mut emulator, &color_schemes),
+                        Keycode::F7 => {
+                            if let Err(e) = slots.load(&mut emulator) {
+                                error!("Failed to load state: {}", e);
+                            }
+                        },
+                        Keycode::F8 => log_sprites(&emulator),
+                        Keycode::F9 if shift => viewer.next_palette(),
+                        Keycode::F9 => viewer.toggle(),
+                        Keycode::F10 => toggle_movie(&mut emulator),
+                        Keycode::F12 => toggle_recording(&mut emulator, &speaker),
+                        Keycode::Backspace => rewinding = true,
+                        Keycode::Num0 => slots.select(0),
+                        Keycode::Num1 => slots.select(1),
+                        Keycode::Num2 => slots.select(2),
+                        Keycode::Num3 => slots.select(3),
+                        Keycode::Num4 => slots.select(4),
+                        Keycode::Num5 => slots.select(5),
+                        Keycode::Num6 => slots.select(6),
+                        Keycode::Num7 => slots.select(7),
+                        Keycode::Num8 => slots.select(8),
+                        Keycode::Num9 => slots.select(9),
                         _ => ()
                     }
                 },
@@ -83,17 +215,54 @@ fn main() -> Result<(), String> {
                         Keycode::N => emulator.input_up(Inputs::B),
                         Keycode::Return => emulator.input_up(Inputs::SELECT),
                         Keycode::Escape => emulator.input_up(Inputs::START),
+                        Keycode::Backspace => rewinding = false,
                         _ => ()
                     }
                 },
                 _ => ()
             }
         }
-        screen.draw(&texture);
+        // Going back in time would leave the other end of a cable behind
+        if rewinding && network.is_none() {
+            emulator.rewind(1);
+        } else {
+            netlink::update(&mut network, &mut emulator);
+        }
+
+        speaker.queue(&emulator.take_samples());
+        // A WAV's rate is fixed in its header, so rate control would bend
+        // the pitch of a recording
+        if !emulator.is_recording() {
+            emulator.set_sample_rate(speaker.adjusted_rate());
+        }
 
-        ::std::thread::sleep(timestep);
+        let frame = emulator.sgb_frame().unwrap_or_else(|| emulator.screenshot());
+        let frame = filters.apply(&frame, emulator.has_cgb_colors(), scale as usize);
+        if let Err(e) = screen.draw(&mut texture, &frame) {
+            error!("Failed to draw: {}", e);
+        }
+        if let Err(e) = viewer.draw(&emulator) {
+            error!("Failed to draw VRAM viewer: {}", e);
+        }
+
+        // Sleep until the next frame is due rather than for a flat timestep,
+        // so time spent emulating doesn't slow the frame rate down
+        next_frame += timestep;
+        let now = Instant::now();
+        if next_frame > now {
+            ::std::thread::sleep(next_frame - now);
+        } else {
+            // Too far behind to catch up, so don't try
+            next_frame = now;
+        }
     };
 
+    emulator.stop_recording().map_err(|e| e.to_string())?;
+
+    if let (Some(filename), Some(movie)) = (&options.record_movie, emulator.stop_movie()) {
+        movie.save(filename).map_err(|e| e.to_string())?;
+    }
+
     // loop {
     //     let begin = SystemTime::now();
 
@@ -109,6 +278,95 @@ fn main() -> Result<(), String> {
     Ok(())
 }
 
+fn is_joypad_key(keycode: Keycode) -> bool {
+    matches!(keycode, Keycode::W | Keycode::A | Keycode::S | Keycode::D
+        | Keycode::M | Keycode::N | Keycode::Return | Keycode::Escape)
+}
+
+// Prints the OAM table, skipping sprites parked off screen
+fn log_sprites(emulator: &Emulator) {
+    for sprite in emulator.sprites() {
+        if sprite.y != 0 && sprite.y < 160 {
+            info!("{}", sprite);
+        }
+    }
+}
+
+// Starts recording a movie from the current state, or stops and saves it
+fn toggle_movie(emulator: &mut Emulator) {
+    // Playback has the joypad, so there's nothing to record until it's done
+    if let Some(frames) = emulator.movie_frames_left() {
+        info!("Can't record while a movie is playing, {} frames to go", frames);
+        return;
+    }
+
+    if !emulator.is_recording_movie() {
+        info!("Recording movie");
+        emulator.start_movie(false);
+        return;
+    }
+
+    if let Some(movie) = emulator.stop_movie() {
+        let filename = Local::now().format("coolboy-%Y%m%d-%H%M%S.cbm").to_string();
+        match movie.save(&filename) {
+            Ok(()) => info!("Saved {} frame movie to {}", movie.inputs.len(), filename),
+            Err(e) => error!("Failed to save movie: {}", e)
+        }
+    }
+}
+
+// Moves on to the next DMG colour scheme, back to the first after the last
+fn next_color_scheme(emulator: &mut Emulator, schemes: &[ColorScheme]) {
+    let current = schemes.iter().position(|scheme| scheme == emulator.color_scheme());
+    let next = &schemes[current.map_or(0, |index| (index + 1) % schemes.len())];
+    info!("Colour scheme: {}", next.name);
+    emulator.set_color_scheme(next.clone());
+}
+
+// Toggles muting a sound channel, or soloing it when shift is held
+fn toggle_channel(emulator: &mut Emulator, channel: Channel, solo: bool) {
+    let mut control = emulator.channel_control(channel);
+    if solo {
+        control.solo = !control.solo;
+        info!("{:?} solo {}", channel, if control.solo { "on" } else { "off" });
+    } else {
+        control.muted = !control.muted;
+        info!("{:?} {}", channel, if control.muted { "muted" } else { "unmuted" });
+    }
+    emulator.set_channel_control(channel, control);
+}
+
+// Cycles a sound channel through following NR51, then left, right and both
+// sides regardless of it
+fn next_panning(emulator: &mut Emulator, channel: Channel) {
+    let mut control = emulator.channel_control(channel);
+    control.panning = match control.panning {
+        Panning::Game => Panning::Left,
+        Panning::Left => Panning::Right,
+        Panning::Right => Panning::Both,
+        Panning::Both => Panning::Game,
+    };
+    info!("{:?} panning {:?}", channel, control.panning);
+    emulator.set_channel_control(channel, control);
+}
+
+fn toggle_recording(emulator: &mut Emulator, speaker: &audio::Speaker) {
+    let result = if emulator.is_recording() {
+        info!("Stopped recording audio");
+        emulator.stop_recording()
+    } else {
+        let filename = Local::now().format("coolboy-%Y%m%d-%H%M%S.wav").to_string();
+        info!("Recording audio to {}", filename);
+        // Recorded at the nominal rate throughout, see the main loop
+        emulator.set_sample_rate(speaker.sample_rate());
+        emulator.start_recording(&filename)
+    };
+
+    if let Err(e) = result {
+        error!("Audio recording failed: {}", e);
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -0,0 +1,326 @@
+// A link cable to another coolboy over TCP. The two ends run in lockstep:
+// every sync interval each sends what happened on its serial port and waits
+// to hear the same from the other, so neither is ever ahead and nothing has
+// to be rolled back.
+//
+// Every message is a kind byte, a big endian u16 payload length, then the
+// payload:
+//   HELLO  "CBLK", protocol version, sync interval in cycles (u32)
+//   SYNC   sequence number (u32), bytes received so far (u32), 1 and SB if
+//          waiting on the other end's clock or 0 and 0, then the bytes sent
+//          on our clock since the last SYNC
+//   BYE    nothing, sent when hanging up
+
+use super::emulator::{Emulator, NullDevice, SerialDevice, CYCLES_PER_FRAME};
+use super::options::Options;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::time::Duration;
+
+const MAGIC: &[u8; 4] = b"CBLK";
+const VERSION: u8 = 1;
+
+const HELLO: u8 = 1;
+const SYNC: u8 = 2;
+const BYE: u8 = 3;
+
+// A quarter of the time a byte takes on the internal clock. Longer copes
+// better with a slow network, but bytes take longer to get across.
+pub const DEFAULT_SYNC_INTERVAL: u32 = 1024;
+// Shorter than the longest instruction would need two syncs in one step
+pub const MIN_SYNC_INTERVAL: u32 = 32;
+// Silence for this long means the other end is gone, even if the
+// connection never closed
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What the serial port has seen since the last sync, and what the other
+// end told us then
+#[derive(Default)]
+struct Port {
+    // SB while waiting on the other end's clock
+    waiting: Option<u8>,
+    // Bytes sent on our clock since the last sync
+    sent: Vec<u8>,
+    sent_total: u32,
+    received_total: u32,
+    // Bytes the other end has clocked across that we haven't taken yet
+    incoming: VecDeque<u8>,
+    // What the other end had in SB at the last sync, if it was waiting for
+    // us and has taken everything we sent
+    peer_waiting: Option<u8>,
+}
+
+struct NetworkPort(Rc<RefCell<Port>>);
+
+impl SerialDevice for NetworkPort {
+    fn exchange(&mut self, data: u8) -> u8 {
+        let mut port = self.0.borrow_mut();
+        port.waiting = None;
+
+        match port.peer_waiting.take() {
+            Some(theirs) => {
+                port.sent.push(data);
+                port.sent_total += 1;
+                theirs
+            },
+            // Not listening yet, as far as we know, so the line floats high
+            None => 0xFF
+        }
+    }
+
+    fn poll(&mut self, data: u8) -> Option<u8> {
+        let mut port = self.0.borrow_mut();
+        match port.incoming.pop_front() {
+            Some(theirs) => {
+                port.waiting = None;
+                port.received_total += 1;
+                Some(theirs)
+            },
+            None => {
+                port.waiting = Some(data);
+                None
+            }
+        }
+    }
+}
+
+pub struct NetworkLink {
+    stream: TcpStream,
+    port: Rc<RefCell<Port>>,
+    interval: u32,
+    // Cycle into the current frame the next sync is due at
+    next_sync: u32,
+    sequence: u32,
+}
+
+impl NetworkLink {
+    // Waits for the other end to connect. The host's sync interval is the
+    // one both ends use.
+    pub fn accept(listener: &TcpListener, interval: u32) -> Result<Self, io::Error> {
+        let (stream, address) = listener.accept()?;
+        info!("Link cable connected from {}", address);
+        NetworkLink::start(stream, interval, true)
+    }
+
+    pub fn connect(address: &str, interval: u32) -> Result<Self, io::Error> {
+        let stream = TcpStream::connect(address)?;
+        info!("Link cable connected to {}", address);
+        NetworkLink::start(stream, interval, false)
+    }
+
+    fn start(mut stream: TcpStream, interval: u32, host: bool) -> Result<Self, io::Error> {
+        // Every sync waits on a reply, so don't let Nagle hold messages back
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let mut hello = MAGIC.to_vec();
+        hello.push(VERSION);
+        hello.extend_from_slice(&interval.to_be_bytes());
+        write_message(&mut stream, HELLO, &hello)?;
+
+        let (kind, payload) = read_message(&mut stream)?;
+        if kind != HELLO || payload.len() != 9 || &payload[..4] != MAGIC {
+            return Err(protocol_error("the other end isn't a coolboy link cable"));
+        }
+        if payload[4] != VERSION {
+            return Err(protocol_error(&format!("link cable version {} is not supported (expected {})", payload[4], VERSION)));
+        }
+
+        let theirs = u32::from_be_bytes([payload[5], payload[6], payload[7], payload[8]]);
+        let interval = if host { interval } else { theirs };
+        if interval < MIN_SYNC_INTERVAL {
+            return Err(protocol_error(&format!("sync interval {} is too short", interval)));
+        }
+        info!("Syncing every {} cycles", interval);
+
+        Ok(NetworkLink {
+            stream,
+            port: Rc::new(RefCell::new(Port::default())),
+            interval,
+            next_sync: interval,
+            sequence: 0,
+        })
+    }
+
+    // This end of the cable, to plug into the emulator
+    pub fn device(&self) -> Box<dyn SerialDevice> {
+        Box::new(NetworkPort(self.port.clone()))
+    }
+
+    // Runs the emulator for a frame, stopping to sync every interval. The
+    // end of a frame counts as its last cycle, so both ends sync the same
+    // number of times per frame however their instructions line up.
+    pub fn update(&mut self, emulator: &mut Emulator) -> Result<(), io::Error> {
+        loop {
+            let done = emulator.step();
+            let now = if done { CYCLES_PER_FRAME } else { emulator.frame_cycles() };
+            while self.next_sync <= now {
+                self.sync()?;
+                self.next_sync += self.interval;
+            }
+
+            if done {
+                self.next_sync -= CYCLES_PER_FRAME;
+                return Ok(());
+            }
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), io::Error> {
+        let message = {
+            let mut port = self.port.borrow_mut();
+            let mut message = Vec::with_capacity(10 + port.sent.len());
+            message.extend_from_slice(&self.sequence.to_be_bytes());
+            message.extend_from_slice(&port.received_total.to_be_bytes());
+            match port.waiting {
+                Some(data) => message.extend_from_slice(&[1, data]),
+                None => message.extend_from_slice(&[0, 0])
+            }
+            message.append(&mut port.sent);
+            message
+        };
+        write_message(&mut self.stream, SYNC, &message)?;
+
+        let (kind, payload) = read_message(&mut self.stream)?;
+        match kind {
+            SYNC if payload.len() >= 10 => (),
+            BYE => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the other end hung up")),
+            _ => return Err(protocol_error("unexpected message"))
+        }
+
+        let sequence = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+        if sequence != self.sequence {
+            return Err(protocol_error(&format!("out of step, expected sync {} but got {}", self.sequence, sequence)));
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let mut port = self.port.borrow_mut();
+        let received = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+        // Until it's taken what we sent, what it has in SB is old news
+        port.peer_waiting = if payload[8] == 1 && received == port.sent_total {
+            Some(payload[9])
+        } else {
+            None
+        };
+        port.incoming.extend(&payload[10..]);
+        Ok(())
+    }
+}
+
+impl Drop for NetworkLink {
+    fn drop(&mut self) {
+        let _ = write_message(&mut self.stream, BYE, &[]);
+    }
+}
+
+// Hosts or joins a networked link cable if the options ask for one, and
+// plugs it in
+pub fn from_options(options: &Options, emulator: &mut Emulator) -> Result<Option<NetworkLink>, String> {
+    let link = if let Some(port) = options.link_host {
+        let listener = TcpListener::bind(("0.0.0.0", port)).map_err(|e| e.to_string())?;
+        info!("Waiting for the other end of the link cable on port {}", port);
+        NetworkLink::accept(&listener, options.link_interval)
+    } else if let Some(address) = &options.link_connect {
+        NetworkLink::connect(address, options.link_interval)
+    } else {
+        return Ok(None);
+    };
+
+    let link = link.map_err(|e| e.to_string())?;
+    emulator.set_serial_device(link.device());
+    // The other end can't go back in time with us, so don't keep history
+    emulator.disable_rewind();
+    Ok(Some(link))
+}
+
+// Runs a frame over the link, or unplugs it if the connection is lost
+pub fn update(link: &mut Option<NetworkLink>, emulator: &mut Emulator) {
+    let result = match link.as_mut() {
+        Some(link) => link.update(emulator),
+        None => {
+            emulator.update();
+            return;
+        }
+    };
+
+    if let Err(e) = result {
+        error!("Link cable disconnected: {}", e);
+        emulator.set_serial_device(Box::new(NullDevice));
+        *link = None;
+    }
+}
+
+fn write_message(stream: &mut TcpStream, kind: u8, payload: &[u8]) -> Result<(), io::Error> {
+    let mut message = Vec::with_capacity(3 + payload.len());
+    message.push(kind);
+    message.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+fn read_message(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), io::Error> {
+    let mut header = [0; 3];
+    stream.read_exact(&mut header)?;
+    let mut payload = vec![0; u16::from_be_bytes([header[1], header[2]]) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::super::emulator::test_roms::serial_rom;
+    use super::super::emulator::{crc32, Emulator};
+    use super::{NetworkLink, DEFAULT_SYNC_INTERVAL};
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    const FRAMES: usize = 10;
+
+    // Runs one end for a while, returning what it received and its final state
+    fn run(mut link: NetworkLink, internal_clock: bool) -> (Vec<u8>, u32) {
+        let mut emulator = Emulator::from_bytes(&serial_rom(internal_clock));
+        emulator.set_serial_device(link.device());
+        for _ in 0..FRAMES {
+            link.update(&mut emulator).unwrap();
+        }
+
+        let received = (0xC000..0xD000).map(|address| emulator.inspect(address, None)).take_while(|data| *data != 0).collect();
+        (received, crc32(&emulator.save_state()))
+    }
+
+    fn session() -> ((Vec<u8>, u32), (Vec<u8>, u32)) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let host = thread::spawn(move || run(NetworkLink::accept(&listener, DEFAULT_SYNC_INTERVAL).unwrap(), true));
+        // The host's interval wins
+        let guest = run(NetworkLink::connect(&address, 4096).unwrap(), false);
+        (host.join().unwrap(), guest)
+    }
+
+    #[test]
+    fn loopback() {
+        let ((sent_back, host_state), (received, guest_state)) = session();
+
+        // The guest counts up as bytes arrive, and the host only gets an
+        // answer when the guest was known to be waiting
+        let answered: Vec<u8> = sent_back.iter().cloned().filter(|data| *data != 0xFF).collect();
+        assert!(answered.len() > 10);
+        assert_eq!(answered, (1..=answered.len() as u8).collect::<Vec<_>>());
+        assert!(received.len() == answered.len() || received.len() + 1 == answered.len());
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Lockstep, so the same again every time
+        assert_eq!(session(), ((sent_back, host_state), (received, guest_state)));
+    }
+}
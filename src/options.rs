@@ -0,0 +1,321 @@
+use super::emulator::{ColorScheme, Emulator, Freeze, Symbols, TraceFilter, Tracer};
+use super::graphics::{Blend, Filters, Grid};
+use super::netlink::{DEFAULT_SYNC_INTERVAL, MIN_SYNC_INTERVAL};
+
+use std::env;
+use std::path::Path;
+
+const DEFAULT_ROM: &str = "roms/tetris.gb";
+const DEFAULT_FRAMES: u32 = 600;
+const DEFAULT_REWIND_BUDGET: usize = 32;
+// Read if it's there and --palettes doesn't say otherwise
+const DEFAULT_PALETTES: &str = "coolboy-palettes.txt";
+
+const USAGE: &str = "Usage: coolboy [OPTIONS] [ROM]
+
+Options:
+    --headless            Run without a window or audio device
+    --debug               Run in the terminal debugger
+    --gdb PORT            Wait for GDB to connect on a local port and debug over that
+    --test-roms DIR       Run the Blargg, Mooneye and acid2 test ROMs in DIR and report
+    --link ROM            Link a second Game Boy running ROM, shown side by side
+    --link-host PORT      Wait for another coolboy to connect a link cable over TCP
+    --link-connect ADDR   Connect a link cable to another coolboy at HOST:PORT
+    --link-interval N     Cycles between link cable syncs when hosting (default 1024)
+    --disasm              Print a disassembly of the whole ROM and exit
+    --frames N            Frames to run in headless mode
+    --wav FILE            Record audio output to a WAV file
+    --rewind-budget MB    Memory for rewind history, 0 to turn it off
+    --record-movie FILE   Record inputs from power on to a movie file
+    --play-movie FILE     Play back a movie (.cbm, .vbm or BizHawk Input Log.txt)
+    --trace FILE          Log every instruction in the Gameboy Doctor format
+    --trace-range RANGE   Only trace PCs in [BANK:]START-END, in hex
+    --trace-ring N        Only keep the last N instructions, written out on a crash or exit
+    --trace-labels        Add labels from the ROM's .sym file to the trace
+    --dump-vram DIR       Save tile, map and sprite views as PNGs after a headless run
+    --freeze WHERE=VALUE  Pin [BANK:]ADDR to a value every frame, in hex; can be repeated
+    --print-serial        Log text sent over the link port, like Blargg test results
+    --palette SCHEME      Colours for DMG games: grey, green, pocket, one from the
+                          palettes file, or 4 or 12 hex colours like FFFFFF,AAAAAA,...
+    --palettes FILE       Colour schemes to add, as lines of NAME = COLOURS
+                          (default coolboy-palettes.txt if there is one)
+    --cgb                 Run DMG games on a Game Boy Color, in the colours it picks
+    --sgb                 Run DMG games on a Super Game Boy, with their borders
+    --color-correction    Tone CGB colours down to look like they did on its screen
+    --blend MODE          Blend frames together: mix for flickering sprites, ghost
+                          for a DMG screen's smearing, or off
+    --lcd-grid MODE       Show the gaps between pixels: lines, dots or off";
+
+pub struct Options {
+    pub rom: String,
+    // Run without a window or audio device
+    pub headless: bool,
+    // Run in the terminal debugger instead of a window
+    pub debug: bool,
+    // Serve the GDB remote protocol on this port instead of opening a window
+    pub gdb: Option<u16>,
+    // Run the test ROM suites in this directory
+    pub test_roms: Option<String>,
+    // Run a second Game Boy with this ROM, linked to the first
+    pub link: Option<String>,
+    // Networked link cable, either end
+    pub link_host: Option<u16>,
+    pub link_connect: Option<String>,
+    pub link_interval: u32,
+    // Print a disassembly listing instead of running
+    pub disasm: bool,
+    // Number of frames to run for in headless mode, if not the movie's length
+    pub frames: Option<u32>,
+    // Record audio output to this file
+    pub wav: Option<String>,
+    // Memory for rewind snapshots, in MB; 0 turns rewinding off
+    pub rewind_budget: usize,
+    pub record_movie: Option<String>,
+    pub play_movie: Option<String>,
+    pub trace: Option<String>,
+    pub trace_filter: Option<TraceFilter>,
+    pub trace_ring: Option<usize>,
+    pub trace_labels: bool,
+    // Directory to write VRAM views to at the end of a headless run
+    pub dump_vram: Option<String>,
+    pub freezes: Vec<Freeze>,
+    // Log what the game sends over the link port
+    pub print_serial: bool,
+    // Colour scheme by name or as hex, and a file of more of them
+    pub palette: Option<String>,
+    pub palettes: Option<String>,
+    pub cgb: bool,
+    pub sgb: bool,
+    // Post-processing in the window
+    pub color_correction: bool,
+    pub blend: Blend,
+    pub lcd_grid: Grid,
+}
+
+impl Options {
+    pub fn from_args() -> Result<Self, String> {
+        let mut options = Options {
+            rom: DEFAULT_ROM.to_string(),
+            headless: false,
+            debug: false,
+            gdb: None,
+            test_roms: None,
+            link: None,
+            link_host: None,
+            link_connect: None,
+            link_interval: DEFAULT_SYNC_INTERVAL,
+            disasm: false,
+            frames: None,
+            wav: None,
+            rewind_budget: DEFAULT_REWIND_BUDGET,
+            record_movie: None,
+            play_movie: None,
+            trace: None,
+            trace_filter: None,
+            trace_ring: None,
+            trace_labels: false,
+            dump_vram: None,
+            freezes: Vec::new(),
+            print_serial: false,
+            palette: None,
+            palettes: None,
+            cgb: false,
+            sgb: false,
+            color_correction: false,
+            blend: Blend::Off,
+            lcd_grid: Grid::Off,
+        };
+
+        let mut args = env::args();
+
+        // Installed or linked as coolboy-disasm, act as the disassembler
+        let program = args.next().unwrap_or_default();
+        if Path::new(&program).file_stem().is_some_and(|stem| stem == "coolboy-disasm") {
+            options.disasm = true;
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--debug" => options.debug = true,
+                "--disasm" => options.disasm = true,
+                "--test-roms" => options.test_roms = Some(args.next().ok_or(USAGE)?),
+                "--link" => options.link = Some(args.next().ok_or(USAGE)?),
+                "--link-host" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.link_host = Some(value.parse()
+                        .map_err(|_| format!("Invalid port {}!", value))?);
+                },
+                "--link-connect" => options.link_connect = Some(args.next().ok_or(USAGE)?),
+                "--link-interval" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.link_interval = value.parse().ok()
+                        .filter(|interval| *interval >= MIN_SYNC_INTERVAL)
+                        .ok_or_else(|| format!("Invalid sync interval {}!", value))?;
+                },
+                "--gdb" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.gdb = Some(value.parse()
+                        .map_err(|_| format!("Invalid port {}!", value))?);
+                },
+                "--frames" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.frames = Some(value.parse()
+                        .map_err(|_| format!("Invalid frame count {}!", value))?);
+                },
+                "--wav" => options.wav = Some(args.next().ok_or(USAGE)?),
+                "--rewind-budget" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.rewind_budget = value.parse()
+                        .map_err(|_| format!("Invalid rewind budget {}!", value))?;
+                },
+                "--record-movie" => options.record_movie = Some(args.next().ok_or(USAGE)?),
+                "--play-movie" => options.play_movie = Some(args.next().ok_or(USAGE)?),
+                "--trace-labels" => options.trace_labels = true,
+                "--trace" => options.trace = Some(args.next().ok_or(USAGE)?),
+                "--trace-range" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.trace_filter = Some(parse_trace_range(&value)
+                        .ok_or_else(|| format!("Invalid trace range {}!", value))?);
+                },
+                "--trace-ring" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.trace_ring = Some(value.parse().ok().filter(|size| *size > 0)
+                        .ok_or_else(|| format!("Invalid trace ring size {}!", value))?);
+                },
+                "--print-serial" => options.print_serial = true,
+                "--palette" => options.palette = Some(args.next().ok_or(USAGE)?),
+                "--palettes" => options.palettes = Some(args.next().ok_or(USAGE)?),
+                "--cgb" => options.cgb = true,
+                "--sgb" => options.sgb = true,
+                "--color-correction" => options.color_correction = true,
+                "--blend" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.blend = Blend::from_name(&value)
+                        .ok_or_else(|| format!("Invalid blend mode {}!", value))?;
+                },
+                "--lcd-grid" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.lcd_grid = Grid::from_name(&value)
+                        .ok_or_else(|| format!("Invalid LCD grid {}!", value))?;
+                },
+                "--dump-vram" => options.dump_vram = Some(args.next().ok_or(USAGE)?),
+                "--freeze" => {
+                    let value = args.next().ok_or(USAGE)?;
+                    options.freezes.push(parse_freeze(&value)
+                        .ok_or_else(|| format!("Invalid freeze {}!", value))?);
+                },
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                _ if arg.starts_with("--") => {
+                    return Err(format!("Unknown option {}!\n{}", arg, USAGE));
+                },
+                _ => options.rom = arg,
+            }
+        }
+
+        if options.cgb && options.sgb {
+            return Err("Pick one of --cgb and --sgb!".to_string());
+        }
+
+        Ok(options)
+    }
+
+    pub fn frames_or_default(&self) -> u32 {
+        self.frames.unwrap_or(DEFAULT_FRAMES)
+    }
+
+    // The instruction tracer asked for, if any
+    pub fn tracer(&self) -> Result<Option<Tracer>, String> {
+        let filename = match &self.trace {
+            Some(filename) => filename,
+            None => return Ok(None)
+        };
+
+        let mut tracer = Tracer::create(filename, self.trace_ring).map_err(|e| e.to_string())?;
+        tracer.filter = self.trace_filter;
+        if self.trace_labels {
+            tracer.symbols = Symbols::for_rom(&self.rom);
+        }
+        Ok(Some(tracer))
+    }
+
+    pub fn filters(&self) -> Filters {
+        let mut filters = Filters::new();
+        filters.color_correction = self.color_correction;
+        filters.blend = self.blend;
+        filters.grid = self.lcd_grid;
+        filters
+    }
+
+    // Sets up how DMG games look, returning every colour scheme there is to
+    // switch between. Powers the emulator off and on again if --cgb or --sgb
+    // is given.
+    pub fn apply_colors(&self, emulator: &mut Emulator) -> Result<Vec<ColorScheme>, String> {
+        let mut schemes = ColorScheme::builtin();
+        match &self.palettes {
+            Some(filename) => {
+                schemes.extend(ColorScheme::load(Path::new(filename)).map_err(|e| format!("{}: {}", filename, e))?);
+            },
+            None if Path::new(DEFAULT_PALETTES).exists() => {
+                match ColorScheme::load(Path::new(DEFAULT_PALETTES)) {
+                    Ok(loaded) => schemes.extend(loaded),
+                    Err(e) => warn!("Failed to load colour schemes from {}: {}", DEFAULT_PALETTES, e)
+                }
+            },
+            None => ()
+        }
+
+        if self.cgb {
+            emulator.use_cgb_hardware();
+            schemes.insert(0, emulator.color_scheme().clone());
+        }
+        if self.sgb {
+            emulator.use_sgb_hardware();
+        }
+
+        if let Some(wanted) = &self.palette {
+            let scheme = match schemes.iter().find(|scheme| &scheme.name == wanted) {
+                Some(scheme) => scheme.clone(),
+                None => {
+                    let scheme = ColorScheme::from_hex("custom", wanted)
+                        .ok_or_else(|| format!("Unknown colour scheme {}!", wanted))?;
+                    schemes.push(scheme.clone());
+                    scheme
+                }
+            };
+            emulator.set_color_scheme(scheme);
+        }
+
+        Ok(schemes)
+    }
+}
+
+// [BANK:]START-END, all in hex
+fn parse_trace_range(text: &str) -> Option<TraceFilter> {
+    let (bank, range) = match text.find(':') {
+        Some(colon) => (Some(usize::from_str_radix(&text[..colon], 16).ok()?), &text[colon + 1..]),
+        None => (None, text)
+    };
+
+    let dash = range.find('-')?;
+    Some(TraceFilter {
+        start: u16::from_str_radix(&range[..dash], 16).ok()?,
+        end: u16::from_str_radix(&range[dash + 1..], 16).ok()?,
+        bank,
+    })
+}
+
+// [BANK:]ADDR=VALUE, all in hex
+fn parse_freeze(text: &str) -> Option<Freeze> {
+    let equals = text.find('=')?;
+    let (bank, address) = match text[..equals].find(':') {
+        Some(colon) => (Some(usize::from_str_radix(&text[..colon], 16).ok()?), &text[colon + 1..equals]),
+        None => (None, &text[..equals])
+    };
+
+    Some(Freeze {
+        address: u16::from_str_radix(address, 16).ok()?,
+        bank,
+        value: u8::from_str_radix(&text[equals + 1..], 16).ok()?,
+    })
+}
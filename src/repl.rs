@@ -0,0 +1,491 @@
+use super::emulator::disasm::io_register_name;
+use super::emulator::{Access, CaptureDevice, Condition, Debugger, Emulator, Flags, Freeze, PatternByte, Region, StopReason, Symbols, Watchpoint, ALL_CHANNELS};
+use super::options::Options;
+
+use std::io::{self, BufRead, Write};
+
+// How long continue and friends run for before giving control back, so a
+// missed breakpoint doesn't hang the terminal
+const DEFAULT_FRAME_LIMIT: u32 = 60 * 60;
+// Bytes shown by examine when not told how many
+const DEFAULT_EXAMINE_LENGTH: usize = 64;
+const EXAMINE_ROW_LENGTH: usize = 16;
+// Columns in each channel's line of the oscilloscope
+const SCOPE_WIDTH: usize = 64;
+const SCOPE_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+const HELP: &str = "Commands:
+    s, step [N]         Step N instructions (default 1)
+    n, next             Step over CALL and RST
+    finish              Run until the current function returns
+    u, until ADDR       Run to an address
+    l, list [ADDR] [N]  Disassemble N instructions (default 10) from ADDR or PC
+    c, continue [N]     Run until a breakpoint, or for N frames
+    b, break ADDR       Set a breakpoint
+    d, delete ADDR      Remove a breakpoint
+    watch WHERE [COND]  Stop on writes, e.g. watch 02:4000 == 3C
+    rwatch WHERE [COND] Stop on reads
+    cwatch WHERE [COND] Stop on writes that change the value, e.g. cwatch FE00-FE9F & F0 == 30
+    unwatch N           Remove a watchpoint
+    i, info             List breakpoints, watchpoints and freezes
+    x, examine WHERE [N] Show N bytes (default 64) from [BANK:]ADDR in hex
+    set WHERE BYTES     Write bytes, e.g. set 01:A000 3C 00
+    find BYTES          Search memory and every bank, ?? matches any byte
+    freeze WHERE [BYTE] Pin an address to a value (default its current one) every frame
+    unfreeze N          Remove a freeze
+    io                  Show the IO registers
+    scope               Run a frame and draw each sound channel's waveform
+    r, regs             Show registers and flags
+    bank                Show the current ROM and RAM banks
+    bt, backtrace       Show the call stack
+    q, quit             Exit
+An empty line repeats the last command. Addresses are hex, e.g. 0150 or $0150,
+and can be given a bank as BANK:ADDR, which for 4000-7FFF and A000-BFFF picks the
+bank to look at rather than the mapped one. Breakpoints, until, list, examine,
+set and freeze also take labels from a .sym file next to the ROM.";
+
+// A terminal debugger: the emulator runs with no window and stops at the
+// first instruction
+pub fn run(options: &Options) -> Result<(), String> {
+    let mut emulator = Emulator::from_file(&options.rom).map_err(|e| e.to_string())?;
+    options.apply_colors(&mut emulator)?;
+    if let Some(tracer) = options.tracer()? {
+        emulator.start_trace(tracer);
+    }
+    for freeze in &options.freezes {
+        emulator.add_freeze(*freeze).map_err(|e| e.to_string())?;
+    }
+    if options.print_serial {
+        emulator.set_serial_device(Box::new(CaptureDevice::new()));
+    }
+    let mut debugger = Debugger::new(emulator);
+    if let Some(symbols) = Symbols::for_rom(&options.rom) {
+        debugger.set_symbols(symbols);
+    }
+
+    println!("Debugging {}, type help for commands", options.rom);
+    print_location(&debugger);
+
+    let stdin = io::stdin();
+    let mut last_command = String::new();
+    loop {
+        print!("(coolboy) ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Ok(());
+        }
+
+        let line = match line.trim() {
+            "" => last_command.clone(),
+            line => line.to_string(),
+        };
+
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue
+        };
+        let argument = words.next();
+        let rest: String = words.collect();
+
+        match command {
+            "s" | "step" => {
+                let count = argument.and_then(|count| count.parse().ok()).unwrap_or(1);
+                let mut reason = StopReason::Step;
+                for _ in 0..count {
+                    reason = debugger.step_in();
+                    if let StopReason::Watchpoint(_) = reason {
+                        break;
+                    }
+                }
+                report(&debugger, reason);
+            },
+            "n" | "next" => {
+                let reason = debugger.step_over(DEFAULT_FRAME_LIMIT);
+                report(&debugger, reason);
+            },
+            "finish" => {
+                let reason = debugger.step_out(DEFAULT_FRAME_LIMIT);
+                report(&debugger, reason);
+            },
+            "u" | "until" => match argument.and_then(|location| parse_location(&debugger, location)) {
+                Some((address, _)) => {
+                    let reason = debugger.run_to(address, DEFAULT_FRAME_LIMIT);
+                    report(&debugger, reason);
+                },
+                None => println!("Usage: until ADDR")
+            },
+            "c" | "continue" => {
+                let frames = argument.and_then(|frames| frames.parse().ok()).unwrap_or(DEFAULT_FRAME_LIMIT);
+                let reason = debugger.run(frames);
+                report(&debugger, reason);
+            },
+            "b" | "break" => match argument.and_then(|location| parse_location(&debugger, location)) {
+                Some((address, bank)) => {
+                    let name = describe_breakpoint(&debugger, address, bank);
+                    if debugger.add_breakpoint(address, bank) {
+                        println!("Breakpoint at {}", name);
+                    } else {
+                        println!("There's already a breakpoint at {}", name);
+                    }
+                },
+                None => println!("Usage: break [BANK:]ADDR or break LABEL")
+            },
+            "d" | "delete" => match argument.and_then(|location| parse_location(&debugger, location)) {
+                Some((address, bank)) => {
+                    if !debugger.remove_breakpoint(address, bank) {
+                        println!("No breakpoint at {}", describe_breakpoint(&debugger, address, bank));
+                    }
+                },
+                None => println!("Usage: delete [BANK:]ADDR or delete LABEL")
+            },
+            "watch" | "rwatch" | "cwatch" => {
+                let access = match command {
+                    "rwatch" => Access::Read,
+                    "cwatch" => Access::Change,
+                    _ => Access::Write
+                };
+                match argument.and_then(|range| parse_watchpoint(access, range, &rest)) {
+                    Some(watchpoint) => debugger.add_watchpoint(watchpoint),
+                    None => println!("Usage: {} [BANK:]ADDR[-END] [== VALUE | & MASK == VALUE]", command)
+                }
+            },
+            "unwatch" => match argument.and_then(|index| index.parse().ok()) {
+                Some(index) => {
+                    if debugger.remove_watchpoint(index).is_none() {
+                        println!("No watchpoint {}", index);
+                    }
+                },
+                None => println!("Usage: unwatch N")
+            },
+            "i" | "info" => {
+                for (address, bank) in debugger.breakpoints() {
+                    println!("Breakpoint {}", describe_breakpoint(&debugger, *address, *bank));
+                }
+                for (index, watchpoint) in debugger.watchpoints().iter().enumerate() {
+                    println!("Watchpoint {}: {}", index, describe_watchpoint(watchpoint));
+                }
+                for (index, freeze) in debugger.freezes().iter().enumerate() {
+                    println!("Freeze {}: {} = {:02X}", index, format_location(freeze.address, freeze.bank), freeze.value);
+                }
+            },
+            "x" | "examine" => match argument.and_then(|location| parse_location(&debugger, location)) {
+                Some((address, bank)) => {
+                    let length = rest.parse().unwrap_or(DEFAULT_EXAMINE_LENGTH);
+                    examine(&debugger, address, bank, length);
+                },
+                None => println!("Usage: examine [BANK:]ADDR [N]")
+            },
+            "set" => {
+                let location = argument.and_then(|location| parse_location(&debugger, location));
+                match (location, parse_bytes(&rest)) {
+                    (Some((address, bank)), Some(bytes)) if !bytes.is_empty() => {
+                        for (offset, byte) in bytes.iter().enumerate() {
+                            let address = address.wrapping_add(offset as u16);
+                            if let Err(e) = debugger.edit(address, bank, *byte) {
+                                println!("Can't write ${:04X}: {}", address, e);
+                                break;
+                            }
+                        }
+                    },
+                    _ => println!("Usage: set [BANK:]ADDR BYTE...")
+                }
+            },
+            "find" => {
+                let pattern = parse_pattern(&format!("{}{}", argument.unwrap_or(""), rest));
+                match pattern {
+                    Some(pattern) if !pattern.is_empty() => {
+                        let found = debugger.search(&pattern);
+                        for (address, bank) in &found {
+                            println!("{} {}", Region::of(*address).name(), format_location(*address, *bank));
+                        }
+                        println!("{} found", found.len());
+                    },
+                    _ => println!("Usage: find BYTE... (?? for any byte)")
+                }
+            },
+            "freeze" => match argument.and_then(|location| parse_location(&debugger, location)) {
+                Some((address, bank)) => {
+                    let value = parse_value(&rest).unwrap_or_else(|| debugger.inspect(address, bank));
+                    if let Err(e) = debugger.add_freeze(Freeze { address, bank, value }) {
+                        println!("Can't freeze ${:04X}: {}", address, e);
+                    }
+                },
+                None => println!("Usage: freeze [BANK:]ADDR [BYTE]")
+            },
+            "unfreeze" => match argument.and_then(|index| index.parse().ok()) {
+                Some(index) => {
+                    if debugger.remove_freeze(index).is_none() {
+                        println!("No freeze {}", index);
+                    }
+                },
+                None => println!("Usage: unfreeze N")
+            },
+            "io" => {
+                for address in 0xFF00..=0xFFFF {
+                    if let Some(name) = io_register_name(address, debugger.is_cgb()) {
+                        println!("{:<6} ${:04X} = ${:02X}", name, address, debugger.read(address));
+                    }
+                }
+            },
+            "scope" => {
+                debugger.set_channel_taps(true);
+                let reason = debugger.run(1);
+                for channel in ALL_CHANNELS.iter() {
+                    println!("{:<8} {}", format!("{:?}", channel), scope_line(&debugger.take_channel_samples(*channel)));
+                }
+                debugger.set_channel_taps(false);
+                // Running out the frame is the point, so only say why it
+                // stopped if it was early
+                match reason {
+                    StopReason::FrameLimit => print_location(&debugger),
+                    reason => report(&debugger, reason),
+                }
+            },
+            "l" | "list" => {
+                let mut address = argument.and_then(|location| parse_location(&debugger, location))
+                    .map_or_else(|| debugger.pc(), |(address, _)| address);
+                let count = rest.parse().unwrap_or(10);
+                for _ in 0..count {
+                    if let Some(label) = debugger.label(address) {
+                        println!("{}:", label);
+                    }
+                    let instruction = debugger.disassemble(address);
+                    println!("${:04X}  {}", address, instruction.format_with(debugger.is_cgb(), |address| debugger.label(address)));
+                    address = address.wrapping_add(instruction.length);
+                }
+            },
+            "r" | "regs" => print_registers(&debugger),
+            "bank" => {
+                let banks = debugger.banks();
+                println!("ROM bank {:02X}, RAM bank {:X} ({})", banks.rom, banks.ram,
+                    if banks.ram_enabled { "enabled" } else { "disabled" });
+            },
+            "bt" | "backtrace" => {
+                if debugger.call_stack().is_empty() {
+                    println!("No calls on the stack");
+                }
+                for (depth, frame) in debugger.call_stack().iter().rev().enumerate() {
+                    if frame.interrupt {
+                        println!("#{:<2} {} interrupted {} (SP ${:04X})",
+                            depth, name_address(&debugger, frame.target), name_address(&debugger, frame.call_site), frame.sp);
+                    } else {
+                        println!("#{:<2} {} called from {}, returns to ${:04X} (SP ${:04X})",
+                            depth, name_address(&debugger, frame.target), name_address(&debugger, frame.call_site),
+                            frame.return_address, frame.sp);
+                    }
+                }
+            },
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return Ok(()),
+            _ => println!("Unknown command {}, type help for commands", command)
+        }
+
+        last_command = line;
+    }
+}
+
+fn report(debugger: &Debugger, reason: StopReason) {
+    match reason {
+        StopReason::Breakpoint(address) => println!("Breakpoint at ${:04X}", address),
+        StopReason::Watchpoint(hit) => {
+            let access = match hit.access {
+                Access::Read => "Read from",
+                Access::Write => "Write to",
+                Access::Change => "Change at",
+            };
+            let bank = hit.bank.map_or(String::new(), |bank| format!("{:02X}:", bank));
+            println!("{} {}{:04X} by ${:04X}: ${:02X} -> ${:02X}",
+                access, bank, hit.address, hit.pc, hit.old, hit.new);
+        },
+        StopReason::FrameLimit => println!("Stopped after the frame limit"),
+        StopReason::Step | StopReason::Finished => ()
+    }
+    print_location(debugger);
+}
+
+fn print_location(debugger: &Debugger) {
+    let pc = debugger.pc();
+    let instruction = debugger.disassemble(pc).format_with(debugger.is_cgb(), |address| debugger.label(address));
+    match debugger.describe(pc) {
+        Some(label) => println!("${:02X}:{:04X}  {:<24} ; {}", debugger.banks().rom, pc, instruction, label),
+        None => println!("${:02X}:{:04X}  {}", debugger.banks().rom, pc, instruction),
+    }
+}
+
+// $1234, followed by the nearest label if there is one
+fn name_address(debugger: &Debugger, address: u16) -> String {
+    match debugger.describe(address) {
+        Some(label) => format!("${:04X} <{}>", address, label),
+        None => format!("${:04X}", address)
+    }
+}
+
+fn describe_breakpoint(debugger: &Debugger, address: u16, bank: Option<usize>) -> String {
+    let label = match bank {
+        Some(bank) => debugger.symbols().describe(bank, address),
+        None => debugger.describe(address),
+    };
+
+    let mut text = match bank {
+        Some(bank) => format!("${:02X}:{:04X}", bank, address),
+        None => format!("${:04X}", address),
+    };
+    if let Some(label) = label {
+        text += &format!(" <{}>", label);
+    }
+    text
+}
+
+fn print_registers(debugger: &Debugger) {
+    let registers = debugger.registers();
+    let flag = |flag: Flags, name: char| if registers.f.contains(flag) { name } else { '-' };
+
+    println!("AF ${:04X}  BC ${:04X}  DE ${:04X}  HL ${:04X}",
+        registers.af(), registers.bc(), registers.de(), registers.hl());
+    println!("SP ${:04X}  PC ${:04X}  IME {}", debugger.sp(), debugger.pc(), debugger.ime() as u8);
+    println!("Flags {}{}{}{}",
+        flag(Flags::ZERO, 'Z'), flag(Flags::SUBTRACT, 'N'),
+        flag(Flags::HALF_CARRY, 'H'), flag(Flags::CARRY, 'C'));
+}
+
+// A label, or [BANK:]ADDR in hex. Labels in switchable ROM only apply to
+// their own bank.
+fn parse_location(debugger: &Debugger, text: &str) -> Option<(u16, Option<usize>)> {
+    if let Some((bank, address)) = debugger.symbols().lookup(text) {
+        let banked = (0x4000..=0x7FFF).contains(&address);
+        return Some((address, if banked { Some(bank) } else { None }));
+    }
+
+    match text.find(':') {
+        Some(colon) => Some((parse_address(&text[colon + 1..])?, Some(usize::from_str_radix(&text[..colon], 16).ok()?))),
+        None => Some((parse_address(text)?, None))
+    }
+}
+
+// Hex, with an optional $ or 0x in front
+fn parse_address(text: &str) -> Option<u16> {
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).ok()
+}
+
+// [BANK:]ADDR[-END], then an optional "== VALUE" or "& MASK == VALUE" with
+// the spaces already taken out
+fn parse_watchpoint(access: Access, range: &str, condition: &str) -> Option<Watchpoint> {
+    let (bank, range) = match range.find(':') {
+        Some(colon) => (Some(usize::from_str_radix(&range[..colon], 16).ok()?), &range[colon + 1..]),
+        None => (None, range)
+    };
+
+    let (start, end) = match range.find('-') {
+        Some(dash) => (parse_address(&range[..dash])?, parse_address(&range[dash + 1..])?),
+        None => (parse_address(range)?, parse_address(range)?)
+    };
+
+    let condition = if condition.is_empty() {
+        Condition::Any
+    } else if let Some(value) = condition.strip_prefix("==") {
+        Condition::Equals(parse_value(value)?)
+    } else if let Some(masked) = condition.strip_prefix('&') {
+        let equals = masked.find("==")?;
+        Condition::Masked {
+            mask: parse_value(&masked[..equals])?,
+            expected: parse_value(&masked[equals + 2..])?,
+        }
+    } else {
+        return None;
+    };
+
+    let mut watchpoint = Watchpoint::new(access, start.min(end));
+    watchpoint.end = start.max(end);
+    watchpoint.bank = bank;
+    watchpoint.condition = condition;
+    Some(watchpoint)
+}
+
+fn parse_value(text: &str) -> Option<u8> {
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    u8::from_str_radix(digits, 16).ok()
+}
+
+// Rows of hex and ASCII, starting each row on a multiple of 16
+fn examine(debugger: &Debugger, address: u16, bank: Option<usize>, length: usize) {
+    let start = address as usize & !(EXAMINE_ROW_LENGTH - 1);
+    let end = (address as usize + length).min(0x10000);
+
+    for row in (start..end).step_by(EXAMINE_ROW_LENGTH) {
+        let mut hex = String::new();
+        let mut text = String::new();
+        for address in row..row + EXAMINE_ROW_LENGTH {
+            let byte = debugger.inspect(address as u16, bank);
+            hex += &format!("{:02X} ", byte);
+            text.push(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+        }
+
+        let row = row as u16;
+        // Only show the bank where it means something
+        let bank = if Region::of(row).is_banked() { bank } else { None };
+        println!("{:<4} {:>8}  {} {}", Region::of(row).name(), format_location(row, bank), hex, text);
+    }
+}
+
+// One character per column, each the average of the samples it covers,
+// from -1 at the bottom to 1 at the top
+fn scope_line(samples: &[f32]) -> String {
+    if samples.is_empty() {
+        return String::new();
+    }
+
+    (0..SCOPE_WIDTH).map(|column| {
+        let start = column * samples.len() / SCOPE_WIDTH;
+        let end = ((column + 1) * samples.len() / SCOPE_WIDTH).max(start + 1);
+        let slice = &samples[start..end.min(samples.len())];
+        let level = slice.iter().sum::<f32>() / slice.len() as f32;
+        let index = ((level + 1.0) / 2.0 * SCOPE_LEVELS.len() as f32) as usize;
+        SCOPE_LEVELS[index.min(SCOPE_LEVELS.len() - 1)]
+    }).collect()
+}
+
+fn format_location(address: u16, bank: Option<usize>) -> String {
+    match bank {
+        Some(bank) => format!("{:02X}:{:04X}", bank, address),
+        None => format!("${:04X}", address),
+    }
+}
+
+// Hex bytes with the spaces already taken out, e.g. "3C00FF"
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    parse_pattern(text)?.into_iter().collect()
+}
+
+// Like parse_bytes, but ?? stands for any byte
+fn parse_pattern(text: &str) -> Option<Vec<PatternByte>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+
+    (0..text.len()).step_by(2).map(|index| match &text[index..index + 2] {
+        "??" => Some(None),
+        pair => u8::from_str_radix(pair, 16).ok().map(Some),
+    }).collect()
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    let mut description = format!("{:?} ", watchpoint.access);
+    if let Some(bank) = watchpoint.bank {
+        description += &format!("{:02X}:", bank);
+    }
+    description += &format!("{:04X}", watchpoint.start);
+    if watchpoint.end != watchpoint.start {
+        description += &format!("-{:04X}", watchpoint.end);
+    }
+
+    match watchpoint.condition {
+        Condition::Any => (),
+        Condition::Equals(value) => description += &format!(" == {:02X}", value),
+        Condition::Masked { mask, expected } => description += &format!(" & {:02X} == {:02X}", mask, expected),
+    }
+    description
+}
@@ -0,0 +1,45 @@
+use super::emulator::Emulator;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const SLOT_COUNT: u8 = 10;
+
+// Numbered save state files kept next to the ROM, e.g. tetris.ss0
+pub struct SaveSlots {
+    base: PathBuf,
+    current: u8,
+}
+
+impl SaveSlots {
+    pub fn new(rom: &str) -> Self {
+        SaveSlots {
+            base: Path::new(rom).with_extension(""),
+            current: 0,
+        }
+    }
+
+    pub fn select(&mut self, slot: u8) {
+        self.current = slot % SLOT_COUNT;
+        info!("Selected save slot {}", self.current);
+    }
+
+    fn path(&self) -> PathBuf {
+        self.base.with_extension(format!("ss{}", self.current))
+    }
+
+    pub fn save(&self, emulator: &Emulator) -> Result<(), io::Error> {
+        let path = self.path();
+        fs::write(&path, emulator.save_state())?;
+        info!("Saved state to {}", path.display());
+        Ok(())
+    }
+
+    pub fn load(&self, emulator: &mut Emulator) -> Result<(), io::Error> {
+        let path = self.path();
+        emulator.load_state(&fs::read(&path)?)?;
+        info!("Loaded state from {}", path.display());
+        Ok(())
+    }
+}